@@ -19,6 +19,14 @@ struct Cli {
     /// Subcommand
     #[command(subcommand)]
     command: Commands,
+
+    /// A file containing the secret the daemon requires
+    #[arg(long, global = true)]
+    auth_file: Option<String>,
+
+    /// Print the responses of the daemon as JSON
+    #[arg(long, global = true)]
+    json: bool,
 }
 
 #[derive(Subcommand)]
@@ -32,6 +40,9 @@ enum Commands {
         #[arg(long = "type", default_value = "application/json")]
         content_type: String,
         content: Option<String>,
+        /// Only check whether the extension can be set up, without adding it
+        #[arg(long)]
+        validate_only: bool,
     },
     #[command(name = "rm")]
     /// Remove an extension
@@ -52,6 +63,26 @@ enum Commands {
         #[arg(value_parser=parse_id)]
         id: QBExtId,
     },
+    /// Preview the changes pending to be synchronized with an interface
+    Diff {
+        /// the id of the interface in hex format
+        #[arg(value_parser=parse_id)]
+        id: QBExtId,
+    },
+    /// Watch the events emitted by the daemon
+    Watch,
+    /// Re-read the config of the daemon from disk
+    Reload,
+    /// Show the status of the daemon
+    Status,
+    /// Show aggregate metrics of the daemon
+    Stats,
+    /// Pause or resume synchronizing with all interfaces
+    Maintenance {
+        /// whether maintenance mode should be enabled
+        #[arg(action = clap::ArgAction::Set)]
+        enabled: bool,
+    },
 }
 
 fn parse_id(s: &str) -> Result<QBExtId, String> {
@@ -89,6 +120,7 @@ async fn process_args(args: Cli) -> Option<()> {
             name,
             content_type,
             content,
+            validate_only,
         } => {
             let content = match content {
                 Some(content) => content.into_bytes(),
@@ -104,64 +136,115 @@ async fn process_args(args: Cli) -> Option<()> {
                     content,
                 },
                 name,
+                validate_only,
             };
 
-            let mut conn = connect().await?;
-            let mut protocol = QBP::default();
-            protocol.negotiate(&mut conn).await.unwrap();
+            let (mut protocol, mut conn) = connect(args.auth_file.as_deref()).await?;
             protocol.send(&mut conn, req).await.unwrap();
-            finish(protocol, conn).await;
+            finish(protocol, conn, args.json).await;
         }
         Commands::Remove { id } => {
             let req = QBCRequest::Remove { id };
-            let mut conn = connect().await?;
-            let mut protocol = QBP::default();
-            protocol.negotiate(&mut conn).await.unwrap();
+            let (mut protocol, mut conn) = connect(args.auth_file.as_deref()).await?;
             protocol.send(&mut conn, req).await.unwrap();
-            finish(protocol, conn).await;
+            finish(protocol, conn, args.json).await;
         }
         Commands::Start { id } => {
             let req = QBCRequest::Start { id };
-            let mut conn = connect().await?;
-            let mut protocol = QBP::default();
-            protocol.negotiate(&mut conn).await.unwrap();
+            let (mut protocol, mut conn) = connect(args.auth_file.as_deref()).await?;
             protocol.send(&mut conn, req).await.unwrap();
-            finish(protocol, conn).await;
+            finish(protocol, conn, args.json).await;
         }
         Commands::Stop { id } => {
             let req = QBCRequest::Stop { id };
-            let mut conn = connect().await?;
-            let mut protocol = QBP::default();
-            protocol.negotiate(&mut conn).await.unwrap();
+            let (mut protocol, mut conn) = connect(args.auth_file.as_deref()).await?;
             protocol.send(&mut conn, req).await.unwrap();
-            finish(protocol, conn).await;
+            finish(protocol, conn, args.json).await;
         }
         Commands::List => {
             let req = QBCRequest::List;
-            let mut conn = connect().await?;
-            let mut protocol = QBP::default();
-            protocol.negotiate(&mut conn).await.unwrap();
+            let (mut protocol, mut conn) = connect(args.auth_file.as_deref()).await?;
+            protocol.send(&mut conn, req).await.unwrap();
+            finish(protocol, conn, args.json).await;
+        }
+        Commands::Diff { id } => {
+            let req = QBCRequest::Diff { id };
+            let (mut protocol, mut conn) = connect(args.auth_file.as_deref()).await?;
+            protocol.send(&mut conn, req).await.unwrap();
+            finish(protocol, conn, args.json).await;
+        }
+        Commands::Reload => {
+            let req = QBCRequest::Reload;
+            let (mut protocol, mut conn) = connect(args.auth_file.as_deref()).await?;
+            protocol.send(&mut conn, req).await.unwrap();
+            finish(protocol, conn, args.json).await;
+        }
+        Commands::Status => {
+            let req = QBCRequest::Status;
+            let (mut protocol, mut conn) = connect(args.auth_file.as_deref()).await?;
+            protocol.send(&mut conn, req).await.unwrap();
+            finish(protocol, conn, args.json).await;
+        }
+        Commands::Stats => {
+            let req = QBCRequest::Stats;
+            let (mut protocol, mut conn) = connect(args.auth_file.as_deref()).await?;
             protocol.send(&mut conn, req).await.unwrap();
-            finish(protocol, conn).await;
+            finish(protocol, conn, args.json).await;
+        }
+        Commands::Maintenance { enabled } => {
+            let req = QBCRequest::Maintenance { enabled };
+            let (mut protocol, mut conn) = connect(args.auth_file.as_deref()).await?;
+            protocol.send(&mut conn, req).await.unwrap();
+            finish(protocol, conn, args.json).await;
+        }
+        Commands::Watch => {
+            let req = QBCRequest::Subscribe;
+            let (mut protocol, mut conn) = connect(args.auth_file.as_deref()).await?;
+            protocol.send(&mut conn, req).await.unwrap();
+            loop {
+                let resp = protocol.recv::<QBCResponse>(&mut conn).await.unwrap();
+                match args.json {
+                    true => println!("{}", serde_json::to_string(&resp).unwrap()),
+                    false => println!("{}", resp),
+                }
+            }
         }
     };
 
     Some(())
 }
 
-async fn finish(mut protocol: QBP, mut conn: TStream) {
-    let resp = protocol.recv::<QBCResponse>(&mut conn).await.unwrap();
+async fn finish(mut protocol: QBP, mut conn: TStream, json: bool) {
+    let resp = match protocol.recv::<QBCResponse>(&mut conn).await {
+        Ok(resp) => resp,
+        Err(err) => {
+            eprintln!("could not receive response: {}", err);
+            return;
+        }
+    };
+    if json {
+        let out = serde_json::to_string(&resp).unwrap();
+        match resp {
+            QBCResponse::Error { .. } => eprintln!("{}", out),
+            _ => println!("{}", out),
+        }
+        return;
+    }
     match resp {
         QBCResponse::Error { .. } => eprintln!("{}", resp),
+        // print the bare id, so that scripts can use it
+        QBCResponse::Added { id } => println!("{}", id),
         _ => println!("{}", resp),
     }
 }
 
-async fn connect() -> Option<TStream> {
+/// Connect to the daemon socket and authenticate,
+/// if the path to a secret is given.
+async fn connect(auth_file: Option<&str>) -> Option<(QBP, TStream)> {
     let name = "qb-daemon.sock";
     let name = name.to_ns_name::<GenericNamespaced>().unwrap();
 
-    let connection = match TStream::connect(name).await {
+    let mut conn = match TStream::connect(name).await {
         Ok(conn) => conn,
         Err(err) => {
             eprintln!("could not connect to daemon socket: {}", err);
@@ -169,5 +252,23 @@ async fn connect() -> Option<TStream> {
         }
     };
 
-    Some(connection)
+    let mut protocol = QBP::default();
+    protocol.negotiate(&mut conn).await.unwrap();
+
+    if let Some(path) = auth_file {
+        let secret = match std::fs::read(path) {
+            Ok(secret) => secret,
+            Err(err) => {
+                eprintln!("could not read secret from {}: {}", path, err);
+                std::process::exit(1);
+            }
+        };
+        let len = secret.trim_ascii_end().len();
+        protocol
+            .send_payload(&mut conn, &secret[..len])
+            .await
+            .unwrap();
+    }
+
+    Some((protocol, conn))
 }
@@ -5,6 +5,8 @@ use qb_core::fs::wrapper::QBFSWrapper;
 use qb_daemon::daemon::QBDaemon;
 use qb_daemon::master::QBMaster;
 use qb_ext_local::QBILocalSetup;
+use qb_ext_pipe::QBIPipeSetup;
+use qb_ext_sftp::QBISftpSetup;
 use qb_ext_tcp::{client::QBITCPClientSetup, server::QBHTCPServerSetup};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tracing::{info, level_filters::LevelFilter};
@@ -40,6 +42,10 @@ struct Cli {
     /// The path, where the daemon stores its files
     #[clap(long, short, default_value = "./run/daemon1")]
     path: String,
+
+    /// A file containing a secret which control clients have to send
+    #[clap(long)]
+    auth_file: Option<String>,
 }
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
@@ -96,8 +102,13 @@ async fn main() {
 
     // Initialize the daemon
     let mut daemon = QBDaemon::init(master, wrapper).await;
+    if let Some(path) = args.auth_file {
+        daemon = daemon.with_auth(read_secret(&path));
+    }
     daemon.register_qbi::<QBILocalSetup, _>("local");
     daemon.register_qbi::<QBITCPClientSetup, _>("tcp-client");
+    daemon.register_qbi::<QBIPipeSetup, _>("pipe");
+    daemon.register_qbi::<QBISftpSetup, _>("sftp");
     daemon.register_qbh::<QBHTCPServerSetup, _, _>("tcp-server");
     daemon.autostart().await;
 
@@ -114,6 +125,12 @@ async fn main() {
                 Some(v) = daemon.master.qbi_rx.recv() => daemon.master.iprocess(v).await,
                 // process hooks
                 Some(v) = daemon.master.qbh_rx.recv() => daemon.master.hprocess(v),
+                // process interfaces whose handshake deadline passed
+                Some(v) = daemon.master.handshake_rx.recv() => daemon.master.check_handshake(v),
+                // process state transitions of interfaces
+                Some(v) = daemon.master.state_rx.recv() => daemon.supervise(v).await,
+                // process autostart interfaces which should be restarted
+                Some(v) = daemon.retry_rx.recv() => daemon.retry(v).await,
                 // process control messages
                 Some(v) = daemon.req_rx.recv() => daemon.process(v).await,
                 // process daemon socket
@@ -129,6 +146,12 @@ async fn main() {
             Some(v) = daemon.master.qbi_rx.recv() => daemon.master.iprocess(v).await,
             // process hooks
             Some(v) = daemon.master.qbh_rx.recv() => daemon.master.hprocess(v),
+            // process interfaces whose handshake deadline passed
+            Some(v) = daemon.master.handshake_rx.recv() => daemon.master.check_handshake(v),
+            // process state transitions of interfaces
+            Some(v) = daemon.master.state_rx.recv() => daemon.supervise(v).await,
+            // process autostart interfaces which should be restarted
+            Some(v) = daemon.retry_rx.recv() => daemon.retry(v).await,
             // process control messages
             Some(v) = daemon.req_rx.recv() => daemon.process(v).await,
             // process daemon setup queue
@@ -137,6 +160,22 @@ async fn main() {
     }
 }
 
+/// Read the secret for control clients from the given file,
+/// ignoring trailing whitespace such as a final newline.
+///
+/// Exits if the file cannot be read.
+fn read_secret(path: &str) -> Vec<u8> {
+    let secret = match std::fs::read(path) {
+        Ok(secret) => secret,
+        Err(err) => {
+            eprintln!("could not read secret from {}: {}", path, err);
+            std::process::exit(1);
+        }
+    };
+    let len = secret.trim_ascii_end().len();
+    secret[..len].to_vec()
+}
+
 #[derive(Debug)]
 struct StdStream {
     stdin: tokio::io::Stdin,
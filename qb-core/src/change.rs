@@ -8,8 +8,32 @@ use std::{collections::HashMap, fmt};
 use bitcode::{Decode, Encode};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-use crate::{diff::QBDiff, path::QBResource, time::QBTimeStampUnique};
+use crate::{
+    device::QBDeviceId,
+    diff::{QBBinaryPatch, QBDiff},
+    hash::QBHash,
+    path::QBResource,
+    time::{QBTimeStampUnique, QB_TIMESTAMP_BASE},
+};
+
+pub mod clock;
+pub mod journal;
+
+use clock::QBVectorClock;
+
+/// Two concurrent changes to the same resource, of which
+/// only one can win when merging.
+#[derive(Debug, Clone)]
+pub struct QBConflict {
+    /// The resource both changes were applied to
+    pub resource: QBResource,
+    /// The change of the local side
+    pub local: QBChange,
+    /// The change of the remote side
+    pub remote: QBChange,
+}
 
 /// This struct represents a change applied to some file.
 #[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone)]
@@ -18,6 +42,14 @@ pub struct QBChange {
     pub timestamp: QBTimeStampUnique,
     /// The kind of change
     pub kind: QBChangeKind,
+    /// The devices which relayed this change, oldest first,
+    /// see [QBChange::relay]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub provenance: Vec<QBDeviceId>,
+    /// The modification time of the file after this change on the
+    /// device which recorded it, in nanoseconds since the unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
 }
 
 impl fmt::Display for QBChange {
@@ -29,7 +61,38 @@ impl fmt::Display for QBChange {
 impl QBChange {
     /// Construct a new change.
     pub fn new(timestamp: QBTimeStampUnique, kind: QBChangeKind) -> Self {
-        Self { timestamp, kind }
+        Self {
+            timestamp,
+            kind,
+            provenance: Vec::new(),
+            mtime: None,
+        }
+    }
+
+    /// Set the modification time the file has after this change,
+    /// which is restored when the change is applied.
+    pub fn with_mtime(mut self, mtime: Option<u64>) -> Self {
+        self.mtime = mtime;
+        self
+    }
+
+    /// The maximum amount of devices stored in the provenance of a change.
+    pub const PROVENANCE_LEN: usize = 16;
+
+    /// Record that this change has been relayed by the given device.
+    ///
+    /// Only the most recent [QBChange::PROVENANCE_LEN] devices are kept.
+    /// Returns false, if the change has been relayed by the device before,
+    /// which means that it went around in a loop.
+    pub fn relay(&mut self, device: &QBDeviceId) -> bool {
+        if self.provenance.contains(device) {
+            return false;
+        }
+        if self.provenance.len() >= Self::PROVENANCE_LEN {
+            self.provenance.remove(0);
+        }
+        self.provenance.push(device.clone());
+        true
     }
 }
 
@@ -45,6 +108,13 @@ pub enum QBChangeKind {
     /// Update file contents (binary)
     #[serde(with = "serde_bytes")]
     UpdateBinary(Vec<u8>),
+    /// Update file contents (binary) using a patch
+    BinaryDiff {
+        /// the hash of the contents the patch is based on
+        base_hash: QBHash,
+        /// the patch
+        patch: QBBinaryPatch,
+    },
     /// Rename resource (destination)
     /// This change should have the same timestamp as the
     /// corresponding RenameFrom entry.
@@ -61,6 +131,14 @@ pub enum QBChangeKind {
     /// This change should have the same timestamp as the
     /// corresponding CopyTo entries.
     CopyFrom,
+    /// Set (or remove, if value is none) an extended attribute
+    XAttr {
+        /// the name of the attribute
+        name: String,
+        /// the value of the attribute
+        #[serde(with = "serde_bytes")]
+        value: Option<Vec<u8>>,
+    },
 }
 
 impl QBChangeKind {
@@ -75,6 +153,27 @@ impl QBChangeKind {
     pub fn is_subtractive(&self) -> bool {
         matches!(self, QBChangeKind::Delete | QBChangeKind::RenameFrom)
     }
+
+    /// Returns whether this change updates the contents of the resource.
+    #[inline(always)]
+    pub fn is_update(&self) -> bool {
+        matches!(
+            self,
+            QBChangeKind::UpdateText(..)
+                | QBChangeKind::UpdateBinary(..)
+                | QBChangeKind::BinaryDiff { .. }
+        )
+    }
+
+    /// Returns the rank used for ordering changes with the same timestamp.
+    /// Changes which other changes rely on come first.
+    #[inline(always)]
+    fn rank(&self) -> u8 {
+        match self.is_external() {
+            true => 0,
+            false => 1,
+        }
+    }
 }
 
 /// This struct is a map which stores a collection of changes for each resource.
@@ -82,11 +181,50 @@ impl QBChangeKind {
 pub struct QBChangeMap {
     changes: HashMap<QBResource, Vec<QBChange>>,
     head: QBTimeStampUnique,
+    /// changes up to this timestamp may have been removed
+    #[serde(default)]
+    watermark: QBTimeStampUnique,
+    /// the newest change seen of every device
+    #[serde(default)]
+    clock: QBVectorClock,
+}
+
+/// Error returned when the changes since a timestamp are requested,
+/// but some of them have already been removed from the changemap.
+///
+/// The peer has to be synchronized from scratch then.
+#[derive(Error, Debug)]
+#[error("changes since {since} have been truncated up to {watermark}")]
+pub struct QBTruncatedError {
+    /// the timestamp the changes were requested since
+    pub since: QBTimeStampUnique,
+    /// the watermark of the changemap
+    pub watermark: QBTimeStampUnique,
 }
 
 impl QBChangeMap {
+    /// Returns an error if changes since the timestamp
+    /// have been removed by [QBChangeMap::truncate].
+    fn check_since(&self, since: &QBTimeStampUnique) -> Result<(), QBTruncatedError> {
+        if since < &self.watermark {
+            return Err(QBTruncatedError {
+                since: since.clone(),
+                watermark: self.watermark.clone(),
+            });
+        }
+        Ok(())
+    }
+
     /// Gets the changes since the timestamp.
-    pub fn since_cloned(&self, since: &QBTimeStampUnique) -> QBChangeMap {
+    ///
+    /// The returned map has the whole clock of this map, not only the one
+    /// of the returned changes: a peer which has every change up to the
+    /// timestamp has seen everything this map has seen, once it merged the
+    /// returned changes. This is what the master records as seen by the
+    /// peer, see [QBChangeMap::since_clock].
+    pub fn since_cloned(&self, since: &QBTimeStampUnique) -> Result<QBChangeMap, QBTruncatedError> {
+        self.check_since(since)?;
+
         // iterator magic
         let changes = self
             .changes
@@ -104,14 +242,21 @@ impl QBChangeMap {
             .filter(|(_, entries)| !entries.is_empty())
             .collect::<HashMap<_, _>>();
 
-        QBChangeMap {
+        Ok(QBChangeMap {
             changes,
             head: self.head.clone(),
-        }
+            watermark: Default::default(),
+            clock: self.clock.clone(),
+        })
     }
 
-    /// Gets the changes since the timestamp.
-    pub fn since(&mut self, since: &QBTimeStampUnique) -> QBChangeMap {
+    /// Gets the changes since the timestamp, removing them from this map.
+    ///
+    /// Like with [QBChangeMap::since_cloned], the returned
+    /// map has the whole clock of this map.
+    pub fn since(&mut self, since: &QBTimeStampUnique) -> Result<QBChangeMap, QBTruncatedError> {
+        self.check_since(since)?;
+
         // iterator magic
         let changes = self
             .changes
@@ -126,10 +271,116 @@ impl QBChangeMap {
             })
             .collect::<HashMap<_, _>>();
 
-        QBChangeMap {
+        Ok(QBChangeMap {
             changes,
             head: self.head.clone(),
+            watermark: Default::default(),
+            clock: self.clock.clone(),
+        })
+    }
+
+    /// Gets the changes which have not been seen by the given clock, e.g.
+    /// the clock of a peer. Unlike [QBChangeMap::since_cloned], this does
+    /// not miss changes which have been recorded concurrently on another
+    /// device before the newest change the peer has seen.
+    pub fn since_clock(&self, clock: &QBVectorClock) -> Result<QBChangeMap, QBTruncatedError> {
+        // the peer has to have seen every change of every device up to
+        // the watermark, as those changes may have been removed
+        let truncated = self.clock.iter().find_map(|mut newest| {
+            if newest.timestamp > self.watermark.timestamp {
+                newest.timestamp = self.watermark.timestamp.clone();
+            }
+            let seen = newest.timestamp == QB_TIMESTAMP_BASE.timestamp || clock.contains(&newest);
+            (!seen).then_some(newest)
+        });
+        if let Some(since) = truncated {
+            return Err(QBTruncatedError {
+                since,
+                watermark: self.watermark.clone(),
+            });
         }
+
+        let changes = self
+            .changes
+            .iter()
+            .map(|(resource, entries)| {
+                (
+                    resource.clone(),
+                    entries
+                        .iter()
+                        .filter(|e| !clock.contains(&e.timestamp))
+                        .cloned()
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .filter(|(_, entries)| !entries.is_empty())
+            .collect::<HashMap<_, _>>();
+
+        Ok(QBChangeMap {
+            changes,
+            head: self.head.clone(),
+            watermark: Default::default(),
+            clock: self.clock.clone(),
+        })
+    }
+
+    /// Remove every change up to and including the timestamp.
+    ///
+    /// This should only be done once every peer has synchronized
+    /// past the timestamp, others will have to be synchronized
+    /// from scratch, see [QBTruncatedError].
+    pub fn truncate(&mut self, until: &QBTimeStampUnique) {
+        for entries in self.changes.values_mut() {
+            entries.retain(|e| &e.timestamp > until);
+        }
+        self.changes.retain(|_, entries| !entries.is_empty());
+        if until > &self.watermark {
+            self.watermark = until.clone();
+        }
+    }
+
+    /// Remove all changes of a resource which has been deleted, once
+    /// every peer has synchronized past the given timestamp, that is,
+    /// once its last change is not newer than it and deletes it.
+    ///
+    /// Unlike [QBChangeMap::truncate], the watermark is not raised, as peers
+    /// which have never synchronized do not have the resource and do not
+    /// need its history. Peers which have synchronized, but not past the
+    /// delete, would keep the resource, so the caller has to make sure that
+    /// there are none. Resources which have been renamed or copied are kept,
+    /// as the changes of other resources rely on them.
+    /// Returns whether the resource has been pruned.
+    pub fn prune_resource(&mut self, resource: &QBResource, until: &QBTimeStampUnique) -> bool {
+        let Some(entries) = self.changes.get(resource) else {
+            return false;
+        };
+        let Some(last) = entries.last() else {
+            return false;
+        };
+        if !matches!(last.kind, QBChangeKind::Delete) || &last.timestamp > until {
+            return false;
+        }
+        let paired = entries.iter().any(|e| {
+            matches!(
+                e.kind,
+                QBChangeKind::RenameFrom
+                    | QBChangeKind::RenameTo
+                    | QBChangeKind::CopyFrom
+                    | QBChangeKind::CopyTo
+            )
+        });
+        if paired {
+            return false;
+        }
+
+        self.changes.remove(resource);
+        true
+    }
+
+    /// Return the watermark of this changemap, changes
+    /// up to it may have been removed.
+    pub fn watermark(&self) -> &QBTimeStampUnique {
+        &self.watermark
     }
 
     /// Append another changemap to this map.
@@ -137,6 +388,10 @@ impl QBChangeMap {
         if other.head > self.head {
             self.head = other.head;
         }
+        if other.watermark > self.watermark {
+            self.watermark = other.watermark;
+        }
+        self.clock.merge(&other.clock);
         for (resource, mut other_entries) in other.changes.into_iter() {
             let entries = self.entries(resource);
             entries.append(&mut other_entries);
@@ -144,6 +399,18 @@ impl QBChangeMap {
         }
     }
 
+    /// Record that the changes in this map have been relayed by the given
+    /// device, see [QBChange::relay]. Returns the amount of changes which
+    /// have been relayed by the device before.
+    pub fn relay(&mut self, device: &QBDeviceId) -> usize {
+        self.changes
+            .values_mut()
+            .flatten()
+            .map(|change| change.relay(device))
+            .filter(|relayed| !relayed)
+            .count()
+    }
+
     /// Append entries to this map.
     pub fn append(&mut self, entries: Vec<(QBResource, QBChange)>) {
         for entry in entries {
@@ -157,6 +424,33 @@ impl QBChangeMap {
         self.changes.is_empty()
     }
 
+    /// Returns the total amount of changes in this changemap.
+    pub fn len(&self) -> usize {
+        self.changes.values().map(Vec::len).sum()
+    }
+
+    /// Returns whether the last change of the resource in this
+    /// changemap does not remove it, e.g. because it has been created.
+    pub fn contains(&self, resource: &QBResource) -> bool {
+        self.changes
+            .get(resource)
+            .and_then(|entries| entries.last())
+            .is_some_and(|change| !change.kind.is_subtractive())
+    }
+
+    /// Returns the amount of resources which have changes in this changemap.
+    #[inline(always)]
+    pub fn resource_count(&self) -> usize {
+        self.changes.len()
+    }
+
+    /// Returns the size of this changemap when serialized in bytes.
+    ///
+    /// This encodes the changemap, so it should not be called too often.
+    pub fn encoded_size(&self) -> usize {
+        bitcode::encode(self).len()
+    }
+
     /// Iterate over the changes.
     pub fn iter(&self) -> impl Iterator<Item = (&QBResource, &QBChange)> {
         self.changes
@@ -165,13 +459,72 @@ impl QBChangeMap {
             .sorted_unstable_by(|a, b| Self::_sort_entry(a.1, b.1))
     }
 
+    /// Iterate over the resources, ordered by their first change.
+    pub fn resources(&self) -> impl Iterator<Item = &QBResource> {
+        self.by_resource().map(|(resource, _)| resource)
+    }
+
+    /// Iterate over the changes grouped by their resource, e.g. for
+    /// showing the history of a file. The groups are ordered by their
+    /// first change, the changes of each group by their timestamp.
+    pub fn by_resource(&self) -> impl Iterator<Item = (&QBResource, &[QBChange])> {
+        self.changes
+            .iter()
+            .filter(|(_, entries)| !entries.is_empty())
+            .map(|(resource, entries)| (resource, entries.as_slice()))
+            .sorted_unstable_by(|a, b| Self::_sort_entry(&a.1[0], &b.1[0]))
+    }
+
+    /// Split this changemap into parts of about the given amount of changes.
+    ///
+    /// The changes of a resource are never split across parts, so a part may
+    /// be larger. Appending the parts to each other yields this changemap
+    /// again. At least one part is returned, even if this changemap is empty.
+    pub fn split(self, max_len: usize) -> impl Iterator<Item = QBChangeMap> {
+        let head = self.head;
+        let watermark = self.watermark;
+        let clock = self.clock;
+        let mut changes = self.changes.into_iter().peekable();
+        let mut first = true;
+        std::iter::from_fn(move || {
+            if changes.peek().is_none() && !first {
+                return None;
+            }
+            first = false;
+
+            let mut part = HashMap::new();
+            let mut len = 0;
+            while len < max_len {
+                let Some((resource, entries)) = changes.next() else {
+                    break;
+                };
+                len += entries.len();
+                part.insert(resource, entries);
+            }
+
+            Some(QBChangeMap {
+                changes: part,
+                head: head.clone(),
+                watermark: watermark.clone(),
+                clock: clock.clone(),
+            })
+        })
+    }
+
     /// Return the head of this changemap (the last change).
     pub fn head(&self) -> &QBTimeStampUnique {
         &self.head
     }
 
+    /// Return the vector clock of this changemap, that is,
+    /// the newest change seen of every device.
+    pub fn clock(&self) -> &QBVectorClock {
+        &self.clock
+    }
+
     /// Registers the change
     pub fn register(&mut self, change: &QBChange) -> bool {
+        self.clock.observe(&change.timestamp);
         if change.timestamp > self.head {
             self.head = change.timestamp.clone();
             return true;
@@ -214,14 +567,15 @@ impl QBChangeMap {
         entries.sort_unstable_by(|a, b| Self::_sort_entry(a, b));
     }
 
+    /// Compare two changes by their timestamp and then by their rank.
+    ///
+    /// This is a total order, so sorting is deterministic regardless
+    /// of the order of the input. Two changes compare equal only if
+    /// they are the same change (timestamps are unique).
     fn _sort_entry(a: &QBChange, b: &QBChange) -> std::cmp::Ordering {
-        match a.timestamp.cmp(&b.timestamp) {
-            std::cmp::Ordering::Equal => match a.kind {
-                QBChangeKind::RenameFrom | QBChangeKind::CopyFrom => std::cmp::Ordering::Less,
-                _ => std::cmp::Ordering::Greater,
-            },
-            v => v,
-        }
+        a.timestamp
+            .cmp(&b.timestamp)
+            .then_with(|| a.kind.rank().cmp(&b.kind.rank()))
     }
 
     /// Minifies this changemap.
@@ -229,6 +583,9 @@ impl QBChangeMap {
         // really bad implementation currently. TODO: fix this
         for (resource, entries) in self.changes.clone().iter() {
             let mut remove_until = 0;
+            // the amount of entries removed, as the indices
+            // refer to the cloned and not to the actual entries
+            let mut removed = 0;
 
             let mut i = 0;
             while i < entries.len() {
@@ -237,18 +594,20 @@ impl QBChangeMap {
                     kind if kind.is_external() => remove_until = i + 1,
                     QBChangeKind::Create => remove_until = i,
                     QBChangeKind::Delete => {
-                        // remove unused, logged changes
-                        if matches!(entries[remove_until].kind, QBChangeKind::Create) {
-                            i += 1;
-                        }
+                        // remove unused, logged changes (including the creation
+                        // and the deletion itself, if the resource was created)
+                        let end = match entries[remove_until].kind {
+                            QBChangeKind::Create => i + 1,
+                            _ => i,
+                        };
 
-                        self.changes
+                        removed += self
+                            .changes
                             .get_mut(resource)
                             .unwrap()
-                            .drain(remove_until..i)
+                            .drain(remove_until - removed..end - removed)
                             .len();
-
-                        continue;
+                        remove_until = i + 1;
                     }
                     QBChangeKind::RenameFrom => {
                         if matches!(entries[remove_until].kind, QBChangeKind::Create) {
@@ -256,8 +615,9 @@ impl QBChangeMap {
                                 .changes
                                 .get_mut(resource)
                                 .unwrap()
-                                .drain(remove_until..i + 1)
+                                .drain(remove_until - removed..i + 1 - removed)
                                 .collect::<Vec<_>>();
+                            removed += changes.len();
                             changes.pop();
 
                             let (index, resource) =
@@ -276,6 +636,8 @@ impl QBChangeMap {
                 i += 1;
             }
         }
+
+        self.changes.retain(|_, entries| !entries.is_empty());
     }
 
     /// Get the rename to for this entry
@@ -288,32 +650,102 @@ impl QBChangeMap {
         })
     }
 
+    /// Find the conflicts between this and another changelog, that is,
+    /// resources whose contents were updated on both sides. Merging
+    /// resolves those by keeping the newer update.
+    pub fn conflicts(&self, remote: &Self) -> Vec<QBConflict> {
+        self.changes
+            .iter()
+            .filter_map(|(resource, entries)| {
+                let local = entries.iter().rfind(|change| change.kind.is_update())?;
+                let remote = remote
+                    .changes
+                    .get(resource)?
+                    .iter()
+                    .rfind(|change| change.kind.is_update())?;
+
+                // the same change might be known to both sides
+                if local.timestamp == remote.timestamp {
+                    return None;
+                }
+
+                Some(QBConflict {
+                    resource: resource.clone(),
+                    local: local.clone(),
+                    remote: remote.clone(),
+                })
+            })
+            .collect()
+    }
+
     // TODO: collision detection
-    // TODO: test whether merge(a, b) == merge(b, a)
     //
     /// merge two changelogs and return either a common changelog plus the changes
     /// required to each individual file system or a vec of merge conflicts.
-    pub fn merge(&mut self, remote: Self) -> Result<Vec<(QBResource, QBChange)>, String> {
+    ///
+    /// The resulting changemap does not depend on which side is local and
+    /// which side is remote, that is, merge(a, b) yields the same map as
+    /// merge(b, a). Changes which exist on both sides are only kept once
+    /// and are not returned, as the local file system already contains them.
+    ///
+    /// If one side renames a resource which the other side edits, the
+    /// edits are forwarded to the destination of the rename.
+    ///
+    /// If one side deletes a resource which the other side creates at the
+    /// same path, the newer of both wins. The remote changes which precede
+    /// it are not returned, as the local file system does not depend on them.
+    ///
+    /// Fails without merging anything if a remote change targets an
+    /// internal resource (see [crate::path::QBPath::is_internal]), as these are never
+    /// synchronized and would overwrite the metadata of this device.
+    pub fn merge(&mut self, mut remote: Self) -> Result<Vec<(QBResource, QBChange)>, String> {
+        if let Some(resource) = remote.changes.keys().find(|r| r.path.is_internal()) {
+            return Err(format!(
+                "remote change targets internal resource {}",
+                resource
+            ));
+        }
+
+        if remote.head > self.head {
+            self.head = remote.head.clone();
+        }
+        self.clock.merge(&remote.clock);
+
+        // forward concurrent edits onto renamed resources
+        let local_renames = self.renames();
+        let remote_renames = remote.renames();
+        self.forward_edits(&remote_renames);
+        remote.forward_edits(&local_renames);
+
         let mut changes = Vec::new();
         for (resource, mut remote_entries) in remote.changes.into_iter() {
             if let Some(entries) = self.changes.get_mut(&resource) {
-                // TODO: do this properly
-                let rchanges = remote_entries.clone();
-                changes.extend(&mut rchanges.into_iter().map(|e| (resource.clone(), e)));
+                let last = Self::last_existence(&remote_entries, entries).cloned();
+                // the local entries are sorted, so we can search
+                // for the changes the local side already has
+                changes.extend(
+                    remote_entries
+                        .iter()
+                        .filter(|e| {
+                            entries
+                                .binary_search_by(|x| Self::_sort_entry(x, e))
+                                .is_err()
+                        })
+                        .filter(|e| !Self::is_superseded(e, last.as_ref()))
+                        .cloned()
+                        .map(|e| (resource.clone(), e)),
+                );
 
                 *entries = Self::_merge(remote_entries, entries);
-                // TODO: update head
             } else {
+                Self::_sort(&mut remote_entries);
                 changes.extend(
                     remote_entries
                         .iter()
                         .cloned()
                         .map(|e| (resource.clone(), e)),
                 );
-                self.register(remote_entries.last().unwrap());
-                let entries = self.entries(resource);
-                entries.append(&mut remote_entries);
-                Self::_sort(entries);
+                self.changes.insert(resource, remote_entries);
             }
         }
 
@@ -321,9 +753,110 @@ impl QBChangeMap {
         Ok(changes)
     }
 
+    /// Returns the resources renamed in this changemap, mapped to the
+    /// resource they have been renamed to. Renames which are chained
+    /// (a -> b -> c) are resolved to the final destination.
+    fn renames(&self) -> HashMap<QBResource, QBResource> {
+        let mut renames = HashMap::new();
+        for (resource, entries) in self.changes.iter() {
+            let rename = entries
+                .iter()
+                .filter(|change| matches!(change.kind, QBChangeKind::RenameFrom))
+                .find_map(|change| self.rename_destination(&change.timestamp));
+            if let Some(to) = rename {
+                renames.insert(resource.clone(), to.clone());
+            }
+        }
+
+        // resolve chained renames, the renames might be circular,
+        // so we stop after visiting every rename once
+        let resolved = renames
+            .keys()
+            .map(|from| {
+                let mut to = &renames[from];
+                for _ in 0..renames.len() {
+                    match renames.get(to) {
+                        Some(next) if next != from => to = next,
+                        _ => break,
+                    }
+                }
+                (from.clone(), to.clone())
+            })
+            .collect();
+        resolved
+    }
+
+    /// Find the resource which has the rename to entry with the given timestamp.
+    fn rename_destination(&self, timestamp: &QBTimeStampUnique) -> Option<&QBResource> {
+        self.changes.iter().find_map(|(resource, entries)| {
+            entries
+                .iter()
+                .any(|change| {
+                    &change.timestamp == timestamp && matches!(change.kind, QBChangeKind::RenameTo)
+                })
+                .then_some(resource)
+        })
+    }
+
+    /// Move the edits of resources, which have been renamed by
+    /// the other side, onto the destination of the rename.
+    fn forward_edits(&mut self, renames: &HashMap<QBResource, QBResource>) {
+        for (from, to) in renames.iter() {
+            let Some(entries) = self.changes.get_mut(from) else {
+                continue;
+            };
+
+            // the resource has been moved by both sides, which
+            // is not a rename-vs-edit conflict
+            if entries.iter().any(|change| change.kind.is_subtractive()) {
+                continue;
+            }
+
+            let (mut edits, rest): (Vec<_>, Vec<_>) = entries
+                .drain(..)
+                .partition(|change| change.kind.is_update());
+            *entries = rest;
+            if entries.is_empty() {
+                self.changes.remove(from);
+            }
+            if edits.is_empty() {
+                continue;
+            }
+
+            let entries = self.entries(to.clone());
+            entries.append(&mut edits);
+            Self::_sort(entries);
+        }
+    }
+
+    /// Find the latest create or delete of either side, which determines
+    /// whether the resource exists after the changes have been merged.
+    fn last_existence<'a>(a: &'a [QBChange], b: &'a [QBChange]) -> Option<&'a QBChange> {
+        a.iter()
+            .chain(b.iter())
+            .filter(|e| matches!(e.kind, QBChangeKind::Create | QBChangeKind::Delete))
+            .max_by(|x, y| Self::_sort_entry(x, y))
+    }
+
+    /// Returns whether the change precedes the latest create or delete of
+    /// the resource, which makes applying it unnecessary. Renames and copies
+    /// are kept, as the changes to another resource rely on them.
+    fn is_superseded(change: &QBChange, last: Option<&QBChange>) -> bool {
+        let paired = matches!(
+            change.kind,
+            QBChangeKind::RenameFrom
+                | QBChangeKind::RenameTo
+                | QBChangeKind::CopyFrom
+                | QBChangeKind::CopyTo
+        );
+        !paired && last.is_some_and(|last| Self::_sort_entry(change, last).is_lt())
+    }
+
     fn _merge(mut a: Vec<QBChange>, b: &mut Vec<QBChange>) -> Vec<QBChange> {
         a.append(b);
         Self::_sort(&mut a);
+        // remove changes which both sides share
+        a.dedup_by(|x, y| Self::_sort_entry(x, y).is_eq());
 
         let til = a
             .iter()
@@ -336,3 +869,224 @@ impl QBChangeMap {
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::{path::QBPath, time::QBTimeStampRecorder};
+
+    fn file(path: &str) -> QBResource {
+        QBPath::try_from(path).unwrap().file()
+    }
+
+    /// Record a random change to one of a few files.
+    fn record(rng: &mut StdRng, recorder: &mut QBTimeStampRecorder) -> (QBResource, QBChange) {
+        let resource = file(&format!("/{}", rng.gen_range(0..4)));
+        let kind = match rng.gen_range(0..3) {
+            0 => QBChangeKind::Create,
+            1 => QBChangeKind::Delete,
+            _ => QBChangeKind::UpdateBinary(vec![rng.gen()]),
+        };
+        (resource, QBChange::new(recorder.record(), kind))
+    }
+
+    /// Returns the entries of the changemap in a comparable form.
+    fn entries(changemap: &QBChangeMap) -> Vec<(String, QBTimeStampUnique, String)> {
+        changemap
+            .iter()
+            .map(|(resource, change)| {
+                let kind = format!("{:?}", change.kind);
+                (resource.to_string(), change.timestamp.clone(), kind)
+            })
+            .sorted()
+            .collect()
+    }
+
+    /// Build two changemaps with a random common history followed
+    /// by random changes recorded concurrently on each side.
+    fn diverged(rng: &mut StdRng) -> (QBChangeMap, QBChangeMap) {
+        let mut recorders = (1..=3)
+            .map(|id| QBTimeStampRecorder::from(QBDeviceId(id)))
+            .collect::<Vec<_>>();
+        let mut a = QBChangeMap::default();
+        for _ in 0..rng.gen_range(0..8) {
+            let i = rng.gen_range(0..recorders.len());
+            a.push(record(rng, &mut recorders[i]));
+        }
+        let mut b = a.clone();
+        for _ in 0..rng.gen_range(0..8) {
+            a.push(record(rng, &mut recorders[0]));
+        }
+        for _ in 0..rng.gen_range(0..8) {
+            b.push(record(rng, &mut recorders[1]));
+        }
+        (a, b)
+    }
+
+    #[test]
+    fn merge_is_commutative() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..256 {
+            let (a, b) = diverged(&mut rng);
+
+            let mut ab = a.clone();
+            ab.merge(b.clone()).unwrap();
+            let mut ba = b;
+            ba.merge(a).unwrap();
+
+            assert_eq!(entries(&ab), entries(&ba));
+            assert_eq!(ab.head(), ba.head());
+            assert_eq!(ab.clock(), ba.clock());
+        }
+    }
+
+    #[test]
+    fn merge_returns_only_missing_changes() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..256 {
+            let (mut local, remote) = diverged(&mut rng);
+            let known = entries(&local);
+
+            let changes = local.merge(remote).unwrap();
+            for (_, change) in changes {
+                assert!(!known
+                    .iter()
+                    .any(|(_, timestamp, _)| timestamp == &change.timestamp));
+            }
+        }
+    }
+
+    #[test]
+    fn merge_skips_shared_changes() {
+        let mut recorder = QBTimeStampRecorder::from(QBDeviceId(1));
+        let create = QBChange::new(recorder.record(), QBChangeKind::Create);
+        let update = QBChange::new(recorder.record(), QBChangeKind::UpdateBinary(vec![1]));
+        let other = QBChange::new(recorder.record(), QBChangeKind::Create);
+
+        let mut local = QBChangeMap::default();
+        local.push((file("/a"), create.clone()));
+        let mut remote = local.clone();
+        remote.push((file("/a"), update.clone()));
+        remote.push((file("/b"), other.clone()));
+
+        let changes = local.merge(remote).unwrap();
+        let timestamps = changes
+            .iter()
+            .map(|(_, change)| &change.timestamp)
+            .collect::<Vec<_>>();
+        assert_eq!(timestamps, vec![&update.timestamp, &other.timestamp]);
+    }
+
+    #[test]
+    fn by_resource_groups_changes() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut recorder = QBTimeStampRecorder::from(QBDeviceId(1));
+        let mut changemap = QBChangeMap::default();
+        for _ in 0..32 {
+            changemap.push(record(&mut rng, &mut recorder));
+        }
+
+        let groups = changemap.by_resource().collect::<Vec<_>>();
+        assert_eq!(
+            groups.iter().map(|(_, group)| group.len()).sum::<usize>(),
+            32
+        );
+        for (resource, group) in groups.iter() {
+            let expected = changemap
+                .iter()
+                .filter(|(r, _)| r == resource)
+                .map(|(_, change)| &change.timestamp)
+                .collect::<Vec<_>>();
+            let timestamps = group
+                .iter()
+                .map(|change| &change.timestamp)
+                .collect::<Vec<_>>();
+            assert_eq!(timestamps, expected);
+        }
+        assert!(groups
+            .windows(2)
+            .all(|pair| pair[0].1[0].timestamp < pair[1].1[0].timestamp));
+    }
+
+    #[test]
+    fn prune_deleted_resource() {
+        let mut recorder = QBTimeStampRecorder::from(QBDeviceId(1));
+        let mut changemap = QBChangeMap::default();
+        for (path, kind) in [
+            ("/deleted", QBChangeKind::Create),
+            ("/kept", QBChangeKind::Create),
+            ("/deleted", QBChangeKind::UpdateBinary(vec![1])),
+            ("/deleted", QBChangeKind::Delete),
+        ] {
+            changemap.push((file(path), QBChange::new(recorder.record(), kind)));
+        }
+        let synced = changemap.head().clone();
+        let change = QBChange::new(recorder.record(), QBChangeKind::Create);
+        changemap.push((file("/later"), change));
+        let change = QBChange::new(recorder.record(), QBChangeKind::Delete);
+        changemap.push((file("/later"), change));
+
+        // the peers have not synchronized past the delete yet
+        assert!(!changemap.prune_resource(&file("/later"), &synced));
+        // the resource still exists
+        assert!(!changemap.prune_resource(&file("/kept"), &synced));
+        assert!(changemap.prune_resource(&file("/deleted"), &synced));
+
+        let resources = changemap.resources().cloned().collect::<Vec<_>>();
+        assert_eq!(resources, vec![file("/kept"), file("/later")]);
+        assert_eq!(changemap.len(), 3);
+        assert!(changemap.since_cloned(&QB_TIMESTAMP_BASE).is_ok());
+    }
+
+    #[test]
+    fn prune_keeps_renamed_resource() {
+        let mut recorder = QBTimeStampRecorder::from(QBDeviceId(1));
+        let mut changemap = QBChangeMap::default();
+        let create = recorder.record();
+        changemap.push((file("/a"), QBChange::new(create, QBChangeKind::Create)));
+        let rename = recorder.record();
+        let change = QBChange::new(rename.clone(), QBChangeKind::RenameFrom);
+        changemap.push((file("/a"), change));
+        changemap.push((file("/b"), QBChange::new(rename, QBChangeKind::RenameTo)));
+        let change = QBChange::new(recorder.record(), QBChangeKind::Delete);
+        changemap.push((file("/b"), change));
+
+        let head = changemap.head().clone();
+        assert!(!changemap.prune_resource(&file("/b"), &head));
+        assert_eq!(changemap.len(), 4);
+    }
+
+    #[test]
+    fn minify_repeated_deletes() {
+        // the second deletion refers to entries behind the ones
+        // which have been drained for the first deletion
+        let resource = crate::path::QBPath::try_from("/a").unwrap().file();
+        let mut recorder = QBTimeStampRecorder::from(crate::device::QBDeviceId(1));
+        let mut changemap = QBChangeMap::default();
+        for kind in [
+            QBChangeKind::Create,
+            QBChangeKind::UpdateBinary(vec![1]),
+            QBChangeKind::Delete,
+            QBChangeKind::Create,
+            QBChangeKind::UpdateBinary(vec![2]),
+            QBChangeKind::Delete,
+            QBChangeKind::Create,
+            QBChangeKind::UpdateBinary(vec![3]),
+        ] {
+            changemap.push((resource.clone(), QBChange::new(recorder.record(), kind)));
+        }
+
+        changemap.minify();
+        let kinds = changemap.iter().map(|(_, change)| &change.kind);
+        let kinds = kinds.collect::<Vec<_>>();
+        assert!(
+            matches!(
+                kinds[..],
+                [QBChangeKind::Create, QBChangeKind::UpdateBinary(content)] if content == &[3]
+            ),
+            "{kinds:?}"
+        );
+    }
+}
@@ -0,0 +1,78 @@
+//! # clock
+//!
+//! A vector clock stores the newest timestamp seen of every device.
+//! Timestamps are recorded in order on every device, so a clock tells
+//! exactly which changes have been seen, even if the changes of different
+//! devices have been recorded concurrently and are seen in another order
+//! than the total order of their timestamps.
+
+use std::collections::HashMap;
+
+use bitcode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    device::QBDeviceId,
+    time::{QBTimeStamp, QBTimeStampUnique},
+};
+
+/// This struct stores the newest timestamp seen of every device.
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct QBVectorClock(HashMap<QBDeviceId, QBTimeStamp>);
+
+impl QBVectorClock {
+    /// Record that the timestamp has been seen.
+    pub fn observe(&mut self, timestamp: &QBTimeStampUnique) {
+        match self.0.get_mut(&timestamp.device_id) {
+            Some(newest) if &timestamp.timestamp <= newest => {}
+            Some(newest) => *newest = timestamp.timestamp.clone(),
+            None => {
+                self.0
+                    .insert(timestamp.device_id.clone(), timestamp.timestamp.clone());
+            }
+        }
+    }
+
+    /// Record that every timestamp seen by the other clock has been seen.
+    pub fn merge(&mut self, other: &QBVectorClock) {
+        for (device_id, timestamp) in other.0.iter() {
+            self.observe(&QBTimeStampUnique {
+                timestamp: timestamp.clone(),
+                device_id: device_id.clone(),
+            });
+        }
+    }
+
+    /// Returns the newest timestamp seen of the given device.
+    pub fn get(&self, device_id: &QBDeviceId) -> Option<&QBTimeStamp> {
+        self.0.get(device_id)
+    }
+
+    /// Returns whether the timestamp has been seen.
+    pub fn contains(&self, timestamp: &QBTimeStampUnique) -> bool {
+        self.0
+            .get(&timestamp.device_id)
+            .is_some_and(|newest| &timestamp.timestamp <= newest)
+    }
+
+    /// Returns whether every timestamp seen by the other clock has been seen.
+    pub fn dominates(&self, other: &QBVectorClock) -> bool {
+        other.iter().all(|timestamp| self.contains(&timestamp))
+    }
+
+    /// Returns whether neither clock has seen every timestamp the other one
+    /// has seen, that is, both sides have recorded changes concurrently.
+    pub fn is_concurrent(&self, other: &QBVectorClock) -> bool {
+        !self.dominates(other) && !other.dominates(self)
+    }
+
+    /// Iterate over the newest timestamps seen of every device.
+    pub fn iter(&self) -> impl Iterator<Item = QBTimeStampUnique> + '_ {
+        self.0
+            .iter()
+            .map(|(device_id, timestamp)| QBTimeStampUnique {
+                timestamp: timestamp.clone(),
+                device_id: device_id.clone(),
+            })
+    }
+}
@@ -0,0 +1,280 @@
+//! # journal
+//!
+//! The changemap journal allows for saving a changemap incrementally.
+//! Instead of encoding the whole changemap on every save, only the
+//! changes since the last save are appended to the journal as records.
+//! The journal is compacted into a snapshot once it grows too large.
+
+use std::collections::HashMap;
+
+use bitcode::{Decode, Encode};
+use tracing::warn;
+
+use crate::{path::QBResource, time::QBTimeStampUnique};
+
+use super::{QBChange, QBChangeMap, QBVectorClock};
+
+/// The minimum size of the journal in bytes before it gets compacted.
+pub const COMPACT_MIN_SIZE: usize = 64 * 1024;
+
+/// A single record in the journal.
+#[derive(Encode, Decode, Debug, Clone)]
+pub enum QBChangeMapRecord {
+    /// Changes have been pushed to the resource.
+    Push {
+        /// the resource
+        resource: QBResource,
+        /// the changes pushed, sorted by their timestamp
+        changes: Vec<QBChange>,
+    },
+    /// The changes of the resource have been replaced.
+    Replace {
+        /// the resource
+        resource: QBResource,
+        /// the new changes, which may be empty
+        changes: Vec<QBChange>,
+    },
+    /// The head of the changemap has been updated.
+    Head(QBTimeStampUnique),
+    /// The watermark of the changemap has been raised.
+    Watermark(QBTimeStampUnique),
+    /// The vector clock of the changemap has advanced, e.g. because
+    /// it has been merged with the clock of a sync. Changes which are
+    /// pushed are observed by the clock when replaying them already.
+    Clock(QBVectorClock),
+}
+
+impl QBChangeMapRecord {
+    /// Apply this record to the given changemap.
+    ///
+    /// Applying a record multiple times has the same effect as applying
+    /// it once, so a journal which has already been compacted into the
+    /// snapshot may be replayed safely.
+    pub fn apply(self, map: &mut QBChangeMap) {
+        match self {
+            QBChangeMapRecord::Push { resource, changes } => {
+                for change in changes.iter() {
+                    map.clock.observe(&change.timestamp);
+                }
+                let entries = map.entries(resource);
+                let last = entries.last().map(|e| e.timestamp.clone());
+                entries.extend(
+                    changes
+                        .into_iter()
+                        .filter(|e| last.as_ref().is_none_or(|last| &e.timestamp > last)),
+                );
+            }
+            QBChangeMapRecord::Replace { resource, changes } => {
+                for change in changes.iter() {
+                    map.clock.observe(&change.timestamp);
+                }
+                match changes.is_empty() {
+                    true => _ = map.changes.remove(&resource),
+                    false => _ = map.changes.insert(resource, changes),
+                }
+            }
+            QBChangeMapRecord::Head(head) => map.head = head,
+            QBChangeMapRecord::Watermark(watermark) => map.watermark = watermark,
+            QBChangeMapRecord::Clock(clock) => map.clock.merge(&clock),
+        }
+    }
+}
+
+/// struct which keeps track of what has been saved of a changemap
+#[derive(Debug, Default)]
+pub struct QBChangeMapJournal {
+    /// the timestamps of the changes of each resource that have been saved
+    saved: HashMap<QBResource, Vec<QBTimeStampUnique>>,
+    /// the head that has been saved
+    saved_head: QBTimeStampUnique,
+    /// the watermark that has been saved
+    saved_watermark: QBTimeStampUnique,
+    /// the vector clock that has been saved
+    saved_clock: QBVectorClock,
+    /// the size of the journal in bytes
+    len: usize,
+    /// the size of the snapshot in bytes
+    snapshot_len: usize,
+    /// whether the journal has to be compacted on the next save,
+    /// e.g. because its end is corrupt
+    dirty: bool,
+}
+
+impl QBChangeMapJournal {
+    /// Load a changemap from a snapshot and the journal written after it.
+    ///
+    /// A truncated or corrupt record ends the replay,
+    /// the journal is compacted on the next save then.
+    pub fn load(snapshot: &[u8], journal: &[u8]) -> (QBChangeMap, Self) {
+        let mut map: QBChangeMap = bitcode::decode(snapshot).unwrap_or_default();
+
+        let mut offset = 0;
+        let mut dirty = false;
+        while offset < journal.len() {
+            let Some(record) = Self::read_record(&journal[offset..]) else {
+                warn!("changemap journal: corrupt record at {}", offset);
+                dirty = true;
+                break;
+            };
+            let (len, record) = record;
+            record.apply(&mut map);
+            offset += len;
+        }
+
+        let mut journal = Self {
+            len: offset,
+            snapshot_len: snapshot.len(),
+            dirty,
+            ..Default::default()
+        };
+        journal.mark_saved(&map);
+
+        (map, journal)
+    }
+
+    /// Read a single record, returns the amount of bytes read and the record.
+    fn read_record(bytes: &[u8]) -> Option<(usize, QBChangeMapRecord)> {
+        let len_bytes = bytes.get(0..8)?;
+        let len = u64::from_be_bytes(len_bytes.try_into().unwrap()) as usize;
+        let record = bitcode::decode(bytes.get(8..8 + len)?).ok()?;
+        Some((8 + len, record))
+    }
+
+    /// Returns whether the journal should be compacted into a snapshot.
+    pub fn should_compact(&self) -> bool {
+        self.dirty || self.len > self.snapshot_len.max(COMPACT_MIN_SIZE)
+    }
+
+    /// Get the encoded records which have to be appended to the journal,
+    /// so that it describes the given changemap. Call [QBChangeMapJournal::appended]
+    /// once they have been written.
+    pub fn delta(&self, map: &QBChangeMap) -> Vec<u8> {
+        let mut records = Vec::new();
+
+        for (resource, entries) in map.changes.iter() {
+            let saved = self.saved.get(resource).map(Vec::as_slice).unwrap_or(&[]);
+            let unchanged = saved.len() <= entries.len()
+                && saved
+                    .iter()
+                    .zip(entries.iter())
+                    .all(|(a, b)| a == &b.timestamp);
+
+            if unchanged && saved.len() == entries.len() {
+                continue;
+            }
+
+            records.push(match unchanged {
+                true => QBChangeMapRecord::Push {
+                    resource: resource.clone(),
+                    changes: entries[saved.len()..].to_vec(),
+                },
+                false => QBChangeMapRecord::Replace {
+                    resource: resource.clone(),
+                    changes: entries.clone(),
+                },
+            });
+        }
+
+        for resource in self.saved.keys() {
+            if !map.changes.contains_key(resource) {
+                records.push(QBChangeMapRecord::Replace {
+                    resource: resource.clone(),
+                    changes: Vec::new(),
+                });
+            }
+        }
+
+        if map.head != self.saved_head {
+            records.push(QBChangeMapRecord::Head(map.head.clone()));
+        }
+
+        if map.watermark != self.saved_watermark {
+            records.push(QBChangeMapRecord::Watermark(map.watermark.clone()));
+        }
+
+        if map.clock != self.saved_clock {
+            records.push(QBChangeMapRecord::Clock(map.clock.clone()));
+        }
+
+        let mut bytes = Vec::new();
+        for record in records {
+            let encoded = bitcode::encode(&record);
+            bytes.extend_from_slice(&(encoded.len() as u64).to_be_bytes());
+            bytes.extend_from_slice(&encoded);
+        }
+        bytes
+    }
+
+    /// Mark the delta of the given changemap as appended to the journal.
+    pub fn appended(&mut self, map: &QBChangeMap, len: usize) {
+        self.len += len;
+        self.mark_saved(map);
+    }
+
+    /// Mark the given changemap as saved as a snapshot with an empty journal.
+    pub fn compacted(&mut self, map: &QBChangeMap, snapshot_len: usize) {
+        self.len = 0;
+        self.snapshot_len = snapshot_len;
+        self.dirty = false;
+        self.mark_saved(map);
+    }
+
+    fn mark_saved(&mut self, map: &QBChangeMap) {
+        self.saved = map
+            .changes
+            .iter()
+            .map(|(resource, entries)| {
+                (
+                    resource.clone(),
+                    entries.iter().map(|e| e.timestamp.clone()).collect(),
+                )
+            })
+            .collect();
+        self.saved_head = map.head.clone();
+        self.saved_watermark = map.watermark.clone();
+        self.saved_clock = map.clock.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        change::QBChangeKind, device::QBDeviceId, path::QBPath, time::QBTimeStampRecorder,
+    };
+
+    use super::*;
+
+    /// Append the delta of the changemap to the journal.
+    fn save(journal: &mut QBChangeMapJournal, bytes: &mut Vec<u8>, map: &QBChangeMap) {
+        let delta = journal.delta(map);
+        bytes.extend_from_slice(&delta);
+        journal.appended(map, delta.len());
+    }
+
+    #[test]
+    fn reload_clock() {
+        let (mut map, mut journal) = QBChangeMapJournal::load(&[], &[]);
+        let mut bytes = Vec::new();
+
+        let mut recorder = QBTimeStampRecorder::from(QBDeviceId(1));
+        let resource = QBPath::try_from("/a").unwrap().file();
+        map.push((
+            resource,
+            QBChange::new(recorder.record(), QBChangeKind::Create),
+        ));
+        save(&mut journal, &mut bytes, &map);
+
+        // the clock has seen a change which is not part of the changemap
+        let mut recorder = QBTimeStampRecorder::from(QBDeviceId(2));
+        let mut clock = QBVectorClock::default();
+        clock.observe(&recorder.record());
+        map.clock.merge(&clock);
+        save(&mut journal, &mut bytes, &map);
+        assert!(journal.delta(&map).is_empty());
+
+        let (reloaded, journal) = QBChangeMapJournal::load(&[], &bytes);
+        assert_eq!(reloaded.clock(), map.clock());
+        assert!(reloaded.clock().dominates(&clock));
+        assert!(journal.delta(&reloaded).is_empty());
+    }
+}
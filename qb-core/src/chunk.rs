@@ -0,0 +1,213 @@
+//! A chunker splits contents into chunks at positions which are
+//! determined by the contents themselves (content-defined chunking).
+//! Inserting or removing bytes therefore only changes the chunks
+//! around the edit, which allows us to detect which parts of a
+//! large file actually changed.
+//!
+//! The implementation is based on FastCDC (gear hash with
+//! normalized chunking).
+
+use crate::hash::QBHash;
+
+/// The gear table used for the rolling hash.
+const GEAR: [u64; 256] = gear_table();
+
+/// Generate the gear table at compile time using splitmix64,
+/// so that every device computes the same chunk boundaries.
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x5142_4348_554e_4b53;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Build a mask which has the given amount of the high bits set.
+///
+/// The gear hash shifts to the left, so the high bits depend
+/// on more of the previous bytes than the low bits do.
+const fn mask(bits: u32) -> u64 {
+    if bits == 0 {
+        return 0;
+    }
+    u64::MAX << (64 - bits)
+}
+
+/// struct which splits contents into content-defined chunks
+#[derive(Debug, Clone)]
+pub struct QBChunker {
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+    mask_s: u64,
+    mask_l: u64,
+}
+
+impl Default for QBChunker {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_AVG_SIZE)
+    }
+}
+
+impl QBChunker {
+    /// The default average chunk size (8 KiB).
+    pub const DEFAULT_AVG_SIZE: usize = 8192;
+    /// The smallest average chunk size supported.
+    pub const MIN_AVG_SIZE: usize = 64;
+
+    /// Create a new chunker with the given average chunk size.
+    ///
+    /// The average size gets rounded down to the next power of two,
+    /// sizes below [QBChunker::MIN_AVG_SIZE] are raised to it.
+    /// Chunks will be at least a quarter and at most eight times
+    /// the average size long.
+    pub fn new(avg_size: usize) -> Self {
+        let bits = avg_size.max(Self::MIN_AVG_SIZE).ilog2();
+        let avg_size = 1 << bits;
+
+        Self {
+            min_size: avg_size / 4,
+            avg_size,
+            max_size: avg_size * 8,
+            // normalized chunking: harder to cut before the average
+            // size is reached, easier to cut afterwards
+            mask_s: mask(bits + 1),
+            mask_l: mask(bits - 1),
+        }
+    }
+
+    /// Returns the average chunk size.
+    #[inline]
+    pub fn avg_size(&self) -> usize {
+        self.avg_size
+    }
+
+    /// Returns the minimum chunk size.
+    #[inline]
+    pub fn min_size(&self) -> usize {
+        self.min_size
+    }
+
+    /// Returns the maximum chunk size.
+    #[inline]
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Find the length of the first chunk in the given contents.
+    pub fn cut(&self, contents: &[u8]) -> usize {
+        let len = contents.len();
+        if len <= self.min_size {
+            return len;
+        }
+
+        let end = len.min(self.max_size);
+        let normal = len.min(self.avg_size);
+
+        let mut hash = 0u64;
+        let mut i = self.min_size;
+        while i < normal {
+            hash = (hash << 1).wrapping_add(GEAR[contents[i] as usize]);
+            if hash & self.mask_s == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        while i < end {
+            hash = (hash << 1).wrapping_add(GEAR[contents[i] as usize]);
+            if hash & self.mask_l == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+
+        end
+    }
+
+    /// Split the given contents into chunks.
+    pub fn chunks<'a>(&'a self, contents: &'a [u8]) -> QBChunks<'a> {
+        QBChunks {
+            chunker: self,
+            contents,
+        }
+    }
+
+    /// Split the given contents into chunks and hash each chunk individually.
+    pub fn hashes(&self, contents: impl AsRef<[u8]>) -> Vec<QBHash> {
+        self.chunks(contents.as_ref())
+            .map(QBHash::compute)
+            .collect()
+    }
+}
+
+/// Iterator over the chunks of some contents, see [QBChunker::chunks].
+pub struct QBChunks<'a> {
+    chunker: &'a QBChunker,
+    contents: &'a [u8],
+}
+
+impl<'a> Iterator for QBChunks<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.contents.is_empty() {
+            return None;
+        }
+
+        let len = self.chunker.cut(self.contents);
+        let (chunk, rest) = self.contents.split_at(len);
+        self.contents = rest;
+        Some(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    /// Generate reproducible pseudo random contents.
+    fn contents(len: usize) -> Vec<u8> {
+        let mut state: u64 = 42;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn small_avg_size_is_clamped() {
+        let chunker = QBChunker::new(1);
+        assert_eq!(chunker.avg_size(), QBChunker::MIN_AVG_SIZE);
+    }
+
+    #[test]
+    fn insertion_changes_bounded_chunks() {
+        let chunker = QBChunker::default();
+        let old = contents(1 << 20);
+        let mut new = old.clone();
+        new.insert(old.len() / 2, 0xff);
+
+        let old_hashes = chunker.hashes(&old).into_iter().collect::<HashSet<_>>();
+        let new_hashes = chunker.hashes(&new);
+        let changed = new_hashes
+            .iter()
+            .filter(|hash| !old_hashes.contains(hash))
+            .count();
+
+        assert!(new_hashes.len() > 16);
+        assert!(changed <= 2, "{} chunks changed", changed);
+    }
+}
@@ -101,8 +101,23 @@ impl Default for QBDeviceTable {
 
 impl QBDeviceTable {
     /// Get the common hash of the connection with the id.
+    ///
+    /// Returns the base timestamp if we have never synchronized
+    /// with the device, see [QBDeviceTable::common].
     pub fn get_common(&self, id: &QBDeviceId) -> &QBTimeStampUnique {
-        self.commons.get(id).unwrap_or(&QB_TIMESTAMP_BASE)
+        self.common(id).unwrap_or(&QB_TIMESTAMP_BASE)
+    }
+
+    /// Get the common hash of the connection with the id,
+    /// or none if we have never synchronized with the device.
+    pub fn common(&self, id: &QBDeviceId) -> Option<&QBTimeStampUnique> {
+        self.commons.get(id)
+    }
+
+    /// Iterate over the devices we have synchronized with
+    /// and the common hashes of their connections.
+    pub fn commons(&self) -> impl Iterator<Item = (&QBDeviceId, &QBTimeStampUnique)> {
+        self.commons.iter()
     }
 
     /// Set the common hash of the connection with the id.
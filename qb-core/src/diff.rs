@@ -1,11 +1,19 @@
 //! A diff describes a transformation that can be applied to a specific
 //! input to get a specific output. It is used for compressing changes on
-//! text files.
+//! text files and, as a patch, on binary files.
+
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+};
 
 use bitcode::{Decode, Encode};
 use serde::{Deserialize, Serialize};
 
-use super::hash::QBHash;
+use super::{
+    chunk::QBChunker,
+    hash::{QBHash, QBHasher},
+};
 
 /// struct which stores operations for a transformation on a string
 #[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone)]
@@ -17,7 +25,7 @@ pub struct QBDiff {
 }
 
 /// struct which stores a single operation for a transformation on a string
-#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone)]
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum QBDiffOp {
     /// range is equal
     Equal {
@@ -41,14 +49,61 @@ pub enum QBDiffOp {
         /// text to be inserted
         content: String,
     },
+    /// range of the original string should get inserted, used for
+    /// blocks which have been moved, see [QBDiff::compute_with_moves]
+    Move {
+        /// the line the range starts at in the original string
+        from: usize,
+        /// length of range
+        len: usize,
+    },
+}
+
+/// The style of the line endings used by a text.
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QBLineEnding {
+    /// unix style line endings (\n)
+    #[default]
+    Lf,
+    /// windows style line endings (\r\n)
+    Crlf,
+}
+
+impl QBLineEnding {
+    /// Canonicalize the line endings of the given text to [QBLineEnding::Lf].
+    ///
+    /// Returns the style the text used originally. Texts with mixed
+    /// line endings are left as they are, as they could not be restored.
+    pub fn normalize(text: String) -> (String, QBLineEnding) {
+        let crlf = text.matches("\r\n").count();
+        if crlf == 0 || crlf != text.matches('\n').count() {
+            return (text, QBLineEnding::Lf);
+        }
+
+        (text.replace("\r\n", "\n"), QBLineEnding::Crlf)
+    }
+
+    /// Restore the line endings of a text normalized with [QBLineEnding::normalize].
+    pub fn restore(&self, text: String) -> String {
+        match self {
+            // the text has not been normalized, keep it as it is
+            QBLineEnding::Crlf if !text.contains("\r\n") => text.replace('\n', "\r\n"),
+            _ => text,
+        }
+    }
 }
 
-struct Index {
-    old_start: usize,
-    old_end: usize,
-    #[allow(dead_code)]
-    new_start: usize,
-    new_end: usize,
+/// The minimum amount of lines a block needs to have
+/// to be detected as moved, see [QBDiff::compute_with_moves].
+pub const MOVE_MIN_LINES: usize = 2;
+
+/// A diff described for every line of the old contents, used for merging.
+struct QBDiffLines {
+    /// whether each line of the old contents is removed
+    removed: Vec<bool>,
+    /// the insert and move operations before each line of the
+    /// old contents, the last entry is after the last line
+    inserted: Vec<Vec<QBDiffOp>>,
 }
 
 impl QBDiff {
@@ -83,183 +138,588 @@ impl QBDiff {
         QBDiff { old_hash, ops }
     }
 
-    /// Apply this diff to a string
-    pub fn apply(&self, old: String) -> String {
-        let old_hash = QBHash::compute(&old);
-        assert!(self.old_hash == old_hash);
-
+    /// Compute a diff, detecting blocks of lines which have been moved.
+    ///
+    /// Moved blocks are still deleted at their old position, but inserted
+    /// as [QBDiffOp::Move] instead of their contents. This allows merging
+    /// the diff with one which edits the block, see [QBDiff::merge].
+    /// Only blocks of at least [MOVE_MIN_LINES] lines are detected.
+    pub fn compute_with_moves(old: String, new: String) -> QBDiff {
+        let mut diff = Self::compute(old.clone(), new);
         let old = old.split_inclusive('\n').collect::<Vec<_>>();
 
+        // the blocks deleted from the old contents
+        let mut deleted = Vec::new();
+        let mut pos = 0;
+        for op in diff.ops.iter() {
+            match op {
+                QBDiffOp::Equal { len } => pos += len,
+                QBDiffOp::Delete { len } | QBDiffOp::Replace { len, .. } => {
+                    if *len >= MOVE_MIN_LINES {
+                        deleted.push((pos, *len));
+                    }
+                    pos += len;
+                }
+                QBDiffOp::Insert { .. } | QBDiffOp::Move { .. } => {}
+            }
+        }
+
+        let mut ops = Vec::with_capacity(diff.ops.len());
+        for op in diff.ops.into_iter() {
+            let (len, content) = match &op {
+                QBDiffOp::Insert { content } => (0, content),
+                QBDiffOp::Replace { len, content } => (*len, content),
+                _ => {
+                    ops.push(op);
+                    continue;
+                }
+            };
+
+            let lines = content.split_inclusive('\n').collect::<Vec<_>>();
+            let found = deleted
+                .iter()
+                .position(|(from, len)| old[*from..from + len] == lines[..]);
+            let Some(found) = found else {
+                ops.push(op);
+                continue;
+            };
+
+            // every deleted block can only be moved once
+            let (from, moved) = deleted.remove(found);
+            if len > 0 {
+                ops.push(QBDiffOp::Delete { len });
+            }
+            ops.push(QBDiffOp::Move { from, len: moved });
+        }
+
+        diff.ops = ops;
+        diff
+    }
+
+    /// Returns the size of this diff when serialized in bytes.
+    ///
+    /// This encodes the diff, so it should not be called too often.
+    pub fn encoded_size(&self) -> usize {
+        bitcode::encode(self).len()
+    }
+
+    /// Apply this diff to a string.
+    ///
+    /// Returns an [io::ErrorKind::InvalidData] error if the hash of old
+    /// does not match or the diff refers to lines which do not exist.
+    pub fn apply(&self, old: String) -> io::Result<String> {
+        if QBHash::compute(&old) != self.old_hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "diff: hash of old contents does not match",
+            ));
+        }
+
+        self.apply_lines(&old.split_inclusive('\n').collect::<Vec<_>>())
+    }
+
+    /// Apply this diff to the lines of a string, without checking the hash.
+    fn apply_lines(&self, old: &[&str]) -> io::Result<String> {
         let mut old_index = 0;
         let mut new = String::new();
         for op in self.ops.iter() {
             match op {
                 QBDiffOp::Equal { len } => {
-                    new += &old[old_index..(old_index + len)].join("");
+                    new += &Self::get_lines(old, old_index, *len)?.join("");
                     old_index += len;
                 }
                 QBDiffOp::Insert { content } => new += content,
-                QBDiffOp::Delete { len } => old_index += len,
+                QBDiffOp::Delete { len } => {
+                    Self::get_lines(old, old_index, *len)?;
+                    old_index += len;
+                }
                 QBDiffOp::Replace { content, len } => {
+                    Self::get_lines(old, old_index, *len)?;
                     new += content;
                     old_index += len;
                 }
+                QBDiffOp::Move { from, len } => new += &Self::get_lines(old, *from, *len)?.join(""),
             }
         }
 
-        new
+        Ok(new)
     }
 
-    /// Get the indicies for each operation
-    fn get_indicies(&self) -> Vec<Index> {
-        let mut old_index = 0;
-        let mut new_index = 0;
-        let mut indicies = Vec::new();
+    /// Returns len lines starting at from, or an error
+    /// if the diff refers to lines which do not exist.
+    fn get_lines<'a>(old: &'a [&'a str], from: usize, len: usize) -> io::Result<&'a [&'a str]> {
+        from.checked_add(len)
+            .and_then(|end| old.get(from..end))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "diff: range is out of the old contents",
+                )
+            })
+    }
+
+    /// Apply this diff to the contents read from old and write
+    /// the transformed contents to new.
+    ///
+    /// Unlike [QBDiff::apply] this processes the contents line by line,
+    /// so neither version has to be held in memory. The hash of old is
+    /// only known after everything has been read, which is why this
+    /// returns an [io::ErrorKind::InvalidData] error at the end and not
+    /// before writing, if the hash of old does not match.
+    ///
+    /// Moved blocks (see [QBDiff::compute_with_moves]) might refer to any
+    /// line of old, so diffs which contain them read old into memory.
+    pub fn apply_to(&self, mut old: impl BufRead, mut new: impl Write) -> io::Result<()> {
+        if self
+            .ops
+            .iter()
+            .any(|op| matches!(op, QBDiffOp::Move { .. }))
+        {
+            let mut contents = Vec::new();
+            old.read_to_end(&mut contents)?;
+            if QBHash::compute(&contents) != self.old_hash {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "diff: hash of old contents does not match",
+                ));
+            }
+            let contents = String::from_utf8(contents)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            let lines = contents.split_inclusive('\n').collect::<Vec<_>>();
+            new.write_all(self.apply_lines(&lines)?.as_bytes())?;
+            return new.flush();
+        }
+
+        let mut hasher = QBHasher::default();
+        let mut line = Vec::new();
+
         for op in self.ops.iter() {
-            let old_start = old_index;
-            let new_start = new_index;
             match op {
-                QBDiffOp::Delete { len } => {
-                    old_index += len;
-                }
                 QBDiffOp::Equal { len } => {
-                    old_index += len;
-                    new_index += len;
+                    Self::copy_lines(&mut old, &mut new, &mut hasher, &mut line, *len)?;
                 }
-                QBDiffOp::Replace { len, content } => {
-                    old_index += len;
-                    new_index += content.len();
+                QBDiffOp::Insert { content } => new.write_all(content.as_bytes())?,
+                QBDiffOp::Delete { len } => {
+                    Self::copy_lines(&mut old, &mut io::sink(), &mut hasher, &mut line, *len)?;
                 }
-                QBDiffOp::Insert { content } => {
-                    new_index += content.len();
+                QBDiffOp::Replace { content, len } => {
+                    new.write_all(content.as_bytes())?;
+                    Self::copy_lines(&mut old, &mut io::sink(), &mut hasher, &mut line, *len)?;
                 }
+                QBDiffOp::Move { .. } => unreachable!("diffs with moves are applied in memory"),
             }
-            let old_end = old_index;
-            let new_end = new_index;
-
-            indicies.push(Index {
-                old_start,
-                old_end,
-                new_start,
-                new_end,
-            });
         }
 
-        indicies
+        // hash the remainder of old
+        loop {
+            let buf = old.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            hasher.update(buf);
+            let len = buf.len();
+            old.consume(len);
+        }
+
+        if hasher.finalize() != self.old_hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "diff: hash of old contents does not match",
+            ));
+        }
+
+        new.flush()
     }
 
-    /// Merge two diffs into one. This may return errors
-    /// TODO: I don't think this is optimal
-    pub fn merge(mut a: QBDiff, mut b: QBDiff) -> Option<QBDiff> {
-        assert!(a.old_hash == b.old_hash);
+    /// Read len lines from old, hash them and write them to new.
+    fn copy_lines(
+        old: &mut impl BufRead,
+        new: &mut impl Write,
+        hasher: &mut QBHasher,
+        line: &mut Vec<u8>,
+        len: usize,
+    ) -> io::Result<()> {
+        for _ in 0..len {
+            line.clear();
+            if old.read_until(b'\n', line)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "diff: old contents ended early",
+                ));
+            }
+            hasher.update(&line);
+            new.write_all(line)?;
+        }
+        Ok(())
+    }
 
-        let mut a_indicies = a.get_indicies();
-        let mut b_indicies = b.get_indicies();
-
-        let mut a_index = 0;
-        let mut b_index = 0;
-
-        // Splitting entities
-        while a_indicies.len() > a_index && b_indicies.len() > b_index {
-            let (stay, split, stay_index, split_index) = match a_indicies[a_index]
-                .old_end
-                .cmp(&b_indicies[b_index].old_end)
-            {
-                std::cmp::Ordering::Greater => (
-                    (&mut b_indicies, &mut b.ops),
-                    (&mut a_indicies, &mut a.ops),
-                    b_index,
-                    a_index,
-                ),
-                std::cmp::Ordering::Less => (
-                    (&mut a_indicies, &mut a.ops),
-                    (&mut b_indicies, &mut b.ops),
-                    a_index,
-                    b_index,
-                ),
-                std::cmp::Ordering::Equal => {
-                    a_index += 1;
-                    b_index += 1;
+    /// Describe this diff for every line of the old contents.
+    fn lines(&self) -> QBDiffLines {
+        let mut removed = Vec::new();
+        let mut inserted = vec![Vec::new()];
+        for op in self.ops.iter() {
+            let (len, remove) = match op {
+                QBDiffOp::Equal { len } => (*len, false),
+                QBDiffOp::Delete { len } => (*len, true),
+                QBDiffOp::Insert { .. } | QBDiffOp::Move { .. } => {
+                    inserted.last_mut().unwrap().push(op.clone());
                     continue;
                 }
+                QBDiffOp::Replace { len, content } => {
+                    let content = content.clone();
+                    inserted
+                        .last_mut()
+                        .unwrap()
+                        .push(QBDiffOp::Insert { content });
+                    (*len, true)
+                }
             };
+            removed.extend(std::iter::repeat_n(remove, len));
+            inserted.extend(std::iter::repeat_with(Vec::new).take(len));
+        }
+        QBDiffLines { removed, inserted }
+    }
+
+    /// Build a diff from its description for every line of the old contents.
+    fn from_lines(old_hash: QBHash, lines: QBDiffLines) -> QBDiff {
+        let mut ops = Vec::new();
+        let mut removed = lines.removed.into_iter();
+        for inserted in lines.inserted {
+            for op in inserted {
+                Self::push_op(&mut ops, op);
+            }
+            match removed.next() {
+                Some(true) => Self::push_op(&mut ops, QBDiffOp::Delete { len: 1 }),
+                Some(false) => Self::push_op(&mut ops, QBDiffOp::Equal { len: 1 }),
+                None => {}
+            }
+        }
+        QBDiff { old_hash, ops }
+    }
 
-            // TODO: if assertion fails, merge conflict => return error
-            assert!(matches!(split.1[split_index], QBDiffOp::Equal { .. }));
-
-            let (a, b) = (&stay.0[stay_index], &split.0[split_index]);
-
-            // Sorry for the confusing naming
-            // len_a is the length of the first part of the split
-            // len_b is the length of the second part of the split
-            let len_a = a.old_end - b.old_start;
-            let len_b = b.old_end - a.old_end;
-
-            let old_split = a.old_end;
-            let new_split = b.new_end - len_b;
-            let old_end = b.old_end;
-            let new_end = b.new_end;
-
-            split.0.insert(
-                split_index,
-                Index {
-                    old_start: old_split,
-                    old_end,
-                    new_start: new_split,
-                    new_end,
+    /// Push an operation, combining it with the previous one if possible.
+    fn push_op(ops: &mut Vec<QBDiffOp>, op: QBDiffOp) {
+        match (ops.last_mut(), op) {
+            (Some(QBDiffOp::Equal { len }), QBDiffOp::Equal { len: more }) => *len += more,
+            (Some(QBDiffOp::Delete { len }), QBDiffOp::Delete { len: more }) => *len += more,
+            (Some(QBDiffOp::Insert { content }), QBDiffOp::Insert { content: more }) => {
+                *content += &more
+            }
+            (
+                Some(QBDiffOp::Move { from, len }),
+                QBDiffOp::Move {
+                    from: next,
+                    len: more,
                 },
-            );
-            split.1[split_index] = QBDiffOp::Equal { len: len_a };
-            split.1.insert(split_index, QBDiffOp::Equal { len: len_b });
-            split.0[split_index].old_end = old_split;
-            split.0[split_index].new_end = new_split;
-
-            a_index += 1;
-            b_index += 1;
+            ) if *from + *len == next => *len += more,
+            (_, op) => ops.push(op),
         }
+    }
 
-        let mut ops = Vec::new();
+    /// Merge two diffs of the same contents into one, or return none
+    /// if they conflict, that is, if both insert different contents at
+    /// the same position or both move overlapping blocks. Diffs which
+    /// move lines that do not exist can not be merged either.
+    ///
+    /// If one diff moves a block of lines which the other one edits,
+    /// the edits are carried over to the new position of the block.
+    pub fn merge(a: QBDiff, b: QBDiff) -> Option<QBDiff> {
+        assert!(a.old_hash == b.old_hash);
+        if a.ops == b.ops {
+            return Some(a);
+        }
 
-        let mut a_index = 0;
-        let mut b_index = 0;
-
-        // Splitting entities
-        while a_indicies.len() > a_index && b_indicies.len() > b_index {
-            match a_indicies[a_index]
-                .old_end
-                .cmp(&b_indicies[b_index].old_end)
-            {
-                std::cmp::Ordering::Less => {
-                    ops.push(a.ops[a_index].clone());
-                    a_index += 1;
-                    continue;
+        let mut a_lines = a.lines();
+        let mut b_lines = b.lines();
+        if a_lines.removed.len() != b_lines.removed.len() {
+            return None;
+        }
+        if !a_lines.is_valid() || !b_lines.is_valid() {
+            return None;
+        }
+
+        let a_moves = a_lines.moves();
+        let b_moves = b_lines.moves();
+        let overlaps = a_moves
+            .iter()
+            .any(|a| b_moves.iter().any(|b| a.start < b.end && b.start < a.end));
+        if overlaps {
+            return None;
+        }
+        a_lines.carry_edits(&mut b_lines);
+        b_lines.carry_edits(&mut a_lines);
+
+        let mut inserted = Vec::with_capacity(a_lines.inserted.len());
+        let lines = a_lines.inserted.into_iter().zip(b_lines.inserted);
+        for (i, (mut a, mut b)) in lines.enumerate() {
+            // contents inserted where a line is removed replace it, so
+            // they go after contents which are only inserted before it
+            let a_replaces = a_lines.removed.get(i).is_some_and(|r| *r);
+            let b_replaces = b_lines.removed.get(i).is_some_and(|r| *r);
+            inserted.push(match (a.is_empty(), b.is_empty()) {
+                (true, _) => b,
+                (_, true) => a,
+                _ if a == b => a,
+                _ if a_replaces && !b_replaces => {
+                    b.append(&mut a);
+                    b
                 }
-                std::cmp::Ordering::Greater => {
-                    ops.push(b.ops[a_index].clone());
-                    b_index += 1;
-                    continue;
+                _ if b_replaces && !a_replaces => {
+                    a.append(&mut b);
+                    a
                 }
-                _ => {}
-            };
+                _ => return None,
+            });
+        }
+        let removed = a_lines
+            .removed
+            .iter()
+            .zip(b_lines.removed.iter())
+            .map(|(a, b)| *a || *b)
+            .collect();
+
+        Some(Self::from_lines(
+            a.old_hash,
+            QBDiffLines { removed, inserted },
+        ))
+    }
+}
+
+impl QBDiffLines {
+    /// Returns whether every moved block is part of the old contents.
+    fn is_valid(&self) -> bool {
+        self.inserted.iter().flatten().all(|op| match op {
+            QBDiffOp::Move { from, len } => from
+                .checked_add(*len)
+                .is_some_and(|end| end <= self.removed.len()),
+            _ => true,
+        })
+    }
+
+    /// Returns the ranges of the lines which are moved, that is,
+    /// which are removed and inserted at another position.
+    fn moves(&self) -> Vec<std::ops::Range<usize>> {
+        self.inserted
+            .iter()
+            .flatten()
+            .filter_map(|op| match op {
+                QBDiffOp::Move { from, len } => Some(*from..from + len),
+                _ => None,
+            })
+            .filter(|range| range.clone().all(|i| self.removed[i]))
+            .collect()
+    }
+
+    /// Carry the edits the other diff makes to the blocks this one
+    /// moves over to their new position. The edits are removed from
+    /// the other diff, as the old position of the block is removed.
+    fn carry_edits(&mut self, other: &mut QBDiffLines) {
+        for inserted in self.inserted.iter_mut() {
+            let mut carried = Vec::with_capacity(inserted.len());
+            for op in inserted.drain(..) {
+                let (from, len) = match op {
+                    QBDiffOp::Move { from, len } if (from..from + len).all(|i| self.removed[i]) => {
+                        (from, len)
+                    }
+                    op => {
+                        carried.push(op);
+                        continue;
+                    }
+                };
 
-            match (&a.ops[a_index], &b.ops[b_index]) {
-                (QBDiffOp::Equal { .. }, _) => {
-                    ops.push(b.ops[b_index].clone());
-                    a_index += 1;
-                    b_index += 1;
+                for i in from..from + len {
+                    // contents inserted where the first line is removed
+                    // replace it, so they belong to the block as well
+                    if i > from || other.removed[from] {
+                        for op in other.inserted[i].drain(..) {
+                            QBDiff::push_op(&mut carried, op);
+                        }
+                    }
+                    if !std::mem::take(&mut other.removed[i]) {
+                        QBDiff::push_op(&mut carried, QBDiffOp::Move { from: i, len: 1 });
+                    }
                 }
-                (_, QBDiffOp::Equal { .. }) => {
-                    ops.push(a.ops[a_index].clone());
-                    a_index += 1;
-                    b_index += 1;
+            }
+            *inserted = carried;
+        }
+    }
+}
+
+/// struct which stores how to build new binary contents from old ones
+///
+/// The old contents are split into chunks (see [QBChunker]) and the
+/// new contents are described as chunks copied from the old contents
+/// plus the bytes that are new, so small changes to large binary files
+/// only require the changed bytes to be transmitted.
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone)]
+pub struct QBBinaryPatch {
+    /// the average chunk size the old contents are split with
+    pub chunk_size: u64,
+    /// the hash of the contents after the transformation
+    pub hash: QBHash,
+    /// the transformations themselves
+    pub ops: Vec<QBBinaryPatchOp>,
+}
+
+/// struct which stores a single operation of a [QBBinaryPatch]
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone)]
+pub enum QBBinaryPatchOp {
+    /// copy chunks of the old contents
+    Copy {
+        /// index of the first chunk
+        index: u64,
+        /// amount of chunks
+        count: u64,
+    },
+    /// insert new content
+    Insert {
+        /// the bytes to be inserted
+        #[serde(with = "serde_bytes")]
+        content: Vec<u8>,
+    },
+}
+
+impl QBBinaryPatch {
+    /// Compute a patch from the chunk hashes of the old contents to the new
+    /// contents. Both have to be split with the given chunker.
+    ///
+    /// Returns none if the patch would not be smaller than half of the
+    /// new contents, in which case the contents should be sent in full.
+    pub fn compute(chunker: &QBChunker, old_chunks: &[QBHash], new: &[u8]) -> Option<Self> {
+        let mut index = HashMap::new();
+        for (i, hash) in old_chunks.iter().enumerate() {
+            index.entry(hash).or_insert(i as u64);
+        }
+
+        let mut ops: Vec<QBBinaryPatchOp> = Vec::new();
+        let mut inserted = 0;
+        for chunk in chunker.chunks(new) {
+            let op = match index.get(&QBHash::compute(chunk)) {
+                Some(&i) => QBBinaryPatchOp::Copy { index: i, count: 1 },
+                None => {
+                    inserted += chunk.len();
+                    QBBinaryPatchOp::Insert {
+                        content: chunk.to_vec(),
+                    }
                 }
-                _ => unimplemented!(),
             };
+
+            // join consecutive operations
+            match (ops.last_mut(), op) {
+                (
+                    Some(QBBinaryPatchOp::Copy { index, count }),
+                    QBBinaryPatchOp::Copy { index: i, .. },
+                ) if *index + *count == i => *count += 1,
+                (
+                    Some(QBBinaryPatchOp::Insert { content }),
+                    QBBinaryPatchOp::Insert { content: c },
+                ) => content.extend_from_slice(&c),
+                (_, op) => ops.push(op),
+            }
+        }
+
+        if inserted * 2 >= new.len() {
+            return None;
         }
 
-        Some(QBDiff {
-            old_hash: a.old_hash,
+        Some(Self {
+            chunk_size: chunker.avg_size() as u64,
+            hash: QBHash::compute(new),
             ops,
         })
     }
+
+    /// Apply this patch to the old contents.
+    ///
+    /// Returns none if the patch does not fit the old contents,
+    /// that is, if it refers to chunks which do not exist or if
+    /// the transformed contents do not match the expected hash.
+    pub fn apply(&self, old: &[u8]) -> Option<Vec<u8>> {
+        let avg_size = usize::try_from(self.chunk_size).ok()?;
+        if avg_size < QBChunker::MIN_AVG_SIZE {
+            return None;
+        }
+        let chunker = QBChunker::new(avg_size);
+        let chunks = chunker.chunks(old).collect::<Vec<_>>();
+
+        let mut new = Vec::new();
+        for op in self.ops.iter() {
+            match op {
+                QBBinaryPatchOp::Copy { index, count } => {
+                    let start = usize::try_from(*index).ok()?;
+                    let end = start.checked_add(usize::try_from(*count).ok()?)?;
+                    for chunk in chunks.get(start..end)? {
+                        new.extend_from_slice(chunk);
+                    }
+                }
+                QBBinaryPatchOp::Insert { content } => new.extend_from_slice(content),
+            }
+        }
+
+        (QBHash::compute(&new) == self.hash).then_some(new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD: &str = "a\nb\nc\nd\ne\nf\n";
+
+    #[test]
+    fn merge_moved_and_edited_block() {
+        // one side moves b and c to the end, the other side edits c
+        let moved = QBDiff::compute_with_moves(OLD.to_owned(), "a\nd\ne\nf\nb\nc\n".to_owned());
+        assert!(moved
+            .ops
+            .iter()
+            .any(|op| matches!(op, QBDiffOp::Move { .. })));
+        let edited = QBDiff::compute(OLD.to_owned(), "a\nb\nC\nd\ne\nf\n".to_owned());
+
+        let expected = "a\nd\ne\nf\nb\nC\n";
+        let merged = QBDiff::merge(moved.clone(), edited.clone()).unwrap();
+        assert_eq!(merged.apply(OLD.to_owned()).unwrap(), expected);
+        let merged = QBDiff::merge(edited, moved).unwrap();
+        assert_eq!(merged.apply(OLD.to_owned()).unwrap(), expected);
+    }
+
+    #[test]
+    fn apply_to_matches_apply() {
+        let news = ["a\nB\nc\nd\nx\ny\n", "e\nf\na\nb\nc\nd", "", OLD];
+        for new in news {
+            for diff in [
+                QBDiff::compute(OLD.to_owned(), new.to_owned()),
+                QBDiff::compute_with_moves(OLD.to_owned(), new.to_owned()),
+            ] {
+                let expected = diff.apply(OLD.to_owned()).unwrap();
+                assert_eq!(expected, new);
+                let mut applied = Vec::new();
+                diff.apply_to(OLD.as_bytes(), &mut applied).unwrap();
+                assert_eq!(String::from_utf8(applied).unwrap(), expected);
+
+                let changed = "a\nb\n";
+                assert!(diff.apply(changed.to_owned()).is_err());
+                assert!(diff.apply_to(changed.as_bytes(), io::sink()).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn reject_invalid_move() {
+        let edited = QBDiff::compute(OLD.to_owned(), "a\nb\nC\nd\ne\nf\n".to_owned());
+        for (from, len) in [(5, 2), (usize::MAX, 2)] {
+            let diff = QBDiff {
+                old_hash: QBHash::compute(OLD),
+                ops: vec![QBDiffOp::Delete { len: 6 }, QBDiffOp::Move { from, len }],
+            };
+
+            let err = diff.apply(OLD.to_owned()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            let mut new = Vec::new();
+            let err = diff.apply_to(OLD.as_bytes(), &mut new).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(QBDiff::merge(diff.clone(), edited.clone()).is_none());
+            assert!(QBDiff::merge(edited.clone(), diff).is_none());
+        }
+    }
 }
@@ -6,36 +6,54 @@ pub mod table;
 pub mod tree;
 pub mod wrapper;
 
-use std::{ffi::OsString, path::Path};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
 
+use bitcode::{Decode, Encode};
 use thiserror::Error;
 use tracing::{debug, info, warn};
 
 use table::QBFileTable;
-use tree::{QBFileTree, TreeFile};
+use tree::{QBFileTree, QBFileTreeNode, TreeFile};
 use wrapper::QBFSWrapper;
 
 use crate::{
-    change::{QBChange, QBChangeKind, QBChangeMap},
-    device::QBDeviceTable,
-    diff::QBDiff,
+    change::{journal::QBChangeMapJournal, QBChange, QBChangeKind, QBChangeMap},
+    chunk::QBChunker,
+    device::{QBDeviceId, QBDeviceTable},
+    diff::{QBBinaryPatch, QBDiff, QBLineEnding},
     hash::QBHash,
     ignore::{QBIgnoreMap, QBIgnoreMapBuilder},
     path::{
         qbpaths::{
-            self, INTERNAL_CHANGEMAP, INTERNAL_DEVICES, INTERNAL_FILETABLE, INTERNAL_FILETREE,
-            INTERNAL_IGNORE,
+            self, INTERNAL_CHANGEMAP, INTERNAL_CHANGEMAP_JOURNAL, INTERNAL_DEVICES,
+            INTERNAL_FILETABLE, INTERNAL_FILETREE, INTERNAL_IGNORE,
         },
-        QBPath, QBPathError, QBResource,
+        QBPath, QBPathError, QBResource, QBResourceKind,
     },
 };
 
+// the number of UTF-8 validations done by this thread, see [QBFS::diff]
+#[cfg(test)]
+thread_local! {
+    static UTF8_CHECKS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
 /// struct describing an error that occured while dealing with the file system
 #[derive(Error, Debug)]
 pub enum Error {
     /// I/O error
-    #[error("I/O error")]
-    IO(#[from] std::io::Error),
+    #[error("I/O error{}: {source}", .path.as_ref().map(|e| format!(" at {}", e.display())).unwrap_or_default())]
+    IO {
+        /// the underlying error
+        source: std::io::Error,
+        /// the path the operation failed on (if known)
+        path: Option<PathBuf>,
+    },
     /// struct encoding/decoding error
     #[error("bitcode error")]
     Bitcode(#[from] bitcode::Error),
@@ -48,24 +66,229 @@ pub enum Error {
     /// file not found in filetree error
     #[error("file tree: not found")]
     NotFound,
+    /// resource of another kind occupies the path
+    #[error("conflict: {resource} but found {found:?}")]
+    KindConflict {
+        /// the resource which should have been applied
+        resource: QBResource,
+        /// the kind of resource which occupies the path
+        found: QBResourceKind,
+    },
+    /// the content a text diff is based on is not stored in the file table,
+    /// or the content a binary patch is based on is not found on disk
+    #[error("missing base {hash} for diff of {resource}")]
+    MissingBase {
+        /// the resource the diff should have been applied to
+        resource: QBResource,
+        /// the hash of the missing content
+        hash: QBHash,
+    },
+    /// there is no space left on the device, applying changes should
+    /// be retried once space has been freed
+    #[error("out of space{}", .path.as_ref().map(|e| format!(" at {}", e.display())).unwrap_or_default())]
+    OutOfSpace {
+        /// the path the operation failed on (if known)
+        path: Option<PathBuf>,
+    },
+    /// the file is larger than the maximum size which is read into memory
+    #[error("{} is too large ({size} bytes, at most {max} bytes)", .path.display())]
+    TooLarge {
+        /// the path of the file
+        path: PathBuf,
+        /// the size of the file
+        size: u64,
+        /// the maximum size
+        max: u64,
+    },
+}
+
+impl Error {
+    fn io(source: std::io::Error, path: Option<PathBuf>) -> Self {
+        match source.kind() {
+            std::io::ErrorKind::StorageFull => Error::OutOfSpace { path },
+            _ => Error::IO { source, path },
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(source: std::io::Error) -> Self {
+        Error::io(source, None)
+    }
 }
 
 pub(crate) type Result<T> = std::result::Result<T, Error>;
 
+/// Attach the path an I/O operation failed on to its error.
+pub(crate) trait WithPath<T> {
+    fn with_path(self, path: impl AsRef<Path>) -> Result<T>;
+}
+
+impl<T> WithPath<T> for std::io::Result<T> {
+    fn with_path(self, path: impl AsRef<Path>) -> Result<T> {
+        self.map_err(|source| Error::io(source, Some(path.as_ref().to_owned())))
+    }
+}
+
 /// struct describing a change that can be directly applied to the file system
 ///
 /// this differs from [QBChange], as the diff stored in UpdateText
 /// is already expanded, so no further processing is required.
-#[derive(Debug)]
+#[derive(Encode, Decode, Debug, Clone)]
 pub struct QBFSChange {
     /// the resource this change affects
     pub resource: QBResource,
     /// the kind of change
     pub kind: QBFSChangeKind,
+    /// the modification time the file gets after creating or
+    /// updating it, in nanoseconds since the unix epoch
+    pub mtime: Option<u64>,
+}
+
+impl QBFSChange {
+    #[inline]
+    fn is_dir_create(&self) -> bool {
+        self.resource.is_dir() && matches!(self.kind, QBFSChangeKind::Create)
+    }
+
+    #[inline]
+    fn is_dir_delete(&self) -> bool {
+        self.resource.is_dir() && matches!(self.kind, QBFSChangeKind::Delete)
+    }
+}
+
+/// Order the given changes, so that directories are created before
+/// the changes to their contents and deleted after their contents.
+///
+/// Deletes are applied before creates of paths which only differ in case,
+/// so that case-only renames work on case-insensitive file systems.
+///
+/// Changes which affect the same path keep their relative order, if
+/// ordering is impossible the original order is used as a fallback.
+pub fn order_changes(changes: Vec<QBFSChange>) -> Vec<QBFSChange> {
+    let len = changes.len();
+    let mut edges = vec![Vec::new(); len];
+    let mut indegree = vec![0usize; len];
+    let mut edge = |from: usize, to: usize| {
+        edges[from].push(to);
+        indegree[to] += 1;
+    };
+
+    let mut by_path: HashMap<&QBPath, Vec<usize>> = HashMap::new();
+    for (i, change) in changes.iter().enumerate() {
+        by_path.entry(&change.resource.path).or_default().push(i);
+    }
+
+    for indices in by_path.values() {
+        for pair in indices.windows(2) {
+            edge(pair[0], pair[1]);
+        }
+    }
+
+    let mut by_case: HashMap<QBPath, Vec<usize>> = HashMap::new();
+    for (i, change) in changes.iter().enumerate() {
+        by_case
+            .entry(change.resource.path.to_lowercase())
+            .or_default()
+            .push(i);
+    }
+
+    for indices in by_case.values().filter(|indices| indices.len() > 1) {
+        for &i in indices {
+            if !matches!(changes[i].kind, QBFSChangeKind::Delete) {
+                continue;
+            }
+            for &j in indices {
+                let other = &changes[j];
+                if other.resource.path != changes[i].resource.path
+                    && matches!(other.kind, QBFSChangeKind::Create)
+                {
+                    edge(i, j);
+                }
+            }
+        }
+    }
+
+    for (i, change) in changes.iter().enumerate() {
+        let is_delete = matches!(change.kind, QBFSChangeKind::Delete);
+        let mut ancestor = change.resource.path.clone().parent();
+        while let Some(path) = ancestor {
+            if let Some(indices) = by_path.get(&path) {
+                if is_delete {
+                    // the directory is deleted for good after this change
+                    let last = *indices.last().unwrap();
+                    if last < i && changes[last].is_dir_delete() {
+                        edge(i, last);
+                    }
+                } else {
+                    // the directory does not exist before it is created
+                    let first = indices[0];
+                    if first > i && changes[first].is_dir_create() {
+                        edge(first, i);
+                    }
+                }
+            }
+            ancestor = path.parent();
+        }
+    }
+
+    // Kahn's algorithm, which prefers the change with the lowest index
+    let mut queue = (0..len)
+        .filter(|&i| indegree[i] == 0)
+        .map(Reverse)
+        .collect::<BinaryHeap<_>>();
+    let mut order = Vec::with_capacity(len);
+    let mut done = vec![false; len];
+    while order.len() < len {
+        let i = match queue.pop() {
+            Some(Reverse(i)) => i,
+            // cycle detected, fall back to the original order
+            None => (0..len).find(|&i| !done[i]).unwrap(),
+        };
+        if done[i] {
+            continue;
+        }
+        done[i] = true;
+        order.push(i);
+        for &next in &edges[i] {
+            indegree[next] -= 1;
+            if indegree[next] == 0 {
+                queue.push(Reverse(next));
+            }
+        }
+    }
+
+    let mut changes = changes.into_iter().map(Some).collect::<Vec<_>>();
+    order
+        .into_iter()
+        .map(|i| changes[i].take().unwrap())
+        .collect()
+}
+
+/// enum describing how to undo a change that has been applied,
+/// see [QBFS::apply_changes]
+enum QBFSUndo {
+    /// restore the entry the tree stored at the path and its children
+    Tree {
+        path: QBPath,
+        subtree: Option<QBFileTree>,
+    },
+    /// remove the resource, which did not exist before
+    Remove(QBResource),
+    /// restore the contents of a file
+    Write { path: QBPath, content: Vec<u8> },
+    /// move a resource back to where it was
+    Rename { from: QBPath, to: QBPath },
+    /// restore an extended attribute of a file
+    XAttr {
+        path: QBPath,
+        name: String,
+        value: Option<Vec<u8>>,
+    },
 }
 
 /// enum describing the different kinds of changes
-#[derive(Debug)]
+#[derive(Encode, Decode, Debug, Clone)]
 pub enum QBFSChangeKind {
     /// update a file
     Update {
@@ -78,6 +301,13 @@ pub enum QBFSChangeKind {
     Create,
     /// delete a file or directory
     Delete,
+    /// update a file using a binary patch
+    Patch {
+        /// the hash of the file content the patch is based on
+        base_hash: QBHash,
+        /// the patch
+        patch: QBBinaryPatch,
+    },
     /// rename a file or directory
     Rename {
         /// location
@@ -88,15 +318,52 @@ pub enum QBFSChangeKind {
         /// location
         from: QBPath,
     },
+    /// set or remove an extended attribute of a file
+    XAttr {
+        /// the name of the attribute
+        name: String,
+        /// the value of the attribute, none if removed
+        value: Option<Vec<u8>>,
+    },
 }
 
 /// struct describing a text or binary diff of a file
 #[derive(Debug)]
 pub enum QBFileDiff {
-    /// binary file
+    /// binary file, a file which has been truncated to zero bytes, or a
+    /// text file whose diff would be larger than its contents
     Binary(Vec<u8>),
+    /// binary file, of which only a small part changed
+    BinaryPatch {
+        /// the hash of the content stored in the file tree
+        base_hash: QBHash,
+        /// the patch from the stored content to the new content
+        patch: QBBinaryPatch,
+    },
     /// text file
     Text(QBDiff),
+    /// the contents are unchanged, but the file has been
+    /// touched, so only its modification time changed
+    Touched,
+    /// the resource has been replaced by a resource of another
+    /// kind, e.g. a file by a directory of the same name
+    Kind {
+        /// the kind which is stored in the file tree
+        from: QBResourceKind,
+        /// the kind which is found on the file system
+        to: QBResourceKind,
+    },
+}
+
+/// A function which transforms the contents of a file, see [QBFS::with_transform].
+pub type QBFSTransformFn = Box<dyn Fn(&QBResource, &[u8]) -> Vec<u8> + Send + Sync>;
+
+/// A transform of the contents of files on disk, see [QBFS::with_transform].
+pub struct QBFSTransform {
+    /// applied to the contents before they are written to disk
+    pub apply: QBFSTransformFn,
+    /// applied to the contents after they are read from disk
+    pub invert: QBFSTransformFn,
 }
 
 /// struct representing a local file system
@@ -115,9 +382,30 @@ pub struct QBFS {
     pub ignore_builder: QBIgnoreMapBuilder,
     /// the ignore
     pub ignore: QBIgnoreMap,
+    /// the chunker used for splitting files into chunks
+    pub chunker: QBChunker,
+    /// whether line endings of text files get canonicalized
+    /// before diffing, see [QBFS::with_normalize_eol]
+    pub normalize_eol: bool,
+    /// whether the contents of text files are kept in the
+    /// file table, see [QBFS::with_file_table]
+    pub file_table: bool,
+    /// the maximum size of files which are read into
+    /// memory, see [QBFS::with_max_file_size]
+    pub max_file_size: u64,
+    /// whether moved blocks of lines are detected when
+    /// diffing text files, see [QBFS::with_detect_moves]
+    pub detect_moves: bool,
+    /// the transform of the contents on disk, see [QBFS::with_transform]
+    pub transform: Option<QBFSTransform>,
+    /// keeps track of what has been saved of the changemap
+    journal: QBChangeMapJournal,
 }
 
 impl QBFS {
+    /// The default maximum size of files which are read into memory (1 GiB).
+    pub const DEFAULT_MAX_FILE_SIZE: u64 = 1 << 30;
+
     /// Initialize this file system
     pub async fn init(root: impl AsRef<Path>) -> Self {
         let wrapper = QBFSWrapper::new(root);
@@ -128,11 +416,19 @@ impl QBFS {
         let ignore_builder: QBIgnoreMapBuilder = wrapper.dload(INTERNAL_IGNORE.as_ref()).await;
         let ignore = ignore_builder.build(&table);
         let devices = wrapper.dload(INTERNAL_DEVICES.as_ref()).await;
-        let changelog = wrapper.dload(INTERNAL_CHANGEMAP.as_ref()).await;
+        let snapshot = wrapper
+            .read(INTERNAL_CHANGEMAP.as_ref())
+            .await
+            .unwrap_or_default();
+        let journal = wrapper
+            .read(INTERNAL_CHANGEMAP_JOURNAL.as_ref())
+            .await
+            .unwrap_or_default();
+        let (changelog, journal) = QBChangeMapJournal::load(&snapshot, &journal);
 
         debug!("loaded {}", ignore);
 
-        Self {
+        let mut fs = Self {
             wrapper,
             tree,
             table,
@@ -140,30 +436,329 @@ impl QBFS {
             changemap: changelog,
             ignore_builder,
             ignore,
+            chunker: Default::default(),
+            normalize_eol: false,
+            file_table: true,
+            max_file_size: Self::DEFAULT_MAX_FILE_SIZE,
+            detect_moves: false,
+            transform: None,
+            journal,
+        };
+
+        // the tree is missing or corrupt, so we have to rebuild it,
+        // otherwise every resource would look like it has been created
+        if fs.tree.is_empty() && !fs.is_empty().await {
+            warn!("fs: tree is empty, rebuilding it from disk");
+            if let Err(err) = fs.rebuild_tree().await {
+                warn!("fs: could not rebuild tree: {}", err);
+            }
+        }
+
+        fs
+    }
+
+    /// Initialize this file system, using the given id for this device
+    /// instead of the one stored, e.g. to get reproducible device ids.
+    pub async fn init_with_device_id(root: impl AsRef<Path>, id: QBDeviceId) -> Self {
+        let mut fs = Self::init(root).await;
+        fs.devices.host_id = id;
+        fs
+    }
+
+    /// Returns whether the file system contains no resources
+    /// besides the internal ones.
+    async fn is_empty(&self) -> bool {
+        match self.wrapper.read_dir(qbpaths::ROOT.clone()).await {
+            Ok(entries) => entries.iter().all(|e| e.path == *qbpaths::INTERNAL),
+            Err(_) => true,
+        }
+    }
+
+    /// Rebuild the file tree by scanning the file system.
+    ///
+    /// This reconstructs the paths and hashes of all directories and files,
+    /// the contents of text files are stored in the file table.
+    pub async fn rebuild_tree(&mut self) -> Result<()> {
+        let mut tree = QBFileTree::default();
+        let mut stack = vec![qbpaths::ROOT.clone()];
+
+        while let Some(dir) = stack.pop() {
+            for resource in self.wrapper.read_dir(&dir).await? {
+                if resource.path == *qbpaths::INTERNAL {
+                    continue;
+                }
+
+                match resource.kind {
+                    QBResourceKind::Dir => {
+                        tree.create(&resource);
+                        stack.push(resource.path);
+                    }
+                    QBResourceKind::File => {
+                        let contents = match self
+                            .wrapper
+                            .read_capped(&resource, self.max_file_size)
+                            .await
+                        {
+                            Ok(contents) => contents,
+                            // only track the hash of files which are too large
+                            Err(Error::TooLarge { .. }) => {
+                                let hash = self.wrapper.hash(&resource).await?;
+                                tree.create(&resource);
+                                tree.get_mut(&resource).unwrap().file_mut().hash = hash;
+                                continue;
+                            }
+                            Err(err) => return Err(err),
+                        };
+                        let contents = self.invert_transform(&resource, contents);
+                        let (contents, eol) = self.normalize_eol(contents);
+                        let hash = QBHash::compute(&contents);
+
+                        tree.create(&resource);
+                        let file = tree.get_mut(&resource).unwrap().file_mut();
+                        file.chunks = self.chunker.hashes(&contents);
+                        file.hash = hash.clone();
+                        file.eol = eol;
+                        file.mtime = self.wrapper.mtime(&resource).await;
+
+                        if !self.caches(&resource.path) {
+                            continue;
+                        }
+                        if let Ok(text) = String::from_utf8(contents) {
+                            self.table.insert_hash(hash, text);
+                        }
+                    }
+                    // symlinks are not supported yet
+                    QBResourceKind::Symlink => {}
+                }
+            }
+        }
+
+        self.tree = tree;
+        Ok(())
+    }
+
+    /// Transform the contents of files before they are written to disk
+    /// and invert the transform after they are read, e.g. for encrypting
+    /// them at rest. Diffs and hashes operate on the inverted contents.
+    ///
+    /// Files are created empty and files which are too large to be read
+    /// (see [QBFS::with_max_file_size]) are hashed as they are on disk,
+    /// so `invert` has to accept (and keep) empty contents. Copies and
+    /// renames move the contents as they are on disk.
+    pub fn with_transform(
+        mut self,
+        apply: impl Fn(&QBResource, &[u8]) -> Vec<u8> + Send + Sync + 'static,
+        invert: impl Fn(&QBResource, &[u8]) -> Vec<u8> + Send + Sync + 'static,
+    ) -> Self {
+        self.transform = Some(QBFSTransform {
+            apply: Box::new(apply),
+            invert: Box::new(invert),
+        });
+        self
+    }
+
+    /// Use the given average chunk size for content-defined chunking.
+    pub fn with_chunk_size(mut self, avg_size: usize) -> Self {
+        self.chunker = QBChunker::new(avg_size);
+        self
+    }
+
+    /// Canonicalize the line endings of text files before diffing.
+    ///
+    /// Files which only differ in their line endings will then not produce
+    /// changes. The original style is restored when changes are applied.
+    pub fn with_normalize_eol(mut self, normalize_eol: bool) -> Self {
+        self.normalize_eol = normalize_eol;
+        self
+    }
+
+    /// Keep the contents of text files in the file table for diffing.
+    ///
+    /// Disabling this saves memory: local updates of text files are sent
+    /// in full then and text diffs are applied to the contents read from
+    /// disk. Ignore files are always kept in the file table.
+    pub fn with_file_table(mut self, file_table: bool) -> Self {
+        self.file_table = file_table;
+        self
+    }
+
+    /// Do not read files larger than the given size into memory.
+    ///
+    /// Diffing such files fails with [Error::TooLarge], so they are not
+    /// synchronized. Their hashes are still computed when rebuilding the tree.
+    pub fn with_max_file_size(mut self, max_file_size: u64) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    /// Detect blocks of lines which have been moved when diffing text files.
+    ///
+    /// See [QBDiff::compute_with_moves], this allows edits made to a moved
+    /// block on another device to follow the block when merging.
+    pub fn with_detect_moves(mut self, detect_moves: bool) -> Self {
+        self.detect_moves = detect_moves;
+        self
+    }
+
+    /// Returns whether the contents of the given path are kept in the file table.
+    fn caches(&self, path: &QBPath) -> bool {
+        self.file_table || path.name() == Some(".qbignore")
+    }
+
+    /// Read the contents of the given text file from disk,
+    /// if they match the given hash.
+    async fn read_text(&self, resource: &QBResource, hash: &QBHash) -> Option<String> {
+        let contents = self
+            .wrapper
+            .read_capped(resource, self.max_file_size)
+            .await
+            .ok()?;
+        let contents = self.invert_transform(resource, contents);
+        let (contents, _) = self.normalize_eol(contents);
+        if &QBHash::compute(&contents) != hash {
+            return None;
+        }
+        String::from_utf8(contents).ok()
+    }
+
+    /// Transform the given contents before writing them to disk, if enabled.
+    fn apply_transform(&self, resource: &QBResource, contents: Vec<u8>) -> Vec<u8> {
+        match &self.transform {
+            Some(transform) => (transform.apply)(resource, &contents),
+            None => contents,
+        }
+    }
+
+    /// Invert the transform of the given contents read from disk, if enabled.
+    fn invert_transform(&self, resource: &QBResource, contents: Vec<u8>) -> Vec<u8> {
+        match &self.transform {
+            Some(transform) => (transform.invert)(resource, &contents),
+            None => contents,
+        }
+    }
+
+    /// Normalize the line endings of the given contents, if enabled.
+    fn normalize_eol(&self, contents: Vec<u8>) -> (Vec<u8>, QBLineEnding) {
+        if !self.normalize_eol {
+            return (contents, QBLineEnding::Lf);
+        }
+
+        match String::from_utf8(contents) {
+            Ok(text) => {
+                let (text, eol) = QBLineEnding::normalize(text);
+                (text.into_bytes(), eol)
+            }
+            Err(err) => (err.into_bytes(), QBLineEnding::Lf),
+        }
+    }
+
+    /// Restore the line endings the given file uses on disk, if enabled.
+    fn restore_eol(&self, resource: &QBResource, content: Vec<u8>) -> Vec<u8> {
+        let eol = match self.tree.get(resource) {
+            Some(QBFileTreeNode::File(file)) if self.normalize_eol => file.eol,
+            _ => return content,
+        };
+
+        match String::from_utf8(content) {
+            Ok(text) => eol.restore(text).into_bytes(),
+            Err(err) => err.into_bytes(),
         }
     }
 
     /// convert the given change to fs change
-    pub fn to_fschanges(&mut self, changes: Vec<(QBResource, QBChange)>) -> Vec<QBFSChange> {
+    ///
+    /// Returns [Error::MissingBase] if a text diff can not be applied,
+    /// because the content it is based on is unknown to this file system
+    /// or is not the current content of the resource.
+    /// Contents which are not in the file table are read from disk.
+    pub async fn to_fschanges(
+        &mut self,
+        changes: Vec<(QBResource, QBChange)>,
+    ) -> Result<Vec<QBFSChange>> {
         // optimistic allocation
         let mut fschanges = Vec::with_capacity(changes.len());
         let mut source = None;
+        // the hashes of the contents the previous changes result in
+        let mut hashes = HashMap::new();
+        // the contents the previous changes result in, which are
+        // not kept in the file table
+        let mut texts = HashMap::new();
         for (resource, change) in changes {
             let kind = match &change.kind {
                 QBChangeKind::Create => Some(QBFSChangeKind::Create),
                 QBChangeKind::Delete => Some(QBFSChangeKind::Delete),
                 QBChangeKind::UpdateBinary(content) => {
                     let hash = QBHash::compute(content);
+                    hashes.insert(resource.clone(), hash.clone());
                     Some(QBFSChangeKind::Update {
                         content: content.clone(),
                         hash,
                     })
                 }
+                QBChangeKind::BinaryDiff { base_hash, patch } => {
+                    let hash = match hashes.get(&resource) {
+                        Some(hash) => Some(hash),
+                        None => match self.tree.get(&resource) {
+                            Some(QBFileTreeNode::File(file)) => Some(&file.hash),
+                            _ => None,
+                        },
+                    };
+                    // the patch might have been applied already
+                    if hash != Some(base_hash) && hash != Some(&patch.hash) {
+                        return Err(Error::MissingBase {
+                            resource,
+                            hash: base_hash.clone(),
+                        });
+                    }
+                    hashes.insert(resource.clone(), patch.hash.clone());
+                    Some(QBFSChangeKind::Patch {
+                        base_hash: base_hash.clone(),
+                        patch: patch.clone(),
+                    })
+                }
                 QBChangeKind::UpdateText(diff) => {
-                    let old = self.table.get(&diff.old_hash).to_string();
-                    let contents = diff.apply(old);
+                    // the diff must be based on the current content, otherwise
+                    // applying it would discard the changes made since then
+                    let hash = match hashes.get(&resource) {
+                        Some(hash) => Some(hash),
+                        None => match self.tree.get(&resource) {
+                            Some(QBFileTreeNode::File(file)) => Some(&file.hash),
+                            _ => None,
+                        },
+                    };
+                    if hash.is_some_and(|hash| hash != &diff.old_hash) {
+                        return Err(Error::MissingBase {
+                            resource,
+                            hash: diff.old_hash.clone(),
+                        });
+                    }
+                    // write directly into the new contents, so the old
+                    // contents are not copied out of the file table
+                    let mut contents = Vec::new();
+                    let applied = match self.table.try_get(&diff.old_hash) {
+                        Some(old) => Some(diff.apply_to(old.as_bytes(), &mut contents)),
+                        None => match texts.remove(&diff.old_hash) {
+                            Some(old) => Some(old),
+                            None => self.read_text(&resource, &diff.old_hash).await,
+                        }
+                        .map(|old| diff.apply_to(old.as_bytes(), &mut contents)),
+                    };
+                    let contents = match applied {
+                        Some(Ok(())) => String::from_utf8(contents).ok(),
+                        _ => None,
+                    };
+                    let Some(contents) = contents else {
+                        return Err(Error::MissingBase {
+                            resource,
+                            hash: diff.old_hash.clone(),
+                        });
+                    };
                     let hash = QBHash::compute(&contents);
-                    self.table.insert_hash(hash.clone(), contents.clone());
+                    hashes.insert(resource.clone(), hash.clone());
+                    match self.caches(&resource.path) {
+                        true => self.table.insert_hash(hash.clone(), contents.clone()),
+                        false => _ = texts.insert(hash.clone(), contents.clone()),
+                    }
                     Some(QBFSChangeKind::Update {
                         content: contents.into(),
                         hash,
@@ -179,17 +774,22 @@ impl QBFS {
                 QBChangeKind::RenameTo => Some(QBFSChangeKind::Rename {
                     from: source.clone().unwrap(),
                 }),
+                QBChangeKind::XAttr { name, value } => Some(QBFSChangeKind::XAttr {
+                    name: name.clone(),
+                    value: value.clone(),
+                }),
             };
 
             if let Some(kind) = kind {
                 fschanges.push(QBFSChange {
                     resource: resource.clone(),
                     kind,
+                    mtime: change.mtime,
                 });
             }
         }
 
-        fschanges
+        Ok(fschanges)
     }
 
     /// Process changes that were applied to the underlying file system
@@ -201,12 +801,98 @@ impl QBFS {
 
     /// Applies changes to this filesystem.
     ///
+    /// The changes are applied all-or-nothing: if one of them fails, the
+    /// changes applied before are rolled back, both on disk and in the tree.
+    /// Deleted and overwritten resources are moved to a directory of their
+    /// batch in [qbpaths::INTERNAL_TRASH] for this, which is removed once the
+    /// batch has been applied. If rolling back fails, the directory is kept,
+    /// so that no contents are lost, later batches do not touch it.
+    ///
+    /// Returns [Error::OutOfSpace] if the device is full, in which case
+    /// the changes can be applied again later.
+    ///
     /// !!!Use with caution, Safety checks not yet implemented!!!
     pub async fn apply_changes(&mut self, changes: Vec<QBFSChange>) -> Result<()> {
-        for change in changes {
-            self.apply_change(change).await?;
+        let ignore_builder = self.ignore_builder.clone();
+        let trash = qbpaths::INTERNAL_TRASH
+            .clone()
+            .substitue(format!("{:016x}", rand::random::<u64>()))?;
+        let mut undos = Vec::new();
+        let mut result = Ok(());
+        for change in order_changes(changes) {
+            result = self.apply(change, &trash, &mut undos).await;
+            if result.is_err() {
+                break;
+            }
         }
 
+        if result.is_err() && !undos.is_empty() {
+            warn!("fs: rolling back {} change(s)", undos.len());
+            let mut complete = true;
+            for undo in undos.into_iter().rev() {
+                if let Err(err) = self.undo(undo).await {
+                    warn!("fs: could not roll back change: {}", err);
+                    complete = false;
+                }
+            }
+
+            self.ignore_builder = ignore_builder;
+            self.ignore = self.ignore_builder.build(&self.table);
+
+            if !complete {
+                warn!("fs: rollback incomplete, keeping {}", trash);
+                return result;
+            }
+        }
+
+        let fspath = self.wrapper.fspath(&trash);
+        match tokio::fs::remove_dir_all(&fspath).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                warn!("fs: could not empty {}: {}", fspath.display(), err)
+            }
+            _ => {}
+        }
+
+        result
+    }
+
+    /// Undo a change that has been applied.
+    async fn undo(&mut self, undo: QBFSUndo) -> Result<()> {
+        match undo {
+            QBFSUndo::Tree { path, subtree } => self.tree.restore(path, subtree),
+            QBFSUndo::Remove(resource) => {
+                let fspath = self.wrapper.fspath(&resource);
+                match resource.is_dir() {
+                    // changes to the contents have been rolled back already,
+                    // so the directory has to be empty
+                    true => tokio::fs::remove_dir(&fspath).await.with_path(&fspath)?,
+                    false => tokio::fs::remove_file(&fspath).await.with_path(&fspath)?,
+                }
+            }
+            QBFSUndo::Write { path, content } => self.wrapper.write(path, content).await?,
+            QBFSUndo::Rename { from, to } => self.wrapper.rename(from, to).await?,
+            QBFSUndo::XAttr { path, name, value } => {
+                self.wrapper
+                    .set_xattr(path, &name, value.as_deref())
+                    .await?
+            }
+        }
+        Ok(())
+    }
+
+    /// Move the resource at the given path to the trash of the batch.
+    async fn trash(&self, path: &QBPath, trash: &QBPath, undos: &mut Vec<QBFSUndo>) -> Result<()> {
+        let fspath = self.wrapper.fspath(trash);
+        tokio::fs::create_dir_all(&fspath)
+            .await
+            .with_path(&fspath)?;
+
+        let trash = trash.clone().substitue(undos.len().to_string())?;
+        self.wrapper.rename(path, &trash).await?;
+        undos.push(QBFSUndo::Rename {
+            from: trash,
+            to: path.clone(),
+        });
         Ok(())
     }
 
@@ -215,20 +901,108 @@ impl QBFS {
         self.tree.notify_change(change);
         self.ignore_builder.notify_change(change);
         self.ignore.notify_change(change);
+
+        if let QBFSChangeKind::Update { content, .. } = &change.kind {
+            if let Some(QBFileTreeNode::File(file)) = self.tree.get_mut(&change.resource) {
+                file.chunks = self.chunker.hashes(content);
+            }
+        }
     }
 
     /// Applies a single change to this filesystem.
     ///
+    /// The change is rolled back if it fails, see [QBFS::apply_changes].
+    ///
     /// !!!Use with caution, Safety checks not yet implemented!!!
     pub async fn apply_change(&mut self, change: QBFSChange) -> Result<()> {
+        self.apply_changes(vec![change]).await
+    }
+
+    /// Applies a single change, recording how to undo it.
+    async fn apply(
+        &mut self,
+        change: QBFSChange,
+        trash: &QBPath,
+        undos: &mut Vec<QBFSUndo>,
+    ) -> Result<()> {
+        // creating a file where a directory is stored (or vice versa)
+        // must not be skipped silently, as this corrupts the tree
+        if let QBFSChangeKind::Create = change.kind {
+            if let Some(found) = self.wrapper.kind(&change.resource).await {
+                if found != change.resource.kind {
+                    return Err(Error::KindConflict {
+                        resource: change.resource,
+                        found,
+                    });
+                }
+            }
+        }
+
+        if let QBFSChangeKind::Rename { from } = &change.kind {
+            undos.push(QBFSUndo::Tree {
+                path: from.clone(),
+                subtree: self.tree.subtree(from),
+            });
+        }
+        undos.push(QBFSUndo::Tree {
+            path: change.resource.path.clone(),
+            subtree: self.tree.subtree(&change.resource),
+        });
         self.notify_change(&change);
 
         let kind = change.kind;
         let resource = change.resource;
         let contains = self.wrapper.contains(&resource).await;
+        let mtime = match &kind {
+            QBFSChangeKind::Update { .. } | QBFSChangeKind::Patch { .. } => change.mtime,
+            QBFSChangeKind::Create if !resource.is_dir() => change.mtime,
+            _ => None,
+        }
+        .map(|mtime| (resource.clone(), mtime));
         match kind {
             QBFSChangeKind::Update { content, .. } => {
-                self.wrapper.write(&resource, &content).await.unwrap();
+                let undo = match contains {
+                    true => QBFSUndo::Write {
+                        path: resource.path.clone(),
+                        content: self.wrapper.read(&resource).await?,
+                    },
+                    false => QBFSUndo::Remove(resource.clone()),
+                };
+                let content = self.restore_eol(&resource, content);
+                let content = self.apply_transform(&resource, content);
+                self.wrapper.write(&resource, &content).await?;
+                undos.push(undo);
+            }
+            QBFSChangeKind::Patch { base_hash, patch } => {
+                let missing = || Error::MissingBase {
+                    resource: resource.clone(),
+                    hash: base_hash.clone(),
+                };
+
+                let raw = self.wrapper.read(&resource).await?;
+                // text files are patched too, if they are not kept in the file table
+                let old = self.invert_transform(&resource, raw.clone());
+                let (old, _) = self.normalize_eol(old);
+                let old_hash = QBHash::compute(&old);
+                if old_hash == patch.hash {
+                    return Ok(());
+                }
+                if old_hash != base_hash {
+                    return Err(missing());
+                }
+                let content = patch.apply(&old).ok_or_else(missing)?;
+                let chunks = self.chunker.hashes(&content);
+                let content = self.restore_eol(&resource, content);
+                let content = self.apply_transform(&resource, content);
+                self.wrapper.write(&resource, &content).await?;
+                undos.push(QBFSUndo::Write {
+                    path: resource.path.clone(),
+                    content: raw,
+                });
+
+                if let Some(QBFileTreeNode::File(file)) = self.tree.get_mut(&resource) {
+                    file.chunks = chunks;
+                }
             }
             QBFSChangeKind::Delete => {
                 if !contains {
@@ -237,11 +1011,7 @@ impl QBFS {
                     return Ok(());
                 }
 
-                let fspath = self.wrapper.fspath(&resource);
-                match resource.is_dir() {
-                    true => tokio::fs::remove_dir_all(&fspath).await?,
-                    false => tokio::fs::remove_file(&fspath).await?,
-                };
+                self.trash(&resource.path, trash, undos).await?;
             }
             QBFSChangeKind::Create => {
                 if contains {
@@ -253,20 +1023,83 @@ impl QBFS {
                 let fspath = self.wrapper.fspath(&resource);
                 match resource.is_dir() {
                     true => {
-                        tokio::fs::create_dir_all(fspath).await?;
+                        tokio::fs::create_dir_all(&fspath)
+                            .await
+                            .with_path(&fspath)?;
                     }
                     false => {
-                        drop(tokio::fs::File::create(fspath).await?);
+                        drop(tokio::fs::File::create(&fspath).await.with_path(&fspath)?);
                     }
                 };
+                undos.push(QBFSUndo::Remove(resource));
             }
 
             QBFSChangeKind::Copy { from } => {
-                self.wrapper.copy(from, resource).await?;
+                let undo = match contains {
+                    true => QBFSUndo::Write {
+                        path: resource.path.clone(),
+                        content: self.wrapper.read(&resource).await?,
+                    },
+                    false => QBFSUndo::Remove(resource.clone()),
+                };
+                self.wrapper.copy(from, &resource).await?;
+                undos.push(undo);
             }
             QBFSChangeKind::Rename { from } => {
-                // TODO: safe overwrites
-                self.wrapper.rename(from, resource).await?;
+                // a case-insensitive file system might treat renaming to
+                // the same name in another case as a no-op, so go through
+                // a temporary name instead
+                if from != resource.path && from.eq_ignore_case(&resource) {
+                    let name = from.name().unwrap_or_default();
+                    let tmp = from.clone().relative(format!("../.{name}.qbrename"))?;
+                    self.wrapper.rename(&from, &tmp).await?;
+                    undos.push(QBFSUndo::Rename {
+                        from: tmp.clone(),
+                        to: from,
+                    });
+                    self.wrapper.rename(&tmp, &resource).await?;
+                    undos.push(QBFSUndo::Rename {
+                        from: resource.path,
+                        to: tmp,
+                    });
+                    return Ok(());
+                }
+
+                // keep the resource which gets overwritten
+                if self.wrapper.kind(&resource).await.is_some() {
+                    self.trash(&resource.path, trash, undos).await?;
+                }
+                self.wrapper.rename(&from, &resource).await?;
+                undos.push(QBFSUndo::Rename {
+                    from: resource.path,
+                    to: from,
+                });
+            }
+            QBFSChangeKind::XAttr { name, value } => {
+                let old = self.wrapper.xattrs(&resource).await?.remove(&name);
+                self.wrapper
+                    .set_xattr(&resource, &name, value.as_deref())
+                    .await?;
+                undos.push(QBFSUndo::XAttr {
+                    path: resource.path,
+                    name,
+                    value: old,
+                });
+            }
+        }
+
+        // keep the modification time of the device which recorded the change
+        if let Some((resource, mtime)) = mtime {
+            let mtime = match self.wrapper.set_mtime(&resource, mtime).await {
+                Ok(()) => Some(mtime),
+                // the contents have been applied, so this is not worth failing for
+                Err(err) => {
+                    warn!("fs: could not set the mtime of {}: {}", resource, err);
+                    self.wrapper.mtime(&resource).await
+                }
+            };
+            if let Some(QBFileTreeNode::File(file)) = self.tree.get_mut(&resource) {
+                file.mtime = mtime;
             }
         }
 
@@ -275,8 +1108,22 @@ impl QBFS {
 
     /// Compare the entry on the filesystem to the entry stored
     pub async fn diff(&mut self, path: impl AsRef<QBPath>) -> Result<Option<QBFileDiff>> {
-        let contents = self.wrapper.read(&path).await?;
+        // a resource of another kind can not be diffed by content
+        let tracked = self.tree.get(&path).and_then(QBFileTreeNode::kind);
+        let found = self.wrapper.kind(&path).await;
+        if let (Some(from), Some(to)) = (tracked, found) {
+            if from != to {
+                return Ok(Some(QBFileDiff::Kind { from, to }));
+            }
+        }
+
+        let contents = self.wrapper.read_capped(&path, self.max_file_size).await?;
+        let resource = QBResource::new_file(path.as_ref().clone());
+        let contents = self.invert_transform(&resource, contents);
+        let (contents, eol) = self.normalize_eol(contents);
         let hash = QBHash::compute(&contents);
+        let caches = self.caches(path.as_ref());
+        let mtime = self.wrapper.mtime(&path).await;
 
         info!("TREE: {} - {}", path.as_ref(), self.tree);
         let file = self
@@ -284,30 +1131,133 @@ impl QBFS {
             .get_or_insert_mut(&path, TreeFile::default().into())
             .unwrap()
             .file_mut();
+        file.eol = eol;
+        let touched = std::mem::replace(&mut file.mtime, mtime) != mtime;
 
         // no changes, nothing to do
         if file.hash == hash {
-            return Ok(None);
+            return Ok(touched.then_some(QBFileDiff::Touched));
+        }
+
+        // a truncated file does not depend on its previous contents, so it
+        // is sent as an empty update instead of a diff which requires a base
+        if contents.is_empty() {
+            file.hash = hash;
+            file.chunks.clear();
+            return Ok(Some(QBFileDiff::Binary(contents)));
         }
 
-        match simdutf8::basic::from_utf8(&contents) {
-            Ok(new) => {
+        let old_chunks = std::mem::replace(&mut file.chunks, self.chunker.hashes(&contents));
+
+        // validating large contents is expensive, so files which have been
+        // found to be binary are not checked again, as long as their first
+        // chunk stays the same (e.g. a file which is appended to)
+        if file.chunks.first() != old_chunks.first() {
+            file.binary = false;
+        }
+        let text = match caches && !file.binary {
+            true => {
+                #[cfg(test)]
+                UTF8_CHECKS.set(UTF8_CHECKS.get() + 1);
+                let text = simdutf8::basic::from_utf8(&contents).ok();
+                file.binary = text.is_none();
+                text
+            }
+            false => None,
+        };
+
+        match text {
+            Some(new) => {
                 let new = new.to_string();
-                let old = self.table.get(&file.hash).to_string();
+                let old = self.table.try_get(&file.hash).map(str::to_string);
                 self.table.insert_hash(hash.clone(), new.clone());
                 file.hash = hash;
+                // files which used to be binary have no text to diff against
+                let Some(old) = old else {
+                    return Ok(Some(QBFileDiff::Binary(contents)));
+                };
 
-                Ok(Some(QBFileDiff::Text(QBDiff::compute(old, new))))
+                let diff = match self.detect_moves {
+                    true => QBDiff::compute_with_moves(old, new),
+                    false => QBDiff::compute(old, new),
+                };
+                // a rewrite of most of the file yields a diff which is
+                // larger than the contents, so those are sent instead
+                match diff.encoded_size() > contents.len() {
+                    true => Ok(Some(QBFileDiff::Binary(contents))),
+                    false => Ok(Some(QBFileDiff::Text(diff))),
+                }
+            }
+            // the old contents of text files which are not kept
+            // in the file table are unknown, so treat them as binary
+            None => {
+                let base_hash = std::mem::replace(&mut file.hash, hash);
+                // new files have no chunks, so they are sent in full
+                match QBBinaryPatch::compute(&self.chunker, &old_chunks, &contents) {
+                    Some(patch) => Ok(Some(QBFileDiff::BinaryPatch { base_hash, patch })),
+                    None => Ok(Some(QBFileDiff::Binary(contents))),
+                }
             }
-            Err(_) => Ok(Some(QBFileDiff::Binary(contents))),
         }
     }
 
+    /// Compare the extended attributes on the filesystem to the ones stored.
+    ///
+    /// Returns the attributes which have been set (some value)
+    /// or removed (none) and updates the file tree accordingly.
+    pub async fn diff_xattrs(
+        &mut self,
+        path: impl AsRef<QBPath>,
+    ) -> Result<Vec<(String, Option<Vec<u8>>)>> {
+        if !self.tree.get(&path).is_some_and(QBFileTreeNode::is_file) {
+            return Ok(Vec::new());
+        }
+
+        let found = self.wrapper.xattrs(&path).await?;
+        let file = self.tree.get_mut(&path).unwrap().file_mut();
+
+        let mut diff = file
+            .xattrs
+            .keys()
+            .filter(|name| !found.contains_key(*name))
+            .map(|name| (name.clone(), None))
+            .collect::<Vec<_>>();
+        diff.extend(
+            found
+                .iter()
+                .filter(|(name, value)| file.xattrs.get(*name) != Some(value))
+                .map(|(name, value)| (name.clone(), Some(value.clone()))),
+        );
+        file.xattrs = found;
+
+        Ok(diff)
+    }
+
     /// Save changelog to file system.
-    pub async fn save_changelog(&self) -> Result<()> {
-        self.wrapper
-            .save(qbpaths::INTERNAL_CHANGEMAP.as_ref(), &self.changemap)
-            .await
+    ///
+    /// Only the changes since the last save are appended to the journal,
+    /// which gets compacted into a snapshot once it grows too large.
+    pub async fn save_changelog(&mut self) -> Result<()> {
+        if self.journal.should_compact() {
+            let snapshot = bitcode::encode(&self.changemap);
+            self.wrapper
+                .write(INTERNAL_CHANGEMAP.as_ref(), &snapshot)
+                .await?;
+            self.wrapper
+                .write(INTERNAL_CHANGEMAP_JOURNAL.as_ref(), [])
+                .await?;
+            self.journal.compacted(&self.changemap, snapshot.len());
+            return Ok(());
+        }
+
+        let delta = self.journal.delta(&self.changemap);
+        if !delta.is_empty() {
+            self.wrapper
+                .append(INTERNAL_CHANGEMAP_JOURNAL.as_ref(), &delta)
+                .await?;
+        }
+        self.journal.appended(&self.changemap, delta.len());
+        Ok(())
     }
 
     /// Save devices to file system.
@@ -339,7 +1289,7 @@ impl QBFS {
     }
 
     /// Save state to file system.
-    pub async fn save(&self) -> Result<()> {
+    pub async fn save(&mut self) -> Result<()> {
         self.save_changelog().await?;
         self.save_devices().await?;
         self.save_tree().await?;
@@ -347,3 +1297,703 @@ impl QBFS {
         self.save_table().await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use futures::future::BoxFuture;
+    use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+    use super::*;
+    use crate::{
+        fs::wrapper::{QBFSBackend, QBFSLocalBackend},
+        time::QBTimeStampRecorder,
+    };
+
+    /// A storage backend which fails the operations it is given errors for.
+    #[derive(Default)]
+    struct Faulty {
+        write: Option<io::ErrorKind>,
+        rename: Option<io::ErrorKind>,
+    }
+
+    impl QBFSBackend for Faulty {
+        fn write<'a>(
+            &'a self,
+            path: &'a Path,
+            contents: &'a [u8],
+        ) -> BoxFuture<'a, io::Result<()>> {
+            match self.write {
+                Some(kind) => Box::pin(async move { Err(kind.into()) }),
+                None => QBFSLocalBackend.write(path, contents),
+            }
+        }
+
+        fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+            match self.rename {
+                Some(kind) => Box::pin(async move { Err(kind.into()) }),
+                None => QBFSLocalBackend.rename(from, to),
+            }
+        }
+    }
+
+    /// A storage backend which, like some case-insensitive file systems,
+    /// ignores renames to the same name in another case.
+    struct CaseInsensitive;
+
+    impl QBFSBackend for CaseInsensitive {
+        fn write<'a>(
+            &'a self,
+            path: &'a Path,
+            contents: &'a [u8],
+        ) -> BoxFuture<'a, io::Result<()>> {
+            QBFSLocalBackend.write(path, contents)
+        }
+
+        fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+            let lowercase = |path: &Path| path.to_string_lossy().to_lowercase();
+            match lowercase(from) == lowercase(to) {
+                true => Box::pin(async { Ok(()) }),
+                false => QBFSLocalBackend.rename(from, to),
+            }
+        }
+    }
+
+    fn change(resource: QBResource, kind: QBFSChangeKind) -> QBFSChange {
+        QBFSChange {
+            resource,
+            kind,
+            mtime: None,
+        }
+    }
+
+    fn file(path: &str) -> QBResource {
+        QBPath::try_from(path).unwrap().file()
+    }
+
+    fn dir(path: &str) -> QBResource {
+        QBPath::try_from(path).unwrap().dir()
+    }
+
+    #[tokio::test]
+    async fn create_reports_kind_conflict() {
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = QBFS::init(root.path()).await;
+        fs.apply_changes(vec![
+            change(dir("/dir"), QBFSChangeKind::Create),
+            change(file("/file"), QBFSChangeKind::Create),
+        ])
+        .await
+        .unwrap();
+
+        for (resource, kind) in [
+            (file("/dir"), QBResourceKind::Dir),
+            (dir("/file"), QBResourceKind::File),
+        ] {
+            let err = fs
+                .apply_change(change(resource.clone(), QBFSChangeKind::Create))
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(&err, Error::KindConflict { resource: r, found } if r == &resource && found == &kind),
+                "{err:?}"
+            );
+        }
+        // the resources which occupy the paths are kept
+        assert_eq!(
+            fs.wrapper.kind(&dir("/dir")).await,
+            Some(QBResourceKind::Dir)
+        );
+        assert_eq!(
+            fs.wrapper.kind(&file("/file")).await,
+            Some(QBResourceKind::File)
+        );
+        assert!(fs.tree.get_resource(&dir("/dir")).is_some());
+        assert!(fs.tree.get_resource(&file("/file")).is_some());
+    }
+
+    #[tokio::test]
+    async fn text_diff_without_base() {
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = QBFS::init(root.path()).await;
+
+        // the base is neither stored in the file table nor on disk
+        let diff = QBDiff::compute("old\n".to_owned(), "new\n".to_owned());
+        let old_hash = diff.old_hash.clone();
+        let change = QBChange::new(Default::default(), QBChangeKind::UpdateText(diff));
+        let err = fs
+            .to_fschanges(vec![(file("/a"), change)])
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, Error::MissingBase { resource, hash } if resource == &file("/a") && hash == &old_hash),
+            "{err:?}"
+        );
+        assert!(!fs.wrapper.contains(&file("/a")).await);
+    }
+
+    #[tokio::test]
+    async fn apply_shuffled_batch() {
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = QBFS::init(root.path()).await;
+
+        // the deepest changes come first
+        let content = b"contents".to_vec();
+        let hash = QBHash::compute(&content);
+        fs.apply_changes(vec![
+            change(file("/a/b/file"), QBFSChangeKind::Create),
+            change(file("/a/b/file"), QBFSChangeKind::Update { content, hash }),
+            change(dir("/a/b"), QBFSChangeKind::Create),
+            change(dir("/a"), QBFSChangeKind::Create),
+        ])
+        .await
+        .unwrap();
+        assert_eq!(
+            fs.wrapper.read(file("/a/b/file")).await.unwrap(),
+            b"contents"
+        );
+        assert!(fs.tree.get_resource(&file("/a/b/file")).is_some());
+
+        // the directories are deleted after their contents
+        let deletes = vec![
+            change(dir("/a"), QBFSChangeKind::Delete),
+            change(dir("/a/b"), QBFSChangeKind::Delete),
+            change(file("/a/b/file"), QBFSChangeKind::Delete),
+        ];
+        let ordered = order_changes(deletes.clone());
+        let ordered = ordered.iter().map(|change| &change.resource);
+        assert_eq!(
+            ordered.collect::<Vec<_>>(),
+            [&file("/a/b/file"), &dir("/a/b"), &dir("/a")]
+        );
+        fs.apply_changes(deletes).await.unwrap();
+        assert!(!root.path().join("a").exists());
+        assert!(fs.tree.get_resource(&dir("/a")).is_none());
+    }
+
+    #[tokio::test]
+    async fn normalize_line_endings() {
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = QBFS::init(root.path()).await.with_normalize_eol(true);
+        let path = root.path().join("a");
+        std::fs::write(&path, "a\nb\n").unwrap();
+        fs.diff(file("/a")).await.unwrap();
+
+        // an editor converts the line endings, but keeps the content
+        std::fs::write(&path, "a\r\nb\r\n").unwrap();
+        let diff = fs.diff(file("/a")).await.unwrap();
+        assert!(matches!(diff, None | Some(QBFileDiff::Touched)), "{diff:?}");
+
+        // updates keep the style of the file
+        let diff = QBDiff::compute("a\nb\n".to_owned(), "a\nB\n".to_owned());
+        let change = QBChange::new(Default::default(), QBChangeKind::UpdateText(diff));
+        let changes = fs.to_fschanges(vec![(file("/a"), change)]).await.unwrap();
+        fs.apply_changes(changes).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a\r\nB\r\n");
+
+        // without normalization, the line endings change the content
+        fs.normalize_eol = false;
+        let diff = fs.diff(file("/a")).await.unwrap();
+        assert!(
+            matches!(diff, Some(QBFileDiff::Text(_) | QBFileDiff::Binary(_))),
+            "{diff:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn rebuild_missing_tree() {
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = QBFS::init(root.path()).await;
+        let text = b"text\n".to_vec();
+        let binary = vec![0xff, 0x00, 0xfe];
+        let update = |resource: QBResource, content: &Vec<u8>| {
+            let hash = QBHash::compute(content);
+            let content = content.clone();
+            change(resource, QBFSChangeKind::Update { content, hash })
+        };
+        fs.apply_changes(vec![
+            change(dir("/dir"), QBFSChangeKind::Create),
+            change(dir("/dir/sub"), QBFSChangeKind::Create),
+            change(file("/dir/sub/text"), QBFSChangeKind::Create),
+            update(file("/dir/sub/text"), &text),
+            change(file("/binary"), QBFSChangeKind::Create),
+            update(file("/binary"), &binary),
+        ])
+        .await
+        .unwrap();
+        fs.save().await.unwrap();
+
+        let tree = fs.wrapper.fspath(INTERNAL_FILETREE.as_ref());
+        std::fs::remove_file(tree).unwrap();
+        let fs = QBFS::init(root.path()).await;
+
+        assert!(fs.tree.get_resource(&dir("/dir")).is_some());
+        assert!(fs.tree.get_resource(&dir("/dir/sub")).is_some());
+        for (resource, content) in [(file("/dir/sub/text"), &text), (file("/binary"), &binary)] {
+            let node = fs.tree.get_resource(&resource).unwrap();
+            assert_eq!(node.file().hash, QBHash::compute(content), "{resource}");
+        }
+        // the contents of text files can be diffed again
+        assert_eq!(fs.table.try_get(&QBHash::compute(&text)), Some("text\n"));
+    }
+
+    #[tokio::test]
+    async fn case_only_rename() {
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = QBFS::init(root.path()).await;
+        fs.wrapper = fs.wrapper.clone().with_backend(CaseInsensitive);
+        fs.apply_change(change(file("/File.txt"), QBFSChangeKind::Create))
+            .await
+            .unwrap();
+
+        let from = QBPath::try_from("/File.txt").unwrap();
+        fs.apply_change(change(file("/file.txt"), QBFSChangeKind::Rename { from }))
+            .await
+            .unwrap();
+        let names = std::fs::read_dir(root.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .filter(|name| name != ".qb")
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["file.txt"]);
+        assert!(fs.tree.get_resource(&file("/file.txt")).is_some());
+        assert!(fs.tree.get_resource(&file("/File.txt")).is_none());
+
+        // the old name is deleted before the new one is created
+        let ordered = order_changes(vec![
+            change(file("/FILE.txt"), QBFSChangeKind::Create),
+            change(file("/file.txt"), QBFSChangeKind::Delete),
+        ]);
+        assert!(matches!(ordered[0].kind, QBFSChangeKind::Delete));
+    }
+
+    #[tokio::test]
+    async fn patch_large_binary() {
+        let mut old = vec![0u8; 1 << 20];
+        StdRng::seed_from_u64(0).fill_bytes(&mut old);
+        let mut new = old.clone();
+        new[1 << 19..(1 << 19) + 16].fill(0);
+
+        // the remote has no base yet, so the contents are sent in full
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = QBFS::init(root.path()).await;
+        std::fs::write(root.path().join("big"), &old).unwrap();
+        let diff = fs.diff(file("/big")).await.unwrap();
+        assert!(matches!(&diff, Some(QBFileDiff::Binary(content)) if content == &old));
+
+        std::fs::write(root.path().join("big"), &new).unwrap();
+        let diff = fs.diff(file("/big")).await.unwrap();
+        let Some(QBFileDiff::BinaryPatch { base_hash, patch }) = diff else {
+            panic!("expected a patch: {diff:?}");
+        };
+        assert_eq!(base_hash, QBHash::compute(&old));
+        assert!(bitcode::encode(&patch).len() < new.len() / 16);
+
+        // apply the patch to a copy of the old contents
+        let remote_root = tempfile::tempdir().unwrap();
+        let mut remote = QBFS::init(remote_root.path()).await;
+        let hash = base_hash.clone();
+        remote
+            .apply_changes(vec![
+                change(file("/big"), QBFSChangeKind::Create),
+                change(file("/big"), QBFSChangeKind::Update { content: old, hash }),
+            ])
+            .await
+            .unwrap();
+        let kind = QBChangeKind::BinaryDiff { base_hash, patch };
+        let change = QBChange::new(Default::default(), kind);
+        let changes = remote
+            .to_fschanges(vec![(file("/big"), change)])
+            .await
+            .unwrap();
+        remote.apply_changes(changes).await.unwrap();
+
+        let patched = std::fs::read(remote_root.path().join("big")).unwrap();
+        assert_eq!(QBHash::compute(&patched), QBHash::compute(&new));
+        let node = remote.tree.get_resource(&file("/big")).unwrap();
+        assert_eq!(node.file().hash, QBHash::compute(&new));
+    }
+
+    #[tokio::test]
+    async fn disabled_file_table_keeps_no_contents() {
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = QBFS::init(root.path()).await.with_file_table(false);
+        let (old, new) = ("a\nb\n", "a\nB\n");
+
+        // the old contents are unknown, so local changes are sent in full
+        std::fs::write(root.path().join("a"), old).unwrap();
+        let diff = fs.diff(file("/a")).await.unwrap();
+        assert!(matches!(diff, Some(QBFileDiff::Binary(_))), "{diff:?}");
+
+        // remote diffs are applied to the contents on disk
+        let diff = QBDiff::compute(old.to_owned(), new.to_owned());
+        let change = QBChange::new(Default::default(), QBChangeKind::UpdateText(diff));
+        let changes = fs.to_fschanges(vec![(file("/a"), change)]).await.unwrap();
+        fs.apply_changes(changes).await.unwrap();
+        assert_eq!(std::fs::read_to_string(root.path().join("a")).unwrap(), new);
+
+        for content in [old, new] {
+            assert!(fs.table.try_get(&QBHash::compute(content)).is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn touch_without_changes() {
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = QBFS::init(root.path()).await;
+        let path = root.path().join("a");
+        std::fs::write(&path, "a\nb\n").unwrap();
+        fs.diff(file("/a")).await.unwrap();
+
+        let mtime = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(mtime))
+            .unwrap();
+        let diff = fs.diff(file("/a")).await.unwrap();
+        assert!(matches!(diff, Some(QBFileDiff::Touched)), "{diff:?}");
+
+        // nothing changed since
+        assert!(fs.diff(file("/a")).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn truncate_file() {
+        let (local, remote) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let mut fs = QBFS::init(local.path()).await;
+        let mut other = QBFS::init(remote.path()).await;
+        let mut contents = vec![0; 1 << 16];
+        StdRng::seed_from_u64(0).fill_bytes(&mut contents);
+        // the truncation does not depend on the contents of the other side
+        std::fs::write(local.path().join("a"), contents).unwrap();
+        std::fs::write(remote.path().join("a"), "c\n").unwrap();
+        fs.diff(file("/a")).await.unwrap();
+        other.diff(file("/a")).await.unwrap();
+
+        std::fs::write(local.path().join("a"), "").unwrap();
+        let Some(QBFileDiff::Binary(contents)) = fs.diff(file("/a")).await.unwrap() else {
+            panic!("expected the file to be sent in full");
+        };
+        assert!(contents.is_empty());
+
+        let change = QBChange::new(Default::default(), QBChangeKind::UpdateBinary(contents));
+        let changes = other
+            .to_fschanges(vec![(file("/a"), change)])
+            .await
+            .unwrap();
+        other.apply_changes(changes).await.unwrap();
+        assert_eq!(std::fs::read(remote.path().join("a")).unwrap(), b"");
+        assert!(other.tree.get_resource(&file("/a")).is_some());
+    }
+
+    #[tokio::test]
+    async fn skip_validating_binary_files() {
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = QBFS::init(root.path()).await;
+        let path = root.path().join("a");
+        let mut contents = vec![0; 1 << 18];
+        StdRng::seed_from_u64(0).fill_bytes(&mut contents);
+        let checks = UTF8_CHECKS.get();
+
+        std::fs::write(&path, &contents).unwrap();
+        fs.diff(file("/a")).await.unwrap();
+        assert!(fs.tree.get_resource(&file("/a")).unwrap().file().binary);
+        assert_eq!(UTF8_CHECKS.get(), checks + 1);
+
+        // appending keeps the first chunk
+        contents.extend_from_slice(b"appended");
+        std::fs::write(&path, &contents).unwrap();
+        fs.diff(file("/a")).await.unwrap();
+        assert_eq!(UTF8_CHECKS.get(), checks + 1);
+
+        // the file might have been replaced by a text file
+        std::fs::write(&path, "a\nb\n").unwrap();
+        fs.diff(file("/a")).await.unwrap();
+        assert_eq!(UTF8_CHECKS.get(), checks + 2);
+        assert!(!fs.tree.get_resource(&file("/a")).unwrap().file().binary);
+    }
+
+    #[tokio::test]
+    async fn transform_contents_on_disk() {
+        fn xor(_: &QBResource, contents: &[u8]) -> Vec<u8> {
+            contents.iter().map(|byte| byte ^ 0x55).collect()
+        }
+
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = QBFS::init(root.path()).await.with_transform(xor, xor);
+        let path = root.path().join("a");
+        let old = "line\n".repeat(50);
+        let new = old.replacen("line", "LINE", 1);
+
+        std::fs::write(&path, xor(&file("/a"), old.as_bytes())).unwrap();
+        fs.diff(file("/a")).await.unwrap();
+        std::fs::write(&path, xor(&file("/a"), new.as_bytes())).unwrap();
+        let Some(QBFileDiff::Text(diff)) = fs.diff(file("/a")).await.unwrap() else {
+            panic!("expected a text diff");
+        };
+        assert_eq!(diff.apply(old.clone()).unwrap(), new);
+
+        // changes from other devices are written transformed
+        let diff = QBDiff::compute(new.clone(), old.clone());
+        let change = QBChange::new(Default::default(), QBChangeKind::UpdateText(diff));
+        let changes = fs.to_fschanges(vec![(file("/a"), change)]).await.unwrap();
+        fs.apply_changes(changes).await.unwrap();
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            xor(&file("/a"), old.as_bytes())
+        );
+        let diff = fs.diff(file("/a")).await.unwrap();
+        assert!(matches!(diff, None | Some(QBFileDiff::Touched)), "{diff:?}");
+    }
+
+    #[tokio::test]
+    async fn merge_with_fixed_device_ids() {
+        let roots = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+        let mut changemaps = Vec::new();
+        for (id, root) in (1..).zip(&roots) {
+            let fs = QBFS::init_with_device_id(root.path(), QBDeviceId(id)).await;
+            assert_eq!(fs.devices.host_id, QBDeviceId(id));
+
+            // both devices update the same file concurrently
+            let mut recorder = QBTimeStampRecorder::from(fs.devices.host_id.clone());
+            let mut changemap = fs.changemap;
+            for contents in [vec![id as u8], vec![id as u8; 2]] {
+                let kind = QBChangeKind::UpdateBinary(contents);
+                changemap.push((file("/a"), QBChange::new(recorder.record(), kind)));
+            }
+            changemaps.push(changemap);
+        }
+
+        let entries = |changemap: &QBChangeMap| {
+            changemap
+                .iter()
+                .map(|(resource, change)| {
+                    (
+                        resource.clone(),
+                        change.timestamp.clone(),
+                        format!("{:?}", change.kind),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut ab = changemaps[0].clone();
+        ab.merge(changemaps[1].clone()).unwrap();
+        let mut ba = changemaps[1].clone();
+        ba.merge(changemaps[0].clone()).unwrap();
+        assert_eq!(entries(&ab), entries(&ba));
+        assert_eq!(ab.head(), ba.head());
+        assert_eq!(ab.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn send_rewrites_in_full() {
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = QBFS::init(root.path()).await;
+        let path = root.path().join("a");
+        let lines = |prefix| {
+            (0..50)
+                .map(|i| format!("{prefix} {i}\n"))
+                .collect::<String>()
+        };
+
+        std::fs::write(&path, lines("line")).unwrap();
+        fs.diff(file("/a")).await.unwrap();
+        let rewritten = lines("edited line");
+        std::fs::write(&path, &rewritten).unwrap();
+        let Some(QBFileDiff::Binary(contents)) = fs.diff(file("/a")).await.unwrap() else {
+            panic!("expected the file to be sent in full");
+        };
+        assert_eq!(contents, rewritten.as_bytes());
+
+        // small edits are still sent as diffs
+        std::fs::write(&path, rewritten.replacen("0", "zero", 1)).unwrap();
+        let diff = fs.diff(file("/a")).await.unwrap();
+        assert!(matches!(diff, Some(QBFileDiff::Text(_))), "{diff:?}");
+    }
+
+    #[tokio::test]
+    async fn rollback_restores_deleted_directory() {
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = QBFS::init(root.path()).await;
+
+        let content = b"contents".to_vec();
+        let hash = QBHash::compute(&content);
+        fs.apply_changes(vec![
+            change(dir("/dir"), QBFSChangeKind::Create),
+            change(file("/dir/file"), QBFSChangeKind::Create),
+            change(file("/dir/file"), QBFSChangeKind::Update { content, hash }),
+            change(dir("/taken"), QBFSChangeKind::Create),
+        ])
+        .await
+        .unwrap();
+
+        // creating a file where a directory is stored fails
+        let err = fs
+            .apply_changes(vec![
+                change(file("/new"), QBFSChangeKind::Create),
+                change(dir("/dir"), QBFSChangeKind::Delete),
+                change(file("/taken"), QBFSChangeKind::Create),
+            ])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::KindConflict { .. }));
+
+        assert!(!fs.wrapper.contains(&file("/new")).await);
+        assert!(fs.tree.get(file("/new")).is_none());
+
+        assert_eq!(
+            fs.wrapper.read(file("/dir/file")).await.unwrap(),
+            b"contents"
+        );
+        assert!(fs.tree.get_resource(&dir("/dir")).is_some());
+        let node = fs.tree.get_resource(&file("/dir/file")).unwrap();
+        assert_eq!(node.file().hash, QBHash::compute(b"contents"));
+    }
+
+    #[test]
+    fn storage_full_is_out_of_space() {
+        let err = Error::io(io::ErrorKind::StorageFull.into(), None);
+        assert!(matches!(err, Error::OutOfSpace { path: None }));
+        // ENOSPC
+        #[cfg(target_os = "linux")]
+        assert!(matches!(
+            Error::from(io::Error::from_raw_os_error(28)),
+            Error::OutOfSpace { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn out_of_space_is_rolled_back() {
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = QBFS::init(root.path()).await;
+        let content = b"old".to_vec();
+        let hash = QBHash::compute(&content);
+        fs.apply_changes(vec![
+            change(file("/file"), QBFSChangeKind::Create),
+            change(file("/file"), QBFSChangeKind::Update { content, hash }),
+        ])
+        .await
+        .unwrap();
+
+        fs.wrapper = fs.wrapper.clone().with_backend(Faulty {
+            write: Some(io::ErrorKind::StorageFull),
+            ..Default::default()
+        });
+        let content = b"new".to_vec();
+        let hash = QBHash::compute(&content);
+        let err = fs
+            .apply_changes(vec![
+                change(file("/new"), QBFSChangeKind::Create),
+                change(file("/file"), QBFSChangeKind::Update { content, hash }),
+            ])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::OutOfSpace { .. }), "{err}");
+
+        assert!(!root.path().join("new").exists());
+        assert!(fs.tree.get(file("/new")).is_none());
+        assert_eq!(std::fs::read(root.path().join("file")).unwrap(), b"old");
+        let node = fs.tree.get_resource(&file("/file")).unwrap();
+        assert_eq!(node.file().hash, QBHash::compute(b"old"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn rename_across_file_systems() {
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = QBFS::init(root.path()).await;
+        let content = b"contents".to_vec();
+        let hash = QBHash::compute(&content);
+        fs.apply_changes(vec![
+            change(dir("/dir"), QBFSChangeKind::Create),
+            change(file("/dir/file"), QBFSChangeKind::Create),
+            change(file("/dir/file"), QBFSChangeKind::Update { content, hash }),
+        ])
+        .await
+        .unwrap();
+        std::os::unix::fs::symlink("file", root.path().join("dir/link")).unwrap();
+        std::os::unix::fs::symlink("missing", root.path().join("dir/dangling")).unwrap();
+        std::os::unix::fs::symlink("missing", root.path().join("dangling")).unwrap();
+
+        fs.wrapper = fs.wrapper.clone().with_backend(Faulty {
+            rename: Some(io::ErrorKind::CrossesDevices),
+            ..Default::default()
+        });
+        let rename = |from: &str, to: QBResource| {
+            let from = QBPath::try_from(from).unwrap();
+            change(to, QBFSChangeKind::Rename { from })
+        };
+        fs.apply_changes(vec![rename("/dir", dir("/moved"))])
+            .await
+            .unwrap();
+        let dangling = QBPath::try_from("/dangling").unwrap();
+        let moved = QBPath::try_from("/dangling2").unwrap();
+        fs.wrapper.rename(&dangling, &moved).await.unwrap();
+        assert!(fs.wrapper.contains(&moved.symlink()).await);
+
+        let root = root.path();
+        assert!(!root.join("dir").exists());
+        assert_eq!(std::fs::read(root.join("moved/file")).unwrap(), b"contents");
+        for (link, target) in [
+            ("moved/link", "file"),
+            ("moved/dangling", "missing"),
+            ("dangling2", "missing"),
+        ] {
+            assert_eq!(
+                std::fs::read_link(root.join(link)).unwrap(),
+                Path::new(target)
+            );
+        }
+        assert!(std::fs::symlink_metadata(root.join("dangling")).is_err());
+        assert!(fs.tree.get_resource(&file("/moved/file")).is_some());
+    }
+
+    #[tokio::test]
+    async fn keep_recorded_mtime() {
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = QBFS::init(root.path()).await;
+
+        let mtime = 1_600_000_000_000_000_000;
+        let content = b"contents".to_vec();
+        let hash = QBHash::compute(&content);
+        fs.apply_changes(vec![
+            change(file("/file"), QBFSChangeKind::Create),
+            QBFSChange {
+                resource: file("/file"),
+                kind: QBFSChangeKind::Update { content, hash },
+                mtime: Some(mtime),
+            },
+        ])
+        .await
+        .unwrap();
+
+        assert_eq!(fs.wrapper.mtime(file("/file")).await, Some(mtime));
+        let node = fs.tree.get_resource(&file("/file")).unwrap();
+        assert_eq!(node.file().mtime, Some(mtime));
+    }
+
+    #[tokio::test]
+    async fn keep_trash_of_incomplete_rollback() {
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = QBFS::init(root.path()).await;
+        fs.apply_changes(vec![change(file("/file"), QBFSChangeKind::Create)])
+            .await
+            .unwrap();
+
+        // the trash an earlier batch could not roll back
+        let kept = root.path().join(".qb/trash/kept");
+        std::fs::create_dir_all(&kept).unwrap();
+        std::fs::write(kept.join("0"), b"kept").unwrap();
+
+        fs.apply_changes(vec![change(file("/file"), QBFSChangeKind::Delete)])
+            .await
+            .unwrap();
+
+        assert!(!root.path().join("file").exists());
+        assert_eq!(std::fs::read(kept.join("0")).unwrap(), b"kept");
+        let trash = std::fs::read_dir(root.path().join(".qb/trash")).unwrap();
+        assert_eq!(trash.count(), 1);
+    }
+}
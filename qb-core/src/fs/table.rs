@@ -34,6 +34,11 @@ impl QBFileTable {
         }
     }
 
+    /// return the contents for this hash, if stored in this table
+    pub fn try_get<'a>(&'a self, hash: &QBHash) -> Option<&'a str> {
+        self.contents.get(hash).map(String::as_str)
+    }
+
     /// remove & return the contents for this hash
     pub fn remove(&mut self, hash: &QBHash) -> String {
         self.contents.remove(hash).unwrap_or_default()
@@ -4,39 +4,37 @@
 
 use core::{fmt, panic};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     ops::{Index, IndexMut},
+    pin::pin,
 };
 
 use bitcode::{Decode, Encode};
+use futures::StreamExt;
 use itertools::Itertools;
-use tracing::{info, warn};
+use tracing::warn;
 
 use crate::{
     change::QBChange,
+    diff::QBLineEnding,
     hash::QBHash,
-    path::{qbpaths, QBPath, QBResource},
+    path::{qbpaths, QBPath, QBResource, QBResourceKind},
 };
 
-use super::{wrapper::QBFSWrapper, QBFSChange, QBFSChangeKind};
+use super::{wrapper::QBFSWrapper, QBFSChange, QBFSChangeKind, Result};
 
 /// a node stored in a [QBFileTree]
-#[derive(Encode, Decode, Clone, Debug)]
+#[derive(Encode, Decode, Clone, Debug, Default)]
 pub enum QBFileTreeNode {
     /// a directory
     Dir(TreeDir),
     /// a file
     File(TreeFile),
     /// unoccupied
+    #[default]
     None,
 }
 
-impl Default for QBFileTreeNode {
-    fn default() -> Self {
-        Self::None
-    }
-}
-
 impl QBFileTreeNode {
     /// check whether this is a file
     #[inline]
@@ -56,6 +54,16 @@ impl QBFileTreeNode {
         matches!(self, QBFileTreeNode::None)
     }
 
+    /// get the kind of resource stored in this node
+    #[inline]
+    pub fn kind(&self) -> Option<QBResourceKind> {
+        match self {
+            QBFileTreeNode::Dir(..) => Some(QBResourceKind::Dir),
+            QBFileTreeNode::File(..) => Some(QBResourceKind::File),
+            QBFileTreeNode::None => None,
+        }
+    }
+
     /// unwrap mutable file
     #[inline]
     pub fn file_mut(&mut self) -> &mut TreeFile {
@@ -127,12 +135,31 @@ impl From<TreeDir> for QBFileTreeNode {
 pub struct TreeFile {
     /// the hash of this file
     pub hash: QBHash,
+    /// the hashes of the content-defined chunks of this file
+    pub chunks: Vec<QBHash>,
+    /// the extended attributes of this file, only
+    /// captured with the `xattr` feature enabled
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+    /// the line endings used by this file on disk, only
+    /// captured with line ending normalization enabled
+    pub eol: QBLineEnding,
+    /// the modification time of this file in nanoseconds
+    /// since the unix epoch, if known
+    pub mtime: Option<u64>,
+    /// whether the contents have been found not to be valid utf8,
+    /// see [crate::fs::QBFS::diff]
+    pub binary: bool,
 }
 
 impl Default for TreeFile {
     fn default() -> Self {
         Self {
             hash: QBHash::compute(vec![]),
+            chunks: Vec::new(),
+            xattrs: BTreeMap::new(),
+            eol: QBLineEnding::default(),
+            mtime: None,
+            binary: false,
         }
     }
 }
@@ -156,12 +183,15 @@ struct Compare {
 #[derive(Encode, Decode)]
 pub struct QBFileTree {
     pub(crate) arena: Vec<QBFileTreeNode>,
+    /// the slots of the arena which have been freed and can be reused
+    free: Vec<usize>,
 }
 
 impl Default for QBFileTree {
     fn default() -> Self {
         Self {
             arena: vec![QBFileTreeNode::Dir(Default::default())],
+            free: Vec::new(),
         }
     }
 }
@@ -208,6 +238,11 @@ impl fmt::Display for QBFileTree {
 }
 
 impl QBFileTree {
+    /// Returns whether this tree contains no entries besides the root.
+    pub fn is_empty(&self) -> bool {
+        self.arena[0].dir().contents.is_empty()
+    }
+
     /// Process changes that were applied to the underlying file system
     pub fn notify_change(&mut self, change: &QBFSChange) {
         let kind = &change.kind;
@@ -216,6 +251,9 @@ impl QBFileTree {
             QBFSChangeKind::Update { hash, .. } => {
                 self.update(resource, hash.clone());
             }
+            QBFSChangeKind::Patch { patch, .. } => {
+                self.update(resource, patch.hash.clone());
+            }
             QBFSChangeKind::Delete => {
                 self.delete(resource);
             }
@@ -223,13 +261,23 @@ impl QBFileTree {
                 self.create(resource);
             }
             QBFSChangeKind::Rename { from } => {
-                let entry = self.remove(from).unwrap();
+                let mut entry = self.remove(from).unwrap();
+                Self::moved(&mut entry, from, &resource.path);
                 self.insert(resource, entry);
             }
             QBFSChangeKind::Copy { from } => {
-                let entry = self.get(from).unwrap().clone();
+                let mut entry = self.clone_subtree(self.index(from).unwrap());
+                Self::moved(&mut entry, from, &resource.path);
                 self.insert(resource, entry);
             }
+            QBFSChangeKind::XAttr { name, value } => {
+                if let Some(QBFileTreeNode::File(file)) = self.get_mut(resource) {
+                    match value {
+                        Some(value) => _ = file.xattrs.insert(name.clone(), value.clone()),
+                        None => _ = file.xattrs.remove(name),
+                    }
+                }
+            }
         }
     }
 
@@ -264,32 +312,31 @@ impl QBFileTree {
             .collect()
     }
 
-    async fn get_fs(&self, fswrapper: &QBFSWrapper, dir: impl AsRef<QBPath>) -> Vec<Compare> {
+    async fn get_fs(
+        &self,
+        fswrapper: &QBFSWrapper,
+        dir: impl AsRef<QBPath>,
+    ) -> Result<Vec<Compare>> {
         let mut entries = Vec::new();
-        let resources = match fswrapper.read_dir(dir).await {
-            Ok(resources) => resources,
-            Err(err) => {
-                warn!("{}", err);
-                return Vec::new();
-            }
-        };
+        let mut resources = pin!(fswrapper.read_dir_stream(dir));
+
+        while let Some(resource) = resources.next().await {
+            let resource = resource?;
 
-        for resource in resources {
             let mut hash = Default::default();
             if resource.kind.is_file() {
-                let contents = fswrapper.read(&resource).await.unwrap();
-                QBHash::compute_mut(&mut hash, contents);
+                hash = fswrapper.hash(&resource).await?;
             }
 
             entries.push(Compare { hash, resource });
         }
 
-        entries
+        Ok(entries)
     }
 
     /// TODO: ignores
     /// TODO: implement
-    pub async fn walk(&self, fswrapper: &QBFSWrapper) -> Vec<QBChange> {
+    pub async fn walk(&self, fswrapper: &QBFSWrapper) -> Result<Vec<QBChange>> {
         let mut stack: Vec<QBPath> = vec![qbpaths::ROOT.clone()];
         let mut changes = HashSet::new();
 
@@ -298,7 +345,7 @@ impl QBFileTree {
                 continue;
             }
 
-            let compare_fs = self.get_fs(fswrapper, &curr).await;
+            let compare_fs = self.get_fs(fswrapper, &curr).await?;
             let mut compare_tree = self.get_tree(&curr);
 
             stack.extend(
@@ -337,7 +384,7 @@ impl QBFileTree {
 
         println!("DIFF: {:#?}", changes);
 
-        Vec::new()
+        Ok(Vec::new())
     }
 
     /// Get an entry of this tree
@@ -354,6 +401,22 @@ impl QBFileTree {
         Some(&mut self.arena[idx])
     }
 
+    /// Get the entry of this resource, returns none if
+    /// the entry at its path is of a different kind.
+    #[inline]
+    pub fn get_resource(&self, resource: &QBResource) -> Option<&QBFileTreeNode> {
+        let idx = self.index_resource(resource)?;
+        Some(&self.arena[idx])
+    }
+
+    /// Get the mutable entry of this resource, returns none
+    /// if the entry at its path is of a different kind.
+    #[inline]
+    pub fn get_resource_mut(&mut self, resource: &QBResource) -> Option<&mut QBFileTreeNode> {
+        let idx = self.index_resource(resource)?;
+        Some(&mut self.arena[idx])
+    }
+
     /// Get an entry of this tree
     #[inline]
     pub fn get_or_insert(
@@ -391,22 +454,140 @@ impl QBFileTree {
                 QBFileTreeNode::Dir(children) => {
                     pointer = children.get(seg)?;
                 }
-                QBFileTreeNode::File(_) => return None,
-                _ => panic!("uninitialized"),
+                // the parent is a file or has been deleted
+                QBFileTreeNode::File(_) | QBFileTreeNode::None => return None,
             }
         }
 
         Some(pointer)
     }
 
-    /// Allocate a spot in the area memory map
+    /// Get the index for this resource, if the entry stored
+    /// at its path is of the same kind as the resource.
+    /// Symlinks are stored as files.
+    fn index_resource(&self, resource: &QBResource) -> Option<usize> {
+        let idx = self.index(resource)?;
+        match &self.arena[idx] {
+            QBFileTreeNode::Dir(_) if resource.is_dir() => Some(idx),
+            QBFileTreeNode::File(_) if !resource.is_dir() => Some(idx),
+            _ => None,
+        }
+    }
+
+    /// Update an entry which has been moved or copied to another path.
     ///
-    /// TODO: use previously freed
+    /// A file whose extension changes is checked for binary contents again.
+    fn moved(entry: &mut QBFileTreeNode, from: &QBPath, to: &QBPath) {
+        if let QBFileTreeNode::File(file) = entry {
+            if from.ext() != to.ext() {
+                file.binary = false;
+            }
+        }
+    }
+
+    /// Allocate a spot in the area memory map, reusing a freed one if possible
     fn alloc(&mut self) -> usize {
+        if let Some(idx) = self.free.pop() {
+            return idx;
+        }
+
         self.arena.push(Default::default());
         self.arena.len() - 1
     }
 
+    /// Free the spot and the spots of the children stored in it.
+    fn release(&mut self, idx: usize) {
+        let mut stack = vec![idx];
+        while let Some(idx) = stack.pop() {
+            if let QBFileTreeNode::Dir(dir) = std::mem::take(&mut self.arena[idx]) {
+                stack.extend(dir.contents.into_values());
+            }
+            // the root is never reused
+            if idx != 0 {
+                self.free.push(idx);
+            }
+        }
+    }
+
+    /// Remove the entry at the path from its parent directory
+    /// and return the spot it is stored in.
+    fn unlink(&mut self, path: impl AsRef<QBPath>) -> Option<usize> {
+        let path = path.as_ref();
+        let name = path.name()?.to_owned();
+        let parent = self.index(path.clone().parent()?)?;
+        match &mut self.arena[parent] {
+            QBFileTreeNode::Dir(dir) => dir.contents.remove(&name),
+            _ => None,
+        }
+    }
+
+    /// Copy the entry and its children into newly allocated spots,
+    /// so that the copy does not share any spots with the original.
+    fn clone_subtree(&mut self, idx: usize) -> QBFileTreeNode {
+        let mut node = self.arena[idx].clone();
+        if let QBFileTreeNode::Dir(dir) = &mut node {
+            for child in dir.contents.values_mut() {
+                let copy = self.clone_subtree(*child);
+                *child = self.alloc();
+                self.arena[*child] = copy;
+            }
+        }
+        node
+    }
+
+    /// Copy the entry of another tree and its children into
+    /// newly allocated spots of this tree.
+    fn copy_subtree(&mut self, src: &QBFileTree, idx: usize) -> QBFileTreeNode {
+        let mut node = src.arena[idx].clone();
+        if let QBFileTreeNode::Dir(dir) = &mut node {
+            for child in dir.contents.values_mut() {
+                let copy = self.copy_subtree(src, *child);
+                *child = self.alloc();
+                self.arena[*child] = copy;
+            }
+        }
+        node
+    }
+
+    /// Copy the entry at the path and its children into a tree of their
+    /// own, which stores the entry as its root.
+    ///
+    /// Returns none if there is no entry at the path.
+    pub fn subtree(&self, path: impl AsRef<QBPath>) -> Option<QBFileTree> {
+        let idx = self.index(path)?;
+        if self.arena[idx].is_none() {
+            return None;
+        }
+
+        let mut subtree = QBFileTree {
+            arena: vec![QBFileTreeNode::None],
+            free: Vec::new(),
+        };
+        subtree.arena[0] = subtree.copy_subtree(self, idx);
+        Some(subtree)
+    }
+
+    /// Replace the entry at the path and its children with the root
+    /// of the given subtree (see [QBFileTree::subtree]), or remove
+    /// them if none is given.
+    pub fn restore(&mut self, path: impl AsRef<QBPath>, subtree: Option<QBFileTree>) {
+        let path = path.as_ref();
+        if let Some(idx) = self.index(path) {
+            if idx != 0 {
+                self.unlink(path);
+            }
+            self.release(idx);
+        }
+
+        if let Some(subtree) = subtree {
+            let node = self.copy_subtree(&subtree, 0);
+            match self.get_or_create_ptr(path) {
+                Some(idx) => self.arena[idx] = node,
+                None => warn!("filetree: restore {} but path not available!", path),
+            }
+        }
+    }
+
     /// Create path in the tree structure
     ///
     /// This might allocate multiple directories.
@@ -460,7 +641,13 @@ impl QBFileTree {
     /// asserts that resource is a file
     pub fn update(&mut self, resource: &QBResource, hash: QBHash) {
         assert!(resource.is_file());
-        self[resource].file_mut().hash = hash;
+        let file = self[resource].file_mut();
+        // diffs update the hash themselves, so a different hash
+        // means that the contents of another device were applied
+        if file.hash != hash {
+            file.hash = hash;
+            file.binary = false;
+        }
     }
 
     /// create this resource
@@ -478,18 +665,15 @@ impl QBFileTree {
 
     /// delete this resource
     pub fn delete(&mut self, resource: &QBResource) {
-        match self.index(resource) {
+        match self.index_resource(resource) {
             Some(ptr) => {
-                if self.arena[ptr].is_dir() != resource.is_dir() {
-                    warn!(
-                        "filetree: delete {} but entry kind does not match!",
-                        resource
-                    );
-                    return;
-                }
-
-                std::mem::take(&mut self.arena[ptr]);
+                self.unlink(resource);
+                self.release(ptr);
             }
+            None if self.get(resource).is_some_and(|node| !node.is_none()) => warn!(
+                "filetree: delete {} but entry kind does not match!",
+                resource
+            ),
             None => warn!("filetree: delete {} but not found!", resource),
         }
     }
@@ -512,8 +696,91 @@ impl QBFileTree {
     }
 
     /// Remove and return an entry
+    ///
+    /// The spots of the children of the entry stay allocated,
+    /// so that it can be inserted elsewhere (see [QBFileTree::insert]).
     pub fn remove(&mut self, path: impl AsRef<QBPath>) -> Option<QBFileTreeNode> {
-        let idx = self.index(path)?;
+        let idx = self.index(&path)?;
+        // the root has no parent and stays allocated
+        if idx != 0 {
+            self.unlink(&path);
+            self.free.push(idx);
+        }
         Some(std::mem::take(&mut self.arena[idx]))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resource(path: &str, dir: bool) -> QBResource {
+        let path = QBPath::try_from(path).unwrap();
+        match dir {
+            true => path.dir(),
+            false => path.file(),
+        }
+    }
+
+    #[test]
+    fn delete_keeps_arena_bounded() {
+        let mut tree = QBFileTree::default();
+        for _ in 0..100 {
+            tree.create(&resource("/dir", true));
+            tree.create(&resource("/dir/sub", true));
+            tree.create(&resource("/dir/a", false));
+            tree.create(&resource("/dir/sub/b", false));
+            tree.delete(&resource("/dir", true));
+        }
+
+        assert!(tree.is_empty());
+        assert!(tree.arena.len() <= 5, "arena grew to {}", tree.arena.len());
+    }
+
+    #[test]
+    fn restore_subtree() {
+        let mut tree = QBFileTree::default();
+        tree.create(&resource("/dir", true));
+        tree.create(&resource("/dir/a", false));
+
+        let subtree = tree.subtree(resource("/dir", true));
+        tree.delete(&resource("/dir", true));
+        // the freed spots are reused by another entry
+        tree.create(&resource("/other", true));
+        tree.create(&resource("/other/b", false));
+        tree.restore(resource("/dir", true), subtree);
+
+        assert!(tree.get_resource(&resource("/dir/a", false)).is_some());
+        assert!(tree.get_resource(&resource("/other/b", false)).is_some());
+        assert!(tree.get(resource("/dir/b", false)).is_none());
+    }
+
+    #[test]
+    fn lookup_checks_kind() {
+        let mut tree = QBFileTree::default();
+        tree.create(&resource("/x", true));
+        assert!(tree.get_resource(&resource("/x", true)).unwrap().is_dir());
+        assert!(tree.get_resource(&resource("/x", false)).is_none());
+
+        // deleting the file leaves the directory in place
+        tree.delete(&resource("/x", false));
+        assert!(tree.get_resource(&resource("/x", true)).is_some());
+
+        tree.delete(&resource("/x", true));
+        tree.create(&resource("/x", false));
+        assert!(tree.get_resource(&resource("/x", false)).unwrap().is_file());
+        assert!(tree.get_resource(&resource("/x", true)).is_none());
+        assert!(tree.get_resource_mut(&resource("/x", true)).is_none());
+    }
+
+    #[tokio::test]
+    async fn walk_fails_on_unreadable_dir() {
+        let root = tempfile::tempdir().unwrap();
+        let wrapper = QBFSWrapper::new(root.path());
+        let mut tree = QBFileTree::default();
+        // the directory is stored in the tree, but not on disk
+        tree.create(&resource("/gone", true));
+
+        assert!(tree.walk(&wrapper).await.is_err());
+    }
+}
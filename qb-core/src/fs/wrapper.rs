@@ -2,15 +2,48 @@
 //! functions like read, write or delete.
 
 use std::{
+    collections::BTreeMap,
     ffi::{OsStr, OsString},
+    io,
     path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
 };
 
 use bitcode::{DecodeOwned, Encode};
+use futures::{future::BoxFuture, Stream, TryStreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-use crate::path::{qbpaths, QBPath, QBResource, QBResourceKind};
+use crate::{
+    hash::{QBHash, QBHasher},
+    path::{qbpaths, QBPath, QBPathConfig, QBResource, QBResourceKind},
+};
+
+use super::{Error, Result, WithPath};
+
+/// The storage the wrapper writes files to. This allows for replacing
+/// the local file system, e.g. to simulate errors which are hard to
+/// provoke, like a full device or a rename across file systems.
+pub trait QBFSBackend: Send + Sync {
+    /// Write the contents to the file at the path, creating it if needed.
+    fn write<'a>(&'a self, path: &'a Path, contents: &'a [u8]) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Rename a path, which fails if both are on different file systems.
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, io::Result<()>>;
+}
+
+/// The local file system, which is the default [QBFSBackend].
+pub struct QBFSLocalBackend;
 
-use super::{Error, Result};
+impl QBFSBackend for QBFSLocalBackend {
+    fn write<'a>(&'a self, path: &'a Path, contents: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(tokio::fs::write(path, contents))
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(tokio::fs::rename(from, to))
+    }
+}
 
 /// struct which wraps the local file system
 #[derive(Clone)]
@@ -19,6 +52,10 @@ pub struct QBFSWrapper {
     pub root: PathBuf,
     /// the root path (as a string)
     pub root_str: String,
+    /// the limits which apply when parsing paths
+    pub path_config: QBPathConfig,
+    /// the storage files are written to
+    backend: Arc<dyn QBFSBackend>,
 }
 
 impl QBFSWrapper {
@@ -30,23 +67,46 @@ impl QBFSWrapper {
             root_str.pop();
         }
 
-        Self { root_str, root }
+        Self {
+            root_str,
+            root,
+            path_config: Default::default(),
+            backend: Arc::new(QBFSLocalBackend),
+        }
     }
 
-    /// Convert a path to a resource
+    /// Use the given limits when parsing paths.
+    pub fn with_path_config(mut self, path_config: QBPathConfig) -> Self {
+        self.path_config = path_config;
+        self
+    }
+
+    /// Write files to the given storage instead of the local file system.
+    pub fn with_backend(mut self, backend: impl QBFSBackend + 'static) -> Self {
+        self.backend = Arc::new(backend);
+        self
+    }
+
+    /// Convert a path to a resource, symlinks are not followed
     pub async fn to_resource(&self, path: QBPath) -> Result<QBResource> {
-        let meta = tokio::fs::metadata(self.fspath(&path)).await?;
+        let fspath = self.fspath(&path);
+        let meta = tokio::fs::symlink_metadata(&fspath)
+            .await
+            .with_path(&fspath)?;
         Ok(QBResource::new(path, QBResourceKind::from_metadata(meta)))
     }
 
     /// Make sure the filesystem is properly setup.
     pub async fn init(&self) -> Result<()> {
-        tokio::fs::create_dir_all(self.fspath(qbpaths::INTERNAL.as_ref())).await?;
+        let fspath = self.fspath(qbpaths::INTERNAL.as_ref());
+        tokio::fs::create_dir_all(&fspath)
+            .await
+            .with_path(&fspath)?;
         Ok(())
     }
 
     /// Load and decode from a path
-    pub async fn load<'a, T: DecodeOwned>(&self, path: impl AsRef<QBPath>) -> Result<T> {
+    pub async fn load<T: DecodeOwned>(&self, path: impl AsRef<QBPath>) -> Result<T> {
         Ok(bitcode::decode(&self.read(path).await?)?)
     }
 
@@ -60,61 +120,299 @@ impl QBFSWrapper {
 
     /// Encode and save to a path
     pub async fn save(&self, path: impl AsRef<QBPath>, item: &impl Encode) -> Result<()> {
-        tokio::fs::write(self.fspath(path), bitcode::encode(item)).await?;
+        let fspath = self.fspath(path);
+        self.backend
+            .write(&fspath, &bitcode::encode(item))
+            .await
+            .with_path(&fspath)?;
         Ok(())
     }
 
     /// Returns whether this filesystem contains the given resource
     pub async fn contains(&self, resource: &QBResource) -> bool {
-        tokio::fs::metadata(self.fspath(resource))
+        tokio::fs::symlink_metadata(self.fspath(resource))
             .await
             .map(|metadata| resource.is_file_type(metadata.file_type()))
             .unwrap_or(false)
     }
 
+    /// Returns the kind of the resource stored at the given path (if any)
+    pub async fn kind(&self, path: impl AsRef<QBPath>) -> Option<QBResourceKind> {
+        tokio::fs::symlink_metadata(self.fspath(path))
+            .await
+            .map(QBResourceKind::from_metadata)
+            .ok()
+    }
+
+    /// Returns the modification time of the given path
+    /// in nanoseconds since the unix epoch (if known)
+    pub async fn mtime(&self, path: impl AsRef<QBPath>) -> Option<u64> {
+        let metadata = tokio::fs::symlink_metadata(self.fspath(path)).await.ok()?;
+        let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(mtime.as_nanos() as u64)
+    }
+
+    /// Sets the modification time of the given file
+    /// in nanoseconds since the unix epoch
+    pub async fn set_mtime(&self, path: impl AsRef<QBPath>, mtime: u64) -> Result<()> {
+        let fspath = self.fspath(path);
+        let file = tokio::fs::File::options()
+            .write(true)
+            .open(&fspath)
+            .await
+            .with_path(&fspath)?;
+        let mtime = UNIX_EPOCH + Duration::from_nanos(mtime);
+        file.into_std().await.set_modified(mtime).with_path(&fspath)
+    }
+
     /// Reads a directory asynchronously
     ///
     /// Stops processing entries once an error occured and returns this error.
     pub async fn read_dir(&self, path: impl AsRef<QBPath>) -> Result<Vec<QBResource>> {
+        self.read_dir_stream(path).try_collect().await
+    }
+
+    /// Reads a directory asynchronously, yielding one entry at a time
+    ///
+    /// Unlike [QBFSWrapper::read_dir] this does not buffer the entries,
+    /// which matters for directories with a huge amount of files.
+    ///
+    /// Stops processing entries once an error occured and yields this error.
+    pub fn read_dir_stream(
+        &self,
+        path: impl AsRef<QBPath>,
+    ) -> impl Stream<Item = Result<QBResource>> + Send + 'static {
+        let path = path.as_ref().clone();
         let fspath = self.fspath(&path);
 
-        let mut entries = Vec::new();
-        let mut iter = tokio::fs::read_dir(fspath).await?;
-        while let Some(entry) = iter.next_entry().await? {
-            let file_type = entry.file_type().await?;
+        futures::stream::try_unfold((None, fspath, path), |(iter, fspath, path)| async move {
+            let mut iter = match iter {
+                Some(iter) => iter,
+                None => tokio::fs::read_dir(&fspath).await.with_path(&fspath)?,
+            };
+
+            let entry = match iter.next_entry().await.with_path(&fspath)? {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+
+            let file_type = entry.file_type().await.with_path(entry.path())?;
             let file_name = Self::str(entry.file_name())?;
 
             let resource = QBResource::new(
-                path.as_ref().clone().substitue(file_name)?,
+                path.clone().substitue(file_name)?,
                 QBResourceKind::from_file_type(file_type),
             );
 
-            entries.push(resource);
-        }
-
-        Ok(entries)
+            Ok(Some((resource, (Some(iter), fspath, path))))
+        })
     }
 
     /// Read a path asynchronously
     pub async fn read(&self, path: impl AsRef<QBPath>) -> Result<Vec<u8>> {
-        Ok(tokio::fs::read(self.fspath(path)).await?)
+        let fspath = self.fspath(path);
+        tokio::fs::read(&fspath).await.with_path(&fspath)
+    }
+
+    /// Read a path asynchronously, but at most the given amount of bytes.
+    ///
+    /// Returns [Error::TooLarge] instead of reading larger files.
+    pub async fn read_capped(&self, path: impl AsRef<QBPath>, max: u64) -> Result<Vec<u8>> {
+        let fspath = self.fspath(path);
+        let too_large = |size| Error::TooLarge {
+            path: fspath.clone(),
+            size,
+            max,
+        };
+
+        let file = tokio::fs::File::open(&fspath).await.with_path(&fspath)?;
+        let size = file.metadata().await.with_path(&fspath)?.len();
+        if size > max {
+            return Err(too_large(size));
+        }
+
+        // the file might grow while we read it
+        let mut contents = Vec::with_capacity(size as usize);
+        file.take(max + 1)
+            .read_to_end(&mut contents)
+            .await
+            .with_path(&fspath)?;
+        if contents.len() as u64 > max {
+            return Err(too_large(contents.len() as u64));
+        }
+        Ok(contents)
+    }
+
+    /// Hash the contents of a path asynchronously, without
+    /// reading the whole file into memory.
+    pub async fn hash(&self, path: impl AsRef<QBPath>) -> Result<QBHash> {
+        let fspath = self.fspath(path);
+        let mut file = tokio::fs::File::open(&fspath).await.with_path(&fspath)?;
+        let mut hasher = QBHasher::default();
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let len = file.read(&mut buf).await.with_path(&fspath)?;
+            if len == 0 {
+                return Ok(hasher.finalize());
+            }
+            hasher.update(&buf[..len]);
+        }
     }
 
     /// Write to a path asynchronously
     pub async fn write(&self, path: impl AsRef<QBPath>, contents: impl AsRef<[u8]>) -> Result<()> {
-        tokio::fs::write(self.fspath(path), contents).await?;
+        let fspath = self.fspath(path);
+        self.backend
+            .write(&fspath, contents.as_ref())
+            .await
+            .with_path(&fspath)?;
         Ok(())
     }
 
+    /// Append to a path asynchronously, the file is created if it does not exist
+    pub async fn append(&self, path: impl AsRef<QBPath>, contents: impl AsRef<[u8]>) -> Result<()> {
+        let fspath = self.fspath(path);
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&fspath)
+            .await
+            .with_path(&fspath)?;
+        file.write_all(contents.as_ref()).await.with_path(&fspath)?;
+        file.flush().await.with_path(&fspath)
+    }
+
     /// Copy a path asynchronously
     pub async fn copy(&self, from: impl AsRef<QBPath>, to: impl AsRef<QBPath>) -> Result<()> {
-        tokio::fs::copy(self.fspath(from), self.fspath(to)).await?;
+        let fspath = self.fspath(from);
+        tokio::fs::copy(&fspath, self.fspath(to))
+            .await
+            .with_path(&fspath)?;
         Ok(())
     }
 
     /// Rename a path asynchronously
+    ///
+    /// If both paths are on different file systems (e.g. the root contains
+    /// a mount point), the path is copied and removed afterwards instead.
     pub async fn rename(&self, from: impl AsRef<QBPath>, to: impl AsRef<QBPath>) -> Result<()> {
-        tokio::fs::rename(self.fspath(from), self.fspath(to)).await?;
+        let fspath = self.fspath(from);
+        let to_fspath = self.fspath(to);
+        match self.backend.rename(&fspath, &to_fspath).await {
+            Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+                Self::move_across(&fspath, &to_fspath).await
+            }
+            result => result.with_path(&fspath),
+        }
+    }
+
+    /// Move a path to another file system by copying and removing it.
+    /// Symlinks are recreated instead of copying what they point to.
+    async fn move_across(from: &Path, to: &Path) -> Result<()> {
+        let metadata = tokio::fs::symlink_metadata(from).await.with_path(from)?;
+        if metadata.is_symlink() {
+            Self::copy_link(from, to).await?;
+            return tokio::fs::remove_file(from).await.with_path(from);
+        }
+        if !metadata.is_dir() {
+            tokio::fs::copy(from, to).await.with_path(from)?;
+            return tokio::fs::remove_file(from).await.with_path(from);
+        }
+
+        // copy directories top-down, so that parents exist before their contents
+        let mut stack = vec![(from.to_owned(), to.to_owned())];
+        while let Some((from, to)) = stack.pop() {
+            tokio::fs::create_dir(&to).await.with_path(&to)?;
+            let mut iter = tokio::fs::read_dir(&from).await.with_path(&from)?;
+            while let Some(entry) = iter.next_entry().await.with_path(&from)? {
+                let to = to.join(entry.file_name());
+                let file_type = entry.file_type().await.with_path(entry.path())?;
+                if file_type.is_dir() {
+                    stack.push((entry.path(), to));
+                } else if file_type.is_symlink() {
+                    Self::copy_link(&entry.path(), &to).await?;
+                } else {
+                    tokio::fs::copy(entry.path(), &to)
+                        .await
+                        .with_path(entry.path())?;
+                }
+            }
+        }
+        tokio::fs::remove_dir_all(from).await.with_path(from)
+    }
+
+    /// Create a symlink at to, which points to where the one at from points to.
+    async fn copy_link(from: &Path, to: &Path) -> Result<()> {
+        let target = tokio::fs::read_link(from).await.with_path(from)?;
+        #[cfg(unix)]
+        tokio::fs::symlink(&target, to).await.with_path(to)?;
+        #[cfg(windows)]
+        match tokio::fs::metadata(from)
+            .await
+            .is_ok_and(|meta| meta.is_dir())
+        {
+            true => tokio::fs::symlink_dir(&target, to).await.with_path(to)?,
+            false => tokio::fs::symlink_file(&target, to).await.with_path(to)?,
+        }
+        Ok(())
+    }
+
+    /// Read the extended attributes of a path asynchronously.
+    ///
+    /// Returns no attributes if the `xattr` feature is disabled or
+    /// extended attributes are not supported on this platform.
+    /// Attributes with names which are not valid utf8 are skipped.
+    #[cfg_attr(not(feature = "xattr"), allow(unused_variables))]
+    pub async fn xattrs(&self, path: impl AsRef<QBPath>) -> Result<BTreeMap<String, Vec<u8>>> {
+        #[cfg(feature = "xattr")]
+        if xattr::SUPPORTED_PLATFORM {
+            let fspath = self.fspath(path);
+            let xattrs_path = fspath.clone();
+            let xattrs = tokio::task::spawn_blocking(move || {
+                let mut xattrs = BTreeMap::new();
+                for name in xattr::list(&fspath)? {
+                    let Some(key) = name.to_str() else {
+                        continue;
+                    };
+                    if let Some(value) = xattr::get(&fspath, &name)? {
+                        xattrs.insert(key.to_owned(), value);
+                    }
+                }
+                std::io::Result::Ok(xattrs)
+            })
+            .await
+            .unwrap()
+            .with_path(xattrs_path)?;
+            return Ok(xattrs);
+        }
+
+        Ok(BTreeMap::new())
+    }
+
+    /// Set (or remove, if value is none) an extended attribute asynchronously.
+    ///
+    /// This is a no-op if the `xattr` feature is disabled or
+    /// extended attributes are not supported on this platform.
+    #[cfg_attr(not(feature = "xattr"), allow(unused_variables))]
+    pub async fn set_xattr(
+        &self,
+        path: impl AsRef<QBPath>,
+        name: &str,
+        value: Option<&[u8]>,
+    ) -> Result<()> {
+        #[cfg(feature = "xattr")]
+        if xattr::SUPPORTED_PLATFORM {
+            let fspath = self.fspath(path);
+            let name = name.to_owned();
+            let value = value.map(|value| value.to_vec());
+            tokio::task::spawn_blocking(move || match value {
+                Some(value) => xattr::set(&fspath, &name, &value),
+                None => xattr::remove(&fspath, &name),
+            })
+            .await
+            .unwrap()?;
+        }
+
         Ok(())
     }
 
@@ -125,15 +423,20 @@ impl QBFSWrapper {
 
     /// Parse a local fs path to a quixbyte path.
     pub fn parse(&self, path: impl AsRef<Path>) -> Result<QBPath> {
-        Ok(QBPath::parse(
+        Ok(QBPath::parse_with(
             self.root_str.as_str(),
             Self::strref(path.as_ref().as_os_str())?,
+            &self.path_config,
         )?)
     }
 
     /// Parse a local fs path to a quixbyte path.
     pub fn parse_str(&self, path: impl AsRef<str>) -> Result<QBPath> {
-        Ok(QBPath::parse(self.root_str.as_str(), path)?)
+        Ok(QBPath::parse_with(
+            self.root_str.as_str(),
+            path,
+            &self.path_config,
+        )?)
     }
 
     /// Utility for converting an osstring into a string
@@ -150,3 +453,84 @@ impl QBFSWrapper {
             .ok_or_else(|| Error::OsString(osstring.to_owned()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn error_mentions_path() {
+        let root = tempfile::tempdir().unwrap();
+        let wrapper = QBFSWrapper::new(root.path());
+        let missing = QBPath::try_from("/missing").unwrap();
+        let other = QBPath::try_from("/other").unwrap();
+        let nested = QBPath::try_from("/missing/file").unwrap();
+        let fspath = root.path().join("missing");
+
+        let errors = [
+            wrapper.read(&missing).await.unwrap_err(),
+            wrapper.copy(&missing, &other).await.unwrap_err(),
+            wrapper.rename(&missing, &other).await.unwrap_err(),
+            wrapper.write(&nested, b"contents").await.unwrap_err(),
+        ];
+        for err in errors {
+            let Error::IO { source, path } = &err else {
+                panic!("expected an I/O error: {err:?}");
+            };
+            assert_eq!(source.kind(), io::ErrorKind::NotFound);
+            assert!(path.as_ref().is_some_and(|path| path.starts_with(&fspath)));
+            assert!(err.to_string().contains(fspath.to_str().unwrap()), "{err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn read_capped_rejects_large_files() {
+        let root = tempfile::tempdir().unwrap();
+        let wrapper = QBFSWrapper::new(root.path());
+        let path = QBPath::try_from("/a").unwrap();
+
+        wrapper.write(&path, b"abcd").await.unwrap();
+        assert_eq!(wrapper.read_capped(&path, 4).await.unwrap(), b"abcd");
+        let err = wrapper.read_capped(&path, 3).await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                Error::TooLarge {
+                    size: 4,
+                    max: 3,
+                    ..
+                }
+            ),
+            "{err:?}"
+        );
+
+        // a sparse file, which would not fit into memory if it was read
+        let file = std::fs::File::create(wrapper.fspath(&path)).unwrap();
+        file.set_len(1 << 40).unwrap();
+        let err = wrapper.read_capped(&path, 1024).await.unwrap_err();
+        assert!(matches!(err, Error::TooLarge { size, .. } if size == 1 << 40));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinks_are_not_followed() {
+        let root = tempfile::tempdir().unwrap();
+        let wrapper = QBFSWrapper::new(root.path());
+        std::fs::create_dir(root.path().join("dir")).unwrap();
+        std::os::unix::fs::symlink("dir", root.path().join("link")).unwrap();
+        std::os::unix::fs::symlink("missing", root.path().join("dangling")).unwrap();
+
+        let link = QBPath::try_from("/link").unwrap();
+        assert_eq!(wrapper.kind(&link).await, Some(QBResourceKind::Symlink));
+        assert_eq!(
+            wrapper.to_resource(link.clone()).await.unwrap(),
+            link.clone().symlink()
+        );
+        assert!(wrapper.contains(&link.clone().symlink()).await);
+        assert!(!wrapper.contains(&link.dir()).await);
+
+        let dangling = QBPath::try_from("/dangling").unwrap();
+        assert!(wrapper.contains(&dangling.clone().symlink()).await);
+        assert!(wrapper.mtime(&dangling).await.is_some());
+    }
+}
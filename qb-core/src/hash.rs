@@ -47,3 +47,22 @@ impl QBHash {
         hasher.finalize_into(GenericArray::from_mut_slice(&mut hash.0));
     }
 }
+
+/// struct which computes a hash incrementally
+#[derive(Default, Clone)]
+pub struct QBHasher(Sha256);
+
+impl QBHasher {
+    /// Feed contents into this hasher.
+    pub fn update(&mut self, contents: impl AsRef<[u8]>) {
+        self.0.update(contents);
+    }
+
+    /// Finish and return the hash.
+    pub fn finalize(self) -> QBHash {
+        let mut hash = QBHash::default();
+        self.0
+            .finalize_into(GenericArray::from_mut_slice(&mut hash.0));
+        hash
+    }
+}
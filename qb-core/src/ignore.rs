@@ -26,6 +26,28 @@ pub enum QBIgnoreError {
 
 pub(crate) type QBIgnoreResult<T> = Result<T, QBIgnoreError>;
 
+/// Patterns matching the temporary files editors create while editing and
+/// saving files, in the format of ignore files. These are swap files, backups
+/// and the temporary files which get renamed onto a file when saving atomically.
+pub const EDITOR_TEMP_PATTERNS: &[&str] = &[
+    // vim swap files and the file vim uses to check write permissions
+    ".*.sw[a-p]",
+    "4913",
+    // backups of vim, emacs, nano and others
+    "*~",
+    // emacs auto-save files and lock files
+    "\\#*#",
+    ".#*",
+    // JetBrains IDEs
+    "*___jb_tmp___",
+    "*___jb_old___",
+    // gedit and other GIO based editors
+    ".goutputstream-*",
+    // LibreOffice lock files
+    ".~lock.*#",
+    "*.tmp",
+];
+
 /// struct describing where the ignore rule was defined
 pub enum QBIgnoreGlob<'a> {
     /// in ignore file
@@ -46,7 +68,7 @@ pub struct QBIgnore(ignore::gitignore::Gitignore);
 
 impl QBIgnore {
     /// Match resource against this ignore file
-    pub fn matched(&self, resource: &QBResource) -> ignore::Match<QBIgnoreGlob> {
+    pub fn matched(&self, resource: &QBResource) -> ignore::Match<QBIgnoreGlob<'_>> {
         // println!("MATCHING: {}", resource);
         self.0
             .matched_path_or_any_parents(resource.path.as_fspath(), resource.is_dir())
@@ -100,6 +122,8 @@ impl QBIgnoreMapBuilder {
                 let hash = self.ignores.get(from).unwrap().clone();
                 self.ignores.insert(path, hash);
             }
+            // ignore files are text files, which are never patched
+            QBFSChangeKind::Patch { .. } | QBFSChangeKind::XAttr { .. } => {}
         };
     }
 
@@ -171,13 +195,15 @@ impl QBIgnoreMap {
                 let hash = self.ignores.get(from).unwrap().clone();
                 self.ignores.insert(path, hash);
             }
+            // ignore files are text files, which are never patched
+            QBFSChangeKind::Patch { .. } | QBFSChangeKind::XAttr { .. } => {}
         };
     }
 
     /// Match resource against this ignore map
     ///
     /// TODO: unexpected behaviour when trying to ignore directories without /
-    pub fn matched(&self, resource: &QBResource) -> ignore::Match<QBIgnoreGlob> {
+    pub fn matched(&self, resource: &QBResource) -> ignore::Match<QBIgnoreGlob<'_>> {
         // ignore internal directories
         if qbpaths::INTERNAL.is_parent(resource) {
             return ignore::Match::Ignore(QBIgnoreGlob::Internal);
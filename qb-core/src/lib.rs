@@ -14,6 +14,7 @@
 #![warn(missing_docs)]
 
 pub mod change;
+pub mod chunk;
 pub mod device;
 pub mod diff;
 pub mod fs;
@@ -25,6 +25,8 @@ pub mod qbpaths {
         pub static ref INTERNAL: QBPath = unsafe { QBPath::new("/.qb") };
         /// the internal changelog path
         pub static ref INTERNAL_CHANGEMAP: QBPath = unsafe { QBPath::new("/.qb/changemap") };
+        /// the internal changelog journal path
+        pub static ref INTERNAL_CHANGEMAP_JOURNAL: QBPath = unsafe { QBPath::new("/.qb/changemap.journal") };
         /// the internal filetree path
         pub static ref INTERNAL_FILETREE: QBPath = unsafe { QBPath::new("/.qb/filetree") };
         /// the internal filetable path
@@ -33,6 +35,10 @@ pub mod qbpaths {
         pub static ref INTERNAL_IGNORE: QBPath = unsafe { QBPath::new("/.qb/ignore") };
         /// the internal devices path
         pub static ref INTERNAL_DEVICES: QBPath = unsafe { QBPath::new("/.qb/devices") };
+        /// the directory where resources are kept while a batch is applied
+        pub static ref INTERNAL_TRASH: QBPath = unsafe { QBPath::new("/.qb/trash") };
+        /// the internal path of a sync which has not been fully applied
+        pub static ref INTERNAL_PENDING: QBPath = unsafe { QBPath::new("/.qb/pending") };
         /// the directory where the daemon config is stored
         pub static ref INTERNAL_CONFIG: QBPath = unsafe { QBPath::new("/.qb/config") };
     }
@@ -44,13 +50,107 @@ pub enum QBPathError {
     /// the maximum amount of segments ("/") in the to be parsed path
     #[error("path exceeds maximum number of segments {0}")]
     MaxSegsExceeded(usize),
+    /// the maximum length (in bytes) of the to be parsed path
+    #[error("path exceeds maximum length of {0} bytes")]
+    MaxLenExceeded(usize),
     /// directory traversal attempt detected while parsing path
     #[error("directory traversal detected")]
     TraversalDetected,
+    /// a segment of the to be parsed path contains a control character
+    #[error("path segment {0:?} contains a control character")]
+    ControlCharacter(String),
+    /// a segment of the to be parsed path can not be
+    /// stored on the file systems of the platform
+    #[error("path segment {0:?} is reserved")]
+    ReservedSegment(String),
 }
 
 pub(crate) type QBPathResult<T> = Result<T, QBPathError>;
 
+/// struct describing the limits which apply when parsing paths
+///
+/// Different file systems support different limits, which is
+/// why these are configurable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QBPathConfig {
+    /// the maximum amount of segments ("/") in a path
+    pub max_segs: usize,
+    /// the maximum length of a path in bytes (no limit if none)
+    pub max_len: Option<usize>,
+    /// whether segments which windows can not store are rejected,
+    /// e.g. reserved names like "CON" or characters like ":"
+    pub windows: bool,
+}
+
+impl Default for QBPathConfig {
+    fn default() -> Self {
+        Self {
+            max_segs: Self::DEFAULT_MAX_SEGS,
+            max_len: None,
+            windows: cfg!(windows),
+        }
+    }
+}
+
+impl QBPathConfig {
+    /// The default maximum amount of segments
+    pub const DEFAULT_MAX_SEGS: usize = 50;
+
+    /// Set the maximum amount of segments
+    pub fn with_max_segs(mut self, max_segs: usize) -> Self {
+        self.max_segs = max_segs;
+        self
+    }
+
+    /// Set the maximum length of a path in bytes
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    /// Set whether segments which windows can not store are rejected.
+    /// This defaults to whether we are running on windows.
+    pub fn with_windows(mut self, windows: bool) -> Self {
+        self.windows = windows;
+        self
+    }
+
+    /// The names which are reserved for devices on windows.
+    const WINDOWS_RESERVED: [&'static str; 22] = [
+        "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+        "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+    ];
+
+    /// The characters windows does not allow in file names.
+    const WINDOWS_INVALID: [char; 8] = ['<', '>', ':', '"', '\\', '|', '?', '*'];
+
+    /// Check whether the given segment can be stored.
+    fn check_segment(&self, seg: &str) -> QBPathResult<()> {
+        if seg.chars().any(char::is_control) {
+            return Err(QBPathError::ControlCharacter(seg.to_owned()));
+        }
+
+        if !self.windows {
+            return Ok(());
+        }
+
+        // reserved names also apply with an extension, e.g. "con.txt"
+        let stem = seg.split('.').next().unwrap_or_default().trim_end();
+        let reserved = Self::WINDOWS_RESERVED
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(stem));
+        if reserved
+            || seg.contains(Self::WINDOWS_INVALID)
+            || seg.ends_with('.')
+            || seg.ends_with(' ')
+        {
+            return Err(QBPathError::ReservedSegment(seg.to_owned()));
+        }
+
+        Ok(())
+    }
+}
+
 /// struct describing a path pointing to a resource
 #[derive(Encode, Decode, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct QBPath(String);
@@ -74,8 +174,6 @@ impl AsRef<QBPath> for QBPath {
 }
 
 impl QBPath {
-    const MAX_SEGS: usize = 50;
-
     /// Do not sanitize path and return QBPath instance
     ///
     /// # Safety
@@ -91,6 +189,11 @@ impl QBPath {
         Ok(Self(Self::clean(path)?))
     }
 
+    /// Sanitize path using the given limits and return QBPath instance
+    pub fn try_from_with(path: impl AsRef<str>, config: &QBPathConfig) -> QBPathResult<Self> {
+        Ok(Self(Self::clean_with(path, config)?))
+    }
+
     /// Convert this path into a resource
     ///
     /// Alias for QBResource::new_file(self)
@@ -122,6 +225,19 @@ impl QBPath {
         other.as_ref().0.starts_with(&(self.0.clone() + "/"))
     }
 
+    /// Returns this path in lowercase, which is the path that
+    /// case-insensitive file systems use to identify it.
+    #[inline]
+    pub fn to_lowercase(&self) -> Self {
+        QBPath(self.0.to_lowercase())
+    }
+
+    /// Checks whether this path and other only differ in case
+    #[inline]
+    pub fn eq_ignore_case(&self, other: impl AsRef<QBPath>) -> bool {
+        self.to_lowercase() == other.as_ref().to_lowercase()
+    }
+
     /// Returns the parent path (if any)
     #[inline]
     pub fn parent(mut self) -> Option<Self> {
@@ -139,6 +255,12 @@ impl QBPath {
         other.as_ref().is_parent(self)
     }
 
+    /// Checks whether this path is [qbpaths::INTERNAL] or inside of it
+    #[inline]
+    pub fn is_internal(&self) -> bool {
+        self == &*qbpaths::INTERNAL || qbpaths::INTERNAL.is_parent(self)
+    }
+
     /// Enter a relative path
     ///
     /// This allows the new path to be outside of the previous
@@ -164,6 +286,17 @@ impl QBPath {
     /// If absolute, this will try to slice of the root path and if
     /// path does not start with the root path, an error is returned.
     pub fn parse(root: &str, path: impl AsRef<str>) -> QBPathResult<QBPath> {
+        Self::parse_with(root, path, &Default::default())
+    }
+
+    /// Clean and parse the path string using the given limits
+    ///
+    /// See [QBPath::parse].
+    pub fn parse_with(
+        root: &str,
+        path: impl AsRef<str>,
+        config: &QBPathConfig,
+    ) -> QBPathResult<QBPath> {
         assert!(!root.ends_with('/'));
 
         // TODO: windows and shit
@@ -171,16 +304,18 @@ impl QBPath {
         if path.starts_with(root) {
             path = &path[root.len()..];
         }
-        let path = Self::clean(path)?;
+        let path = Self::clean_with(path, config)?;
 
         Ok(QBPath(path))
     }
 
     /// Return the segments of this path
+    ///
+    /// The root path has no segments, regardless of whether
+    /// it is stored with a slash or as an empty string.
     #[inline]
-    pub fn segments(&self) -> std::iter::Skip<std::str::Split<'_, char>> {
-        // skip the first segment, as it is always empty
-        self.0.split('/').skip(1)
+    pub fn segments(&self) -> impl Iterator<Item = &str> {
+        self.0.split('/').filter(|seg| !seg.is_empty())
     }
 
     /// Return the file extension of this path.
@@ -221,17 +356,25 @@ impl QBPath {
 
     /// Cleans the given path string
     ///
-    /// TODO: testing
-    /// TODO: windows
-    /// TODO: path escapes
+    /// Segments containing control characters are rejected,
+    /// see [QBPathConfig::with_windows] for windows paths.
     pub fn clean(path: impl AsRef<str>) -> QBPathResult<String> {
-        let segs = path
-            .as_ref()
-            .splitn(Self::MAX_SEGS, '/')
-            .collect::<Vec<_>>();
+        Self::clean_with(path, &Default::default())
+    }
 
-        if segs.len() == Self::MAX_SEGS {
-            return Err(QBPathError::MaxSegsExceeded(Self::MAX_SEGS));
+    /// Cleans the given path string using the given limits
+    pub fn clean_with(path: impl AsRef<str>, config: &QBPathConfig) -> QBPathResult<String> {
+        let path = path.as_ref();
+        if let Some(max_len) = config.max_len {
+            if path.len() > max_len {
+                return Err(QBPathError::MaxLenExceeded(max_len));
+            }
+        }
+
+        let segs = path.splitn(config.max_segs, '/').collect::<Vec<_>>();
+
+        if segs.len() == config.max_segs {
+            return Err(QBPathError::MaxSegsExceeded(config.max_segs));
         }
 
         // Path stack
@@ -246,8 +389,7 @@ impl QBPath {
                 continue;
             }
 
-            // TODO: sanitize segment, remove escapes etc.
-
+            config.check_segment(seg)?;
             stack.push(seg);
         }
 
@@ -442,3 +584,57 @@ impl QBResource {
         self.kind.is_symlink()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configurable_max_segs() {
+        let path = "/a".repeat(60);
+        let res = QBPath::try_from(&path);
+        assert!(
+            matches!(
+                res,
+                Err(QBPathError::MaxSegsExceeded(QBPathConfig::DEFAULT_MAX_SEGS))
+            ),
+            "{res:?}"
+        );
+
+        let config = QBPathConfig::default().with_max_segs(100);
+        let parsed = QBPath::try_from_with(&path, &config).unwrap();
+        assert_eq!(parsed.segments().count(), 60);
+
+        let config = config.with_max_len(100);
+        let res = QBPath::try_from_with(&path, &config);
+        assert!(
+            matches!(res, Err(QBPathError::MaxLenExceeded(100))),
+            "{res:?}"
+        );
+    }
+
+    #[test]
+    fn reject_unstorable_segments() {
+        let unix = QBPathConfig::default().with_windows(false);
+        let windows = QBPathConfig::default().with_windows(true);
+
+        for config in [&unix, &windows] {
+            let res = QBPath::try_from_with("/dir/a\0b", config);
+            assert!(
+                matches!(&res, Err(QBPathError::ControlCharacter(seg)) if seg == "a\0b"),
+                "{res:?}"
+            );
+        }
+
+        for path in ["/CON", "/dir/con.txt", "/aux", "/a:b", "/trailing."] {
+            let res = QBPath::try_from_with(path, &windows);
+            assert!(
+                matches!(res, Err(QBPathError::ReservedSegment(_))),
+                "{path}: {res:?}"
+            );
+            assert!(QBPath::try_from_with(path, &unix).is_ok(), "{path}");
+        }
+        // names which only start like reserved ones are fine
+        assert!(QBPath::try_from_with("/console/COM10", &windows).is_ok());
+    }
+}
@@ -11,22 +11,27 @@ use std::{
     collections::{HashMap, HashSet},
     future::Future,
     pin::Pin,
-    time::Duration,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::JoinSet,
 };
-use tokio::{sync::mpsc, task::JoinSet};
 
 use bitcode::{Decode, Encode};
 use qb_ext::{
-    control::{QBCId, QBCRequest, QBCResponse},
+    control::{QBCErrorKind, QBCId, QBCRequest, QBCResponse},
     hook::QBHContext,
     interface::QBIContext,
-    QBExtId, QBExtSetup,
+    QBExtId, QBExtSetup, QBExtSetupError,
 };
-use qb_proto::{QBPBlob, QBPDeserialize, QBP};
+use qb_proto::{error_code, QBPBlob, QBPDeserialize, QBP};
+use subtle::ConstantTimeEq;
 use thiserror::Error;
-use tracing::{info, info_span, trace, warn, Instrument};
+use tracing::{debug, info, info_span, trace, warn, Instrument};
 
-use crate::master::QBMaster;
+use crate::master::{QBIStateEvent, QBMaster, QBMasterEvent};
 
 /// Error struct for daemons.
 ///
@@ -37,20 +42,43 @@ pub enum Error {
     #[error("protocol error: {0}")]
     Protocol(#[from] qb_proto::Error),
     /// Join error
-    #[error("error while joining to QBI task")]
+    #[error("error while joining to QBI task: {0}")]
     JoinError(#[from] tokio::task::JoinError),
+    /// Setup error
+    #[error("error while setting up extension: {0}")]
+    Setup(QBExtSetupError),
     /// NotFound error
     #[error("a QBI with the given id could not be found")]
     NotFound,
     /// NotSupported error
     #[error("this type of QBI is not supported")]
     NotSupported,
+    /// The kind of an extension in the config has not been registered,
+    /// e.g. because support for it has not been compiled in
+    #[error("extension kind {0} has not been registered")]
+    UnregisteredKind(String),
     /// Malformed error
     #[error("the given content is malformed")]
     Malformed,
     /// Master error
     #[error("master error: {0}")]
     MasterError(#[from] crate::master::Error),
+    /// Unsupported error
+    #[error("this request is not supported")]
+    Unsupported,
+    /// Unauthorized error
+    #[error("the control handle sent an incorrect secret")]
+    Unauthorized,
+}
+
+impl Error {
+    /// Returns the kind of this error used for responding to control handles.
+    pub fn kind(&self) -> QBCErrorKind {
+        match self {
+            Error::Unsupported => QBCErrorKind::Unsupported,
+            _ => QBCErrorKind::Other,
+        }
+    }
 }
 
 /// Result type alias for making our life easier.
@@ -67,16 +95,36 @@ pub type QBExtStartFn = Box<
         + Sync,
 >;
 /// Function pointer to a function which sets up an interface.
-pub type QBExtSetupFn = Box<dyn Fn(&mut SetupQueue, QBCId, String, QBPBlob) + Send + Sync>;
+pub type QBExtSetupFn = Box<dyn Fn(&mut SetupQueue, QBCId, String, QBPBlob, bool) + Send + Sync>;
 
 /// A struct which can be stored persistently that describes how to
 /// start a specific extension using its kind's name and a data payload.
-#[derive(Encode, Decode)]
+#[derive(Encode, Decode, PartialEq, Eq)]
 pub struct QBExtDescriptor {
     name: String,
     data: Vec<u8>,
 }
 
+impl From<QBMasterEvent> for QBCResponse {
+    fn from(value: QBMasterEvent) -> Self {
+        match value {
+            QBMasterEvent::Sync {
+                id,
+                resource,
+                phase,
+            } => QBCResponse::SyncEvent {
+                id,
+                resource,
+                phase,
+            },
+            QBMasterEvent::Conflicts { id, conflicts } => QBCResponse::Conflicts {
+                id,
+                conflicts: conflicts.into_iter().map(Into::into).collect(),
+            },
+        }
+    }
+}
+
 /// A handle to a task processing a QBP stream for controlling the daemon.
 pub struct QBCHandle {
     tx: mpsc::Sender<QBCResponse>,
@@ -99,6 +147,7 @@ where
     conn: T,
     tx: mpsc::Sender<(QBCId, QBCRequest)>,
     rx: mpsc::Receiver<QBCResponse>,
+    auth: Option<Arc<[u8]>>,
 }
 
 /// A struct which can be stored persistently to configure a daemon.
@@ -118,15 +167,25 @@ impl QBDaemonConfig {
     }
 }
 
+/// The result of setting up an extension, see [QBDaemon::add].
+pub struct QBExtSetupResult {
+    /// the control handle which requested the setup
+    pub caller: QBCId,
+    /// whether the extension should only be validated and not be added
+    pub validate_only: bool,
+    /// the descriptor of the extension which has been set up
+    pub descriptor: Result<QBExtDescriptor>,
+}
+
 /// TODO: doc
 #[derive(Default)]
 pub struct SetupQueue {
-    join_set: JoinSet<(QBCId, Result<QBExtDescriptor>)>,
+    join_set: JoinSet<QBExtSetupResult>,
 }
 
 impl SetupQueue {
     /// TODO: doc
-    pub async fn join(&mut self) -> (QBCId, Result<QBExtDescriptor>) {
+    pub async fn join(&mut self) -> QBExtSetupResult {
         loop {
             match self.join_set.join_next().await {
                 Some(Ok(val)) => return val,
@@ -157,53 +216,173 @@ pub struct QBDaemon {
     /// A channel for receiving messages from controlling tasks
     pub req_rx: mpsc::Receiver<(QBCId, QBCRequest)>,
     handles: HashMap<QBCId, QBCHandle>,
+    auth: Option<Arc<[u8]>>,
+    started: Instant,
+
+    // supervisor stuff
+    retries: HashMap<QBExtId, u32>,
+    retry_tx: mpsc::Sender<QBExtId>,
+    /// A channel for receiving autostart interfaces which should
+    /// be started again, see [QBDaemon::retry].
+    pub retry_rx: mpsc::Receiver<QBExtId>,
 }
 
 impl QBDaemon {
+    /// The time to wait before restarting an autostart
+    /// interface for the first time after it stopped.
+    pub const RETRY_BACKOFF_MIN: Duration = Duration::from_secs(1);
+    /// The maximum time to wait before restarting an autostart interface.
+    pub const RETRY_BACKOFF_MAX: Duration = Duration::from_secs(300);
+
     /// Build the daemon
     pub async fn init(master: QBMaster, wrapper: QBFSWrapper) -> Self {
         let (req_tx, req_rx) = mpsc::channel(10);
+        let (retry_tx, retry_rx) = mpsc::channel(10);
         let config = wrapper.dload(INTERNAL_CONFIG.as_ref()).await;
         Self {
             start_fns: Default::default(),
             setup_fns: Default::default(),
             handles: Default::default(),
+            auth: None,
+            started: Instant::now(),
             setup: Default::default(),
             master,
             wrapper,
             config,
             req_tx,
             req_rx,
+            retries: Default::default(),
+            retry_tx,
+            retry_rx,
         }
     }
 
+    /// Require control handles to send the given secret as their
+    /// first payload before any of their requests get processed.
+    pub fn with_auth(mut self, auth: impl Into<Vec<u8>>) -> Self {
+        self.auth = Some(auth.into().into());
+        self
+    }
+
+    /// Returns the extensions in the config whose kind has not been
+    /// registered, so they can not be started, see [QBDaemon::register_qbi].
+    pub fn unregistered(&self) -> Vec<(QBExtId, String)> {
+        self.config
+            .ext_table
+            .iter()
+            .filter(|(_, descriptor)| !self.start_fns.contains_key(&descriptor.name))
+            .map(|(id, descriptor)| (id.clone(), descriptor.name.clone()))
+            .collect()
+    }
+
     /// Start all available interfaces
+    ///
+    /// Extensions whose kind has not been registered are reported
+    /// and skipped, call this after registering all kinds.
     pub async fn autostart(&mut self) {
-        // autostart
+        let unregistered = self.unregistered();
+        for (id, name) in unregistered.iter() {
+            warn!(
+                "extension {}: {}",
+                id,
+                Error::UnregisteredKind(name.clone())
+            );
+        }
+
         let ids = self
             .config
             .ext_autostart
             .iter()
+            .filter(|id| !unregistered.iter().any(|(other, _)| other == *id))
             .cloned()
             .collect::<Vec<_>>();
         for id in ids {
-            self.start(id).await.unwrap();
+            if let Err(err) = self.launch(id.clone()).await {
+                warn!("could not start extension {}: {}", id, err);
+                self.schedule_retry(id);
+            }
+        }
+    }
+
+    /// Process a state transition of an interface.
+    ///
+    /// Autostart interfaces which stopped without being told
+    /// to get started again with an exponential backoff, unless
+    /// they did not finish their handshake.
+    pub async fn supervise(&mut self, (id, event): (QBExtId, QBIStateEvent)) {
+        match event {
+            QBIStateEvent::Available => _ = self.retries.remove(&id),
+            QBIStateEvent::Failed => {
+                warn!("interface {} failed, not restarting it", id);
+                self.retries.remove(&id);
+            }
+            QBIStateEvent::Terminated => {
+                if !self.master.check_terminated(&id) {
+                    return;
+                }
+
+                if self.config.ext_autostart.contains(&id) {
+                    warn!("interface {} stopped unexpectedly", id);
+                    self.schedule_retry(id);
+                }
+            }
         }
     }
 
+    /// Start the given autostart interface again. This should be
+    /// called with the ids received from [QBDaemon::retry_rx].
+    pub async fn retry(&mut self, id: QBExtId) {
+        // the interface might have been stopped or started in the meantime
+        let running = self.master.is_attached(&id) || self.master.is_hooked(&id);
+        if running || !self.config.ext_autostart.contains(&id) || self.master.has_failed(&id) {
+            return;
+        }
+
+        info!("restarting interface {}", id);
+        if let Err(err) = self.launch(id.clone()).await {
+            warn!("could not restart extension {}: {}", id, err);
+            self.schedule_retry(id);
+        }
+    }
+
+    /// Send the given id to [QBDaemon::retry_rx] once its backoff has passed.
+    fn schedule_retry(&mut self, id: QBExtId) {
+        let attempt = self.retries.entry(id.clone()).or_default();
+        let backoff = Self::RETRY_BACKOFF_MIN
+            .saturating_mul(1 << (*attempt).min(16))
+            .min(Self::RETRY_BACKOFF_MAX);
+        *attempt += 1;
+
+        let retry_tx = self.retry_tx.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(backoff).await;
+            // this only fails if the daemon has been dropped
+            _ = retry_tx.send(id).await;
+        });
+    }
+
     /// Process the result of the setup queue.
-    pub async fn process_setup(&mut self, (id, maybe_setup): (QBCId, Result<QBExtDescriptor>)) {
-        match maybe_setup {
+    pub async fn process_setup(&mut self, result: QBExtSetupResult) {
+        let id = result.caller;
+        match result.descriptor {
+            Ok(_) if result.validate_only => {
+                if id.is_root() {
+                    return;
+                }
+
+                let handle = self.handles.get(&id).unwrap();
+                handle.send(QBCResponse::Success).await;
+            }
             Ok(val) => {
                 // success: add the descriptor to this daemon
-                self.add_already_setup(val).await.unwrap();
+                let ext_id = self.add_already_setup(val).await.unwrap();
 
                 if id.is_root() {
                     return;
                 }
 
                 let handle = self.handles.get(&id).unwrap();
-                handle.send(QBCResponse::Success).await;
+                handle.send(QBCResponse::Added { id: ext_id }).await;
             }
             Err(err) => {
                 warn!("error while setting up extension: {err}");
@@ -216,6 +395,7 @@ impl QBDaemon {
                 let handle = self.handles.get(&id).unwrap();
                 handle
                     .send(QBCResponse::Error {
+                        kind: err.kind(),
                         msg: err.to_string(),
                     })
                     .await;
@@ -231,13 +411,66 @@ impl QBDaemon {
             .unwrap();
     }
 
+    /// Re-read the config from disk and apply the difference to
+    /// the running extensions without restarting the daemon.
+    ///
+    /// Extensions which have been added to the autostart set get started,
+    /// extensions which have been removed from it get stopped and
+    /// extensions whose descriptor changed get restarted. Every other
+    /// extension is left untouched.
+    pub async fn reload(&mut self) -> Result<()> {
+        let config: QBDaemonConfig = self.wrapper.dload(INTERNAL_CONFIG.as_ref()).await;
+
+        let changed = |id: &QBExtId| self.config.ext_table.get(id) != config.ext_table.get(id);
+        let running = |id: &QBExtId| self.master.is_attached(id) || self.master.is_hooked(id);
+        let to_stop = self
+            .config
+            .ext_table
+            .keys()
+            .filter(|id| running(id) && (!config.ext_autostart.contains(id) || changed(id)))
+            .cloned()
+            .collect::<Vec<_>>();
+        let to_start = config
+            .ext_autostart
+            .iter()
+            .filter(|id| !running(id) || changed(id))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        info!(
+            "reload: stopping {} and starting {} extensions",
+            to_stop.len(),
+            to_start.len()
+        );
+
+        for id in to_stop {
+            self.master.stop(&id).await?.await?;
+        }
+
+        self.config = config;
+
+        for id in to_start {
+            self.launch(id).await?;
+        }
+
+        Ok(())
+    }
+
     /// Start an interface by the given id.
     pub async fn start(&mut self, id: QBExtId) -> Result<()> {
         self.config.ext_autostart.insert(id.clone());
         self.save().await;
+        self.launch(id).await
+    }
+
+    /// Start an interface by the given id without touching the config.
+    async fn launch(&mut self, id: QBExtId) -> Result<()> {
         let descriptor = self.config.get(&id)?;
         let name = &descriptor.name;
-        let start = self.start_fns.get(name).ok_or(Error::NotSupported)?;
+        let start = self
+            .start_fns
+            .get(name)
+            .ok_or_else(|| Error::UnregisteredKind(name.clone()))?;
         start(&mut self.master, id, &descriptor.data).await?;
         Ok(())
     }
@@ -251,19 +484,27 @@ impl QBDaemon {
     }
 
     /// Add an interface.
-    pub fn add(&mut self, caller: QBCId, name: String, blob: QBPBlob) -> Result<()> {
+    pub fn add(
+        &mut self,
+        caller: QBCId,
+        name: String,
+        blob: QBPBlob,
+        validate_only: bool,
+    ) -> Result<()> {
         let setup = self.setup_fns.get(&name).ok_or(Error::NotSupported)?;
-        setup(&mut self.setup, caller, name, blob);
+        setup(&mut self.setup, caller, name, blob, validate_only);
         Ok(())
     }
 
     /// Add an interface that has already been setup.
-    pub async fn add_already_setup(&mut self, descriptor: QBExtDescriptor) -> Result<()> {
+    ///
+    /// Returns the id of the new interface.
+    pub async fn add_already_setup(&mut self, descriptor: QBExtDescriptor) -> Result<QBExtId> {
         let id = QBExtId::generate();
         self.config.ext_table.insert(id.clone(), descriptor);
         self.save().await;
-        self.start(id).await?;
-        Ok(())
+        self.start(id.clone()).await?;
+        Ok(id)
     }
 
     /// Remove an interface
@@ -277,6 +518,59 @@ impl QBDaemon {
         Ok(())
     }
 
+    /// Forward the events of the master to the handle with the given id.
+    pub fn subscribe(&mut self, caller: &QBCId) {
+        let tx = self.handles.get(caller).unwrap().tx.clone();
+        let mut events = self.master.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => {
+                        // the handle has been closed
+                        if tx.send(event.into()).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("handle lagged behind, skipped {} events", n);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    /// Get the status of this daemon.
+    pub fn status(&self) -> QBCResponse {
+        let changemap = self.master.changemap();
+        QBCResponse::Status {
+            changes: changemap.len() as u64,
+            resources: changemap.resource_count() as u64,
+            changemap_size: changemap.encoded_size() as u64,
+            interfaces: self
+                .master
+                .descriptions()
+                .map(|(id, description)| (id.clone(), description.to_owned()))
+                .collect(),
+        }
+    }
+
+    /// Get aggregate metrics of this daemon.
+    pub fn stats(&self) -> QBCResponse {
+        let ids = self.config.ext_table.keys();
+        let changemap = self.master.changemap();
+        QBCResponse::Stats {
+            extensions: ids.len() as u64,
+            attached: ids.clone().filter(|id| self.master.is_attached(id)).count() as u64,
+            hooked: ids.filter(|id| self.master.is_hooked(id)).count() as u64,
+            bytes_received: self.master.bytes_received(),
+            bytes_sent: self.master.bytes_sent(),
+            changes: changemap.len() as u64,
+            changemap_size: changemap.encoded_size() as u64,
+            uptime: self.started.elapsed().as_secs(),
+        }
+    }
+
     /// List the QBIs.
     pub fn list(&self) -> Vec<(QBExtId, String, String)> {
         self.config
@@ -286,6 +580,7 @@ impl QBDaemon {
                 let mut desc = match () {
                     _ if self.master.is_attached(id) => "attached",
                     _ if self.master.is_hooked(id) => "hooked",
+                    _ if self.master.has_failed(id) => "handshake failed",
                     _ => "not active",
                 }
                 .into();
@@ -317,17 +612,25 @@ impl QBDaemon {
         );
         self.setup_fns.insert(
             name,
-            Box::new(move |setup, caller, name, blob| {
+            Box::new(move |setup, caller, name, blob, validate_only| {
                 setup.join_set.spawn(async move {
-                    let maybe_setup: Result<QBExtDescriptor> = async move {
+                    let descriptor: Result<QBExtDescriptor> = async move {
                         let span = info_span!("qbi-setup", name);
                         let setup = blob.deserialize::<S>()?;
-                        let cx = setup.setup().instrument(span).await;
+                        // run the setup in its own task, so that a failing
+                        // setup is reported to the caller instead of dropped
+                        let cx = tokio::spawn(setup.setup().instrument(span))
+                            .await?
+                            .map_err(Error::Setup)?;
                         let data = bitcode::encode(&cx);
                         Ok(QBExtDescriptor { name, data })
                     }
                     .await;
-                    (caller, maybe_setup)
+                    QBExtSetupResult {
+                        caller,
+                        validate_only,
+                        descriptor,
+                    }
                 });
             }),
         );
@@ -352,17 +655,25 @@ impl QBDaemon {
         );
         self.setup_fns.insert(
             name,
-            Box::new(move |setup, caller, name, blob| {
+            Box::new(move |setup, caller, name, blob, validate_only| {
                 setup.join_set.spawn(async move {
-                    let maybe_setup: Result<QBExtDescriptor> = async move {
+                    let descriptor: Result<QBExtDescriptor> = async move {
                         let span = info_span!("qbi-setup", name);
                         let setup = blob.deserialize::<S>()?;
-                        let cx = setup.setup().instrument(span).await;
+                        // run the setup in its own task, so that a failing
+                        // setup is reported to the caller instead of dropped
+                        let cx = tokio::spawn(setup.setup().instrument(span))
+                            .await?
+                            .map_err(Error::Setup)?;
                         let data = bitcode::encode(&cx);
                         Ok(QBExtDescriptor { name, data })
                     }
                     .await;
-                    (caller, maybe_setup)
+                    QBExtSetupResult {
+                        caller,
+                        validate_only,
+                        descriptor,
+                    }
                 });
             }),
         );
@@ -378,6 +689,7 @@ impl QBDaemon {
             Err(err) => {
                 handle
                     .send(QBCResponse::Error {
+                        kind: err.kind(),
                         msg: format!("{:?}", err),
                     })
                     .await
@@ -390,8 +702,12 @@ impl QBDaemon {
         match msg {
             QBCRequest::Start { id } => self.start(id).await?,
             QBCRequest::Stop { id } => self.stop(id).await?,
-            QBCRequest::Add { name, blob } => {
-                self.add(caller, name, blob)?;
+            QBCRequest::Add {
+                name,
+                blob,
+                validate_only,
+            } => {
+                self.add(caller, name, blob, validate_only)?;
                 return Ok(false);
             }
             QBCRequest::Remove { id } => self.remove(id).await?,
@@ -400,7 +716,33 @@ impl QBDaemon {
                 handle.send(QBCResponse::List { list: self.list() }).await;
                 return Ok(false);
             }
-            _ => unimplemented!(),
+            QBCRequest::Subscribe => self.subscribe(&caller),
+            QBCRequest::Reload => self.reload().await?,
+            QBCRequest::Status => {
+                let handle = self.handles.get(&caller).unwrap();
+                handle.send(self.status()).await;
+                return Ok(false);
+            }
+            QBCRequest::Stats => {
+                let handle = self.handles.get(&caller).unwrap();
+                handle.send(self.stats()).await;
+                return Ok(false);
+            }
+            QBCRequest::Maintenance { enabled } => self.master.set_maintenance(enabled).await,
+            QBCRequest::Diff { id } => {
+                let changes = self
+                    .master
+                    .preview_changes(&id)?
+                    .iter()
+                    .map(|(resource, change)| (resource.clone(), (&change.kind).into()))
+                    .collect();
+                let handle = self.handles.get(&caller).unwrap();
+                handle.send(QBCResponse::Diff { id, changes }).await;
+                return Ok(false);
+            }
+            // requests sent by newer clients, which this daemon
+            // does not know how to handle yet
+            _ => return Err(Error::Unsupported),
         };
 
         Ok(true)
@@ -418,6 +760,7 @@ impl QBDaemon {
         let init = HandleInit {
             tx: self.req_tx.clone(),
             rx: resp_rx,
+            auth: self.auth.clone(),
             conn,
             id,
         };
@@ -445,6 +788,24 @@ where
 
     let mut protocol = QBP::default();
 
+    if let Some(auth) = &init.auth {
+        protocol.negotiate(&mut init.conn).await?;
+        let payload = protocol.recv_payload(&mut init.conn).await?;
+        // compare in constant time, so that the secret can not be guessed
+        // byte by byte by measuring how long the comparison takes
+        if !bool::from(payload.as_slice().ct_eq(auth.as_ref())) {
+            // older clients do not understand error frames
+            if let Err(err) = protocol
+                .send_error(&mut init.conn, error_code::UNAUTHORIZED, "incorrect secret")
+                .await
+            {
+                debug!("could not send error: {}", err);
+            }
+            return Err(Error::Unauthorized);
+        }
+        trace!("handle authenticated");
+    }
+
     loop {
         tokio::select! {
             Some(response) = init.rx.recv() => {
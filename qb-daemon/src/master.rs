@@ -4,23 +4,43 @@
 //! which handles interfaces and their communication.
 //! It owns a device table and a changelog to allow syncing.
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use qb_core::{
-    change::QBChangeMap,
+    change::{
+        clock::QBVectorClock, QBChange, QBChangeKind, QBChangeMap, QBConflict, QBTruncatedError,
+    },
     device::{QBDeviceId, QBDeviceTable},
     fs::wrapper::QBFSWrapper,
+    hash::QBHash,
     path::qbpaths::{INTERNAL_CHANGEMAP, INTERNAL_DEVICES},
+    path::QBResource,
+    time::{QBTimeStampUnique, QB_TIMESTAMP_BASE},
 };
 use qb_ext::{
+    control::QBCSyncPhase,
     hook::{QBHChannel, QBHContext, QBHHostMessage, QBHSlaveMessage},
     interface::{QBIChannel, QBIContext, QBIHostMessage, QBIMessage, QBISlaveMessage},
     QBExtId,
 };
+use qb_proto::error_code;
 use thiserror::Error;
-use tokio::{sync::mpsc, task::JoinHandle};
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, info, info_span, warn, Instrument};
 
+/// The time an interface has to stop after being detached, after which
+/// its token is canceled, see [qb_ext::QBExtChannel::cancel_token].
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// An error that occured related to the master
 #[derive(Error, Debug)]
 pub enum Error {
@@ -36,6 +56,18 @@ pub enum Error {
     /// with an id, of which another hook is already hooked.
     #[error("a hook with the same id is already hooked")]
     AlreadyHooked,
+    /// This error propagates when we try to work with an interface
+    /// which has not sent its device id and common change yet.
+    #[error("the interface has not been initialized yet")]
+    NotInitialized,
+    /// This error propagates when we try to send a message
+    /// to an interface which has already been detached.
+    #[error("the interface has already been detached")]
+    Detached,
+    /// This error propagates when the changes pending to be synchronized
+    /// with an interface have already been removed from the changemap.
+    #[error("{0}")]
+    Truncated(#[from] QBTruncatedError),
 }
 
 /// Result type alias for making our life easier.
@@ -59,11 +91,112 @@ pub enum QBIState {
     },
 }
 
+/// A transition of the state of an interface, see [QBMaster::state_rx].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QBIStateEvent {
+    /// The interface has finished its handshake and is available.
+    Available,
+    /// The interface has stopped running. This is also emitted for
+    /// interfaces which have been detached, see [QBMaster::check_terminated].
+    Terminated,
+    /// The interface did not finish its handshake in time.
+    Failed,
+}
+
+/// Emits [QBIStateEvent::Terminated] once dropped, that is, once
+/// the task of the interface finishes, panics or gets aborted.
+struct QBITerminateGuard {
+    id: QBExtId,
+    tx: mpsc::Sender<(QBExtId, QBIStateEvent)>,
+}
+
+impl Drop for QBITerminateGuard {
+    fn drop(&mut self) {
+        // this only fails if the master has been dropped or is lagging behind
+        _ = self
+            .tx
+            .try_send((self.id.clone(), QBIStateEvent::Terminated));
+    }
+}
+
 /// A handle to an interface.
 pub struct QBIHandle {
     join_handle: JoinHandle<()>,
     state: QBIState,
     tx: mpsc::Sender<QBIHostMessage>,
+    /// canceled if the interface does not stop in time
+    cancel: CancellationToken,
+    attached_at: Instant,
+    /// the configuration of the interface, see [QBIContext::describe]
+    description: String,
+    /// the priority of the interface, see [QBIContext::priority]
+    priority: i32,
+    /// the parts of the sync which is currently being received
+    parts: QBChangeMap,
+    /// the clock of the changes sent to the interface, which
+    /// it has seen once it answers the sync
+    sent: QBVectorClock,
+    /// the messages which are being sent to the interface
+    outbox: QBIOutbox,
+}
+
+/// Sends messages to an interface in the background, so that
+/// a slow interface does not hold back the master.
+struct QBIOutbox {
+    id: QBExtId,
+    tx: mpsc::Sender<QBIHostMessage>,
+    /// the task sending the previous messages, which finishes
+    /// first, so that the messages arrive in order
+    task: Option<JoinHandle<()>>,
+}
+
+impl QBIOutbox {
+    /// Send the messages after the previous ones, without waiting for them.
+    fn send(&mut self, msgs: impl IntoIterator<Item = QBIHostMessage>) {
+        let msgs = msgs.into_iter().collect::<Vec<_>>();
+        let previous = self.task.take();
+        let tx = self.tx.clone();
+        let id = self.id.clone();
+        self.task = Some(tokio::spawn(async move {
+            if let Some(previous) = previous {
+                _ = previous.await;
+            }
+            for msg in msgs {
+                if tx.send(msg).await.is_err() {
+                    warn!("could not send to {}: the interface is detached", id);
+                    break;
+                }
+            }
+        }));
+    }
+}
+
+/// A reference to an attached interface, which is returned by
+/// [QBMaster::attach]. This allows for sending messages to the
+/// interface directly, without going through the master.
+#[derive(Clone)]
+pub struct QBIRef {
+    id: QBExtId,
+    tx: mpsc::Sender<QBIHostMessage>,
+}
+
+impl QBIRef {
+    /// Returns the identifier of the interface.
+    #[inline(always)]
+    pub fn id(&self) -> &QBExtId {
+        &self.id
+    }
+
+    /// Returns whether the interface is still running.
+    #[inline(always)]
+    pub fn is_running(&self) -> bool {
+        !self.tx.is_closed()
+    }
+
+    /// Send a message to the interface.
+    pub async fn send(&self, msg: impl Into<QBIHostMessage>) -> Result<()> {
+        self.tx.send(msg.into()).await.map_err(|_| Error::Detached)
+    }
 }
 
 /// Handler
@@ -76,6 +209,210 @@ pub struct QBHHandle {
     tx: mpsc::Sender<QBHHostMessage>,
 }
 
+/// An event emitted by the master, see [QBMaster::subscribe].
+#[derive(Debug, Clone)]
+pub enum QBMasterEvent {
+    /// A resource is being synchronized with an interface.
+    Sync {
+        /// the identifier of the interface
+        id: QBExtId,
+        /// the resource
+        resource: QBResource,
+        /// the phase the synchronization is in
+        phase: QBCSyncPhase,
+    },
+    /// A synchronization with an interface yielded conflicts.
+    Conflicts {
+        /// the identifier of the interface
+        id: QBExtId,
+        /// the conflicts
+        conflicts: Vec<QBConflict>,
+    },
+}
+
+/// Changes which an interface has synchronized to the master,
+/// see [QBMaster::add_observer].
+#[derive(Debug, Clone)]
+pub struct QBObservedChanges {
+    /// the identifier of the interface the changes came from
+    pub id: QBExtId,
+    /// the changes, before they were merged with the local ones
+    pub changes: QBChangeMap,
+}
+
+/// Send the given changes to the observers and remove the closed ones.
+fn notify_observers(
+    observers: &mut Vec<mpsc::Sender<QBObservedChanges>>,
+    id: &QBExtId,
+    changes: &QBChangeMap,
+) {
+    observers.retain(|observer| {
+        let msg = QBObservedChanges {
+            id: id.clone(),
+            changes: changes.clone(),
+        };
+        match observer.try_send(msg) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                warn!("observer is lagging behind, dropping changes");
+                true
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    });
+}
+
+/// A change of the device table of the master, see [QBMaster::add_device_observer].
+#[derive(Debug, Clone)]
+pub enum QBDeviceEvent {
+    /// The master has synchronized with a device for the first time.
+    Added {
+        /// the id of the device
+        device_id: QBDeviceId,
+    },
+    /// The common change of a device has been updated.
+    Common {
+        /// the id of the device
+        device_id: QBDeviceId,
+        /// the new common change
+        common: QBTimeStampUnique,
+    },
+}
+
+/// Send the given event to the device observers and remove the closed ones.
+fn notify_device_observers(observers: &mut Vec<mpsc::Sender<QBDeviceEvent>>, event: QBDeviceEvent) {
+    observers.retain(|observer| match observer.try_send(event.clone()) {
+        Ok(()) => true,
+        Err(mpsc::error::TrySendError::Full(_)) => {
+            warn!("device observer is lagging behind, dropping event");
+            true
+        }
+        Err(mpsc::error::TrySendError::Closed(_)) => false,
+    });
+}
+
+/// Update the common change of a device and notify the device observers.
+fn update_common(
+    devices: &mut QBDeviceTable,
+    observers: &mut Vec<mpsc::Sender<QBDeviceEvent>>,
+    device_id: &QBDeviceId,
+    common: QBTimeStampUnique,
+) {
+    let added = devices.common(device_id).is_none();
+    devices.set_common(device_id, common.clone());
+    if observers.is_empty() {
+        return;
+    }
+    if added {
+        let device_id = device_id.clone();
+        notify_device_observers(observers, QBDeviceEvent::Added { device_id });
+    }
+    let device_id = device_id.clone();
+    notify_device_observers(observers, QBDeviceEvent::Common { device_id, common });
+}
+
+/// Returns the changes which the device has not seen yet. Once it is known
+/// which changes the device has seen, this includes changes which have been
+/// recorded concurrently on other devices before its common change, see
+/// [QBChangeMap::since_clock]. Until then, every change since it is returned.
+fn unseen_changes(
+    changemap: &QBChangeMap,
+    seen: &HashMap<QBDeviceId, QBVectorClock>,
+    devices: &QBDeviceTable,
+    device_id: &QBDeviceId,
+) -> std::result::Result<QBChangeMap, QBTruncatedError> {
+    match seen.get(device_id) {
+        Some(clock) => changemap.since_clock(clock),
+        None => changemap.since_cloned(devices.get_common(device_id)),
+    }
+}
+
+/// Forget the history of the deleted resources which every device we have
+/// synchronized with has seen, see [QBChangeMap::prune_resource]. Devices
+/// which have never synchronized are sent the remaining changes only.
+fn prune_deleted(
+    changemap: &mut QBChangeMap,
+    seen: &HashMap<QBDeviceId, QBVectorClock>,
+    devices: &QBDeviceTable,
+) {
+    let seen_by_all = |change: &QBChange| {
+        devices
+            .commons()
+            .filter(|(_, common)| *common != &QB_TIMESTAMP_BASE)
+            .all(|(device_id, common)| match seen.get(device_id) {
+                Some(clock) => clock.contains(&change.timestamp),
+                None => common >= &change.timestamp,
+            })
+    };
+    let deleted = changemap
+        .by_resource()
+        .filter_map(|(resource, changes)| {
+            let last = changes.last()?;
+            let prunable =
+                matches!(last.kind, QBChangeKind::Delete) && changes.iter().all(seen_by_all);
+            prunable.then(|| (resource.clone(), last.timestamp.clone()))
+        })
+        .collect::<Vec<_>>();
+    for (resource, until) in deleted {
+        if changemap.prune_resource(&resource, &until) {
+            debug!("pruned the history of {}", resource);
+        }
+    }
+}
+
+/// Emit a sync event for every given resource.
+fn emit_sync<'a>(
+    events: &broadcast::Sender<QBMasterEvent>,
+    id: &QBExtId,
+    resources: impl Iterator<Item = &'a QBResource>,
+    phase: QBCSyncPhase,
+) {
+    // skip the work if nobody is listening
+    if events.receiver_count() == 0 {
+        return;
+    }
+
+    for resource in resources {
+        // this only fails if there are no receivers
+        _ = events.send(QBMasterEvent::Sync {
+            id: id.clone(),
+            resource: resource.clone(),
+            phase: phase.clone(),
+        });
+    }
+}
+
+/// A bounded cache of the broadcasts which have been seen recently,
+/// used for dropping broadcasts which arrive multiple times, e.g.
+/// because they are re-broadcast in a mesh of devices.
+#[derive(Default)]
+struct QBBroadcastCache {
+    seen: HashSet<QBHash>,
+    order: VecDeque<(QBHash, Instant)>,
+}
+
+impl QBBroadcastCache {
+    /// Remember the given message and return whether it has not
+    /// been seen within the given time to live.
+    fn insert(&mut self, msg: &str, ttl: Duration, capacity: usize) -> bool {
+        let now = Instant::now();
+        while let Some((hash, seen_at)) = self.order.front() {
+            if now.duration_since(*seen_at) < ttl && self.order.len() < capacity {
+                break;
+            }
+            self.seen.remove(hash);
+            self.order.pop_front();
+        }
+
+        let hash = QBHash::compute(msg);
+        if !self.seen.insert(hash.clone()) {
+            return false;
+        }
+        self.order.push_back((hash, now));
+        true
+    }
+}
+
 /// The master, that is, the struct that houses connection
 /// to the individual interfaces and manages communication.
 pub struct QBMaster {
@@ -91,10 +428,45 @@ pub struct QBMaster {
 
     devices: QBDeviceTable,
     changemap: QBChangeMap,
+    /// the changes each device has seen, which are not stored,
+    /// so this falls back to their common change after a restart
+    seen: HashMap<QBDeviceId, QBVectorClock>,
     wrapper: QBFSWrapper,
+
+    events: broadcast::Sender<QBMasterEvent>,
+
+    handshake_timeout: Option<Duration>,
+    handshake_failed: HashSet<QBExtId>,
+    /// Receiver for interfaces whose handshake deadline has passed,
+    /// see [QBMaster::check_handshake].
+    pub handshake_rx: mpsc::Receiver<QBExtId>,
+    handshake_tx: mpsc::Sender<QBExtId>,
+
+    /// Receiver for state transitions of interfaces.
+    pub state_rx: mpsc::Receiver<(QBExtId, QBIStateEvent)>,
+    state_tx: mpsc::Sender<(QBExtId, QBIStateEvent)>,
+
+    broadcasts: QBBroadcastCache,
+    observers: Vec<mpsc::Sender<QBObservedChanges>>,
+    device_observers: Vec<mpsc::Sender<QBDeviceEvent>>,
+
+    bytes_received: u64,
+    bytes_sent: u64,
+
+    maintenance: bool,
+    /// the syncs received during maintenance, which are applied afterwards
+    deferred: Vec<(QBExtId, QBIMessage)>,
 }
 
 impl QBMaster {
+    /// The default time an interface has to send its
+    /// device id and common change after being attached.
+    pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+    /// The time in which the same broadcast is only fanned out once.
+    pub const BROADCAST_TTL: Duration = Duration::from_secs(10);
+    /// The maximum amount of recent broadcasts remembered.
+    pub const BROADCAST_CACHE_SIZE: usize = 1024;
+
     /// Initialize the master with the given device id.
     ///
     /// This identifier should be negotiated and then stored
@@ -106,6 +478,9 @@ impl QBMaster {
         wrapper.init().await.unwrap();
         let devices = wrapper.dload(INTERNAL_DEVICES.as_ref()).await;
         let changemap = wrapper.dload(INTERNAL_CHANGEMAP.as_ref()).await;
+        let (events, _) = broadcast::channel(128);
+        let (handshake_tx, handshake_rx) = mpsc::channel(10);
+        let (state_tx, state_rx) = mpsc::channel(32);
 
         QBMaster {
             qbi_handles: HashMap::new(),
@@ -116,8 +491,168 @@ impl QBMaster {
             qbh_tx: hook_tx,
             devices,
             changemap,
+            seen: HashMap::new(),
             wrapper,
+            events,
+            handshake_timeout: Some(Self::DEFAULT_HANDSHAKE_TIMEOUT),
+            handshake_failed: HashSet::new(),
+            handshake_rx,
+            handshake_tx,
+            state_rx,
+            state_tx,
+            broadcasts: Default::default(),
+            observers: Vec::new(),
+            device_observers: Vec::new(),
+            bytes_received: 0,
+            bytes_sent: 0,
+            maintenance: false,
+            deferred: Vec::new(),
+        }
+    }
+
+    /// Use the given handshake deadline for interfaces attached from
+    /// now on. Interfaces which have not finished their handshake once
+    /// it has passed get detached. None disables the deadline.
+    pub fn with_handshake_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Detach the interface with the given id, if it has not finished its
+    /// handshake yet. This should be called with the ids received from
+    /// [QBMaster::handshake_rx].
+    pub fn check_handshake(&mut self, id: QBExtId) {
+        let Some(handle) = self.qbi_handles.get(&id) else {
+            return;
+        };
+
+        if let QBIState::Available { .. } = handle.state {
+            return;
+        }
+
+        // the deadline might belong to a previous attachment with the same id
+        let timeout = self.handshake_timeout.unwrap_or(Duration::MAX);
+        if handle.attached_at.elapsed() < timeout {
+            return;
+        }
+
+        warn!("interface {} did not finish its handshake in time", id);
+        let handle = self.qbi_handles.remove(&id).unwrap();
+        // the interface might not respond at all, so do not wait for it
+        _ = handle.tx.try_send(QBIHostMessage::Stop);
+        handle.join_handle.abort();
+        self.handshake_failed.insert(id.clone());
+        _ = self.state_tx.try_send((id, QBIStateEvent::Failed));
+    }
+
+    /// Returns whether the interface with the given id has stopped running
+    /// without being detached and removes it. This should be called with the
+    /// ids received from [QBMaster::state_rx] as [QBIStateEvent::Terminated].
+    pub fn check_terminated(&mut self, id: &QBExtId) -> bool {
+        // the channel is closed once the interface has dropped its end,
+        // an interface attached again with the same id has a new one
+        let terminated = self
+            .qbi_handles
+            .get(id)
+            .is_some_and(|handle| handle.tx.is_closed());
+        if terminated {
+            self.qbi_handles.remove(id);
+        }
+        terminated
+    }
+
+    /// Returns whether the interface with the given id has been
+    /// detached, because it did not finish its handshake in time.
+    #[inline(always)]
+    pub fn has_failed(&self, id: &QBExtId) -> bool {
+        self.handshake_failed.contains(id)
+    }
+
+    /// Returns the descriptions of the attached interfaces as JSON,
+    /// see [QBIContext::describe].
+    pub fn descriptions(&self) -> impl Iterator<Item = (&QBExtId, &str)> {
+        self.qbi_handles
+            .iter()
+            .map(|(id, handle)| (id, handle.description.as_str()))
+    }
+
+    /// Returns the changemap of this master.
+    #[inline(always)]
+    pub fn changemap(&self) -> &QBChangeMap {
+        &self.changemap
+    }
+
+    /// Returns the total size of the changes received from interfaces in bytes.
+    #[inline(always)]
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    /// Returns the total size of the changes sent to interfaces in bytes.
+    #[inline(always)]
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    /// Returns whether maintenance mode is enabled, see [QBMaster::set_maintenance].
+    #[inline(always)]
+    pub fn is_maintenance(&self) -> bool {
+        self.maintenance
+    }
+
+    /// Enable or disable maintenance mode. While enabled, no changes are
+    /// synchronized: syncs received from interfaces are deferred until it
+    /// gets disabled again, which applies them and then synchronizes.
+    pub async fn set_maintenance(&mut self, enabled: bool) {
+        if self.maintenance == enabled {
+            return;
+        }
+
+        info!(
+            "maintenance mode {}",
+            if enabled { "enabled" } else { "disabled" }
+        );
+        self.maintenance = enabled;
+        if enabled {
+            return;
+        }
+
+        self.iclean_handles();
+        for (id, msg) in std::mem::take(&mut self.deferred) {
+            // the interface might have been detached in the meantime
+            if self.qbi_handles.contains_key(&id) {
+                self.iprocess((id, QBISlaveMessage::Message(msg))).await;
+            }
         }
+        self.sync().await;
+    }
+
+    /// Register an observer, which receives every change that interfaces
+    /// synchronize to the master. Observers are not peers, they neither
+    /// take part in the synchronization nor do they slow it down: changes
+    /// are dropped for observers which do not keep up with them.
+    ///
+    /// The observer is removed once the receiver is dropped.
+    pub fn add_observer(&mut self, capacity: usize) -> mpsc::Receiver<QBObservedChanges> {
+        let (tx, rx) = mpsc::channel(capacity);
+        self.observers.push(tx);
+        rx
+    }
+
+    /// Register an observer, which receives every change of the device table,
+    /// e.g. for storing the state of the devices in an external database.
+    /// Events are dropped for observers which do not keep up with them.
+    ///
+    /// The observer is removed once the receiver is dropped.
+    pub fn add_device_observer(&mut self, capacity: usize) -> mpsc::Receiver<QBDeviceEvent> {
+        let (tx, rx) = mpsc::channel(capacity);
+        self.device_observers.push(tx);
+        rx
+    }
+
+    /// Subscribe to the events emitted by this master.
+    pub fn subscribe(&self) -> broadcast::Receiver<QBMasterEvent> {
+        self.events.subscribe()
     }
 
     /// TODO: doc
@@ -182,12 +717,30 @@ impl QBMaster {
             QBIState::Device { ref device_id } => {
                 match msg {
                     QBIMessage::Common { common } => {
-                        // TODO: negotiate this instead
-                        self.devices.set_common(device_id, common);
+                        let common = match self.devices.common(device_id) {
+                            // TODO: negotiate this instead
+                            Some(_) => common,
+                            // we have never synchronized with this device,
+                            // so send every change instead of trusting its
+                            // common change, which may refer to another host
+                            None => {
+                                info!("first sync with device {}", device_id);
+                                QB_TIMESTAMP_BASE.clone()
+                            }
+                        };
+                        update_common(
+                            &mut self.devices,
+                            &mut self.device_observers,
+                            device_id,
+                            common,
+                        );
                         handle.state = QBIState::Available {
                             device_id: device_id.clone(),
                             syncing: false,
                         };
+                        _ = self
+                            .state_tx
+                            .try_send((id.clone(), QBIStateEvent::Available));
                         self.sync().await;
                     }
                     // The interface should not send any messages before the
@@ -202,7 +755,7 @@ impl QBMaster {
                         let common = self.devices.get_common(&device_id).clone();
                         handle.state = QBIState::Device { device_id };
                         let msg = QBIMessage::Common { common }.into();
-                        handle.tx.send(msg).await.unwrap();
+                        handle.outbox.send([msg]);
                     }
                     // The interface should not send any messages before the
                     // init message has been sent. This is likely an error.
@@ -215,33 +768,111 @@ impl QBMaster {
         let handle_common = self.devices.get_common(device_id);
 
         match msg {
-            QBIMessage::Sync {
-                common,
-                changes: remote,
-            } => {
+            QBIMessage::SyncPart { changes } => handle.parts.append_map(changes),
+            QBIMessage::Sync { common, changes } if self.maintenance => {
+                debug!("maintenance: deferring sync");
+                self.deferred
+                    .push((id, QBIMessage::Sync { common, changes }));
+                return;
+            }
+            QBIMessage::Sync { common, changes } => {
                 assert!(handle_common == &common);
+                let mut remote = std::mem::take(&mut handle.parts);
+                remote.append_map(changes);
+                self.bytes_received += remote.encoded_size() as u64;
+                let looped = remote.relay(&self.devices.host_id);
+                if looped > 0 {
+                    debug!(
+                        "{} change(s) from {} have passed this device before",
+                        looped, id
+                    );
+                }
+                let resources = remote.resources().cloned().collect::<Vec<_>>();
+                emit_sync(
+                    &self.events,
+                    &id,
+                    resources.iter(),
+                    QBCSyncPhase::Transferred,
+                );
+
+                // Find local changes, the changes the device has not seen
+                // are found before they are taken out of the changemap
+                let res = unseen_changes(&self.changemap, &self.seen, &self.devices, device_id)
+                    .and_then(|unseen| Ok((unseen, self.changemap.since(&common)?)));
+                let (unseen, local) = match res {
+                    Ok(changes) => changes,
+                    Err(err) => {
+                        warn!("could not sync with {}: {}", id, err);
+                        *syncing = false;
+                        let msg = QBIMessage::Error {
+                            code: error_code::TRUNCATED,
+                            msg: err.to_string(),
+                        }
+                        .into();
+                        handle.outbox.send([msg]);
+                        return;
+                    }
+                };
+
+                let conflicts = local.conflicts(&remote);
+                if !conflicts.is_empty() {
+                    warn!("sync yielded {} conflicts", conflicts.len());
+                    // this only fails if there are no receivers
+                    _ = self.events.send(QBMasterEvent::Conflicts {
+                        id: id.clone(),
+                        conflicts,
+                    });
+                }
 
-                // Find local changes
-                let local = self.changemap.since(&common);
+                if !self.observers.is_empty() {
+                    notify_observers(&mut self.observers, &id, &remote);
+                }
+
+                // the device has seen the changes it sent and the ones we
+                // sent it, either when starting the sync or as an answer
+                let mut seen = match *syncing {
+                    true => std::mem::take(&mut handle.sent),
+                    false => unseen.clock().clone(),
+                };
+                seen.merge(remote.clock());
 
                 // Apply changes to changelog
                 let mut changemap = local.clone();
-                _ = changemap.merge(remote).unwrap();
+                if let Err(err) = changemap.merge(remote) {
+                    // reject the sync, keeping our own changes
+                    warn!("could not sync with {}: {}", id, err);
+                    self.changemap.append_map(local);
+                    *syncing = false;
+                    let msg = QBIMessage::Error {
+                        code: error_code::APPLY_FAILED,
+                        msg: err,
+                    }
+                    .into();
+                    handle.outbox.send([msg]);
+                    return;
+                }
                 self.changemap.append_map(changemap);
+                emit_sync(&self.events, &id, resources.iter(), QBCSyncPhase::Applied);
 
                 // find the new common hash
                 let new_common = self.changemap.head().clone();
                 debug!("new common: {}", new_common);
-                self.devices.set_common(device_id, new_common);
+                update_common(
+                    &mut self.devices,
+                    &mut self.device_observers,
+                    device_id,
+                    new_common,
+                );
+
+                self.seen.insert(device_id.clone(), seen);
+                prune_deleted(&mut self.changemap, &self.seen, &self.devices);
 
                 // Send sync to remote
                 if !*syncing {
-                    let msg = QBIMessage::Sync {
-                        common,
-                        changes: local,
-                    }
-                    .into();
-                    handle.tx.send(msg).await.unwrap();
+                    emit_sync(&self.events, &id, unseen.resources(), QBCSyncPhase::Started);
+                    self.bytes_sent += unseen.encoded_size() as u64;
+                    let msgs = QBIMessage::sync(common, unseen);
+                    handle.outbox.send(msgs.into_iter().map(Into::into));
                 }
 
                 *syncing = false;
@@ -250,9 +881,27 @@ impl QBMaster {
             }
             // TODO: negotiate this instead
             QBIMessage::Common { common } => {
-                self.devices.set_common(device_id, common);
+                update_common(
+                    &mut self.devices,
+                    &mut self.device_observers,
+                    device_id,
+                    common,
+                );
+            }
+            QBIMessage::Broadcast { msg } => {
+                match self
+                    .broadcasts
+                    .insert(&msg, Self::BROADCAST_TTL, Self::BROADCAST_CACHE_SIZE)
+                {
+                    true => broadcast.push(msg),
+                    false => debug!("dropping duplicate broadcast"),
+                }
+            }
+            QBIMessage::Error { code, msg } => {
+                warn!("interface reported error {}: {}", code, msg);
+                // the interface will not answer the sync we sent
+                *syncing = false;
             }
-            QBIMessage::Broadcast { msg } => broadcast.push(msg),
             QBIMessage::Device { .. } => {
                 warn!("received init message, even though already initialized")
             }
@@ -262,7 +911,7 @@ impl QBMaster {
         for msg in broadcast {
             for handle in self.qbi_handles.values_mut() {
                 let msg = QBIMessage::Broadcast { msg: msg.clone() }.into();
-                handle.tx.send(msg).await.unwrap();
+                handle.outbox.send([msg]);
             }
         }
     }
@@ -303,8 +952,9 @@ impl QBMaster {
         Ok(())
     }
 
-    /// Try to attach an interface to the master. Returns error if already attached.
-    pub fn attach(&mut self, id: QBExtId, cx: impl QBIContext) -> Result<()> {
+    /// Try to attach an interface to the master. Returns error if already attached,
+    /// otherwise a [QBIRef] for sending messages to the interface directly.
+    pub fn attach(&mut self, id: QBExtId, mut cx: impl QBIContext + 'static) -> Result<QBIRef> {
         let span = info_span!("qb-interface", id = id.to_hex());
 
         // make sure we do not attach an interface twice
@@ -314,22 +964,62 @@ impl QBMaster {
 
         let (master_tx, master_rx) = tokio::sync::mpsc::channel::<QBIHostMessage>(32);
 
+        let host_id = self.devices.host_id.clone();
+        let cancel = CancellationToken::new();
+        let com =
+            QBIChannel::new(id.clone(), self.qbi_tx.clone(), master_rx).with_cancel(cancel.clone());
+
+        let qbi_ref = QBIRef {
+            id: id.clone(),
+            tx: master_tx.clone(),
+        };
+
+        let description = cx.describe().to_string();
+        let priority = cx.priority();
+        let guard = QBITerminateGuard {
+            id: id.clone(),
+            tx: self.state_tx.clone(),
+        };
+
         // create the handle
         let handle = QBIHandle {
             join_handle: tokio::spawn(
-                cx.run(
-                    self.devices.host_id.clone(),
-                    QBIChannel::new(id.clone(), self.qbi_tx.clone(), master_rx),
-                )
+                async move {
+                    let _guard = guard;
+                    cx.on_attach().await;
+                    cx.run(host_id, com).await;
+                    cx.on_detach().await;
+                }
                 .instrument(span),
             ),
+            outbox: QBIOutbox {
+                id: id.clone(),
+                tx: master_tx.clone(),
+                task: None,
+            },
             tx: master_tx,
+            cancel,
             state: QBIState::Init,
+            attached_at: Instant::now(),
+            description,
+            priority,
+            parts: Default::default(),
+            sent: Default::default(),
         };
 
         self.qbi_handles.insert(id.clone(), handle);
+        self.handshake_failed.remove(&id);
 
-        Ok(())
+        if let Some(timeout) = self.handshake_timeout {
+            let handshake_tx = self.handshake_tx.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(timeout).await;
+                // this only fails if the master has been dropped
+                _ = handshake_tx.send(id).await;
+            });
+        }
+
+        Ok(qbi_ref)
     }
 
     /// Returns whether an interface with the given id is attached to the master.
@@ -349,6 +1039,13 @@ impl QBMaster {
         let handle = self.qbi_handles.remove(id).ok_or(Error::NotFound)?;
         handle.tx.send(QBIHostMessage::Stop).await.unwrap();
 
+        // abort the reads of the interface, if it is stuck waiting for a peer
+        let cancel = handle.cancel;
+        tokio::spawn(async move {
+            tokio::time::sleep(STOP_TIMEOUT).await;
+            cancel.cancel();
+        });
+
         Ok(handle.join_handle)
     }
 
@@ -384,7 +1081,16 @@ impl QBMaster {
     /// # Cancelation safety
     /// This method is not cancelation safe.
     pub async fn sync(&mut self) {
-        for (id, handle) in self.qbi_handles.iter_mut() {
+        // nothing gets synchronized during maintenance
+        if self.maintenance {
+            return;
+        }
+
+        // the syncs of interfaces with a higher priority are started first
+        let mut handles = self.qbi_handles.iter_mut().collect::<Vec<_>>();
+        handles.sort_by_key(|(_, handle)| Reverse(handle.priority));
+
+        for (id, handle) in handles {
             // skip uninitialized
             if let QBIState::Available {
                 ref device_id,
@@ -397,7 +1103,14 @@ impl QBMaster {
                 }
 
                 let handle_common = self.devices.get_common(device_id);
-                let changes = self.changemap.since_cloned(handle_common);
+                let changes =
+                    match unseen_changes(&self.changemap, &self.seen, &self.devices, device_id) {
+                        Ok(changes) => changes,
+                        Err(err) => {
+                            warn!("could not sync with {}: {}", id, err);
+                            continue;
+                        }
+                    };
 
                 // skip if no changes to sync
                 if changes.is_empty() {
@@ -405,24 +1118,342 @@ impl QBMaster {
                 }
 
                 info!("syncing with {}", id);
+                emit_sync(&self.events, id, changes.resources(), QBCSyncPhase::Started);
 
                 // synchronize
                 *syncing = true;
-                let msg = QBIMessage::Sync {
-                    common: handle_common.clone(),
-                    changes,
+                handle.sent = changes.clock().clone();
+                self.bytes_sent += changes.encoded_size() as u64;
+                // a slow interface must not hold back the others
+                let msgs = QBIMessage::sync(handle_common.clone(), changes);
+                handle.outbox.send(msgs.into_iter().map(Into::into));
+            }
+        }
+    }
+
+    /// Returns the changes which are pending to be synchronized with
+    /// the interface with the given id, that is, every change the
+    /// device of the interface has not seen yet.
+    pub fn preview_changes(&self, id: &QBExtId) -> Result<QBChangeMap> {
+        let handle = self.qbi_handles.get(id).ok_or(Error::NotFound)?;
+        match handle.state {
+            QBIState::Available { ref device_id, .. } => Ok(unseen_changes(
+                &self.changemap,
+                &self.seen,
+                &self.devices,
+                device_id,
+            )?),
+            _ => Err(Error::NotInitialized),
+        }
+    }
+
+    /// Send a message to an interface with the given id, after the
+    /// messages which are already being sent, without waiting for it.
+    pub fn send(&mut self, id: &QBExtId, msg: impl Into<QBIHostMessage>) -> Result<()> {
+        let handle = self.qbi_handles.get_mut(id).ok_or(Error::NotFound)?;
+        handle.outbox.send([msg.into()]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use qb_core::{path::QBPath, time::QBTimeStampRecorder};
+
+    use super::*;
+
+    /// An interface which forwards the messages of the master to a [Device].
+    struct Mock {
+        tx: mpsc::Sender<QBIHostMessage>,
+        priority: i32,
+        /// the number of messages forwarded before the interface stalls
+        limit: usize,
+    }
+
+    impl QBIContext for Mock {
+        async fn run(&mut self, _host_id: QBDeviceId, mut com: QBIChannel) {
+            for _ in 0..self.limit {
+                let msg = com.recv::<QBIHostMessage>().await;
+                if self.tx.send(msg).await.is_err() {
+                    return;
                 }
-                .into();
-                handle.tx.send(msg).await.unwrap();
             }
+            std::future::pending().await
+        }
+
+        fn priority(&self) -> i32 {
+            self.priority
         }
     }
 
-    /// Send a message to an interface with the given id.
-    ///
-    /// This is expected to never fail.
-    pub async fn send(&self, id: &QBExtId, msg: impl Into<QBIHostMessage>) {
-        let handle = self.qbi_handles.get(id).unwrap();
-        handle.tx.send(msg.into()).await.unwrap()
+    /// A device behind a mock interface, which synchronizes
+    /// like the local interface does.
+    struct Device {
+        ext: QBExtId,
+        qbi_ref: QBIRef,
+        recorder: QBTimeStampRecorder,
+        changemap: QBChangeMap,
+        common: QBTimeStampUnique,
+        syncing: bool,
+        parts: QBChangeMap,
+        rx: mpsc::Receiver<QBIHostMessage>,
+    }
+
+    impl Device {
+        async fn attach(master: &mut QBMaster) -> Self {
+            Self::attach_with(master, 0, usize::MAX).await
+        }
+
+        async fn attach_with(master: &mut QBMaster, priority: i32, limit: usize) -> Self {
+            let (tx, mut rx) = mpsc::channel(32);
+            let ext = QBExtId::generate();
+            let mock = Mock {
+                tx,
+                priority,
+                limit,
+            };
+            let qbi_ref = master.attach(ext.clone(), mock).unwrap();
+
+            let device_id = QBDeviceId::generate();
+            let msg = QBIMessage::Device {
+                device_id: device_id.clone(),
+            };
+            master.iprocess((ext.clone(), msg.into())).await;
+            let Some(QBIHostMessage::Message(QBIMessage::Common { common })) = rx.recv().await
+            else {
+                panic!("expected the common change");
+            };
+            let msg = QBIMessage::Common {
+                common: common.clone(),
+            };
+            master.iprocess((ext.clone(), msg.into())).await;
+
+            Device {
+                ext,
+                qbi_ref,
+                recorder: QBTimeStampRecorder::from(device_id),
+                changemap: Default::default(),
+                common,
+                syncing: false,
+                parts: Default::default(),
+                rx,
+            }
+        }
+
+        fn create(&mut self, path: &str) {
+            self.record(path, QBChangeKind::Create);
+        }
+
+        fn record(&mut self, path: &str, kind: QBChangeKind) {
+            let change = QBChange::new(self.recorder.record(), kind);
+            self.changemap.push((file(path), change));
+        }
+
+        async fn sync(&mut self, master: &mut QBMaster) {
+            self.syncing = true;
+            let changes = self.changemap.since_cloned(&self.common).unwrap();
+            let msg = QBIMessage::Sync {
+                common: self.common.clone(),
+                changes,
+            };
+            master.iprocess((self.ext.clone(), msg.into())).await;
+        }
+
+        /// Handle the messages the master has sent, answering its syncs.
+        /// Returns whether there have been any messages.
+        async fn process(&mut self, master: &mut QBMaster) -> bool {
+            let mut processed = false;
+            while let Ok(msg) = self.rx.try_recv() {
+                processed = true;
+                let (common, changes) = match msg {
+                    QBIHostMessage::Message(QBIMessage::SyncPart { changes }) => {
+                        self.parts.append_map(changes);
+                        continue;
+                    }
+                    QBIHostMessage::Message(QBIMessage::Sync { common, changes }) => {
+                        (common, changes)
+                    }
+                    _ => continue,
+                };
+
+                let mut remote = std::mem::take(&mut self.parts);
+                remote.append_map(changes);
+                let local = self.changemap.since_cloned(&common).unwrap();
+                self.changemap.merge(remote).unwrap();
+                self.common = self.changemap.head().clone();
+                if !self.syncing {
+                    let msg = QBIMessage::Sync {
+                        common,
+                        changes: local,
+                    };
+                    master.iprocess((self.ext.clone(), msg.into())).await;
+                }
+                self.syncing = false;
+            }
+            processed
+        }
+    }
+
+    fn file(path: &str) -> QBResource {
+        QBPath::try_from(path).unwrap().file()
+    }
+
+    /// Process the messages of the devices until none are sent anymore.
+    async fn settle(master: &mut QBMaster, devices: &mut [Device]) {
+        loop {
+            // give the mock interfaces time to forward the messages
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let mut processed = false;
+            for device in devices.iter_mut() {
+                processed |= device.process(master).await;
+            }
+            if !processed {
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_changes_converge() {
+        let root = tempfile::tempdir().unwrap();
+        let mut master = QBMaster::init(QBFSWrapper::new(root.path())).await;
+        let mut devices = Vec::new();
+        for _ in 0..3 {
+            devices.push(Device::attach(&mut master).await);
+        }
+
+        // every device records a change before any of them synchronizes,
+        // so the oldest change reaches the master last
+        for (device, path) in devices.iter_mut().rev().zip(["/c", "/b", "/a"]) {
+            device.create(path);
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        devices[0].sync(&mut master).await;
+        settle(&mut master, &mut devices).await;
+
+        for device in devices.iter() {
+            for path in ["/a", "/b", "/c"] {
+                assert!(device.changemap.contains(&file(path)), "missing {path}");
+            }
+        }
+        for path in ["/a", "/b", "/c"] {
+            assert!(master.changemap().contains(&file(path)));
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_interface_does_not_block() {
+        let root = tempfile::tempdir().unwrap();
+        let mut master = QBMaster::init(QBFSWrapper::new(root.path())).await;
+        let mut devices = [
+            Device::attach(&mut master).await,
+            Device::attach(&mut master).await,
+        ];
+        // stalls after the handshake, with a full channel
+        let slow = Device::attach_with(&mut master, 1, 1).await;
+        for _ in 0..32 {
+            let msg = QBIMessage::Common {
+                common: QB_TIMESTAMP_BASE.clone(),
+            };
+            slow.qbi_ref.send(msg).await.unwrap();
+        }
+
+        devices[0].create("/a");
+        tokio::time::timeout(Duration::from_secs(1), devices[0].sync(&mut master))
+            .await
+            .expect("the slow interface blocks the master");
+        settle(&mut master, &mut devices).await;
+
+        assert!(devices[1].changemap.contains(&file("/a")));
+    }
+
+    #[tokio::test]
+    async fn slow_interface_does_not_block_reply() {
+        let root = tempfile::tempdir().unwrap();
+        let mut master = QBMaster::init(QBFSWrapper::new(root.path())).await;
+        let mut devices = [
+            Device::attach(&mut master).await,
+            Device::attach(&mut master).await,
+        ];
+        devices[0].create("/a");
+        devices[0].sync(&mut master).await;
+        settle(&mut master, &mut devices).await;
+
+        // stalls after the handshake, with a full channel
+        let mut slow = Device::attach_with(&mut master, 1, 1).await;
+        let msg = QBIHostMessage::Message(QBIMessage::Common {
+            common: QB_TIMESTAMP_BASE.clone(),
+        });
+        while slow.qbi_ref.tx.try_send(msg.clone()).is_ok() {}
+        // the sync of the master is not answered, so the master
+        // answers the sync of the slow device instead
+        let msg = QBIMessage::Error {
+            code: error_code::OTHER,
+            msg: String::new(),
+        };
+        master.iprocess((slow.ext.clone(), msg.into())).await;
+
+        slow.create("/b");
+        tokio::time::timeout(Duration::from_secs(1), slow.sync(&mut master))
+            .await
+            .expect("the slow interface blocks the master");
+        assert!(master.changemap().contains(&file("/b")));
+    }
+
+    #[tokio::test]
+    async fn reject_internal_sync() {
+        let root = tempfile::tempdir().unwrap();
+        let mut master = QBMaster::init(QBFSWrapper::new(root.path())).await;
+        let mut devices = [
+            Device::attach(&mut master).await,
+            Device::attach(&mut master).await,
+        ];
+        devices[1].create("/b");
+        devices[1].sync(&mut master).await;
+        let before = master.changemap().len();
+
+        devices[0].create("/.qb/devices");
+        devices[0].sync(&mut master).await;
+
+        assert_eq!(master.changemap().len(), before);
+        assert!(master.changemap().contains(&file("/b")));
+        assert!(!master.changemap().contains(&file("/.qb/devices")));
+    }
+
+    #[tokio::test]
+    async fn prune_synced_delete() {
+        let root = tempfile::tempdir().unwrap();
+        let mut master = QBMaster::init(QBFSWrapper::new(root.path())).await;
+        let mut devices = [
+            Device::attach(&mut master).await,
+            Device::attach(&mut master).await,
+        ];
+        // has never synchronized, so it does not block pruning
+        let _fresh = Device::attach(&mut master).await;
+
+        devices[0].create("/kept");
+        devices[0].create("/deleted");
+        devices[0].sync(&mut master).await;
+        settle(&mut master, &mut devices).await;
+        assert!(master
+            .changemap()
+            .iter()
+            .any(|(r, _)| r == &file("/deleted")));
+
+        devices[0].record("/deleted", QBChangeKind::Delete);
+        devices[0].sync(&mut master).await;
+        // the other device has not seen the delete yet
+        let has_deleted = |master: &QBMaster| {
+            master
+                .changemap()
+                .iter()
+                .any(|(r, _)| r == &file("/deleted"))
+        };
+        assert!(has_deleted(&master));
+
+        settle(&mut master, &mut devices).await;
+        assert!(!has_deleted(&master));
+        assert!(master.changemap().contains(&file("/kept")));
+        assert!(!devices[1].changemap.contains(&file("/deleted")));
     }
 }
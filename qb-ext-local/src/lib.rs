@@ -1,59 +1,146 @@
 use core::panic;
-use std::{collections::HashMap, path::PathBuf, time::Duration};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use bitcode::{Decode, Encode};
 use notify::{
-    event::{CreateKind, ModifyKind, RemoveKind, RenameMode},
+    event::{CreateKind, DataChange, ModifyKind, RemoveKind, RenameMode},
     Event, EventKind, RecursiveMode, Watcher,
 };
 use qb_core::{
-    change::{QBChange, QBChangeKind},
+    change::{clock::QBVectorClock, QBChange, QBChangeKind, QBChangeMap},
     device::QBDeviceId,
-    fs::{QBFileDiff, QBFS},
-    path::{qbpaths::INTERNAL, QBPath, QBResource},
-    time::QBTimeStampRecorder,
+    fs::{self, order_changes, QBFSChange, QBFileDiff, QBFS},
+    ignore::{QBIgnore, EDITOR_TEMP_PATTERNS},
+    path::{
+        qbpaths::{INTERNAL, INTERNAL_PENDING, ROOT},
+        QBPath, QBResource,
+    },
+    time::{QBTimeStampRecorder, QBTimeStampUnique},
 };
 use qb_ext::{
     interface::{QBIChannel, QBIContext, QBIHostMessage, QBIMessage},
-    QBExtSetup,
+    QBExtSetup, QBExtSetupError,
 };
+use qb_proto::error_code;
 use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, info, warn};
 
 pub type QBILocalSetup = QBILocal;
 #[derive(Encode, Decode, Serialize, Deserialize)]
 pub struct QBILocal {
     pub path: String,
+    /// Canonicalize line endings of text files before diffing
+    #[serde(default)]
+    pub normalize_eol: bool,
+    /// Synchronize the temporary files editors create, which are
+    /// skipped by default, see [qb_core::ignore::EDITOR_TEMP_PATTERNS]
+    #[serde(default)]
+    pub sync_temp_files: bool,
+    /// Additional patterns of temporary files to skip
+    #[serde(default)]
+    pub temp_patterns: Vec<String>,
+    /// The device id of the file system in hex format, generated if not given
+    #[serde(default)]
+    pub device_id: Option<String>,
 }
 
 impl QBIContext for QBILocal {
-    async fn run(self, host_id: QBDeviceId, com: QBIChannel) {
+    fn describe(&self) -> serde_json::Value {
+        serde_json::json!({
+            "path": self.path,
+            "normalize_eol": self.normalize_eol,
+            "sync_temp_files": self.sync_temp_files,
+            "temp_patterns": self.temp_patterns,
+            "device_id": self.device_id,
+        })
+    }
+
+    async fn run(&mut self, host_id: QBDeviceId, com: QBIChannel) {
         Runner::init(self, host_id, com).await.run().await;
     }
 }
 
 impl QBExtSetup<QBILocal> for QBILocalSetup {
-    async fn setup(self) -> QBILocal {
-        let mut fs = QBFS::init(self.path.clone()).await;
-        fs.devices.host_id = QBDeviceId::generate();
-        fs.save().await.unwrap();
-        self
+    async fn setup(self) -> Result<QBILocal, QBExtSetupError> {
+        let id = match &self.device_id {
+            Some(id) => QBDeviceId::from_hex(id)?,
+            None => QBDeviceId::generate(),
+        };
+        let mut fs = QBFS::init_with_device_id(self.path.clone(), id).await;
+        fs.save().await?;
+        Ok(self)
     }
 }
 
+/// The amount of changes applied per iteration of the event loop, so
+/// that watcher and control messages are not starved by a large sync.
+const APPLY_BATCH_SIZE: usize = 32;
+
+/// The amount of watcher events per second above which changes are
+/// coalesced before being committed to the changemap (e.g. git checkout).
+const BURST_THRESHOLD: u32 = 64;
+
+/// The time without watcher events after which a burst is committed.
+const BURST_WINDOW: Duration = Duration::from_millis(500);
+
+/// The time after which applying changes is retried, if the device is full.
+const OUT_OF_SPACE_RETRY: Duration = Duration::from_secs(30);
+
+/// A sync which has been merged, but whose changes have
+/// not been fully applied to the file system yet.
+#[derive(Encode, Decode)]
+struct PendingSync {
+    /// the common change the sync was based on
+    common: QBTimeStampUnique,
+    /// the common change after the sync has been applied
+    new_common: QBTimeStampUnique,
+    /// the local changes to send back to the remote
+    local: QBChangeMap,
+    /// the clock of the changemap after merging, changes
+    /// which it has not seen have been recorded since
+    clock: QBVectorClock,
+    /// the changes which still have to be applied
+    changes: VecDeque<QBFSChange>,
+}
+
 pub struct Runner {
     com: QBIChannel,
+    /// canceled by the host, if we do not stop in time
+    cancel: CancellationToken,
     fs: QBFS,
     syncing: bool,
+    /// the parts of the sync which is currently being received
+    parts: QBChangeMap,
+    pending: Option<PendingSync>,
+    /// the time until which applying the pending sync is paused,
+    /// because the device is full
+    paused_until: Option<Instant>,
     watcher_skip: Vec<PathBuf>,
     host_id: QBDeviceId,
     recorder: QBTimeStampRecorder,
     trackers: HashMap<usize, QBPath>,
+    /// matches the temporary files of editors, which are skipped
+    temp_filter: QBIgnore,
+    /// the changes of the current burst of watcher events
+    burst: QBChangeMap,
+    /// the start of the current rate window and the events counted in it
+    rate: (Instant, u32),
+    /// the time of the last watcher event
+    last_event: Instant,
 }
 
 impl Runner {
-    async fn init(cx: QBILocal, host_id: QBDeviceId, com: QBIChannel) -> Self {
-        let fs = QBFS::init(cx.path).await;
+    async fn init(cx: &QBILocal, host_id: QBDeviceId, com: QBIChannel) -> Self {
+        let fs = QBFS::init(&cx.path)
+            .await
+            .with_normalize_eol(cx.normalize_eol);
 
         com.send(QBIMessage::Device {
             device_id: fs.devices.host_id.clone(),
@@ -66,12 +153,51 @@ impl Runner {
 
         let recorder = QBTimeStampRecorder::from(fs.devices.host_id.clone());
 
+        // resume the sync which was pending when the runner has been stopped
+        let pending = match fs.wrapper.load(INTERNAL_PENDING.as_ref()).await {
+            Ok(pending) => {
+                let fspath = fs.wrapper.fspath(INTERNAL_PENDING.as_ref());
+                if let Err(err) = tokio::fs::remove_file(&fspath).await {
+                    warn!("could not remove {}: {}", fspath.display(), err);
+                }
+                Some(pending)
+            }
+            Err(fs::Error::IO { source, .. }) if source.kind() == ErrorKind::NotFound => None,
+            Err(err) => {
+                warn!("could not load the pending sync: {}", err);
+                None
+            }
+        };
+
+        let builtin = match cx.sync_temp_files {
+            true => &[][..],
+            false => EDITOR_TEMP_PATTERNS,
+        };
+        let patterns = builtin
+            .iter()
+            .copied()
+            .chain(cx.temp_patterns.iter().map(String::as_str))
+            .collect::<Vec<_>>();
+        let temp_filter =
+            QBIgnore::parse(ROOT.clone(), patterns.join("\n")).unwrap_or_else(|err| {
+                warn!("skipping invalid temp patterns: {}", err);
+                QBIgnore::parse(ROOT.clone(), builtin.join("\n")).unwrap()
+            });
+
         Self {
             syncing: false,
+            parts: Default::default(),
+            pending,
+            paused_until: None,
             watcher_skip: Vec::new(),
             trackers: Default::default(),
+            temp_filter,
+            burst: Default::default(),
+            rate: (Instant::now(), 0),
+            last_event: Instant::now(),
             host_id,
             fs,
+            cancel: com.cancel_token(),
             com,
             recorder,
         }
@@ -85,52 +211,238 @@ impl Runner {
                 self.fs.devices.set_common(&self.host_id, common);
                 self.fs.save_devices().await.unwrap();
             }
-            QBIMessage::Sync {
-                common,
-                changes: remote,
-            } => {
+            QBIMessage::SyncPart { changes } => self.parts.append_map(changes),
+            QBIMessage::Sync { common, changes } => {
+                let mut remote = std::mem::take(&mut self.parts);
+                remote.append_map(changes);
+
+                // a sync is based on the previous one, so finish it first
+                if !self.finish_pending().await {
+                    warn!("could not sync: the previous sync is still pending");
+                    self.syncing = false;
+                    self.com
+                        .send(QBIMessage::Error {
+                            code: error_code::APPLY_FAILED,
+                            msg: "the previous sync is still pending".into(),
+                        })
+                        .await;
+                    return;
+                }
+                self.commit_burst();
+
                 assert!(self.fs.devices.get_common(&self.host_id).clone() == common);
 
-                let local = self.fs.changemap.since(&common);
+                let local = match self.fs.changemap.since(&common) {
+                    Ok(local) => local,
+                    Err(err) => {
+                        warn!("could not sync: {}", err);
+                        self.syncing = false;
+                        self.com
+                            .send(QBIMessage::Error {
+                                code: error_code::TRUNCATED,
+                                msg: err.to_string(),
+                            })
+                            .await;
+                        return;
+                    }
+                };
 
-                // Apply changes
+                // Merge changes, they are applied in batches by the event loop
                 let mut changemap = local.clone();
-                let changes = changemap.merge(remote).unwrap();
+                let changes = match changemap.merge(remote) {
+                    Ok(changes) => changes,
+                    Err(err) => {
+                        // reject the sync, keeping our own changes
+                        warn!("could not merge changes: {}", err);
+                        self.fs.changemap.append_map(local);
+                        self.syncing = false;
+                        self.com
+                            .send(QBIMessage::Error {
+                                code: error_code::APPLY_FAILED,
+                                msg: err,
+                            })
+                            .await;
+                        return;
+                    }
+                };
+                let fschanges = match self.fs.to_fschanges(changes).await {
+                    Ok(fschanges) => fschanges,
+                    Err(err) => {
+                        // reject the sync, keeping our own changes
+                        warn!("could not convert changes: {}", err);
+                        self.fs.changemap.append_map(local);
+                        self.syncing = false;
+                        let code = match err {
+                            fs::Error::MissingBase { .. } => error_code::MISSING_BASE,
+                            _ => error_code::APPLY_FAILED,
+                        };
+                        self.com
+                            .send(QBIMessage::Error {
+                                code,
+                                msg: err.to_string(),
+                            })
+                            .await;
+                        return;
+                    }
+                };
+                let clock = changemap.clock().clone();
                 self.fs.changemap.append_map(changemap);
-                let fschanges = self.fs.to_fschanges(changes);
-                self.watcher_skip.append(
-                    &mut fschanges
-                        .iter()
-                        .map(|e| self.fs.wrapper.fspath(&e.resource))
-                        .collect(),
-                );
-                self.fs.apply_changes(fschanges).await.unwrap();
 
                 // TODO: implement conversion code
                 //let fschanges = self.fs.table.to_fschanges(fschanges);
                 //self.fs.apply_changes(fschanges).await.unwrap();
 
-                let new_common = self.fs.changemap.head().clone();
-                self.fs.devices.set_common(&self.host_id, new_common);
+                self.pending = Some(PendingSync {
+                    common,
+                    new_common: self.fs.changemap.head().clone(),
+                    local,
+                    clock,
+                    changes: order_changes(fschanges).into(),
+                });
+            }
+            QBIMessage::Broadcast { msg } => debug!("BROADCAST: {}", msg),
+            QBIMessage::Error { code, msg } => {
+                warn!("master reported error {}: {}", code, msg);
+                // the master will not answer the sync we sent
+                self.syncing = false;
+            }
+            val => warn!("unexpected message: {}", val),
+        }
+    }
 
-                // Send sync to remote
-                if !self.syncing {
-                    self.com
-                        .send(QBIMessage::Sync {
-                            common,
-                            changes: local,
-                        })
-                        .await;
+    /// Apply the next batch of changes of the pending sync.
+    async fn apply_batch(&mut self) {
+        let Some(pending) = self.pending.as_mut() else {
+            return;
+        };
+
+        let len = pending.changes.len().min(APPLY_BATCH_SIZE);
+        let batch = pending.changes.drain(..len).collect::<Vec<_>>();
+        self.watcher_skip
+            .extend(batch.iter().map(|e| self.fs.wrapper.fspath(&e.resource)));
+        if let Err(err) = self.fs.apply_changes(batch.clone()).await {
+            if let fs::Error::OutOfSpace { .. } = err {
+                // the batch has been rolled back, so it is retried as a whole
+                warn!("could not apply changes: {}, retrying later", err);
+                for change in batch.into_iter().rev() {
+                    pending.changes.push_front(change);
                 }
+                self.paused_until = Some(Instant::now() + OUT_OF_SPACE_RETRY);
+                return;
+            }
 
-                self.syncing = false;
+            // reject the sync, keeping our own changes
+            warn!("could not apply changes: {}", err);
+            self.rollback_pending().await;
+            self.syncing = false;
+            let code = match err {
+                fs::Error::MissingBase { .. } => error_code::MISSING_BASE,
+                _ => error_code::APPLY_FAILED,
+            };
+            self.com
+                .send(QBIMessage::Error {
+                    code,
+                    msg: err.to_string(),
+                })
+                .await;
+            return;
+        }
+
+        if pending.changes.is_empty() {
+            self.complete_pending().await;
+        }
+    }
 
-                // save the changes applied
-                self.fs.save().await.unwrap();
+    /// Apply all remaining changes of the pending sync. Returns false if the
+    /// device is full or the host has canceled, in which case the sync is
+    /// kept pending. A batch is never interrupted, as it would not be rolled back.
+    async fn finish_pending(&mut self) -> bool {
+        self.paused_until = None;
+        while self.pending.is_some() {
+            if self.cancel.is_cancelled() {
+                return false;
+            }
+            self.apply_batch().await;
+            if self.paused_until.is_some() {
+                return false;
             }
-            QBIMessage::Broadcast { msg } => debug!("BROADCAST: {}", msg),
-            val => warn!("unexpected message: {}", val),
         }
+        true
+    }
+
+    /// Save the pending sync, so that applying it is resumed once the
+    /// runner is started again. It is rolled back if it cannot be saved.
+    async fn save_pending(&mut self) {
+        let Some(pending) = self.pending.as_ref() else {
+            return;
+        };
+
+        if let Err(err) = self
+            .fs
+            .wrapper
+            .save(INTERNAL_PENDING.as_ref(), pending)
+            .await
+        {
+            warn!("could not save the pending sync: {}", err);
+            self.rollback_pending().await;
+        }
+    }
+
+    /// Drop the pending sync, restoring the changemap to its state before
+    /// the sync has been merged. The common change is not moved forward,
+    /// so the remote changes are sent again by the next sync.
+    ///
+    /// Only the batch which failed is rolled back on disk, the batches
+    /// applied before are kept, as their trash has been removed already.
+    /// The tree is rebuilt from disk, so that it describes them.
+    async fn rollback_pending(&mut self) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+
+        if let Err(err) = self.fs.rebuild_tree().await {
+            warn!("could not rebuild the tree: {}", err);
+        }
+
+        let merged = match self.fs.changemap.since(&pending.common) {
+            Ok(merged) => merged,
+            Err(err) => {
+                warn!("could not roll back sync: {}", err);
+                return;
+            }
+        };
+        self.fs.changemap.append_map(pending.local);
+        // keep the changes which have been recorded after merging
+        self.fs.changemap.append(
+            merged
+                .iter()
+                .filter(|(_, change)| !pending.clock.contains(&change.timestamp))
+                .map(|(resource, change)| (resource.clone(), change.clone()))
+                .collect(),
+        );
+    }
+
+    /// Complete the pending sync, after all of its changes have been applied.
+    async fn complete_pending(&mut self) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+
+        self.fs
+            .devices
+            .set_common(&self.host_id, pending.new_common);
+
+        // Send sync to remote
+        if !self.syncing {
+            for msg in QBIMessage::sync(pending.common, pending.local) {
+                self.com.send(msg).await;
+            }
+        }
+
+        self.syncing = false;
+
+        // save the changes applied
+        self.fs.save().await.unwrap();
     }
 
     // TODO: filter events caused by apply
@@ -155,9 +467,17 @@ impl Runner {
             EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
                 self.fs.wrapper.to_resource(path).await.unwrap()
             }
+            // depending on the platform, symlinks are reported as files or others
+            EventKind::Create(CreateKind::File | CreateKind::Other)
+            | EventKind::Remove(RemoveKind::File | RemoveKind::Other)
+                if self.is_symlink(fspath, &path).await =>
+            {
+                path.symlink()
+            }
             EventKind::Create(CreateKind::File)
             | EventKind::Remove(RemoveKind::File)
-            | EventKind::Modify(ModifyKind::Data(_)) => path.file(),
+            | EventKind::Modify(ModifyKind::Data(_))
+            | EventKind::Modify(ModifyKind::Metadata(_)) => path.file(),
             _ => return,
         };
 
@@ -166,14 +486,54 @@ impl Runner {
             return;
         }
 
+        // skip temporary files of editors
+        if self.temp_filter.matched(&resource).is_ignore() {
+            debug!("skip temporary {}", resource);
+            if let Some(tracker) = event.tracker() {
+                self.trackers.remove(&tracker);
+            }
+            return;
+        }
+
+        let exists = self
+            .fs
+            .tree
+            .get(&resource.path)
+            .is_some_and(|node| !node.is_none());
+        let kind = match event.kind {
+            // editors save atomically by renaming a temporary file onto
+            // the file, which we record as an update of the file instead
+            EventKind::Modify(ModifyKind::Name(RenameMode::To))
+                if self.is_temp_rename(&event, &resource) =>
+            {
+                self.trackers.remove(&event.tracker().unwrap());
+                match exists {
+                    true => EventKind::Modify(ModifyKind::Data(DataChange::Any)),
+                    false => EventKind::Create(CreateKind::File),
+                }
+            }
+            // the file has been moved away as a backup before
+            // being written again, which we have skipped
+            EventKind::Create(CreateKind::File) if exists => {
+                EventKind::Modify(ModifyKind::Data(DataChange::Any))
+            }
+            kind => kind,
+        };
+
         if self.watcher_skip.iter().any(|e| e == fspath) {
             debug!("skip {:?}", resource);
             return;
         }
 
-        let entries = match event.kind {
+        let mut entries = match kind {
             EventKind::Modify(ModifyKind::Data(_)) => {
-                let kind = self.fs.diff(&resource).await.unwrap();
+                let kind = match self.fs.diff(&resource).await {
+                    Ok(kind) => kind,
+                    Err(err) => {
+                        warn!("could not diff {}: {}", resource, err);
+                        return;
+                    }
+                };
                 match kind {
                     Some(QBFileDiff::Text(diff)) => {
                         vec![(
@@ -190,9 +550,56 @@ impl Runner {
                             ),
                         )]
                     }
-                    None => return,
+                    Some(QBFileDiff::BinaryPatch { base_hash, patch }) => {
+                        vec![(
+                            resource,
+                            QBChange::new(
+                                self.recorder.record(),
+                                QBChangeKind::BinaryDiff { base_hash, patch },
+                            ),
+                        )]
+                    }
+                    Some(QBFileDiff::Kind { from, to }) => {
+                        vec![
+                            (
+                                QBResource::new(resource.path.clone(), from),
+                                QBChange::new(self.recorder.record(), QBChangeKind::Delete),
+                            ),
+                            (
+                                QBResource::new(resource.path, to),
+                                QBChange::new(self.recorder.record(), QBChangeKind::Create),
+                            ),
+                        ]
+                    }
+                    // the tree has been updated already
+                    Some(QBFileDiff::Touched) | None => return,
                 }
             }
+            EventKind::Modify(ModifyKind::Metadata(_)) => {
+                let xattrs = match self.fs.diff_xattrs(&resource).await {
+                    Ok(xattrs) => xattrs,
+                    Err(err) => {
+                        warn!("could not diff xattrs of {}: {}", resource, err);
+                        return;
+                    }
+                };
+                if xattrs.is_empty() {
+                    return;
+                }
+
+                xattrs
+                    .into_iter()
+                    .map(|(name, value)| {
+                        (
+                            resource.clone(),
+                            QBChange::new(
+                                self.recorder.record(),
+                                QBChangeKind::XAttr { name, value },
+                            ),
+                        )
+                    })
+                    .collect()
+            }
             EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
                 let ts = self.recorder.record();
                 let previouspath = self.trackers.remove(&event.tracker().unwrap()).unwrap();
@@ -218,13 +625,78 @@ impl Runner {
             _ => panic!("this should not happen"),
         };
 
-        let fschanges = self.fs.to_fschanges(entries.clone());
+        // keep the modification time when the change is applied elsewhere
+        for (resource, change) in entries.iter_mut() {
+            let writes = change.kind.is_update() || matches!(change.kind, QBChangeKind::Create);
+            if writes && resource.is_file() {
+                change.mtime = self.fs.wrapper.mtime(&resource.path).await;
+            }
+        }
+
+        let fschanges = match self.fs.to_fschanges(entries.clone()).await {
+            Ok(fschanges) => fschanges,
+            Err(err) => {
+                warn!("could not convert changes: {}", err);
+                return;
+            }
+        };
         self.fs.tree.notify_changes(fschanges.iter());
-        self.fs.changemap.append(entries);
+        self.commit(entries);
+    }
+
+    /// Returns whether the path is a symlink. Removed symlinks can not be
+    /// inspected anymore, so the changes recorded for them are used then.
+    async fn is_symlink(&self, fspath: &Path, path: &QBPath) -> bool {
+        match tokio::fs::symlink_metadata(fspath).await {
+            Ok(metadata) => metadata.file_type().is_symlink(),
+            Err(_) => self.fs.changemap.contains(&path.clone().symlink()),
+        }
+    }
+
+    /// Returns whether the rename event moves a temporary file onto the resource.
+    fn is_temp_rename(&self, event: &Event, resource: &QBResource) -> bool {
+        let Some(from) = event.tracker().and_then(|t| self.trackers.get(&t)) else {
+            return false;
+        };
+        let from = QBResource::new(from.clone(), resource.kind.clone());
+        self.temp_filter.matched(&from).is_ignore()
+    }
+
+    /// Commit the entries to the changemap, or to the current burst
+    /// if the watcher is emitting events at a high rate.
+    fn commit(&mut self, entries: Vec<(QBResource, QBChange)>) {
+        let now = Instant::now();
+        if now.duration_since(self.rate.0) >= Duration::from_secs(1) {
+            self.rate = (now, 0);
+        }
+        self.rate.1 += 1;
+        self.last_event = now;
+
+        if self.rate.1 > BURST_THRESHOLD || !self.burst.is_empty() {
+            self.burst.append(entries);
+        } else {
+            self.fs.changemap.append(entries);
+        }
+    }
+
+    /// Minify the changes of the current burst and commit them to the changemap.
+    fn commit_burst(&mut self) {
+        if self.burst.is_empty() {
+            return;
+        }
+
+        let mut burst = std::mem::take(&mut self.burst);
+        let len = burst.len();
+        burst.minify();
+        debug!("commit burst: {} changes minified to {}", len, burst.len());
+        self.fs.changemap.append_map(burst);
     }
 
     fn should_sync(&mut self) -> bool {
-        !self.syncing && self.fs.changemap.head() != self.fs.devices.get_common(&self.host_id)
+        !self.syncing
+            && self.pending.is_none()
+            && self.burst.is_empty()
+            && self.fs.changemap.head() != self.fs.devices.get_common(&self.host_id)
     }
 
     async fn sync(&mut self) {
@@ -234,14 +706,36 @@ impl Runner {
 
         // Complete transaction
         let common = self.fs.devices.get_common(&self.host_id).clone();
-        let mut changes = self.fs.changemap.since_cloned(&common);
+        let mut changes = match self.fs.changemap.since_cloned(&common) {
+            Ok(changes) => changes,
+            Err(err) => {
+                warn!("could not sync: {}", err);
+                self.syncing = false;
+                return;
+            }
+        };
         changes.minify();
 
         // save the changes applied
         self.fs.save().await.unwrap();
 
         // notify remote
-        self.com.send(QBIMessage::Sync { common, changes }).await;
+        for msg in QBIMessage::sync(common, changes) {
+            self.com.send(msg).await;
+        }
+    }
+
+    /// Finish the pending sync and save the changes, before the runner stops.
+    async fn stop(&mut self) {
+        if !self.finish_pending().await {
+            warn!("could not finish the pending sync, saving it");
+            self.save_pending().await;
+        }
+        self.commit_burst();
+
+        if let Err(err) = self.fs.save().await {
+            warn!("could not save: {}", err);
+        }
     }
 
     async fn run(mut self) {
@@ -264,6 +758,7 @@ impl Runner {
                         QBIHostMessage::Message(msg) => self.on_message(msg).await,
                         QBIHostMessage::Stop => {
                             info!("stopping...");
+                            self.stop().await;
                             break
                         }
                         _ => unimplemented!("unknown message: {msg:?}"),
@@ -272,6 +767,15 @@ impl Runner {
                 Some(Ok(event)) = watcher_rx.recv() => {
                     self.on_watcher(event).await;
                 },
+                _ = tokio::time::sleep_until(self.last_event + BURST_WINDOW), if !self.burst.is_empty() => {
+                    self.commit_burst();
+                },
+                _ = std::future::ready(()), if self.pending.is_some() && self.paused_until.is_none() => {
+                    self.apply_batch().await;
+                },
+                _ = tokio::time::sleep_until(self.paused_until.unwrap_or_else(Instant::now)), if self.paused_until.is_some() => {
+                    self.paused_until = None;
+                },
                 _ = tokio::time::sleep(Duration::from_secs(3)), if self.should_sync() => {
                     self.sync().await;
                 },
@@ -282,3 +786,307 @@ impl Runner {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use qb_core::diff::QBDiff;
+    use qb_ext::{interface::QBISlaveMessage, QBExtId};
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    /// The ends of the channel of a runner which are held by the master.
+    type Master = (
+        mpsc::Receiver<(QBExtId, QBISlaveMessage)>,
+        mpsc::Sender<QBIHostMessage>,
+    );
+
+    async fn setup(root: &Path) -> QBILocal {
+        let cx = QBILocal {
+            path: root.to_str().unwrap().to_owned(),
+            normalize_eol: false,
+            sync_temp_files: false,
+            temp_patterns: Vec::new(),
+            device_id: None,
+        };
+        cx.setup().await.unwrap()
+    }
+
+    async fn runner(cx: &QBILocal, host_id: &QBDeviceId) -> (Runner, Master) {
+        let (slave_tx, slave_rx) = mpsc::channel(16);
+        let (host_tx, host_rx) = mpsc::channel(16);
+        let com = QBIChannel::new(QBExtId(0), slave_tx, host_rx);
+        let runner = Runner::init(cx, host_id.clone(), com).await;
+        (runner, (slave_rx, host_tx))
+    }
+
+    fn file(path: &str) -> QBResource {
+        QBPath::try_from(path).unwrap().file()
+    }
+
+    /// Returns the codes of the errors the runner has reported to the master.
+    fn errors(master: &mut Master) -> Vec<u16> {
+        std::iter::from_fn(|| master.0.try_recv().ok())
+            .filter_map(|(_, msg)| match msg {
+                QBISlaveMessage::Message(QBIMessage::Error { code, .. }) => Some(code),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Create a sync from the master, which changes the files.
+    fn sync(
+        runner: &Runner,
+        host_id: &QBDeviceId,
+        entries: Vec<(&str, QBChangeKind)>,
+    ) -> QBIMessage {
+        let mut recorder = QBTimeStampRecorder::from(QBDeviceId::generate());
+        let mut changes = QBChangeMap::default();
+        for (path, kind) in entries {
+            changes.push((file(path), QBChange::new(recorder.record(), kind)));
+        }
+        QBIMessage::Sync {
+            common: runner.fs.devices.get_common(host_id).clone(),
+            changes,
+        }
+    }
+
+    #[tokio::test]
+    async fn replace_file_with_dir() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("x"), b"contents").unwrap();
+        let host_id = QBDeviceId::generate();
+        let cx = setup(root.path()).await;
+        let (mut runner, _master) = runner(&cx, &host_id).await;
+        assert!(runner.fs.tree.get_resource(&file("/x")).is_some());
+
+        std::fs::remove_file(root.path().join("x")).unwrap();
+        std::fs::create_dir(root.path().join("x")).unwrap();
+        let event = Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+            .add_path(root.path().join("x"));
+        runner.on_watcher(event).await;
+
+        // the file is deleted and the directory created instead of diffing it
+        let dir = QBPath::try_from("/x").unwrap().dir();
+        let mut changes = runner.fs.changemap.iter().collect::<Vec<_>>();
+        changes.sort_by_key(|(_, change)| &change.timestamp);
+        assert!(
+            matches!(
+                changes[..],
+                [(deleted, QBChange { kind: QBChangeKind::Delete, .. }),
+                 (created, QBChange { kind: QBChangeKind::Create, .. })]
+                if deleted == &file("/x") && created == &dir
+            ),
+            "{changes:?}"
+        );
+        assert!(runner.fs.tree.get_resource(&dir).is_some());
+    }
+
+    #[tokio::test]
+    async fn rollback_failed_sync() {
+        let root = tempfile::tempdir().unwrap();
+        // a directory occupies the path the master creates a file at
+        std::fs::create_dir(root.path().join("taken")).unwrap();
+        let host_id = QBDeviceId::generate();
+        let cx = setup(root.path()).await;
+        let (mut runner, mut master) = runner(&cx, &host_id).await;
+        let common = runner.fs.devices.get_common(&host_id).clone();
+
+        let change = QBChange::new(runner.recorder.record(), QBChangeKind::Create);
+        runner.commit(vec![(file("/local"), change)]);
+        let entries = vec![
+            ("/new", QBChangeKind::Create),
+            ("/taken", QBChangeKind::Create),
+        ];
+        let msg = sync(&runner, &host_id, entries);
+        runner.on_message(msg).await;
+        assert!(runner.pending.is_some());
+
+        // recorded while the sync is pending
+        let change = QBChange::new(runner.recorder.record(), QBChangeKind::Create);
+        runner.commit(vec![(file("/later"), change)]);
+        runner.apply_batch().await;
+
+        assert!(runner.pending.is_none());
+        assert!(!root.path().join("new").exists());
+        assert_eq!(runner.fs.devices.get_common(&host_id), &common);
+        assert!(runner.fs.changemap.contains(&file("/local")));
+        assert!(runner.fs.changemap.contains(&file("/later")));
+        assert!(!runner.fs.changemap.contains(&file("/new")));
+        assert!(!runner.fs.changemap.contains(&file("/taken")));
+        assert_eq!(errors(&mut master), vec![error_code::APPLY_FAILED]);
+    }
+
+    #[tokio::test]
+    async fn keep_applied_batches() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("taken")).unwrap();
+        let host_id = QBDeviceId::generate();
+        let cx = setup(root.path()).await;
+        let (mut runner, mut master) = runner(&cx, &host_id).await;
+
+        let paths = (0..APPLY_BATCH_SIZE)
+            .map(|i| format!("/{i}"))
+            .collect::<Vec<_>>();
+        let mut entries = paths
+            .iter()
+            .map(|path| (path.as_str(), QBChangeKind::Create))
+            .collect::<Vec<_>>();
+        entries.push(("/taken", QBChangeKind::Create));
+        let msg = sync(&runner, &host_id, entries);
+        runner.on_message(msg).await;
+
+        // the change which fails is applied in the second batch
+        let pending = runner.pending.as_mut().unwrap();
+        let taken = pending
+            .changes
+            .iter()
+            .position(|change| change.resource == file("/taken"))
+            .unwrap();
+        let taken = pending.changes.remove(taken).unwrap();
+        pending.changes.push_back(taken);
+        runner.apply_batch().await;
+        assert!(runner.pending.is_some());
+        runner.apply_batch().await;
+
+        assert!(runner.pending.is_none());
+        for path in paths.iter() {
+            assert!(root.path().join(&path[1..]).is_file());
+            assert!(runner.fs.tree.get(file(path)).is_some());
+        }
+        assert!(!runner.fs.changemap.contains(&file(&paths[0])));
+        assert_eq!(errors(&mut master), vec![error_code::APPLY_FAILED]);
+    }
+
+    #[tokio::test]
+    async fn reject_internal_sync() {
+        let root = tempfile::tempdir().unwrap();
+        let host_id = QBDeviceId::generate();
+        let cx = setup(root.path()).await;
+        let (mut runner, mut master) = runner(&cx, &host_id).await;
+        let devices = std::fs::read(root.path().join(".qb/devices")).unwrap();
+
+        let change = QBChange::new(runner.recorder.record(), QBChangeKind::Create);
+        runner.commit(vec![(file("/local"), change)]);
+        let before = runner.fs.changemap.len();
+        let kind = QBChangeKind::UpdateBinary(b"corrupted".to_vec());
+        let msg = sync(&runner, &host_id, vec![("/.qb/devices", kind)]);
+        runner.on_message(msg).await;
+
+        assert!(runner.pending.is_none());
+        assert_eq!(runner.fs.changemap.len(), before);
+        assert!(runner.fs.changemap.contains(&file("/local")));
+        assert_eq!(
+            std::fs::read(root.path().join(".qb/devices")).unwrap(),
+            devices
+        );
+        assert_eq!(errors(&mut master), vec![error_code::APPLY_FAILED]);
+    }
+
+    #[tokio::test]
+    async fn request_missing_base() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("doc"), "local\n").unwrap();
+        let host_id = QBDeviceId::generate();
+        let cx = setup(root.path()).await;
+        let (mut runner, mut master) = runner(&cx, &host_id).await;
+
+        // the diff was computed against contents this device does not have
+        let diff = QBDiff::compute("remote\n".to_owned(), "remote, edited\n".to_owned());
+        let msg = sync(
+            &runner,
+            &host_id,
+            vec![("/doc", QBChangeKind::UpdateText(diff))],
+        );
+        runner.on_message(msg).await;
+
+        assert!(runner.pending.is_none());
+        assert_eq!(
+            std::fs::read_to_string(root.path().join("doc")).unwrap(),
+            "local\n"
+        );
+        assert_eq!(errors(&mut master), vec![error_code::MISSING_BASE]);
+    }
+
+    /// Sync an update of a file, which has been replaced by a symlink to
+    /// /dev/full, so writing it fails as if the device was full.
+    #[cfg(target_os = "linux")]
+    async fn sync_full(runner: &mut Runner, host_id: &QBDeviceId, root: &Path) {
+        std::fs::remove_file(root.join("full")).unwrap();
+        std::os::unix::fs::symlink("/dev/full", root.join("full")).unwrap();
+        let kind = QBChangeKind::UpdateBinary(b"hello".to_vec());
+        let msg = sync(runner, host_id, vec![("/full", kind)]);
+        runner.on_message(msg).await;
+        runner.apply_batch().await;
+        assert!(runner.paused_until.is_some());
+        assert!(runner.pending.is_some());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn retry_when_full() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("full"), b"").unwrap();
+        let host_id = QBDeviceId::generate();
+        let cx = setup(root.path()).await;
+        let (mut runner, mut master) = runner(&cx, &host_id).await;
+        let common = runner.fs.devices.get_common(&host_id).clone();
+        sync_full(&mut runner, &host_id, root.path()).await;
+
+        // a new sync is rejected, as it is based on the pending one
+        let msg = sync(&runner, &host_id, vec![("/other", QBChangeKind::Create)]);
+        runner.on_message(msg).await;
+        assert!(runner.pending.is_some());
+        assert!(!root.path().join("other").exists());
+        assert_eq!(errors(&mut master), vec![error_code::APPLY_FAILED]);
+
+        // space has been freed
+        std::fs::remove_file(root.path().join("full")).unwrap();
+        assert!(runner.finish_pending().await);
+        assert!(runner.pending.is_none());
+        assert_eq!(std::fs::read(root.path().join("full")).unwrap(), b"hello");
+        assert_ne!(runner.fs.devices.get_common(&host_id), &common);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn resume_after_stop() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("full"), b"").unwrap();
+        let host_id = QBDeviceId::generate();
+        let cx = setup(root.path()).await;
+        let (mut stopped, _master) = runner(&cx, &host_id).await;
+        sync_full(&mut stopped, &host_id, root.path()).await;
+        stopped.stop().await;
+        drop(stopped);
+
+        std::fs::remove_file(root.path().join("full")).unwrap();
+        let (mut resumed, _master) = runner(&cx, &host_id).await;
+        assert!(resumed.pending.is_some());
+        assert!(resumed.finish_pending().await);
+        assert_eq!(std::fs::read(root.path().join("full")).unwrap(), b"hello");
+
+        // the sync is not resumed twice
+        drop(resumed);
+        let (restarted, _master) = runner(&cx, &host_id).await;
+        assert!(restarted.pending.is_none());
+    }
+
+    #[tokio::test]
+    async fn stop_when_canceled() {
+        let root = tempfile::tempdir().unwrap();
+        let host_id = QBDeviceId::generate();
+        let cx = setup(root.path()).await;
+        let (mut runner, _master) = runner(&cx, &host_id).await;
+        let msg = sync(&runner, &host_id, vec![("/new", QBChangeKind::Create)]);
+        runner.on_message(msg).await;
+
+        // the host does not wait for the sync to be applied
+        runner.cancel.cancel();
+        runner.stop().await;
+        assert!(!root.path().join("new").exists());
+        assert!(root.path().join(".qb/pending").exists());
+    }
+}
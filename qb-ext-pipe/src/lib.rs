@@ -0,0 +1,294 @@
+//! # qbi-pipe
+//!
+//! This crate contains an interface which speaks the quixbyte
+//! protocol over a local socket (unix domain socket or named pipe).
+//! This allows external programs, written in any language, to
+//! act as a backend for synchronization.
+
+use bitcode::{Decode, Encode};
+use interprocess::local_socket::{
+    tokio::Stream, traits::tokio::Stream as _, GenericFilePath, ToFsName,
+};
+use qb_core::device::QBDeviceId;
+use qb_ext::{
+    interface::{QBIChannel, QBIContext, QBIHostMessage, QBIMessage, QBISlaveMessage},
+    QBExtSetup, QBExtSetupError,
+};
+use qb_proto::{QBPPriority, ReadWrite, DEFAULT_HEARTBEAT_INTERVAL, QBP};
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+pub type QBIPipeSetup = QBIPipe;
+#[derive(Encode, Decode, Serialize, Deserialize, Debug)]
+pub struct QBIPipe {
+    /// The path of the socket or named pipe to connect to
+    pub path: String,
+}
+
+impl QBIPipe {
+    async fn connect(&self) -> std::io::Result<Stream> {
+        let name = self.path.as_str().to_fs_name::<GenericFilePath>()?;
+        Stream::connect(name).await
+    }
+}
+
+impl QBIContext for QBIPipe {
+    fn describe(&self) -> serde_json::Value {
+        serde_json::json!({ "path": self.path })
+    }
+
+    async fn run(&mut self, host_id: QBDeviceId, com: QBIChannel) {
+        debug!("connecting to pipe: {}", self.path);
+
+        let stream = match self.connect().await {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("could not connect to pipe {}: {}", self.path, err);
+                return;
+            }
+        };
+        let runner = match Runner::init(host_id, com, stream).await {
+            Ok(runner) => runner,
+            Err(err) => {
+                warn!("could not negotiate with pipe {}: {}", self.path, err);
+                return;
+            }
+        };
+
+        info!("connected to pipe: {}", self.path);
+
+        runner.run().await;
+    }
+}
+
+impl QBExtSetup<QBIPipe> for QBIPipeSetup {
+    async fn setup(self) -> Result<QBIPipe, QBExtSetupError> {
+        // check whether the other end is listening
+        debug!("checking pipe: {}", self.path);
+        self.connect().await?;
+        Ok(self)
+    }
+}
+
+/// A runner which just proxies all incoming
+/// and outgoing messages.
+struct Runner<S> {
+    host_id: QBDeviceId,
+    com: QBIChannel,
+    /// canceled by the host, if we do not stop in time
+    cancel: CancellationToken,
+    stream: S,
+    protocol: QBP,
+}
+
+impl<S: ReadWrite> Runner<S> {
+    /// Negotiate the protocol with the program on the other end of the stream.
+    async fn init(host_id: QBDeviceId, com: QBIChannel, mut stream: S) -> qb_proto::Result<Self> {
+        let cancel = com.cancel_token();
+        let mut protocol = QBP::default().with_heartbeat(DEFAULT_HEARTBEAT_INTERVAL);
+        tokio::select! {
+            biased;
+            _ = cancel.cancelled() => return Err(qb_proto::Error::Canceled),
+            res = protocol.negotiate(&mut stream) => res?,
+        }
+        Ok(Self {
+            host_id,
+            com,
+            cancel,
+            stream,
+            protocol,
+        })
+    }
+
+    async fn run(mut self) {
+        // initialize
+        let device = QBIMessage::Device {
+            device_id: self.host_id.clone(),
+        };
+        if let Err(err) = self.protocol.send(&mut self.stream, device).await {
+            error!("could not send device id: {}", err);
+            return;
+        }
+
+        // proxy messages, reusing the buffer for receiving
+        let mut buf = Vec::new();
+        let mut heartbeat = self
+            .protocol
+            .heartbeat_interval()
+            .map(tokio::time::interval);
+        loop {
+            tokio::select! {
+                _ = async { heartbeat.as_mut().unwrap().tick().await }, if heartbeat.is_some() => {
+                    if self.protocol.is_timed_out() {
+                        error!("peer timed out");
+                        break;
+                    }
+                    if let Err(err) = self.protocol.send_heartbeat(&mut self.stream).await {
+                        warn!("could not send heartbeat: {}", err);
+                    }
+                },
+                res = self.protocol.progress_cancelable::<QBIMessage>(&mut self.stream, &mut buf, &self.cancel) => {
+                    let msg = match res {
+                        Ok(Some(msg)) => msg,
+                        // a queued message has been (partially) sent
+                        Ok(None) => continue,
+                        // the host did not wait for us to stop
+                        Err(qb_proto::Error::Canceled) => {
+                            warn!("stopping, as the host canceled");
+                            break;
+                        }
+                        // the peer reported an error, but the connection is still intact
+                        Err(qb_proto::Error::PeerError(code, msg)) => QBIMessage::Error { code, msg },
+                        // the connection is still intact, so skip the message
+                        Err(err) if err.is_recoverable() || matches!(err, qb_proto::Error::DecodeFailed { .. }) => {
+                            warn!("could not receive message: {}", err);
+                            continue;
+                        }
+                        Err(err) => {
+                            error!("connection failed: {}", err);
+                            break;
+                        }
+                    };
+                    debug!("proxy to master: {}", msg);
+                    self.com.send(QBISlaveMessage::Message(msg)).await;
+                },
+                msg = self.com.recv::<QBIHostMessage>() => {
+                    match msg {
+                        QBIHostMessage::Message(QBIMessage::Error { code, msg }) => {
+                            debug!("proxy error to remote: {} {}", code, msg);
+                            if let Err(err) = self.protocol.send_error(&mut self.stream, code, &msg).await {
+                                warn!("could not send error: {}", err);
+                            }
+                        }
+                        QBIHostMessage::Message(msg) => {
+                            debug!("proxy to remote: {}", msg);
+                            // syncs may be large, so they must not block other messages
+                            let priority = match msg {
+                                QBIMessage::SyncPart { .. } | QBIMessage::Sync { .. } => QBPPriority::Low,
+                                _ => QBPPriority::High,
+                            };
+                            if let Err(err) = self.protocol.queue(msg, priority) {
+                                warn!("could not queue message: {}", err);
+                            }
+                        }
+                        QBIHostMessage::Stop => {
+                            info!("stopping...");
+                            let res = tokio::select! {
+                                biased;
+                                _ = self.cancel.cancelled() => Err(qb_proto::Error::Canceled),
+                                res = self.protocol.flush_queue(&mut self.stream) => res,
+                            };
+                            if let Err(err) = res {
+                                warn!("could not send queued messages: {}", err);
+                            }
+                            break;
+                        }
+                        _ => warn!("unknown message: {msg:?}"),
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use qb_ext::QBExtId;
+    use tokio::{io::AsyncWriteExt, sync::mpsc};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn handshake_over_duplex() {
+        let (stream, mut peer_stream) = tokio::io::duplex(64 * 1024);
+        let (slave_tx, mut slave_rx) = mpsc::channel(8);
+        let (_host_tx, host_rx) = mpsc::channel(8);
+        let com = QBIChannel::new(QBExtId(0), slave_tx, host_rx);
+        let host_id = QBDeviceId::generate();
+
+        let runner = tokio::spawn({
+            let host_id = host_id.clone();
+            async move {
+                Runner::init(host_id, com, stream)
+                    .await
+                    .unwrap()
+                    .run()
+                    .await
+            }
+        });
+
+        let mut peer = QBP::default();
+        peer.negotiate(&mut peer_stream).await.unwrap();
+        let msg = peer.recv::<QBIMessage>(&mut peer_stream).await.unwrap();
+        assert!(matches!(msg, QBIMessage::Device { device_id } if device_id == host_id));
+
+        let peer_id = QBDeviceId::generate();
+        let device = QBIMessage::Device {
+            device_id: peer_id.clone(),
+        };
+        peer.send(&mut peer_stream, device).await.unwrap();
+        let (_, msg) = slave_rx.recv().await.unwrap();
+        assert!(matches!(
+            msg,
+            QBISlaveMessage::Message(QBIMessage::Device { device_id }) if device_id == peer_id
+        ));
+
+        // the runner stops once the peer closes the connection
+        drop(peer_stream);
+        tokio::time::timeout(Duration::from_secs(5), runner)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancel_aborts_stuck_recv() {
+        let (stream, mut peer_stream) = tokio::io::duplex(64 * 1024);
+        let (slave_tx, _slave_rx) = mpsc::channel(8);
+        let (_host_tx, host_rx) = mpsc::channel(8);
+        let cancel = CancellationToken::new();
+        let com = QBIChannel::new(QBExtId(0), slave_tx, host_rx).with_cancel(cancel.clone());
+
+        let runner = tokio::spawn(async move {
+            Runner::init(QBDeviceId::generate(), com, stream)
+                .await
+                .unwrap()
+                .run()
+                .await
+        });
+
+        let mut peer = QBP::default();
+        peer.negotiate(&mut peer_stream).await.unwrap();
+        peer.recv::<QBIMessage>(&mut peer_stream).await.unwrap();
+        // the peer stops sending in the middle of a packet
+        peer_stream.write_all(&[0; 4]).await.unwrap();
+
+        cancel.cancel();
+        tokio::time::timeout(Duration::from_secs(5), runner)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancel_aborts_negotiation() {
+        let (stream, _peer_stream) = tokio::io::duplex(64 * 1024);
+        let (slave_tx, _slave_rx) = mpsc::channel(8);
+        let (_host_tx, host_rx) = mpsc::channel(8);
+        let cancel = CancellationToken::new();
+        let com = QBIChannel::new(QBExtId(0), slave_tx, host_rx).with_cancel(cancel.clone());
+
+        // the peer never answers the negotiation
+        cancel.cancel();
+        let res = tokio::time::timeout(
+            Duration::from_secs(5),
+            Runner::init(QBDeviceId::generate(), com, stream),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(res, Err(qb_proto::Error::Canceled)));
+    }
+}
@@ -0,0 +1,767 @@
+//! # qbi-sftp
+//!
+//! This crate contains an interface which synchronizes a folder
+//! on an SFTP server. The server does not run quixbyte, so the interface
+//! detects remote changes by periodically scanning the folder and keeps
+//! its state in the internal directory of the remote folder.
+
+use std::{collections::HashMap, time::Duration};
+
+use bitcode::{Decode, Encode};
+use qb_core::{
+    change::{QBChange, QBChangeKind, QBChangeMap},
+    device::{QBDeviceId, QBDeviceTable},
+    fs::{order_changes, QBFSChange, QBFSChangeKind},
+    hash::QBHash,
+    path::{
+        qbpaths::{INTERNAL, INTERNAL_CHANGEMAP, INTERNAL_DEVICES, INTERNAL_FILETREE, ROOT},
+        QBPath, QBResource, QBResourceKind,
+    },
+    time::QBTimeStampRecorder,
+};
+use qb_ext::{
+    interface::{QBIChannel, QBIContext, QBIHostMessage, QBIMessage},
+    QBExtSetup, QBExtSetupError,
+};
+use qb_proto::error_code;
+use remote::{Error, QBSftpAuth, QBSftpRemote, QBSftpStat, Result, MAX_FILE_SIZE};
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+pub mod remote;
+
+fn default_port() -> u16 {
+    22
+}
+
+fn default_poll_interval() -> u64 {
+    30
+}
+
+pub type QBISftpSetup = QBISftp;
+#[derive(Encode, Decode, Serialize, Deserialize)]
+pub struct QBISftp {
+    /// the host name of the server
+    pub host: String,
+    /// the port of the server
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// the user to log in as
+    pub user: String,
+    /// the password to log in with
+    #[serde(default)]
+    pub password: Option<String>,
+    /// the path of the private key to log in with, used if no password is given
+    #[serde(default)]
+    pub key_path: Option<String>,
+    /// the path of the folder on the server
+    pub path: String,
+    /// the interval in seconds in which the folder is scanned for changes
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: u64,
+    /// the fingerprint of the host key, which is recorded during setup
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+}
+
+impl QBISftp {
+    async fn connect(&self) -> Result<QBSftpRemote> {
+        let auth = match (&self.password, &self.key_path) {
+            (Some(password), _) => QBSftpAuth::Password(password),
+            (None, Some(key_path)) => QBSftpAuth::Key(key_path),
+            (None, None) => QBSftpAuth::Password(""),
+        };
+        QBSftpRemote::connect(
+            &self.host,
+            self.port,
+            &self.user,
+            auth,
+            &self.path,
+            self.fingerprint.as_deref(),
+        )
+        .await
+    }
+}
+
+impl QBIContext for QBISftp {
+    fn describe(&self) -> serde_json::Value {
+        serde_json::json!({
+            "host": self.host,
+            "port": self.port,
+            "user": self.user,
+            "key_path": self.key_path,
+            "path": self.path,
+            "poll_interval": self.poll_interval,
+            "fingerprint": self.fingerprint,
+        })
+    }
+
+    async fn run(&mut self, host_id: QBDeviceId, com: QBIChannel) {
+        let remote = match self.connect().await {
+            Ok(remote) => remote,
+            Err(err) => {
+                warn!("could not connect to {}: {}", self.host, err);
+                return;
+            }
+        };
+        let poll_interval = Duration::from_secs(self.poll_interval.max(1));
+        Runner::init(remote, poll_interval, host_id, com)
+            .await
+            .run()
+            .await;
+    }
+}
+
+impl QBExtSetup<QBISftp> for QBISftpSetup {
+    async fn setup(mut self) -> std::result::Result<QBISftp, QBExtSetupError> {
+        let remote = self.connect().await?;
+        // trust the host key on first use
+        self.fingerprint = Some(remote.fingerprint().to_owned());
+
+        remote.create_dir(&ROOT).await?;
+        remote.create_dir(&INTERNAL).await?;
+        // the default device table has a newly generated host id
+        let devices = QBDeviceTable::default();
+        remote
+            .write(&INTERNAL_DEVICES, &bitcode::encode(&devices))
+            .await?;
+        Ok(self)
+    }
+}
+
+/// The state of a file in the remote folder when it was last scanned.
+#[derive(Encode, Decode, Debug, Clone)]
+struct QBSftpEntry {
+    kind: QBResourceKind,
+    size: u64,
+    mtime: u32,
+    /// the hash of the contents, if known
+    hash: Option<QBHash>,
+}
+
+impl QBSftpEntry {
+    fn new(stat: QBSftpStat, hash: Option<QBHash>) -> Self {
+        Self {
+            kind: stat.kind,
+            size: stat.size,
+            mtime: stat.mtime,
+            hash,
+        }
+    }
+}
+
+pub struct Runner {
+    com: QBIChannel,
+    /// canceled by the host, if we do not stop in time
+    cancel: CancellationToken,
+    remote: QBSftpRemote,
+    syncing: bool,
+    /// the parts of the sync which is currently being received
+    parts: QBChangeMap,
+    host_id: QBDeviceId,
+    recorder: QBTimeStampRecorder,
+    changemap: QBChangeMap,
+    devices: QBDeviceTable,
+    /// the resources of the remote folder when it was last scanned
+    index: HashMap<QBPath, QBSftpEntry>,
+    poll_interval: Duration,
+}
+
+impl Runner {
+    async fn init(
+        remote: QBSftpRemote,
+        poll_interval: Duration,
+        host_id: QBDeviceId,
+        com: QBIChannel,
+    ) -> Self {
+        let changemap = Self::load(&remote, &INTERNAL_CHANGEMAP).await;
+        let devices: QBDeviceTable = Self::load(&remote, &INTERNAL_DEVICES).await;
+        let index = Self::load(&remote, &INTERNAL_FILETREE).await;
+
+        com.send(QBIMessage::Device {
+            device_id: devices.host_id.clone(),
+        })
+        .await;
+        com.send(QBIMessage::Common {
+            common: devices.get_common(&host_id).clone(),
+        })
+        .await;
+
+        let recorder = QBTimeStampRecorder::from(devices.host_id.clone());
+
+        Self {
+            syncing: false,
+            parts: Default::default(),
+            host_id,
+            changemap,
+            devices,
+            index,
+            poll_interval,
+            remote,
+            cancel: com.cancel_token(),
+            com,
+            recorder,
+        }
+    }
+
+    /// Load the internal file at the path, falling back to the default.
+    async fn load<T: bitcode::DecodeOwned + Default>(remote: &QBSftpRemote, path: &QBPath) -> T {
+        match remote.try_read(path).await {
+            Ok(Some(contents)) => bitcode::decode(&contents).unwrap_or_else(|err| {
+                warn!("could not decode {}: {}", path, err);
+                Default::default()
+            }),
+            Ok(None) => Default::default(),
+            Err(err) => {
+                warn!("could not read {}: {}", path, err);
+                Default::default()
+            }
+        }
+    }
+
+    /// Save the state to the remote folder.
+    async fn save(&self) -> Result<()> {
+        self.remote
+            .write(&INTERNAL_CHANGEMAP, &bitcode::encode(&self.changemap))
+            .await?;
+        self.remote
+            .write(&INTERNAL_DEVICES, &bitcode::encode(&self.devices))
+            .await?;
+        self.remote
+            .write(&INTERNAL_FILETREE, &bitcode::encode(&self.index))
+            .await
+    }
+
+    async fn on_message(&mut self, msg: QBIMessage) {
+        debug!("recv {}", msg);
+
+        match msg {
+            QBIMessage::Common { common } => {
+                self.devices.set_common(&self.host_id, common);
+                if let Err(err) = self.save().await {
+                    warn!("could not save: {}", err);
+                }
+            }
+            QBIMessage::SyncPart { changes } => self.parts.append_map(changes),
+            QBIMessage::Sync { common, changes } => {
+                let mut remote = std::mem::take(&mut self.parts);
+                remote.append_map(changes);
+
+                assert!(self.devices.get_common(&self.host_id).clone() == common);
+
+                let local = match self.changemap.since(&common) {
+                    Ok(local) => local,
+                    Err(err) => {
+                        warn!("could not sync: {}", err);
+                        self.syncing = false;
+                        self.com
+                            .send(QBIMessage::Error {
+                                code: error_code::TRUNCATED,
+                                msg: err.to_string(),
+                            })
+                            .await;
+                        return;
+                    }
+                };
+
+                // Merge changes
+                let mut changemap = local.clone();
+                let changes = match changemap.merge(remote) {
+                    Ok(changes) => changes,
+                    Err(err) => {
+                        // reject the sync, keeping our own changes
+                        warn!("could not merge changes: {}", err);
+                        self.changemap.append_map(local);
+                        self.syncing = false;
+                        self.com
+                            .send(QBIMessage::Error {
+                                code: error_code::APPLY_FAILED,
+                                msg: err,
+                            })
+                            .await;
+                        return;
+                    }
+                };
+                if let Err(err) = self.apply_changes(changes).await {
+                    // reject the sync, keeping our own changes
+                    warn!("could not apply changes: {}", err);
+                    self.changemap.append_map(local);
+                    self.syncing = false;
+                    let code = match err {
+                        Error::MissingBase(..) => error_code::MISSING_BASE,
+                        _ => error_code::APPLY_FAILED,
+                    };
+                    self.com
+                        .send(QBIMessage::Error {
+                            code,
+                            msg: err.to_string(),
+                        })
+                        .await;
+                    return;
+                }
+                self.changemap.append_map(changemap);
+                self.devices
+                    .set_common(&self.host_id, self.changemap.head().clone());
+
+                // Send sync to remote
+                if !self.syncing {
+                    for msg in QBIMessage::sync(common, local) {
+                        self.com.send(msg).await;
+                    }
+                }
+
+                self.syncing = false;
+
+                // save the changes applied
+                if let Err(err) = self.save().await {
+                    warn!("could not save: {}", err);
+                }
+            }
+            QBIMessage::Broadcast { msg } => debug!("BROADCAST: {}", msg),
+            QBIMessage::Error { code, msg } => {
+                warn!("master reported error {}: {}", code, msg);
+                // the master will not answer the sync we sent
+                self.syncing = false;
+            }
+            val => warn!("unexpected message: {}", val),
+        }
+    }
+
+    /// Convert the changes to file system changes, reading the
+    /// contents diffs are based on from the remote folder.
+    async fn to_fschanges(&self, changes: Vec<(QBResource, QBChange)>) -> Result<Vec<QBFSChange>> {
+        let mut fschanges = Vec::with_capacity(changes.len());
+        let mut source = None;
+        // the contents the previous changes result in
+        let mut contents = HashMap::new();
+        for (resource, change) in changes {
+            let kind = match change.kind {
+                QBChangeKind::Create => Some(QBFSChangeKind::Create),
+                QBChangeKind::Delete => Some(QBFSChangeKind::Delete),
+                QBChangeKind::UpdateBinary(content) => {
+                    contents.insert(resource.path.clone(), content.clone());
+                    Some(QBFSChangeKind::Update {
+                        hash: QBHash::compute(&content),
+                        content,
+                    })
+                }
+                QBChangeKind::BinaryDiff { base_hash, patch } => {
+                    let old = match contents.remove(&resource.path) {
+                        Some(old) => old,
+                        None => self.remote.read(&resource.path).await?,
+                    };
+                    let old_hash = QBHash::compute(&old);
+                    // the patch might have been applied already
+                    let content = match old_hash {
+                        hash if hash == patch.hash => old,
+                        hash if hash == base_hash => patch
+                            .apply(&old)
+                            .ok_or_else(|| Error::MissingBase(resource.path.clone()))?,
+                        _ => return Err(Error::MissingBase(resource.path)),
+                    };
+                    contents.insert(resource.path.clone(), content.clone());
+                    Some(QBFSChangeKind::Update {
+                        hash: patch.hash,
+                        content,
+                    })
+                }
+                QBChangeKind::UpdateText(diff) => {
+                    let old = match contents.remove(&resource.path) {
+                        Some(old) => old,
+                        None => self.remote.read(&resource.path).await?,
+                    };
+                    if QBHash::compute(&old) != diff.old_hash {
+                        return Err(Error::MissingBase(resource.path));
+                    }
+                    let old = String::from_utf8(old)
+                        .map_err(|_| Error::MissingBase(resource.path.clone()))?;
+                    let content = diff
+                        .apply(old)
+                        .map_err(|_| Error::MissingBase(resource.path.clone()))?
+                        .into_bytes();
+                    contents.insert(resource.path.clone(), content.clone());
+                    Some(QBFSChangeKind::Update {
+                        hash: QBHash::compute(&content),
+                        content,
+                    })
+                }
+                QBChangeKind::CopyFrom | QBChangeKind::RenameFrom => {
+                    source = Some(resource.path.clone());
+                    None
+                }
+                QBChangeKind::CopyTo => Some(QBFSChangeKind::Copy {
+                    from: source.clone().unwrap(),
+                }),
+                QBChangeKind::RenameTo => Some(QBFSChangeKind::Rename {
+                    from: source.clone().unwrap(),
+                }),
+                // extended attributes are not supported by SFTP
+                QBChangeKind::XAttr { .. } => None,
+            };
+
+            if let Some(kind) = kind {
+                fschanges.push(QBFSChange {
+                    resource,
+                    kind,
+                    mtime: change.mtime,
+                });
+            }
+        }
+        Ok(fschanges)
+    }
+
+    /// Apply the changes to the remote folder.
+    async fn apply_changes(&mut self, changes: Vec<(QBResource, QBChange)>) -> Result<()> {
+        let fschanges = self.to_fschanges(changes).await?;
+        for change in order_changes(fschanges) {
+            self.apply(change).await?;
+        }
+        Ok(())
+    }
+
+    /// Apply a single change and update the index, so
+    /// that it is not detected as a remote change.
+    async fn apply(&mut self, change: QBFSChange) -> Result<()> {
+        let path = &change.resource.path;
+        debug!("apply {:?}", change.resource);
+        let mut hash = None;
+        match change.kind {
+            QBFSChangeKind::Create => match change.resource.is_dir() {
+                true => self.remote.create_dir(path).await?,
+                false => self.remote.create_file(path).await?,
+            },
+            QBFSChangeKind::Delete => self.remote.remove(path).await?,
+            QBFSChangeKind::Update {
+                content,
+                hash: content_hash,
+            } => {
+                self.remote.write(path, &content).await?;
+                hash = Some(content_hash);
+            }
+            QBFSChangeKind::Rename { from } => {
+                self.remote.rename(&from, path).await?;
+                self.unindex(&from);
+            }
+            QBFSChangeKind::Copy { from } => self.remote.copy(&from, path).await?,
+            kind => warn!("unsupported change {:?} of {}", kind, path),
+        }
+
+        self.reindex(path, hash).await
+    }
+
+    /// Remove the resource at the path and its children from the index.
+    fn unindex(&mut self, path: &QBPath) {
+        self.index.retain(|e, _| e != path && !path.is_parent(e));
+    }
+
+    /// Scan the resource at the path and its children again.
+    async fn reindex(&mut self, path: &QBPath, hash: Option<QBHash>) -> Result<()> {
+        self.unindex(path);
+        let Some(stat) = self.remote.stat(path).await? else {
+            return Ok(());
+        };
+
+        let is_dir = stat.kind.is_dir();
+        self.index
+            .insert(path.clone(), QBSftpEntry::new(stat, hash));
+        if is_dir {
+            for (path, stat) in self.remote.walk(path).await? {
+                self.index.insert(path, QBSftpEntry::new(stat, None));
+            }
+        }
+        Ok(())
+    }
+
+    /// Scan the remote folder and commit the changes found.
+    async fn poll(&mut self) -> Result<()> {
+        let mut index = HashMap::new();
+        let mut entries = Vec::new();
+        for (path, stat) in self.remote.walk(&ROOT).await? {
+            // skip internal files and symlinks
+            if path == *INTERNAL || INTERNAL.is_parent(&path) || stat.kind.is_symlink() {
+                continue;
+            }
+
+            let resource = QBResource::new(path.clone(), stat.kind.clone());
+            let previous = self.index.get(&path);
+            let mut hash = previous.and_then(|e| e.hash.clone());
+            match previous {
+                Some(previous) if previous.kind == stat.kind => {
+                    let modified = previous.size != stat.size || previous.mtime != stat.mtime;
+                    if stat.kind.is_file() && modified {
+                        if let Some(contents) = self.read(&path, &stat).await? {
+                            let new_hash = QBHash::compute(&contents);
+                            if hash.as_ref() != Some(&new_hash) {
+                                entries.push((
+                                    resource,
+                                    QBChange::new(
+                                        self.recorder.record(),
+                                        QBChangeKind::UpdateBinary(contents),
+                                    ),
+                                ));
+                            }
+                            hash = Some(new_hash);
+                        }
+                    }
+                }
+                _ => {
+                    if let Some(previous) = previous {
+                        entries.push((
+                            QBResource::new(path.clone(), previous.kind.clone()),
+                            QBChange::new(self.recorder.record(), QBChangeKind::Delete),
+                        ));
+                    }
+
+                    entries.push((
+                        resource.clone(),
+                        QBChange::new(self.recorder.record(), QBChangeKind::Create),
+                    ));
+                    hash = None;
+                    if stat.kind.is_file() && stat.size > 0 {
+                        if let Some(contents) = self.read(&path, &stat).await? {
+                            hash = Some(QBHash::compute(&contents));
+                            entries.push((
+                                resource,
+                                QBChange::new(
+                                    self.recorder.record(),
+                                    QBChangeKind::UpdateBinary(contents),
+                                ),
+                            ));
+                        }
+                    }
+                }
+            }
+
+            index.insert(path, QBSftpEntry::new(stat, hash));
+        }
+
+        // only the topmost resource that has been removed is deleted
+        for (path, entry) in self.index.iter() {
+            if index.contains_key(path) {
+                continue;
+            }
+            let parent_removed = path.clone().parent().is_some_and(|parent| {
+                self.index.contains_key(&parent) && !index.contains_key(&parent)
+            });
+            if !parent_removed {
+                info!("DELETE {}", path);
+                entries.push((
+                    QBResource::new(path.clone(), entry.kind.clone()),
+                    QBChange::new(self.recorder.record(), QBChangeKind::Delete),
+                ));
+            }
+        }
+
+        self.index = index;
+        if !entries.is_empty() {
+            debug!("found {} remote changes", entries.len());
+            self.changemap.append(entries);
+            self.save().await?;
+        }
+        Ok(())
+    }
+
+    /// Read the contents of the file, or none if it is too large.
+    async fn read(&self, path: &QBPath, stat: &QBSftpStat) -> Result<Option<Vec<u8>>> {
+        if stat.size > MAX_FILE_SIZE {
+            warn!("skipping {}: too large ({} bytes)", path, stat.size);
+            return Ok(None);
+        }
+        self.remote.read(path).await.map(Some)
+    }
+
+    fn should_sync(&mut self) -> bool {
+        !self.syncing && self.changemap.head() != self.devices.get_common(&self.host_id)
+    }
+
+    async fn sync(&mut self) {
+        info!("syncing");
+        self.syncing = true;
+
+        let common = self.devices.get_common(&self.host_id).clone();
+        let mut changes = match self.changemap.since_cloned(&common) {
+            Ok(changes) => changes,
+            Err(err) => {
+                warn!("could not sync: {}", err);
+                self.syncing = false;
+                return;
+            }
+        };
+        changes.minify();
+
+        // notify remote
+        for msg in QBIMessage::sync(common, changes) {
+            self.com.send(msg).await;
+        }
+    }
+
+    async fn run(mut self) {
+        let mut poll = tokio::time::interval(self.poll_interval);
+        loop {
+            tokio::select! {
+                Some(msg) = self.com.recv() => {
+                    match msg {
+                        QBIHostMessage::Message(msg) => self.on_message(msg).await,
+                        QBIHostMessage::Stop => {
+                            info!("stopping...");
+                            break
+                        }
+                        _ => warn!("unknown message: {msg:?}"),
+                    }
+                },
+                _ = poll.tick(), if !self.syncing => {
+                    // scanning a large folder takes a while, so it
+                    // is aborted if the host does not wait for it
+                    let cancel = self.cancel.clone();
+                    let res = tokio::select! {
+                        biased;
+                        _ = cancel.cancelled() => {
+                            warn!("stopping, as the host canceled");
+                            break;
+                        }
+                        res = self.poll() => res,
+                    };
+                    if let Err(err) = res {
+                        warn!("could not scan {}: {}", self.remote.remote_path(&ROOT), err);
+                    }
+                },
+                _ = tokio::time::sleep(Duration::from_secs(3)), if self.should_sync() => {
+                    self.sync().await;
+                },
+            };
+        }
+    }
+}
+
+// the mock server is only available on unix
+#[cfg(all(test, unix))]
+mod tests {
+    use std::path::Path;
+
+    use qb_ext::{interface::QBISlaveMessage, QBExtId};
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    /// The ends of the channel of a runner which are held by the master.
+    type Master = (
+        mpsc::Receiver<(QBExtId, QBISlaveMessage)>,
+        mpsc::Sender<QBIHostMessage>,
+    );
+
+    /// Create a runner for the directory, which is served by a mock server.
+    async fn runner(root: &Path) -> (Runner, Master) {
+        // the internal directory is created during setup
+        std::fs::create_dir(root.join(".qb")).unwrap();
+        let remote = remote::mock::connect(root).await;
+        let (slave_tx, slave_rx) = mpsc::channel(16);
+        let (host_tx, host_rx) = mpsc::channel(16);
+        let com = QBIChannel::new(QBExtId(0), slave_tx, host_rx);
+        let runner =
+            Runner::init(remote, Duration::from_secs(1), QBDeviceId::generate(), com).await;
+        (runner, (slave_rx, host_tx))
+    }
+
+    fn resource(path: &str, dir: bool) -> QBResource {
+        let path = QBPath::try_from(path).unwrap();
+        match dir {
+            true => path.dir(),
+            false => path.file(),
+        }
+    }
+
+    fn change(recorder: &mut QBTimeStampRecorder, kind: QBChangeKind) -> QBChange {
+        QBChange::new(recorder.record(), kind)
+    }
+
+    #[tokio::test]
+    async fn create_and_update() {
+        let root = tempfile::tempdir().unwrap();
+        let (mut runner, _master) = runner(root.path()).await;
+        let mut recorder = QBTimeStampRecorder::from(QBDeviceId::generate());
+
+        let changes = vec![
+            (
+                resource("/dir", true),
+                change(&mut recorder, QBChangeKind::Create),
+            ),
+            (
+                resource("/dir/file", false),
+                change(&mut recorder, QBChangeKind::Create),
+            ),
+            (
+                resource("/dir/file", false),
+                change(&mut recorder, QBChangeKind::UpdateBinary(b"hello".to_vec())),
+            ),
+        ];
+        runner.apply_changes(changes).await.unwrap();
+
+        assert!(root.path().join("dir").is_dir());
+        assert_eq!(
+            std::fs::read(root.path().join("dir/file")).unwrap(),
+            b"hello"
+        );
+        let entry = &runner.index[&QBPath::try_from("/dir/file").unwrap()];
+        assert_eq!(entry.hash, Some(QBHash::compute(b"hello")));
+    }
+
+    #[tokio::test]
+    async fn delete_dir() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("dir/sub")).unwrap();
+        std::fs::write(root.path().join("dir/sub/file"), b"hello").unwrap();
+        let (mut runner, _master) = runner(root.path()).await;
+        let mut recorder = QBTimeStampRecorder::from(QBDeviceId::generate());
+
+        let changes = vec![(
+            resource("/dir", true),
+            change(&mut recorder, QBChangeKind::Delete),
+        )];
+        runner.apply_changes(changes).await.unwrap();
+
+        assert!(!root.path().join("dir").exists());
+        assert!(runner.index.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rename_file() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("from"), b"hello").unwrap();
+        std::fs::write(root.path().join("to"), b"replaced").unwrap();
+        let (mut runner, _master) = runner(root.path()).await;
+        runner.poll().await.unwrap();
+        let mut recorder = QBTimeStampRecorder::from(QBDeviceId::generate());
+
+        let changes = vec![
+            (
+                resource("/from", false),
+                change(&mut recorder, QBChangeKind::RenameFrom),
+            ),
+            (
+                resource("/to", false),
+                change(&mut recorder, QBChangeKind::RenameTo),
+            ),
+        ];
+        runner.apply_changes(changes).await.unwrap();
+
+        assert!(!root.path().join("from").exists());
+        assert_eq!(std::fs::read(root.path().join("to")).unwrap(), b"hello");
+        assert!(!runner
+            .index
+            .contains_key(&QBPath::try_from("/from").unwrap()));
+        assert!(runner.index.contains_key(&QBPath::try_from("/to").unwrap()));
+    }
+
+    #[tokio::test]
+    async fn poll_detects_remote_changes() {
+        let root = tempfile::tempdir().unwrap();
+        let (mut runner, _master) = runner(root.path()).await;
+        std::fs::write(root.path().join("file"), b"hello").unwrap();
+
+        runner.poll().await.unwrap();
+        let changes = runner.changemap.since_cloned(&Default::default()).unwrap();
+        let file = resource("/file", false);
+        assert!(changes.iter().any(|(resource, _)| resource == &file));
+    }
+}
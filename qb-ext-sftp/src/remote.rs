@@ -0,0 +1,557 @@
+//! # remote
+//!
+//! This module contains the connection to the SFTP server
+//! and the file operations on the remote folder.
+
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use qb_core::path::{QBPath, QBResourceKind};
+use russh::client;
+use russh_keys::key::PublicKey;
+use russh_sftp::{
+    client::{error::Error as SftpError, SftpSession},
+    protocol::StatusCode,
+};
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+
+/// error type for the sftp interface
+#[derive(Error, Debug)]
+pub enum Error {
+    /// ssh error
+    #[error("ssh: {0}")]
+    Ssh(#[from] russh::Error),
+    /// error loading the private key
+    #[error("key: {0}")]
+    Key(#[from] russh_keys::Error),
+    /// sftp error
+    #[error("sftp: {0}")]
+    Sftp(#[from] SftpError),
+    /// the server rejected our credentials
+    #[error("authentication failed for {0}")]
+    AuthFailed(String),
+    /// the host key does not match the one seen during setup
+    #[error("host key mismatch: expected {expected}, found {found}")]
+    HostKeyMismatch {
+        /// the fingerprint recorded during setup
+        expected: String,
+        /// the fingerprint the server presented
+        found: String,
+    },
+    /// the remote path could not be parsed
+    #[error("path: {0}")]
+    Path(#[from] qb_core::path::QBPathError),
+    /// the contents of a resource could not be found
+    #[error("missing base for diff of {0}")]
+    MissingBase(QBPath),
+}
+
+/// result type for the sftp interface
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The credentials used for logging into the server.
+pub enum QBSftpAuth<'a> {
+    /// log in using a password
+    Password(&'a str),
+    /// log in using the private key stored at the path
+    Key(&'a str),
+}
+
+/// The maximum size of a remote file that is read into memory.
+pub const MAX_FILE_SIZE: u64 = 1 << 30;
+
+/// ssh client handler, which verifies the host key
+struct Client {
+    /// the fingerprint of the host key we expect, if any
+    expected: Option<String>,
+    /// the fingerprint of the host key the server presented
+    found: Arc<Mutex<Option<String>>>,
+}
+
+#[async_trait]
+impl client::Handler for Client {
+    type Error = Error;
+
+    async fn check_server_key(&mut self, key: &PublicKey) -> Result<bool> {
+        let fingerprint = key.fingerprint();
+        *self.found.lock().unwrap() = Some(fingerprint.clone());
+        match &self.expected {
+            Some(expected) if expected != &fingerprint => Err(Error::HostKeyMismatch {
+                expected: expected.clone(),
+                found: fingerprint,
+            }),
+            _ => Ok(true),
+        }
+    }
+}
+
+/// An entry of the remote folder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QBSftpStat {
+    /// the kind of the resource
+    pub kind: QBResourceKind,
+    /// the size in bytes
+    pub size: u64,
+    /// the modification time in seconds
+    pub mtime: u32,
+}
+
+/// struct which represents a folder on an SFTP server
+pub struct QBSftpRemote {
+    sftp: SftpSession,
+    // the session is closed when the handle is dropped
+    _handle: Option<client::Handle<Client>>,
+    root: String,
+    fingerprint: String,
+}
+
+impl QBSftpRemote {
+    /// Connect to the server and open the folder at the root path.
+    ///
+    /// If a fingerprint is given, the connection is aborted if the
+    /// host key of the server does not match it.
+    pub async fn connect(
+        host: &str,
+        port: u16,
+        user: &str,
+        auth: QBSftpAuth<'_>,
+        root: &str,
+        fingerprint: Option<&str>,
+    ) -> Result<Self> {
+        let found = Arc::new(Mutex::new(None));
+        let handler = Client {
+            expected: fingerprint.map(str::to_owned),
+            found: found.clone(),
+        };
+        let config = Arc::new(client::Config::default());
+        let mut handle = client::connect(config, (host, port), handler).await?;
+
+        let authenticated = match auth {
+            QBSftpAuth::Password(password) => handle.authenticate_password(user, password).await?,
+            QBSftpAuth::Key(path) => {
+                let key = russh_keys::load_secret_key(path, None)?;
+                handle.authenticate_publickey(user, Arc::new(key)).await?
+            }
+        };
+        if !authenticated {
+            return Err(Error::AuthFailed(user.to_owned()));
+        }
+
+        let channel = handle.channel_open_session().await?;
+        channel.request_subsystem(true, "sftp").await?;
+        let sftp = SftpSession::new(channel.into_stream()).await?;
+
+        let fingerprint = found.lock().unwrap().take().unwrap_or_default();
+        Ok(Self {
+            sftp,
+            _handle: Some(handle),
+            root: root.trim_end_matches('/').to_owned(),
+            fingerprint,
+        })
+    }
+
+    /// Open the folder at the root path over an SFTP stream
+    /// which does not run over SSH, e.g. an in-process server.
+    #[cfg(all(test, unix))]
+    pub(crate) async fn from_stream<S>(stream: S, root: &str) -> Result<Self>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        Ok(Self {
+            sftp: SftpSession::new(stream).await?,
+            _handle: None,
+            root: root.trim_end_matches('/').to_owned(),
+            fingerprint: String::new(),
+        })
+    }
+
+    /// Returns the fingerprint of the host key of the server.
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Returns the remote path of the given path.
+    pub fn remote_path(&self, path: &QBPath) -> String {
+        let path = path.to_string(&self.root);
+        match path.is_empty() {
+            true => "/".to_owned(),
+            false => path,
+        }
+    }
+
+    /// Get the stat of the resource at the path, or none if it does not exist.
+    pub async fn stat(&self, path: &QBPath) -> Result<Option<QBSftpStat>> {
+        match self.sftp.symlink_metadata(self.remote_path(path)).await {
+            Ok(metadata) => Ok(Some(QBSftpStat {
+                kind: to_kind(metadata.file_type()),
+                size: metadata.size.unwrap_or_default(),
+                mtime: metadata.mtime.unwrap_or_default(),
+            })),
+            Err(SftpError::Status(status)) if status.status_code == StatusCode::NoSuchFile => {
+                Ok(None)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Walk the resources below the path, parents are listed before their children.
+    pub async fn walk(&self, path: &QBPath) -> Result<Vec<(QBPath, QBSftpStat)>> {
+        let mut entries = Vec::new();
+        let mut stack = vec![path.clone()];
+        while let Some(dir) = stack.pop() {
+            let mut children = Vec::new();
+            for entry in self.sftp.read_dir(self.remote_path(&dir)).await? {
+                let name = entry.file_name();
+                if name == "." || name == ".." {
+                    continue;
+                }
+
+                let metadata = entry.metadata();
+                let path = dir.clone().substitue(&name)?;
+                let stat = QBSftpStat {
+                    kind: to_kind(entry.file_type()),
+                    size: metadata.size.unwrap_or_default(),
+                    mtime: metadata.mtime.unwrap_or_default(),
+                };
+                if stat.kind.is_dir() {
+                    children.push(path.clone());
+                }
+                entries.push((path, stat));
+            }
+            // keep the traversal in order
+            stack.extend(children.into_iter().rev());
+        }
+        Ok(entries)
+    }
+
+    /// Read the contents of the file at the path.
+    pub async fn read(&self, path: &QBPath) -> Result<Vec<u8>> {
+        Ok(self.sftp.read(self.remote_path(path)).await?)
+    }
+
+    /// Read the contents of the file at the path, or none if it does not exist.
+    pub async fn try_read(&self, path: &QBPath) -> Result<Option<Vec<u8>>> {
+        match self.sftp.read(self.remote_path(path)).await {
+            Ok(contents) => Ok(Some(contents)),
+            Err(SftpError::Status(status)) if status.status_code == StatusCode::NoSuchFile => {
+                Ok(None)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Write the contents to the file at the path, creating it if necessary.
+    pub async fn write(&self, path: &QBPath, contents: &[u8]) -> Result<()> {
+        let mut file = self.sftp.create(self.remote_path(path)).await?;
+        file.write_all(contents)
+            .await
+            .map_err(|err| SftpError::IO(err.to_string()))?;
+        file.shutdown()
+            .await
+            .map_err(|err| SftpError::IO(err.to_string()))?;
+        Ok(())
+    }
+
+    /// Create the directory at the path, if it does not exist yet.
+    pub async fn create_dir(&self, path: &QBPath) -> Result<()> {
+        if self.stat(path).await?.is_none() {
+            self.sftp.create_dir(self.remote_path(path)).await?;
+        }
+        Ok(())
+    }
+
+    /// Create an empty file at the path, if it does not exist yet.
+    pub async fn create_file(&self, path: &QBPath) -> Result<()> {
+        if self.stat(path).await?.is_none() {
+            self.write(path, &[]).await?;
+        }
+        Ok(())
+    }
+
+    /// Remove the resource at the path, directories are removed recursively.
+    pub async fn remove(&self, path: &QBPath) -> Result<()> {
+        let Some(stat) = self.stat(path).await? else {
+            return Ok(());
+        };
+
+        if !stat.kind.is_dir() {
+            self.sftp.remove_file(self.remote_path(path)).await?;
+            return Ok(());
+        }
+
+        // children are listed after their parents
+        for (path, stat) in self.walk(path).await?.into_iter().rev() {
+            match stat.kind.is_dir() {
+                true => self.sftp.remove_dir(self.remote_path(&path)).await?,
+                false => self.sftp.remove_file(self.remote_path(&path)).await?,
+            }
+        }
+        self.sftp.remove_dir(self.remote_path(path)).await?;
+        Ok(())
+    }
+
+    /// Rename the resource, replacing the resource at the destination.
+    pub async fn rename(&self, from: &QBPath, to: &QBPath) -> Result<()> {
+        // SFTP v3 does not allow renaming onto an existing path
+        self.remove(to).await?;
+        self.sftp
+            .rename(self.remote_path(from), self.remote_path(to))
+            .await?;
+        Ok(())
+    }
+
+    /// Copy the resource, replacing the resource at the destination.
+    pub async fn copy(&self, from: &QBPath, to: &QBPath) -> Result<()> {
+        let Some(stat) = self.stat(from).await? else {
+            return Ok(());
+        };
+        self.remove(to).await?;
+
+        if !stat.kind.is_dir() {
+            let contents = self.read(from).await?;
+            return self.write(to, &contents).await;
+        }
+
+        self.sftp.create_dir(self.remote_path(to)).await?;
+        let from_root = from.to_string("");
+        for (path, stat) in self.walk(from).await? {
+            let path_str = path.to_string("");
+            let target = to.clone().substitue(&path_str[from_root.len()..])?;
+            match stat.kind.is_dir() {
+                true => self.sftp.create_dir(self.remote_path(&target)).await?,
+                false => {
+                    let contents = self.read(&path).await?;
+                    self.write(&target, &contents).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn to_kind(file_type: russh_sftp::protocol::FileType) -> QBResourceKind {
+    match file_type {
+        russh_sftp::protocol::FileType::Dir => QBResourceKind::Dir,
+        russh_sftp::protocol::FileType::Symlink => QBResourceKind::Symlink,
+        _ => QBResourceKind::File,
+    }
+}
+
+/// An in-process SFTP server which serves a local directory.
+#[cfg(all(test, unix))]
+pub(crate) mod mock {
+    use std::{
+        collections::HashMap,
+        fs, io,
+        os::unix::fs::FileExt,
+        path::{Path, PathBuf},
+    };
+
+    use russh_sftp::{
+        protocol::{
+            Attrs, Data, File, FileAttributes, Handle, Name, OpenFlags, Status, StatusCode,
+        },
+        server::{self, Handler},
+    };
+
+    use super::QBSftpRemote;
+
+    enum MockHandle {
+        File(fs::File),
+        /// the directory, which is taken once its entries have been read
+        Dir(Option<PathBuf>),
+    }
+
+    struct MockServer {
+        root: PathBuf,
+        handles: HashMap<String, MockHandle>,
+        next_handle: u64,
+    }
+
+    fn status(err: io::Error) -> StatusCode {
+        match err.kind() {
+            io::ErrorKind::NotFound => StatusCode::NoSuchFile,
+            io::ErrorKind::PermissionDenied => StatusCode::PermissionDenied,
+            _ => StatusCode::Failure,
+        }
+    }
+
+    fn ok(id: u32) -> Status {
+        Status {
+            id,
+            status_code: StatusCode::Ok,
+            error_message: "Ok".to_owned(),
+            language_tag: "en-US".to_owned(),
+        }
+    }
+
+    impl MockServer {
+        fn path(&self, path: &str) -> PathBuf {
+            self.root.join(path.trim_start_matches('/'))
+        }
+
+        fn insert(&mut self, id: u32, handle: MockHandle) -> Handle {
+            self.next_handle += 1;
+            let name = self.next_handle.to_string();
+            self.handles.insert(name.clone(), handle);
+            Handle { id, handle: name }
+        }
+
+        fn file(&self, handle: &str) -> Result<&fs::File, StatusCode> {
+            match self.handles.get(handle) {
+                Some(MockHandle::File(file)) => Ok(file),
+                _ => Err(StatusCode::Failure),
+            }
+        }
+    }
+
+    impl Handler for MockServer {
+        type Error = StatusCode;
+
+        fn unimplemented(&self) -> Self::Error {
+            StatusCode::OpUnsupported
+        }
+
+        async fn open(
+            &mut self,
+            id: u32,
+            filename: String,
+            pflags: OpenFlags,
+            _attrs: FileAttributes,
+        ) -> Result<Handle, Self::Error> {
+            let file = fs::OpenOptions::new()
+                .read(pflags.contains(OpenFlags::READ))
+                .write(pflags.contains(OpenFlags::WRITE))
+                .create(pflags.contains(OpenFlags::CREATE))
+                .truncate(pflags.contains(OpenFlags::TRUNCATE))
+                .open(self.path(&filename))
+                .map_err(status)?;
+            Ok(self.insert(id, MockHandle::File(file)))
+        }
+
+        async fn close(&mut self, id: u32, handle: String) -> Result<Status, Self::Error> {
+            self.handles.remove(&handle);
+            Ok(ok(id))
+        }
+
+        async fn read(
+            &mut self,
+            id: u32,
+            handle: String,
+            offset: u64,
+            len: u32,
+        ) -> Result<Data, Self::Error> {
+            let mut data = vec![0; len as usize];
+            let len = self
+                .file(&handle)?
+                .read_at(&mut data, offset)
+                .map_err(status)?;
+            if len == 0 {
+                return Err(StatusCode::Eof);
+            }
+            data.truncate(len);
+            Ok(Data { id, data })
+        }
+
+        async fn write(
+            &mut self,
+            id: u32,
+            handle: String,
+            offset: u64,
+            data: Vec<u8>,
+        ) -> Result<Status, Self::Error> {
+            self.file(&handle)?
+                .write_all_at(&data, offset)
+                .map_err(status)?;
+            Ok(ok(id))
+        }
+
+        async fn lstat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+            let metadata = fs::symlink_metadata(self.path(&path)).map_err(status)?;
+            Ok(Attrs {
+                id,
+                attrs: FileAttributes::from(&metadata),
+            })
+        }
+
+        async fn fstat(&mut self, id: u32, handle: String) -> Result<Attrs, Self::Error> {
+            let metadata = self.file(&handle)?.metadata().map_err(status)?;
+            Ok(Attrs {
+                id,
+                attrs: FileAttributes::from(&metadata),
+            })
+        }
+
+        async fn opendir(&mut self, id: u32, path: String) -> Result<Handle, Self::Error> {
+            let path = self.path(&path);
+            if !fs::metadata(&path).map_err(status)?.is_dir() {
+                return Err(StatusCode::NoSuchFile);
+            }
+            Ok(self.insert(id, MockHandle::Dir(Some(path))))
+        }
+
+        async fn readdir(&mut self, id: u32, handle: String) -> Result<Name, Self::Error> {
+            let Some(MockHandle::Dir(dir)) = self.handles.get_mut(&handle) else {
+                return Err(StatusCode::Failure);
+            };
+            let Some(dir) = dir.take() else {
+                return Err(StatusCode::Eof);
+            };
+            let files = fs::read_dir(dir)
+                .and_then(|entries| {
+                    entries
+                        .map(|entry| {
+                            let entry = entry?;
+                            let attrs = FileAttributes::from(&entry.metadata()?);
+                            Ok(File::new(entry.file_name().to_string_lossy(), attrs))
+                        })
+                        .collect::<io::Result<Vec<_>>>()
+                })
+                .map_err(status)?;
+            Ok(Name { id, files })
+        }
+
+        async fn remove(&mut self, id: u32, filename: String) -> Result<Status, Self::Error> {
+            fs::remove_file(self.path(&filename)).map_err(status)?;
+            Ok(ok(id))
+        }
+
+        async fn mkdir(
+            &mut self,
+            id: u32,
+            path: String,
+            _attrs: FileAttributes,
+        ) -> Result<Status, Self::Error> {
+            fs::create_dir(self.path(&path)).map_err(status)?;
+            Ok(ok(id))
+        }
+
+        async fn rmdir(&mut self, id: u32, path: String) -> Result<Status, Self::Error> {
+            fs::remove_dir(self.path(&path)).map_err(status)?;
+            Ok(ok(id))
+        }
+
+        async fn rename(
+            &mut self,
+            id: u32,
+            oldpath: String,
+            newpath: String,
+        ) -> Result<Status, Self::Error> {
+            fs::rename(self.path(&oldpath), self.path(&newpath)).map_err(status)?;
+            Ok(ok(id))
+        }
+    }
+
+    /// Serve the directory at the root and open it as a remote folder.
+    pub(crate) async fn connect(root: &Path) -> QBSftpRemote {
+        let (client, server) = tokio::io::duplex(1 << 20);
+        let handler = MockServer {
+            root: root.to_owned(),
+            handles: HashMap::new(),
+            next_handle: 0,
+        };
+        server::run(server, handler).await;
+        QBSftpRemote::from_stream(client, "").await.unwrap()
+    }
+}
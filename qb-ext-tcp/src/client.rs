@@ -2,17 +2,20 @@
 //!
 //! This module is for the stuff that runs on the client.
 
-use std::sync::Arc;
+use std::{io, sync::Arc, time::Duration};
 
 use bitcode::{Decode, Encode};
 use qb_core::device::QBDeviceId;
 use qb_ext::{
     interface::{QBIChannel, QBIContext},
-    QBExtSetup,
+    QBExtSetup, QBExtSetupError,
 };
-use qb_proto::QBP;
+use qb_proto::{DEFAULT_HEARTBEAT_INTERVAL, QBP};
 use serde::{Deserialize, Serialize};
-use tokio::net::TcpSocket;
+use tokio::{
+    net::{TcpSocket, TcpStream},
+    time::Instant,
+};
 use tokio_rustls::rustls::{
     self,
     client::{danger::ServerCertVerifier, WebPkiServerVerifier},
@@ -20,10 +23,18 @@ use tokio_rustls::rustls::{
     pki_types::{CertificateDer, ServerName},
     RootCertStore,
 };
-use tokio_rustls::{TlsConnector, TlsStream};
-use tracing::{debug, info};
+use tokio_rustls::{client, TlsConnector, TlsStream};
+use tracing::{debug, error, info};
+
+use crate::{auth_payload, Runner};
 
-use crate::Runner;
+/// The default time in seconds to wait for the connection
+/// and the TLS handshake to complete.
+pub const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+
+fn default_connect_timeout() -> u64 {
+    DEFAULT_CONNECT_TIMEOUT
+}
 
 pub type QBITCPClientSetup = QBITCPClient;
 #[derive(Encode, Decode, Serialize, Deserialize, Debug)]
@@ -31,79 +42,124 @@ pub struct QBITCPClient {
     pub addr: String,
     /// An authentication token sent on boot
     pub auth: Vec<u8>,
+    /// Send a session key derived from the authentication
+    /// token instead of the token itself
+    #[serde(default)]
+    pub session_key: bool,
+    /// The time in seconds to wait for the connection
+    /// and the TLS handshake to complete
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
 
     #[serde(skip)]
     pub cert: Vec<u8>,
 }
 
 impl QBIContext for QBITCPClient {
-    async fn run(self, host_id: QBDeviceId, com: QBIChannel) {
-        debug!("initializing socket: {}", self.addr);
-
-        let socket = TcpSocket::new_v4().unwrap();
-        let addr = self.addr.parse().unwrap();
-        let stream = socket.connect(addr).await.unwrap();
+    fn describe(&self) -> serde_json::Value {
+        serde_json::json!({
+            "addr": self.addr,
+            "auth": "<redacted>",
+            "session_key": self.session_key,
+            "connect_timeout": self.connect_timeout,
+        })
+    }
 
+    async fn run(&mut self, host_id: QBDeviceId, com: QBIChannel) {
         let cert = Arc::new(Mutex::new(None));
-        let config = rustls::ClientConfig::builder()
-            .dangerous()
-            .with_custom_certificate_verifier(SetupVerifier::new(cert.clone()))
-            .with_no_client_auth();
-        let connector = TlsConnector::from(Arc::new(config));
-        let dnsname = ServerName::try_from("quixbyte.local").unwrap();
-        let mut stream = connector.connect(dnsname, stream).await.unwrap();
+        let mut stream = match self.connect(cert).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                error!("could not connect to {}: {}", self.addr, err);
+                return;
+            }
+        };
 
-        let mut protocol = QBP::default();
+        let mut protocol = QBP::default().with_heartbeat(DEFAULT_HEARTBEAT_INTERVAL);
         protocol.negotiate(&mut stream).await.unwrap();
-        protocol
-            .send_payload(&mut stream, &self.auth)
-            .await
-            .unwrap();
+        let auth = auth_payload(&protocol, &self.auth, self.session_key, &host_id).unwrap();
+        protocol.send_payload(&mut stream, &auth).await.unwrap();
 
         info!("connected to socket: {:?}", stream);
 
         let runner = Runner {
             host_id,
+            cancel: com.cancel_token(),
             com,
             stream: TlsStream::Client(stream),
             protocol,
+            peer_id: None,
+            limits: None,
+            pending_changes: 0,
+            rate: (Instant::now(), 0),
         };
 
         runner.run().await;
     }
 }
 
-impl QBExtSetup<QBITCPClient> for QBITCPClientSetup {
-    async fn setup(mut self) -> QBITCPClient {
-        debug!("initializing socket: {}", self.addr);
+impl QBITCPClient {
+    /// Connect to the server and do the TLS handshake, storing
+    /// the certificate of the server in the given cell.
+    ///
+    /// Fails with [io::ErrorKind::TimedOut] if this
+    /// takes longer than the connect timeout.
+    async fn connect(
+        &self,
+        cert: Arc<Mutex<Option<Vec<u8>>>>,
+    ) -> io::Result<client::TlsStream<TcpStream>> {
+        let timeout = Duration::from_secs(self.connect_timeout);
+        let connect = async {
+            debug!("initializing socket: {}", self.addr);
+            let socket = TcpSocket::new_v4()?;
+            let addr = self
+                .addr
+                .parse()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            let stream = socket.connect(addr).await?;
+
+            let config = rustls::ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(SetupVerifier::new(cert))
+                .with_no_client_auth();
+            let connector = TlsConnector::from(Arc::new(config));
+            let dnsname = ServerName::try_from("quixbyte.local").unwrap();
+            debug!("do TLS handshake");
+            connector.connect(dnsname, stream).await
+        };
 
-        let socket = TcpSocket::new_v4().unwrap();
-        let addr = self.addr.parse().unwrap();
-        let stream = socket.connect(addr).await.unwrap();
+        tokio::time::timeout(timeout, connect).await.map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no connection after {}s", self.connect_timeout),
+            )
+        })?
+    }
+}
 
+impl QBExtSetup<QBITCPClient> for QBITCPClientSetup {
+    async fn setup(mut self) -> Result<QBITCPClient, QBExtSetupError> {
         let cert = Arc::new(Mutex::new(None));
-        let config = rustls::ClientConfig::builder()
-            .dangerous()
-            .with_custom_certificate_verifier(SetupVerifier::new(cert.clone()))
-            .with_no_client_auth();
-        let connector = TlsConnector::from(Arc::new(config));
-        let dnsname = ServerName::try_from("quixbyte.local").unwrap();
-        debug!("do TLS handshake");
-        let mut stream = connector.connect(dnsname, stream).await.unwrap();
-        self.cert.clone_from(cert.lock().unwrap().as_ref().unwrap());
+        let mut stream = self.connect(cert.clone()).await?;
+        self.cert.clone_from(
+            cert.lock()
+                .unwrap()
+                .as_ref()
+                .ok_or("the server did not send a certificate")?,
+        );
         debug!("successfully extracted certificate");
 
         debug!("do quixbyte protocol handshake");
         let mut protocol = QBP::default();
-        protocol.negotiate(&mut stream).await.unwrap();
+        protocol.negotiate(&mut stream).await?;
         debug!("do quixbyte protocol auth");
-        protocol
-            .send_payload(&mut stream, &self.auth)
-            .await
-            .unwrap();
+        // the connection is dropped after setup, so the device id does not matter
+        let device_id = QBDeviceId::default();
+        let auth = auth_payload(&protocol, &self.auth, self.session_key, &device_id)?;
+        protocol.send_payload(&mut stream, &auth).await?;
         info!("client-socket successfully setup");
 
-        self
+        Ok(self)
     }
 }
 
@@ -170,3 +226,32 @@ impl ServerCertVerifier for SetupVerifier {
         self.webpki.supported_verify_schemes()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn connect_times_out() {
+        // the connection is never accepted, like on a black-holed address
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = QBITCPClient {
+            addr: listener.local_addr().unwrap().to_string(),
+            auth: Vec::new(),
+            session_key: false,
+            connect_timeout: 1,
+            cert: Vec::new(),
+        };
+
+        let start = Instant::now();
+        let err = client
+            .connect(Arc::new(Mutex::new(None)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(1) && elapsed < Duration::from_secs(3));
+    }
+}
@@ -4,12 +4,18 @@
 //! that allow for two devices running quixbyte to communicate
 //! over the TCP protocol (with TLS).
 
+use std::time::Duration;
+
+use bitcode::{Decode, Encode};
 use qb_core::device::QBDeviceId;
 use qb_ext::interface::{QBIChannel, QBIHostMessage, QBIMessage, QBISlaveMessage};
-use qb_proto::QBP;
-use tokio::net::TcpStream;
+use qb_proto::{error_code, QBPPriority, QBP};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tokio::{net::TcpStream, time::Instant};
 use tokio_rustls::TlsStream;
-use tracing::{debug, info};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
 
 pub mod client;
 pub mod server;
@@ -18,43 +24,258 @@ pub use client::QBITCPClient;
 pub use server::QBHTCPServer;
 pub use server::QBITCPServer;
 
+/// The payload which is sent for authentication.
+#[derive(Encode, Decode)]
+struct QBITCPAuth {
+    /// the device id of the sender
+    device_id: QBDeviceId,
+    /// either the auth token itself or a session key
+    /// derived from it and the device id
+    proof: Vec<u8>,
+}
+
+/// Get the payload which is sent for authentication by the device with
+/// the given id. When using session keys, the device id is bound into the
+/// key, so that a peer can not claim to be another device.
+fn auth_payload(
+    protocol: &QBP,
+    auth: &[u8],
+    session_key: bool,
+    device_id: &QBDeviceId,
+) -> qb_proto::Result<Vec<u8>> {
+    let proof = match session_key {
+        true => {
+            let secret = [auth, &bitcode::encode(device_id)].concat();
+            protocol.session_key(&secret)?.to_vec()
+        }
+        false => auth.to_vec(),
+    };
+    Ok(bitcode::encode(&QBITCPAuth {
+        device_id: device_id.clone(),
+        proof,
+    }))
+}
+
+/// The identity a peer has authenticated with, see [verify_auth].
+#[derive(Debug, PartialEq, Eq)]
+enum QBITCPIdentity {
+    /// the peer has authenticated as the device with the given id
+    Device(QBDeviceId),
+    /// the peer has sent the auth token itself, like clients
+    /// from before the device id has been sent along with it
+    Token,
+}
+
+/// Verify the payload the peer sent for authentication, see [auth_payload].
+/// Returns the identity of the peer or none if the payload is incorrect.
+///
+/// Without session keys, the payload may also be the auth token itself,
+/// which older clients send. This does not bind a device id, but as the
+/// token is sent as it is, binding it would not prove anything anyway.
+fn verify_auth(
+    protocol: &QBP,
+    auth: &[u8],
+    session_key: bool,
+    payload: &[u8],
+) -> qb_proto::Result<Option<QBITCPIdentity>> {
+    // compare in constant time, so that the secret can not be guessed
+    // byte by byte by measuring how long the comparison takes
+    if let Ok(received) = bitcode::decode::<QBITCPAuth>(payload) {
+        let expected = auth_payload(protocol, auth, session_key, &received.device_id)?;
+        if bool::from(expected.ct_eq(payload)) {
+            return Ok(Some(QBITCPIdentity::Device(received.device_id)));
+        }
+    }
+    if !session_key && bool::from(auth.ct_eq(payload)) {
+        return Ok(Some(QBITCPIdentity::Token));
+    }
+    Ok(None)
+}
+
+/// Limits for the messages a client may send to the server, so
+/// that a single client can not monopolize the master.
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone)]
+pub struct QBITCPLimits {
+    /// The maximum amount of changes of a sync, including its parts.
+    /// Clients which exceed this are disconnected.
+    #[serde(default = "max_sync_changes_default")]
+    pub max_sync_changes: usize,
+    /// The maximum amount of messages per second which are proxied
+    /// to the master, further messages are delayed.
+    #[serde(default = "max_messages_per_sec_default")]
+    pub max_messages_per_sec: u32,
+}
+
+fn max_sync_changes_default() -> usize {
+    1 << 20
+}
+
+fn max_messages_per_sec_default() -> u32 {
+    64
+}
+
+impl Default for QBITCPLimits {
+    fn default() -> Self {
+        Self {
+            max_sync_changes: max_sync_changes_default(),
+            max_messages_per_sec: max_messages_per_sec_default(),
+        }
+    }
+}
+
 /// A common runner which just proxies all incoming
 /// and outgoing messages.
 struct Runner {
     host_id: QBDeviceId,
     com: QBIChannel,
+    /// canceled by the host, if we do not stop in time
+    cancel: CancellationToken,
     stream: TlsStream<TcpStream>,
     protocol: QBP,
+    /// the device id the peer authenticated with, if we required it to
+    peer_id: Option<QBDeviceId>,
+    /// the limits for the messages of the peer, if any
+    limits: Option<QBITCPLimits>,
+    /// the amount of changes of the sync which is currently being received
+    pending_changes: usize,
+    /// the start of the current second and the messages proxied in it
+    rate: (Instant, u32),
 }
 
 impl Runner {
+    /// Check a message of the peer against the limits, delaying it if the
+    /// peer sends messages too fast. Returns an error if it exceeds them.
+    async fn limit(&mut self, msg: &QBIMessage) -> Result<(), String> {
+        let Some(limits) = &self.limits else {
+            return Ok(());
+        };
+
+        match msg {
+            QBIMessage::SyncPart { changes } => self.pending_changes += changes.len(),
+            QBIMessage::Sync { changes, .. } => self.pending_changes += changes.len(),
+            _ => {}
+        }
+        let pending = self.pending_changes;
+        if matches!(msg, QBIMessage::Sync { .. }) {
+            self.pending_changes = 0;
+        }
+        if pending > limits.max_sync_changes {
+            return Err(format!(
+                "sync has {} changes, at most {} are allowed",
+                pending, limits.max_sync_changes
+            ));
+        }
+
+        let now = Instant::now();
+        if now.duration_since(self.rate.0) >= Duration::from_secs(1) {
+            self.rate = (now, 0);
+        }
+        if self.rate.1 >= limits.max_messages_per_sec {
+            let next = self.rate.0 + Duration::from_secs(1);
+            debug!("throttling peer until {:?}", next);
+            tokio::time::sleep_until(next).await;
+            self.rate = (next, 0);
+        }
+        self.rate.1 += 1;
+        Ok(())
+    }
+
     async fn run(mut self) {
         // initialize
         self.protocol
             .send(
                 &mut self.stream,
                 QBIMessage::Device {
-                    device_id: self.host_id,
+                    device_id: self.host_id.clone(),
                 },
             )
             .await
             .unwrap();
 
-        // proxy messages
+        // proxy messages, reusing the buffer for receiving
+        let mut buf = Vec::new();
+        let mut heartbeat = self
+            .protocol
+            .heartbeat_interval()
+            .map(tokio::time::interval);
         loop {
             tokio::select! {
-                Ok(msg) = self.protocol.recv::<QBIMessage>(&mut self.stream) => {
+                _ = async { heartbeat.as_mut().unwrap().tick().await }, if heartbeat.is_some() => {
+                    if self.protocol.is_timed_out() {
+                        error!("peer timed out");
+                        break;
+                    }
+                    if let Err(err) = self.protocol.send_heartbeat(&mut self.stream).await {
+                        warn!("could not send heartbeat: {}", err);
+                    }
+                },
+                res = self.protocol.progress_cancelable::<QBIMessage>(&mut self.stream, &mut buf, &self.cancel) => {
+                    let msg = match res {
+                        Ok(Some(QBIMessage::Device { device_id }))
+                            if self.peer_id.as_ref().is_some_and(|id| id != &device_id) =>
+                        {
+                            error!("peer claims to be {}, but authenticated as a different device", device_id);
+                            break;
+                        }
+                        Ok(Some(msg)) => msg,
+                        // a queued message has been (partially) sent
+                        Ok(None) => continue,
+                        // the host did not wait for us to stop
+                        Err(qb_proto::Error::Canceled) => {
+                            warn!("stopping, as the host canceled");
+                            break;
+                        }
+                        // the peer reported an error, but the connection is still intact
+                        Err(qb_proto::Error::PeerError(code, msg)) => QBIMessage::Error { code, msg },
+                        // the connection is still intact, so skip the message
+                        Err(err) if err.is_recoverable() || matches!(err, qb_proto::Error::DecodeFailed { .. }) => {
+                            warn!("could not receive message: {}", err);
+                            continue;
+                        }
+                        Err(err) => {
+                            error!("connection failed: {}", err);
+                            break;
+                        }
+                    };
+                    if let Err(err) = self.limit(&msg).await {
+                        error!("peer exceeded limits: {}", err);
+                        if let Err(err) = self.protocol.send_error(&mut self.stream, error_code::LIMIT_EXCEEDED, &err).await {
+                            warn!("could not send error: {}", err);
+                        }
+                        break;
+                    }
                     debug!("proxy to master: {}", msg);
                     self.com.send(QBISlaveMessage::Message(msg)).await;
                 },
                 msg = self.com.recv::<QBIHostMessage>() => {
                     match msg {
+                        QBIHostMessage::Message(QBIMessage::Error { code, msg }) => {
+                            debug!("proxy error to remote: {} {}", code, msg);
+                            if let Err(err) = self.protocol.send_error(&mut self.stream, code, &msg).await {
+                                warn!("could not send error: {}", err);
+                            }
+                        }
                         QBIHostMessage::Message(msg) => {
                             debug!("proxy to remote: {}", msg);
-                            self.protocol.send(&mut self.stream, msg).await.unwrap();
+                            // syncs may be large, so they must not block other messages
+                            let priority = match msg {
+                                QBIMessage::SyncPart { .. } | QBIMessage::Sync { .. } => QBPPriority::Low,
+                                _ => QBPPriority::High,
+                            };
+                            if let Err(err) = self.protocol.queue(msg, priority) {
+                                warn!("could not queue message: {}", err);
+                            }
                         }
                         QBIHostMessage::Stop => {
                             info!("stopping...");
+                            let res = tokio::select! {
+                                biased;
+                                _ = self.cancel.cancelled() => Err(qb_proto::Error::Canceled),
+                                res = self.protocol.flush_queue(&mut self.stream) => res,
+                            };
+                            if let Err(err) = res {
+                                warn!("could not send queued messages: {}", err);
+                            }
                             break;
                         }
                         _ => unimplemented!("unknown message: {msg:?}"),
@@ -64,3 +285,352 @@ impl Runner {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use qb_core::{
+        change::{QBChange, QBChangeKind, QBChangeMap},
+        path::{QBPath, QBResource},
+        time::{QBTimeStampRecorder, QBTimeStampUnique},
+    };
+    use qb_ext::{
+        hook::{QBHChannel, QBHContext, QBHSlaveMessage},
+        interface::QBIContext,
+        QBExtId, QBExtSetup,
+    };
+    use tokio::{net::TcpListener, sync::mpsc, task::JoinHandle};
+
+    use super::*;
+    use crate::server::QBHTCPServerSetup;
+
+    const AUTH: &[u8] = b"secret";
+
+    /// The side of the master of a running interface.
+    struct Host {
+        tx: mpsc::Sender<QBIHostMessage>,
+        rx: mpsc::Receiver<(QBExtId, QBISlaveMessage)>,
+        task: JoinHandle<()>,
+    }
+
+    impl Host {
+        fn spawn(mut context: impl QBIContext + 'static, host_id: QBDeviceId) -> Self {
+            let (slave_tx, rx) = mpsc::channel(32);
+            let (tx, host_rx) = mpsc::channel(32);
+            let com = QBIChannel::new(QBExtId(0), slave_tx, host_rx);
+            let task = tokio::spawn(async move { context.run(host_id, com).await });
+            Self { tx, rx, task }
+        }
+
+        async fn send(&self, msg: QBIMessage) {
+            self.tx.send(msg.into()).await.unwrap();
+        }
+
+        /// Receive the next message, none if the interface stopped.
+        async fn recv(&mut self) -> Option<QBIMessage> {
+            let msg = tokio::time::timeout(Duration::from_secs(5), self.rx.recv())
+                .await
+                .expect("no message received");
+            msg.map(|(_, msg)| match msg {
+                QBISlaveMessage::Message(msg) => msg,
+                _ => unreachable!(),
+            })
+        }
+    }
+
+    /// A listener, which runs the accepted connections like the hook does.
+    struct Server {
+        hook: QBHTCPServer,
+        listener: TcpListener,
+        auth: Vec<u8>,
+        session_key: bool,
+        limits: QBITCPLimits,
+    }
+
+    impl Server {
+        async fn bind(session_key: bool) -> Self {
+            let hook = QBHTCPServerSetup {
+                port: 0,
+                host: "127.0.0.1".to_owned(),
+                auth: AUTH.to_vec(),
+                session_key,
+                limits: QBITCPLimits::default(),
+            }
+            .setup()
+            .await
+            .unwrap();
+            Self {
+                hook,
+                listener: TcpListener::bind("127.0.0.1:0").await.unwrap(),
+                auth: AUTH.to_vec(),
+                session_key,
+                limits: QBITCPLimits::default(),
+            }
+        }
+
+        fn client(&self, auth: &[u8]) -> QBITCPClient {
+            QBITCPClient {
+                addr: self.listener.local_addr().unwrap().to_string(),
+                auth: auth.to_vec(),
+                session_key: self.session_key,
+                connect_timeout: 5,
+                cert: Vec::new(),
+            }
+        }
+
+        /// Accept the next connection and run it.
+        async fn accept(&self, host_id: QBDeviceId) -> Host {
+            let (stream, addr) = self.listener.accept().await.unwrap();
+            let interface = QBITCPServer {
+                stream: Some(stream),
+                addr,
+                config: self.hook.tls_config(),
+                auth: self.auth.clone(),
+                session_key: self.session_key,
+                limits: self.limits.clone(),
+            };
+            Host::spawn(interface, host_id)
+        }
+
+        /// Connect a client and wait for both sides to
+        /// receive the device id of the other side.
+        async fn connect(&self) -> (Host, Host) {
+            let server_id = QBDeviceId::generate();
+            let client_id = QBDeviceId::generate();
+            let mut client = Host::spawn(self.client(AUTH), client_id.clone());
+            let mut server = self.accept(server_id.clone()).await;
+
+            let msg = server.recv().await;
+            assert!(
+                matches!(msg, Some(QBIMessage::Device { device_id }) if device_id == client_id)
+            );
+            let msg = client.recv().await;
+            assert!(
+                matches!(msg, Some(QBIMessage::Device { device_id }) if device_id == server_id)
+            );
+            (server, client)
+        }
+    }
+
+    fn broadcast(msg: &str) -> QBIMessage {
+        QBIMessage::Broadcast {
+            msg: msg.to_owned(),
+        }
+    }
+
+    /// A sync recorded by the given device, which creates the given files.
+    fn sync(device_id: &QBDeviceId, resources: impl IntoIterator<Item = QBResource>) -> QBIMessage {
+        let mut recorder = QBTimeStampRecorder::from(device_id.clone());
+        let mut changes = QBChangeMap::default();
+        for resource in resources {
+            let change = QBChange::new(recorder.record(), QBChangeKind::Create);
+            changes.push((resource, change));
+        }
+        QBIMessage::Sync {
+            common: QBTimeStampUnique::default(),
+            changes,
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_clients() {
+        let server = Server::bind(true).await;
+        let (mut server_a, client_a) = server.connect().await;
+        let (mut server_b, client_b) = server.connect().await;
+        // a client with an incorrect secret does not affect the others
+        let _client = Host::spawn(server.client(b"other"), QBDeviceId::generate());
+        let mut rejected = server.accept(QBDeviceId::generate()).await;
+        assert!(rejected.recv().await.is_none());
+
+        let a = QBPath::try_from("/a").unwrap().file();
+        let b = QBPath::try_from("/b").unwrap().file();
+        let device_id = QBDeviceId::generate();
+        tokio::join!(
+            client_a.send(sync(&device_id, [a.clone()])),
+            client_b.send(sync(&device_id, [b.clone()])),
+        );
+
+        // every interface only receives the changes of its own client
+        for (host, resource) in [(&mut server_a, a), (&mut server_b, b)] {
+            let msg = host.recv().await;
+            let Some(QBIMessage::Sync { changes, .. }) = msg else {
+                panic!("expected a sync: {msg:?}");
+            };
+            let resources = changes.iter().map(|(resource, _)| resource);
+            assert_eq!(resources.collect::<Vec<_>>(), [&resource]);
+        }
+        assert!(!server_a.task.is_finished() && !server_b.task.is_finished());
+    }
+
+    #[tokio::test]
+    async fn bind_to_loopback() {
+        // reserve a free port, as the hook does not tell which one it bound to
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        // bound to loopback, the hook only accepts connections from this machine
+        let hook = QBHTCPServerSetup {
+            port,
+            host: "127.0.0.1".to_owned(),
+            auth: AUTH.to_vec(),
+            session_key: true,
+            limits: QBITCPLimits::default(),
+        }
+        .setup()
+        .await
+        .unwrap();
+        let (tx, mut rx) = mpsc::channel(32);
+        let (_host_tx, host_rx) = mpsc::channel(32);
+        tokio::spawn(hook.run(QBHChannel::new(QBExtId(0), tx, host_rx).into()));
+        // wait for the hook to listen, the probe is attached like any other connection
+        while TcpStream::connect(("127.0.0.1", port)).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(rx.recv().await.is_some());
+
+        let client_id = QBDeviceId::generate();
+        let mut client = Host::spawn(
+            QBITCPClient {
+                addr: format!("127.0.0.1:{port}"),
+                auth: AUTH.to_vec(),
+                session_key: true,
+                connect_timeout: 5,
+                cert: Vec::new(),
+            },
+            client_id.clone(),
+        );
+        let msg = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("no connection attached");
+        let Some((_, QBHSlaveMessage::Attach { context })) = msg else {
+            unreachable!();
+        };
+        let interface = context.downcast::<QBITCPServer>().unwrap();
+        assert!(interface.addr.ip().is_loopback());
+
+        let mut server = Host::spawn(*interface, QBDeviceId::generate());
+        let msg = server.recv().await;
+        assert!(matches!(msg, Some(QBIMessage::Device { device_id }) if device_id == client_id));
+        assert!(matches!(
+            client.recv().await,
+            Some(QBIMessage::Device { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn flooding_client_is_throttled() {
+        let mut server = Server::bind(false).await;
+        server.limits = QBITCPLimits {
+            max_sync_changes: 2,
+            max_messages_per_sec: 10,
+        };
+        let (mut flooded, mut flooder) = server.connect().await;
+        let (mut other, client) = server.connect().await;
+
+        for i in 0..30 {
+            flooder.send(broadcast(&i.to_string())).await;
+        }
+        let start = Instant::now();
+        client.send(broadcast("hello")).await;
+        // the other interface is not held up by the flooder
+        let msg = other.recv().await;
+        assert!(matches!(&msg, Some(QBIMessage::Broadcast { msg }) if msg == "hello"));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        // every message of the flooder arrives, but at most 10 per second
+        for i in 0..30 {
+            let msg = flooded.recv().await;
+            assert!(matches!(&msg, Some(QBIMessage::Broadcast { msg }) if msg == &i.to_string()));
+        }
+        assert!(start.elapsed() >= Duration::from_secs(2));
+
+        // a sync with too many changes disconnects the client
+        let resources = ["/a", "/b", "/c"].map(|path| QBPath::try_from(path).unwrap().file());
+        flooder.send(sync(&QBDeviceId::generate(), resources)).await;
+        assert!(flooded.recv().await.is_none());
+        let msg = flooder.recv().await;
+        assert!(
+            matches!(
+                msg,
+                Some(QBIMessage::Error {
+                    code: error_code::LIMIT_EXCEEDED,
+                    ..
+                })
+            ),
+            "{msg:?}"
+        );
+        client.send(broadcast("still there")).await;
+        let msg = other.recv().await;
+        assert!(matches!(&msg, Some(QBIMessage::Broadcast { msg }) if msg == "still there"));
+    }
+
+    #[tokio::test]
+    async fn session_key_handshake() {
+        let (server, mut client) = Server::bind(true).await.connect().await;
+        server.send(broadcast("hello")).await;
+        let msg = client.recv().await;
+        assert!(matches!(&msg, Some(QBIMessage::Broadcast { msg }) if msg == "hello"));
+    }
+
+    #[tokio::test]
+    async fn reject_mismatched_secret() {
+        let server = Server::bind(true).await;
+        let _client = Host::spawn(server.client(b"other"), QBDeviceId::generate());
+        let mut host = server.accept(QBDeviceId::generate()).await;
+        // the connection is closed before the device id is proxied
+        assert!(host.recv().await.is_none());
+        assert!(host.task.await.is_ok());
+    }
+
+    #[test]
+    fn accept_plain_token() {
+        let protocol = QBP::default();
+        let identity = verify_auth(&protocol, AUTH, false, AUTH).unwrap();
+        assert_eq!(identity, Some(QBITCPIdentity::Token));
+        assert_eq!(verify_auth(&protocol, AUTH, false, b"other").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn reject_tampered_device_id() {
+        let (mut a, mut b) = tokio::io::duplex(64 * 1024);
+        let (mut host, mut peer) = (QBP::default(), QBP::default());
+        let (res_a, res_b) = tokio::join!(host.negotiate(&mut a), peer.negotiate(&mut b));
+        res_a.unwrap();
+        res_b.unwrap();
+
+        let device_id = QBDeviceId::generate();
+        let payload = auth_payload(&peer, AUTH, true, &device_id).unwrap();
+        let identity = verify_auth(&host, AUTH, true, &payload).unwrap();
+        assert_eq!(identity, Some(QBITCPIdentity::Device(device_id)));
+
+        // claim to be another device, but keep the proof of the original one
+        let mut auth = bitcode::decode::<QBITCPAuth>(&payload).unwrap();
+        auth.device_id = QBDeviceId::generate();
+        let tampered = bitcode::encode(&auth);
+        assert_eq!(verify_auth(&host, AUTH, true, &tampered).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn error_keeps_connection() {
+        let (server, mut client) = Server::bind(false).await.connect().await;
+
+        // e.g. the changes of a sync could not be applied
+        server
+            .send(QBIMessage::Error {
+                code: error_code::APPLY_FAILED,
+                msg: "no space left".to_owned(),
+            })
+            .await;
+        server.send(broadcast("hello")).await;
+
+        let msg = client.recv().await;
+        assert!(
+            matches!(&msg, Some(QBIMessage::Error { code: error_code::APPLY_FAILED, msg }) if msg == "no space left"),
+            "{msg:?}"
+        );
+        let msg = client.recv().await;
+        assert!(matches!(&msg, Some(QBIMessage::Broadcast { msg }) if msg == "hello"));
+        assert!(!server.task.is_finished());
+    }
+}
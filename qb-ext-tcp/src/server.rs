@@ -2,34 +2,50 @@
 //!
 //! This module is for the stuff that runs on the server.
 
-use std::{net::IpAddr, str::FromStr, sync::Arc};
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
 
 use bitcode::{Decode, Encode};
 use qb_core::device::QBDeviceId;
 use qb_ext::{
     hook::{QBHContext, QBHHostMessage, QBHInit},
     interface::{QBIChannel, QBIContext},
-    QBExtSetup,
+    QBExtSetup, QBExtSetupError,
 };
-use qb_proto::QBP;
+use qb_proto::{DEFAULT_HEARTBEAT_INTERVAL, QBP};
 use rcgen::SanType;
 use rustls_cert_gen::CertificateBuilder;
 use rustls_pemfile::private_key;
 use serde::Deserialize;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    time::Instant,
+};
 use tokio_rustls::rustls::ServerConfig;
 use tokio_rustls::{TlsAcceptor, TlsStream};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, info_span, Instrument};
 
-use crate::Runner;
+use crate::{verify_auth, QBITCPIdentity, QBITCPLimits, Runner};
 
 #[derive(Decode, Deserialize)]
 pub struct QBHTCPServerSetup {
     #[serde(default = "port_default")]
     pub port: u16,
-    #[serde(default = "host_default")]
+    /// The address to bind to. `0.0.0.0` accepts connections on all
+    /// interfaces, `127.0.0.1` only those from this machine.
+    #[serde(default = "host_default", alias = "bind_addr")]
     pub host: String,
     pub auth: Vec<u8>,
+    /// Expect a session key derived from the authentication
+    /// token instead of the token itself
+    #[serde(default)]
+    pub session_key: bool,
+    /// The limits for the messages of each client
+    #[serde(default)]
+    pub limits: QBITCPLimits,
 }
 
 fn port_default() -> u16 {
@@ -41,7 +57,7 @@ fn host_default() -> String {
 }
 
 impl QBExtSetup<QBHTCPServer> for QBHTCPServerSetup {
-    async fn setup(self) -> QBHTCPServer {
+    async fn setup(self) -> Result<QBHTCPServer, QBExtSetupError> {
         debug!("generating certificate...");
         let ca = CertificateBuilder::new()
             .certificate_authority()
@@ -52,27 +68,35 @@ impl QBExtSetup<QBHTCPServer> for QBHTCPServerSetup {
             .unwrap();
         let chain_pem = ca.serialize_pem();
         let chain_bytes = chain_pem.cert_pem;
+        let mut names = vec![
+            SanType::DnsName("quixbyte.local".try_into().unwrap()),
+            SanType::IpAddress(IpAddr::from_str("0.0.0.0").unwrap()),
+        ];
+        if let Ok(addr) = IpAddr::from_str(&self.host) {
+            if !addr.is_unspecified() {
+                names.push(SanType::IpAddress(addr));
+            }
+        }
         let entity_pem = CertificateBuilder::new()
             .end_entity()
             .common_name("Tls End-Entity Certificate")
-            .subject_alternative_names(vec![
-                SanType::DnsName("quixbyte.local".try_into().unwrap()),
-                SanType::IpAddress(IpAddr::from_str("0.0.0.0").unwrap()),
-            ])
+            .subject_alternative_names(names)
             .build(&ca)
             .unwrap()
             .serialize_pem();
         let entity_key_bytes = entity_pem.private_key_pem;
         let entity_cert_bytes = entity_pem.cert_pem;
 
-        QBHTCPServer {
+        Ok(QBHTCPServer {
             chain_bytes,
             entity_key_bytes,
             entity_cert_bytes,
             host: self.host,
             port: self.port,
             auth: self.auth,
-        }
+            session_key: self.session_key,
+            limits: self.limits,
+        })
     }
 }
 
@@ -84,26 +108,20 @@ pub struct QBHTCPServer {
     entity_cert_bytes: String,
     chain_bytes: String,
 
+    /// The address the listener is bound to
     host: String,
     port: u16,
     /// An authentication token sent on boot
     auth: Vec<u8>,
+    /// Whether clients send a session key instead of the token
+    session_key: bool,
+    /// The limits for the messages of each client
+    limits: QBITCPLimits,
 }
 
-impl QBHContext<QBITCPServer> for QBHTCPServer {
-    async fn run(self, mut init: QBHInit<QBITCPServer>) {
-        let addr = format!("{}:{}", self.host, self.port);
-        let listener = match TcpListener::bind(addr.clone()).await {
-            Ok(val) => {
-                info!("successfully bind on {}", addr);
-                val
-            }
-            Err(err) => {
-                error!("unable to bind on {}: {}", addr, err);
-                return;
-            }
-        };
-
+impl QBHTCPServer {
+    /// Build the TLS config from the generated certificates.
+    pub(crate) fn tls_config(&self) -> Arc<ServerConfig> {
         let mut ca_certs = rustls_pemfile::certs(&mut self.chain_bytes.as_bytes())
             .filter_map(|e| e.ok())
             .collect();
@@ -115,10 +133,36 @@ impl QBHContext<QBITCPServer> for QBHTCPServer {
             .collect();
         certs.append(&mut ca_certs);
 
-        let config = ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(certs, key)
-            .unwrap();
+        Arc::new(
+            ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(certs, key)
+                .unwrap(),
+        )
+    }
+}
+
+impl QBHContext<QBITCPServer> for QBHTCPServer {
+    async fn run(self, mut init: QBHInit<QBITCPServer>) {
+        // binding to a tuple instead of a formatted
+        // string also supports IPv6 addresses like ::1
+        let listener = match TcpListener::bind((self.host.as_str(), self.port)).await {
+            Ok(val) => {
+                match val.local_addr() {
+                    Ok(addr) => info!("successfully bind on {}", addr),
+                    Err(_) => info!("successfully bind on {}:{}", self.host, self.port),
+                }
+                val
+            }
+            Err(err) => {
+                error!("unable to bind on {}:{}: {}", self.host, self.port, err);
+                return;
+            }
+        };
+
+        // the config is shared between all connections, every
+        // connection gets its own TLS session and auth check
+        let config = self.tls_config();
 
         loop {
             tokio::select! {
@@ -132,8 +176,11 @@ impl QBHContext<QBITCPServer> for QBHTCPServer {
                     // yield a [QBIServerSocket]
                     init.attach(QBITCPServer {
                         config: config.clone(),
-                        stream,
+                        stream: Some(stream),
+                        addr,
                         auth: self.auth.clone(),
+                        session_key: self.session_key,
+                        limits: self.limits.clone(),
                     })
                     .await;
                 }
@@ -147,32 +194,98 @@ impl QBHContext<QBITCPServer> for QBHTCPServer {
 /// the [QBHTCPServer].
 #[derive(Debug)]
 pub struct QBITCPServer {
-    pub stream: TcpStream,
-    pub config: ServerConfig,
+    /// The accepted socket, taken when the interface starts running
+    pub stream: Option<TcpStream>,
+    /// The address of the connected client
+    pub addr: SocketAddr,
+    pub config: Arc<ServerConfig>,
     /// An authentication token sent on boot
     pub auth: Vec<u8>,
+    /// Whether the client sends a session key instead of the token
+    pub session_key: bool,
+    /// The limits for the messages of the client
+    pub limits: QBITCPLimits,
 }
 
 impl QBIContext for QBITCPServer {
-    async fn run(self, host_id: QBDeviceId, com: QBIChannel) {
-        let stream = self.stream;
+    fn describe(&self) -> serde_json::Value {
+        serde_json::json!({
+            "addr": self.addr.to_string(),
+            "auth": "<redacted>",
+            "session_key": self.session_key,
+            "limits": self.limits,
+        })
+    }
+
+    async fn run(&mut self, host_id: QBDeviceId, com: QBIChannel) {
+        let span = info_span!("tcp-server", addr = self.addr.to_string());
+        self._run(host_id, com).instrument(span).await
+    }
+}
 
-        let acceptor = TlsAcceptor::from(Arc::new(self.config));
-        let mut stream = acceptor.accept(stream).await.unwrap();
+impl QBITCPServer {
+    // a failing client must never affect the other clients
+    // connected to the same server, so we do not panic here
+    async fn _run(&mut self, host_id: QBDeviceId, com: QBIChannel) {
+        let Some(stream) = self.stream.take() else {
+            error!("socket has already been consumed");
+            return;
+        };
+
+        let acceptor = TlsAcceptor::from(self.config.clone());
+        let mut stream = match acceptor.accept(stream).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                error!("TLS handshake failed: {}", err);
+                return;
+            }
+        };
 
-        let mut protocol = QBP::default();
-        protocol.negotiate(&mut stream).await.unwrap();
-        let auth = protocol.recv_payload(&mut stream).await.unwrap();
-        if self.auth != auth {
-            error!("client sent incorrect auth token!");
+        let mut protocol = QBP::default().with_heartbeat(DEFAULT_HEARTBEAT_INTERVAL);
+        if let Err(err) = protocol.negotiate(&mut stream).await {
+            error!("negotiation failed: {} ({})", err, protocol.state_detail());
             return;
         }
+        let auth = match protocol.recv_payload(&mut stream).await {
+            Ok(auth) => auth,
+            Err(err) => {
+                error!(
+                    "could not receive auth token: {} ({})",
+                    err,
+                    protocol.state_detail()
+                );
+                return;
+            }
+        };
+        let peer_id = match verify_auth(&protocol, &self.auth, self.session_key, &auth) {
+            Ok(Some(QBITCPIdentity::Device(peer_id))) => {
+                info!("client authenticated as {}", peer_id);
+                Some(peer_id)
+            }
+            Ok(Some(QBITCPIdentity::Token)) => {
+                info!("client authenticated with the auth token");
+                None
+            }
+            Ok(None) => {
+                error!("client sent incorrect auth token!");
+                return;
+            }
+            Err(err) => {
+                error!("could not derive session key: {}", err);
+                return;
+            }
+        };
 
         let runner = Runner {
             host_id,
+            cancel: com.cancel_token(),
             com,
             stream: TlsStream::Server(stream),
             protocol,
+            peer_id,
+            limits: Some(self.limits.clone()),
+            pending_changes: 0,
+            rate: (Instant::now(), 0),
         };
 
         runner.run().await;
@@ -12,6 +12,10 @@ use crate::QBExtId;
 use bitcode::{Decode, Encode};
 use hex::FromHexError;
 
+use qb_core::{
+    change::{QBChange, QBChangeKind, QBConflict},
+    path::QBResource,
+};
 use qb_proto::QBPBlob;
 
 use rand::Rng;
@@ -75,6 +79,10 @@ pub enum QBCRequest {
         name: String,
         /// The setup blob
         blob: QBPBlob,
+        /// Only set up the extension to check whether the setup
+        /// blob works, without adding the extension afterwards
+        #[serde(default)]
+        validate_only: bool,
     },
     /// Remove an interface or hook.
     Remove {
@@ -93,19 +101,46 @@ pub enum QBCRequest {
     },
     /// List the available interfaces and hooks.
     List,
+    /// Subscribe to events, like [QBCResponse::SyncEvent].
+    Subscribe,
+    /// Re-read the config of the daemon from disk.
+    Reload,
+    /// Get the status of the daemon.
+    Status,
+    /// Preview the changes which are pending to be synchronized
+    /// with an interface.
+    Diff {
+        /// the identifier
+        id: QBExtId,
+    },
+    /// Get aggregate metrics of the daemon.
+    Stats,
+    /// Pause (or resume) synchronizing with all interfaces at once.
+    Maintenance {
+        /// whether maintenance mode should be enabled
+        enabled: bool,
+    },
 }
 
 impl fmt::Display for QBCRequest {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            QBCRequest::Add { name, blob } => {
+            QBCRequest::Add {
+                name,
+                blob,
+                validate_only,
+            } => {
                 write!(
                     f,
                     "QBC_MSG_REQ_ADD {} {} {}",
                     name,
                     blob.content_type,
                     simdutf8::basic::from_utf8(&blob.content).unwrap_or("binary data")
-                )
+                )?;
+                if *validate_only {
+                    write!(f, " (validate only)")?;
+                }
+                Ok(())
             }
             QBCRequest::Remove { id } => {
                 write!(f, "QBC_MSG_REQ_REMOVE {}", id)
@@ -119,16 +154,152 @@ impl fmt::Display for QBCRequest {
             QBCRequest::List => {
                 write!(f, "QBC_MSG_REQ_LIST")
             }
+            QBCRequest::Subscribe => {
+                write!(f, "QBC_MSG_REQ_SUBSCRIBE")
+            }
+            QBCRequest::Reload => {
+                write!(f, "QBC_MSG_REQ_RELOAD")
+            }
+            QBCRequest::Status => {
+                write!(f, "QBC_MSG_REQ_STATUS")
+            }
+            QBCRequest::Diff { id } => {
+                write!(f, "QBC_MSG_REQ_DIFF {}", id)
+            }
+            QBCRequest::Stats => {
+                write!(f, "QBC_MSG_REQ_STATS")
+            }
+            QBCRequest::Maintenance { enabled } => {
+                write!(f, "QBC_MSG_REQ_MAINTENANCE {}", enabled)
+            }
+        }
+    }
+}
+
+/// The phase a resource is in while synchronizing.
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QBCSyncPhase {
+    /// The changes of the resource are being sent to an interface.
+    Started,
+    /// The changes of the resource have been received from an interface.
+    Transferred,
+    /// The changes of the resource have been applied to the master.
+    Applied,
+}
+
+impl fmt::Display for QBCSyncPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QBCSyncPhase::Started => write!(f, "started"),
+            QBCSyncPhase::Transferred => write!(f, "transferred"),
+            QBCSyncPhase::Applied => write!(f, "applied"),
+        }
+    }
+}
+
+/// The kind of a change without its contents, see [QBCResponse::Diff].
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QBCChangeKind {
+    /// Create resource
+    Create,
+    /// Delete resource
+    Delete,
+    /// Update file contents (text)
+    UpdateText,
+    /// Update file contents (binary)
+    UpdateBinary,
+    /// Update file contents (binary) using a patch
+    BinaryDiff,
+    /// Rename resource (destination)
+    RenameTo,
+    /// Rename resource (source)
+    RenameFrom,
+    /// Copy resource (destination)
+    CopyTo,
+    /// Copy resource (source)
+    CopyFrom,
+    /// Set or remove an extended attribute
+    XAttr,
+}
+
+impl From<&QBChangeKind> for QBCChangeKind {
+    fn from(kind: &QBChangeKind) -> Self {
+        match kind {
+            QBChangeKind::Create => QBCChangeKind::Create,
+            QBChangeKind::Delete => QBCChangeKind::Delete,
+            QBChangeKind::UpdateText(..) => QBCChangeKind::UpdateText,
+            QBChangeKind::UpdateBinary(..) => QBCChangeKind::UpdateBinary,
+            QBChangeKind::BinaryDiff { .. } => QBCChangeKind::BinaryDiff,
+            QBChangeKind::RenameTo => QBCChangeKind::RenameTo,
+            QBChangeKind::RenameFrom => QBCChangeKind::RenameFrom,
+            QBChangeKind::CopyTo => QBCChangeKind::CopyTo,
+            QBChangeKind::CopyFrom => QBCChangeKind::CopyFrom,
+            QBChangeKind::XAttr { .. } => QBCChangeKind::XAttr,
         }
     }
 }
 
+impl fmt::Display for QBCChangeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QBCChangeKind::Create => write!(f, "create"),
+            QBCChangeKind::Delete => write!(f, "delete"),
+            QBCChangeKind::UpdateText => write!(f, "updatetext"),
+            QBCChangeKind::UpdateBinary => write!(f, "updatebinary"),
+            QBCChangeKind::BinaryDiff => write!(f, "binarydiff"),
+            QBCChangeKind::RenameTo => write!(f, "renameto"),
+            QBCChangeKind::RenameFrom => write!(f, "renamefrom"),
+            QBCChangeKind::CopyTo => write!(f, "copyto"),
+            QBCChangeKind::CopyFrom => write!(f, "copyfrom"),
+            QBCChangeKind::XAttr => write!(f, "xattr"),
+        }
+    }
+}
+
+/// A conflict which occured while synchronizing, see [QBCResponse::Conflicts].
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone)]
+pub struct QBCConflict {
+    /// the resource both changes were applied to
+    pub resource: QBResource,
+    /// a description of the local change
+    pub local: String,
+    /// a description of the remote change
+    pub remote: String,
+}
+
+impl From<QBConflict> for QBCConflict {
+    fn from(conflict: QBConflict) -> Self {
+        let describe = |change: &QBChange| {
+            format!("{} {}", change.timestamp, QBCChangeKind::from(&change.kind))
+        };
+        Self {
+            local: describe(&conflict.local),
+            remote: describe(&conflict.remote),
+            resource: conflict.resource,
+        }
+    }
+}
+
+/// The kind of error a daemon responds with.
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum QBCErrorKind {
+    /// The request is not supported by this daemon.
+    Unsupported,
+    /// Any other error.
+    Other,
+}
+
 /// A response comming from the daemon.
 #[derive(Encode, Decode, Serialize, Deserialize)]
 #[non_exhaustive]
 pub enum QBCResponse {
     /// An error has occured.
     Error {
+        /// The kind of error
+        kind: QBCErrorKind,
         /// The error message
         msg: String,
     },
@@ -139,17 +310,147 @@ pub enum QBCResponse {
     },
     /// Generic success request.
     Success,
+    /// Response for the add request, after the extension
+    /// has been setup successfully.
+    Added {
+        /// the identifier of the new extension
+        id: QBExtId,
+    },
+    /// A resource is being synchronized (sent to subscribed handles).
+    SyncEvent {
+        /// the identifier of the interface
+        id: QBExtId,
+        /// the resource
+        resource: QBResource,
+        /// the phase the synchronization is in
+        phase: QBCSyncPhase,
+    },
+    /// Response for the status request.
+    Status {
+        /// the amount of changes stored in the changemap
+        changes: u64,
+        /// the amount of resources with changes in the changemap
+        resources: u64,
+        /// the size of the changemap when serialized in bytes
+        changemap_size: u64,
+        /// the configuration of each attached interface as JSON (redacted)
+        interfaces: Vec<(QBExtId, String)>,
+    },
+    /// A synchronization yielded conflicts (sent to subscribed handles).
+    Conflicts {
+        /// the identifier of the interface
+        id: QBExtId,
+        /// the conflicts
+        conflicts: Vec<QBCConflict>,
+    },
+    /// Response for the diff request.
+    Diff {
+        /// the identifier of the interface
+        id: QBExtId,
+        /// the pending changes in the order they will be applied
+        changes: Vec<(QBResource, QBCChangeKind)>,
+    },
+    /// Response for the stats request.
+    Stats {
+        /// the amount of interfaces and hooks which have been added
+        extensions: u64,
+        /// the amount of interfaces which are attached
+        attached: u64,
+        /// the amount of hooks which are hooked
+        hooked: u64,
+        /// the size of the changes received from interfaces in bytes
+        bytes_received: u64,
+        /// the size of the changes sent to interfaces in bytes
+        bytes_sent: u64,
+        /// the amount of changes stored in the changemap
+        changes: u64,
+        /// the size of the changemap when serialized in bytes
+        changemap_size: u64,
+        /// the time the daemon has been running for in seconds
+        uptime: u64,
+    },
 }
 
 impl fmt::Display for QBCResponse {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            QBCResponse::Error { msg } => {
+            QBCResponse::Error { msg, .. } => {
                 write!(f, "QBC_MSG_RESP_ERROR: {}", msg)
             }
             QBCResponse::Success => {
                 write!(f, "QBC_MSG_RESP_SUCCESS")
             }
+            QBCResponse::Added { id } => {
+                write!(f, "QBC_MSG_RESP_ADDED {}", id)
+            }
+            QBCResponse::SyncEvent {
+                id,
+                resource,
+                phase,
+            } => {
+                write!(f, "QBC_MSG_RESP_SYNC_EVENT {} {} {}", id, phase, resource)
+            }
+            QBCResponse::Status {
+                changes,
+                resources,
+                changemap_size,
+                interfaces,
+            } => {
+                write!(
+                    f,
+                    "QBC_MSG_RESP_STATUS changes: {} resources: {} changemap size: {}",
+                    changes, resources, changemap_size
+                )?;
+                for (id, description) in interfaces {
+                    write!(f, "\n{} {}", id, description)?;
+                }
+                Ok(())
+            }
+            QBCResponse::Conflicts { id, conflicts } => {
+                write!(f, "QBC_MSG_RESP_CONFLICTS {}:", id)?;
+                for conflict in conflicts {
+                    write!(
+                        f,
+                        "\n{} local: {} remote: {}",
+                        conflict.resource, conflict.local, conflict.remote
+                    )?;
+                }
+
+                Ok(())
+            }
+            QBCResponse::Diff { id, changes } => {
+                write!(f, "QBC_MSG_RESP_DIFF {}:", id)?;
+                for (resource, kind) in changes {
+                    write!(f, "\n{} {}", kind, resource)?;
+                }
+
+                Ok(())
+            }
+            QBCResponse::Stats {
+                extensions,
+                attached,
+                hooked,
+                bytes_received,
+                bytes_sent,
+                changes,
+                changemap_size,
+                uptime,
+            } => {
+                write!(
+                    f,
+                    "QBC_MSG_RESP_STATS extensions: {} attached: {} hooked: {} \
+                    received: {} bytes sent: {} bytes changes: {} \
+                    changemap size: {} uptime: {}s",
+                    extensions,
+                    attached,
+                    hooked,
+                    bytes_received,
+                    bytes_sent,
+                    changes,
+                    changemap_size,
+                    uptime
+                )
+            }
             QBCResponse::List { list } => {
                 write!(f, "QBC_MSG_RESP_LIST:")?;
                 for entry in list {
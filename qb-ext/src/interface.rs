@@ -44,6 +44,12 @@ pub enum QBIMessage {
         /// hash that points to the common change
         common: QBTimeStampUnique,
     },
+    /// a part of a synchronization, which is applied together
+    /// with the following [QBIMessage::Sync], see [QBIMessage::sync]
+    SyncPart {
+        /// a part of the changes
+        changes: QBChangeMap,
+    },
     /// synchronize
     Sync {
         /// the common hash that was used for creating the changes vector
@@ -59,6 +65,14 @@ pub enum QBIMessage {
         /// The device id
         device_id: QBDeviceId,
     },
+    /// Something went wrong on the other side, e.g. the
+    /// changes of the last sync could not be applied.
+    Error {
+        /// the error code, see [qb_proto::error_code]
+        code: u16,
+        /// the error message
+        msg: String,
+    },
 }
 
 impl fmt::Display for QBIMessage {
@@ -74,6 +88,9 @@ impl fmt::Display for QBIMessage {
                 }
                 Ok(())
             }
+            QBIMessage::SyncPart { changes } => {
+                write!(f, "QBI_MSG_SYNC_PART {} changes", changes.len())
+            }
             QBIMessage::Common { common } => {
                 write!(f, "QBI_MSG_COMMON {}", common)
             }
@@ -83,10 +100,32 @@ impl fmt::Display for QBIMessage {
             QBIMessage::Device { device_id } => {
                 write!(f, "QBI_MSG_DEVICE {}", device_id)
             }
+            QBIMessage::Error { code, msg } => {
+                write!(f, "QBI_MSG_ERROR {} {}", code, msg)
+            }
         }
     }
 }
 
+impl QBIMessage {
+    /// The amount of changes sent in a single [QBIMessage::SyncPart].
+    pub const SYNC_PART_LEN: usize = 1024;
+
+    /// Build the messages for synchronizing the changes, so that a large
+    /// sync is not serialized as a single message. These are some
+    /// [QBIMessage::SyncPart]s followed by a [QBIMessage::Sync].
+    pub fn sync(common: QBTimeStampUnique, changes: QBChangeMap) -> Vec<QBIMessage> {
+        let mut parts = changes
+            .split(Self::SYNC_PART_LEN)
+            .map(|changes| QBIMessage::SyncPart { changes })
+            .collect::<Vec<_>>();
+        if let Some(QBIMessage::SyncPart { changes }) = parts.pop() {
+            parts.push(QBIMessage::Sync { common, changes });
+        }
+        parts
+    }
+}
+
 impl From<QBIMessage> for QBISlaveMessage {
     fn from(val: QBIMessage) -> Self {
         QBISlaveMessage::Message(val)
@@ -123,9 +162,38 @@ pub enum QBIHostMessage {
 /// the QBI. It is send between the master thread and the QBI thread
 /// created by the master (might be the same thread as well, depends
 /// on what tokio chooses to do). QBIs are asynchronous by default.
+///
+/// The master calls [QBIContext::on_attach] before and
+/// [QBIContext::on_detach] after [QBIContext::run], both inside of
+/// the task that runs the QBI.
 pub trait QBIContext: Send + Sync {
+    /// Called once before the interface starts running. Useful for
+    /// one-time setup which should not be part of the main loop.
+    fn on_attach(&mut self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
     /// The main function of the QBI which will be spawned into a seperate
     /// async task (might be a thread, depends on how tokio handles this).
-    fn run(self, host_id: QBDeviceId, com: QBIChannel)
-        -> impl Future<Output = ()> + Send + 'static;
+    fn run(&mut self, host_id: QBDeviceId, com: QBIChannel) -> impl Future<Output = ()> + Send;
+
+    /// Called once after the interface has stopped running, e.g. for
+    /// flushing caches or releasing sessions.
+    fn on_detach(&mut self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Describe the configuration of this interface for display.
+    ///
+    /// Secrets (auth tokens, keys, ...) must not be included.
+    fn describe(&self) -> serde_json::Value {
+        serde_json::Value::Null
+    }
+
+    /// The priority of this interface when synchronizing. Interfaces
+    /// with a higher priority (e.g. low-latency peers in the local
+    /// network) are synchronized first.
+    fn priority(&self) -> i32 {
+        0
+    }
 }
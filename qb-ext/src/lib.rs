@@ -5,6 +5,7 @@
 //! messages.
 
 use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 
 pub mod control;
 pub mod hook;
@@ -14,7 +15,10 @@ use core::fmt;
 use std::future::Future;
 
 use bitcode::{Decode, Encode};
+use control::QBCRequest;
 use hex::FromHexError;
+use interface::QBIMessage;
+use qb_proto::{QBPContentType, QBPTagged, QBP};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 
@@ -54,10 +58,13 @@ impl QBExtId {
     }
 }
 
+/// The error returned if setting up an extension fails.
+pub type QBExtSetupError = Box<dyn std::error::Error + Send + Sync>;
+
 /// TODO: doc
 pub trait QBExtSetup<T> {
     /// Setup this extension.
-    fn setup(self) -> impl Future<Output = T> + Send + 'static;
+    fn setup(self) -> impl Future<Output = Result<T, QBExtSetupError>> + Send + 'static;
 }
 
 /// A channel used for communication from a slave
@@ -65,12 +72,31 @@ pub struct QBExtChannel<I: Clone, S, R> {
     id: I,
     tx: mpsc::Sender<(I, S)>,
     rx: mpsc::Receiver<R>,
+    cancel: CancellationToken,
 }
 
 impl<I: Clone, S, R> QBExtChannel<I, S, R> {
     /// Construct a new channel
     pub fn new(id: I, tx: mpsc::Sender<(I, S)>, rx: mpsc::Receiver<R>) -> Self {
-        QBExtChannel { id, tx, rx }
+        QBExtChannel {
+            id,
+            tx,
+            rx,
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    /// Use the given token, which the host cancels if the
+    /// extension does not stop in time after being told to.
+    pub fn with_cancel(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Returns the token which the host cancels if the extension does not
+    /// stop in time, which should abort reads that are waiting for a peer.
+    pub fn cancel_token(&self) -> CancellationToken {
+        self.cancel.clone()
     }
 
     /// Send a message to this channel
@@ -86,3 +112,123 @@ impl<I: Clone, S, R> QBExtChannel<I, S, R> {
         self.rx.recv().await.expect("channel closed").into()
     }
 }
+
+/// The tags of the message types a connection can carry, see [QBExtMessage].
+pub mod message_tag {
+    /// interface messages, see [crate::interface::QBIMessage]
+    pub const INTERFACE: u8 = 0;
+    /// control requests, see [crate::control::QBCRequest]
+    pub const CONTROL: u8 = 1;
+}
+
+/// A message of a connection which carries both interface
+/// messages and control requests, which are told apart by
+/// their tag, see [qb_proto::QBP::recv_any].
+pub enum QBExtMessage {
+    /// an interface message
+    Interface(QBIMessage),
+    /// a control request
+    Control(QBCRequest),
+}
+
+impl From<QBIMessage> for QBExtMessage {
+    fn from(value: QBIMessage) -> Self {
+        Self::Interface(value)
+    }
+}
+
+impl From<QBCRequest> for QBExtMessage {
+    fn from(value: QBCRequest) -> Self {
+        Self::Control(value)
+    }
+}
+
+impl QBExtMessage {
+    /// Returns the tag this message is sent with.
+    pub fn tag(&self) -> u8 {
+        match self {
+            Self::Interface(_) => message_tag::INTERFACE,
+            Self::Control(_) => message_tag::CONTROL,
+        }
+    }
+
+    /// Send this message tagged with its type.
+    pub async fn send(
+        self,
+        qbp: &mut QBP,
+        write: &mut impl qb_proto::Write,
+    ) -> qb_proto::Result<()> {
+        let tag = self.tag();
+        match self {
+            Self::Interface(msg) => qbp.send_tagged(write, tag, msg).await,
+            Self::Control(msg) => qbp.send_tagged(write, tag, msg).await,
+        }
+    }
+}
+
+impl QBPTagged for QBExtMessage {
+    fn from_tagged(
+        tag: Option<u8>,
+        content_type: &QBPContentType,
+        data: &[u8],
+    ) -> qb_proto::Result<Self> {
+        match tag {
+            // peers which do not tag their messages only send interface messages
+            Some(message_tag::INTERFACE) | None => {
+                content_type.from_bytes(data).map(Self::Interface)
+            }
+            Some(message_tag::CONTROL) => content_type.from_bytes(data).map(Self::Control),
+            tag => Err(qb_proto::Error::UnknownTag(tag)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn carry_interface_and_control_messages() {
+        let (mut a, mut b) = tokio::io::duplex(64 * 1024);
+        let mut host = QBP::default();
+        let mut peer = QBP::default();
+        let (res_a, res_b) = tokio::join!(host.negotiate(&mut a), peer.negotiate(&mut b));
+        res_a.unwrap();
+        res_b.unwrap();
+
+        let msg = QBIMessage::Broadcast {
+            msg: "hello".to_owned(),
+        };
+        QBExtMessage::from(msg)
+            .send(&mut host, &mut a)
+            .await
+            .unwrap();
+        let req = QBCRequest::Start { id: QBExtId(42) };
+        QBExtMessage::from(req)
+            .send(&mut host, &mut a)
+            .await
+            .unwrap();
+
+        let msg = peer.recv_any::<QBExtMessage>(&mut b).await.unwrap();
+        assert!(matches!(
+            msg,
+            QBExtMessage::Interface(QBIMessage::Broadcast { msg }) if msg == "hello"
+        ));
+        let msg = peer.recv_any::<QBExtMessage>(&mut b).await.unwrap();
+        assert!(matches!(
+            msg,
+            QBExtMessage::Control(QBCRequest::Start { id }) if id == QBExtId(42)
+        ));
+
+        // untagged messages are interface messages
+        let msg = QBIMessage::Broadcast {
+            msg: "untagged".to_owned(),
+        };
+        host.send(&mut a, msg).await.unwrap();
+        let msg = peer.recv_any::<QBExtMessage>(&mut b).await.unwrap();
+        assert!(matches!(
+            msg,
+            QBExtMessage::Interface(QBIMessage::Broadcast { msg }) if msg == "untagged"
+        ));
+    }
+}
@@ -3,7 +3,7 @@ use std::time::Duration;
 
 use bitcode::{Decode, Encode};
 use qb_core::{
-    change::{QBChange, QBChangeKind},
+    change::{QBChange, QBChangeKind, QBChangeMap},
     device::QBDeviceId,
     fs::{QBFileDiff, QBFS},
     path::{qbpaths::INTERNAL, QBResource},
@@ -11,8 +11,9 @@ use qb_core::{
 };
 use qb_ext::{
     interface::{QBIChannel, QBIContext, QBIHostMessage, QBIMessage},
-    QBExtSetup,
+    QBExtSetup, QBExtSetupError,
 };
+use qb_proto::error_code;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, info, warn};
 
@@ -22,18 +23,18 @@ pub struct QBIAndroid {
 }
 
 impl QBIContext for QBIAndroid {
-    async fn run(self, host_id: QBDeviceId, com: QBIChannel) {
+    async fn run(&mut self, host_id: QBDeviceId, com: QBIChannel) {
         Runner::init(self, host_id, com).await.run().await;
     }
 }
 
 impl QBExtSetup<QBIAndroid> for QBIAndroid {
-    async fn setup(self) -> Self {
+    async fn setup(self) -> Result<Self, QBExtSetupError> {
         info!("PATH: {}", self.path);
         let mut fs = QBFS::init(self.path.clone()).await;
         fs.devices.host_id = QBDeviceId::generate();
-        fs.save().await.unwrap();
-        self
+        fs.save().await?;
+        Ok(self)
     }
 }
 
@@ -41,13 +42,15 @@ struct Runner {
     com: QBIChannel,
     fs: QBFS,
     syncing: bool,
+    /// the parts of the sync which is currently being received
+    parts: QBChangeMap,
     host_id: QBDeviceId,
     recorder: QBTimeStampRecorder,
 }
 
 impl Runner {
-    async fn init(cx: QBIAndroid, host_id: QBDeviceId, com: QBIChannel) -> Self {
-        let fs = QBFS::init(cx.path).await;
+    async fn init(cx: &QBIAndroid, host_id: QBDeviceId, com: QBIChannel) -> Self {
+        let fs = QBFS::init(&cx.path).await;
 
         com.send(QBIMessage::Device {
             device_id: fs.devices.host_id.clone(),
@@ -62,6 +65,7 @@ impl Runner {
 
         Self {
             syncing: false,
+            parts: Default::default(),
             recorder,
             host_id,
             fs,
@@ -77,32 +81,81 @@ impl Runner {
                 self.fs.devices.set_common(&self.host_id, common);
                 self.fs.save_devices().await.unwrap();
             }
-            QBIMessage::Sync {
-                common,
-                changes: remote,
-            } => {
+            QBIMessage::SyncPart { changes } => self.parts.append_map(changes),
+            QBIMessage::Sync { common, changes } => {
+                let mut remote = std::mem::take(&mut self.parts);
+                remote.append_map(changes);
+
                 assert!(self.fs.devices.get_common(&self.host_id).clone() == common);
 
-                let local = self.fs.changemap.since(&common);
+                let local = match self.fs.changemap.since(&common) {
+                    Ok(local) => local,
+                    Err(err) => {
+                        warn!("could not sync: {}", err);
+                        self.syncing = false;
+                        self.com
+                            .send(QBIMessage::Error {
+                                code: error_code::TRUNCATED,
+                                msg: err.to_string(),
+                            })
+                            .await;
+                        return;
+                    }
+                };
 
                 // Apply changes
                 let mut changemap = local.clone();
-                let changes = changemap.merge(remote).unwrap();
+                let changes = match changemap.merge(remote) {
+                    Ok(changes) => changes,
+                    Err(err) => {
+                        // reject the sync, keeping our own changes
+                        warn!("could not merge changes: {}", err);
+                        self.fs.changemap.append_map(local);
+                        self.syncing = false;
+                        self.com
+                            .send(QBIMessage::Error {
+                                code: error_code::APPLY_FAILED,
+                                msg: err,
+                            })
+                            .await;
+                        return;
+                    }
+                };
+                let fschanges = match self.fs.to_fschanges(changes).await {
+                    Ok(fschanges) => fschanges,
+                    Err(err) => {
+                        // reject the sync, keeping our own changes
+                        warn!("could not convert changes: {}", err);
+                        self.fs.changemap.append_map(local);
+                        self.syncing = false;
+                        self.com
+                            .send(QBIMessage::Error {
+                                code: error_code::APPLY_FAILED,
+                                msg: err.to_string(),
+                            })
+                            .await;
+                        return;
+                    }
+                };
                 self.fs.changemap.append_map(changemap);
-                let fschanges = self.fs.to_fschanges(changes);
-                self.fs.apply_changes(fschanges).await.unwrap();
+                if let Err(err) = self.fs.apply_changes(fschanges).await {
+                    warn!("could not apply changes: {}", err);
+                    self.com
+                        .send(QBIMessage::Error {
+                            code: error_code::APPLY_FAILED,
+                            msg: err.to_string(),
+                        })
+                        .await;
+                }
 
                 let new_common = self.fs.changemap.head().clone();
                 self.fs.devices.set_common(&self.host_id, new_common);
 
                 // Send sync to remote
                 if !self.syncing {
-                    self.com
-                        .send(QBIMessage::Sync {
-                            common,
-                            changes: local,
-                        })
-                        .await;
+                    for msg in QBIMessage::sync(common, local) {
+                        self.com.send(msg).await;
+                    }
                 }
 
                 self.syncing = false;
@@ -111,6 +164,11 @@ impl Runner {
                 self.fs.save().await.unwrap();
             }
             QBIMessage::Broadcast { msg } => debug!("BROADCAST: {}", msg),
+            QBIMessage::Error { code, msg } => {
+                warn!("master reported error {}: {}", code, msg);
+                // the master will not answer the sync we sent
+                self.syncing = false;
+            }
             val => warn!("unexpected message: {}", val),
         }
     }
@@ -126,14 +184,23 @@ impl Runner {
 
         // Complete transaction
         let common = self.fs.devices.get_common(&self.host_id).clone();
-        let mut changes = self.fs.changemap.since_cloned(&common);
+        let mut changes = match self.fs.changemap.since_cloned(&common) {
+            Ok(changes) => changes,
+            Err(err) => {
+                warn!("could not sync: {}", err);
+                self.syncing = false;
+                return;
+            }
+        };
         changes.minify();
 
         // save the changes applied
         self.fs.save().await.unwrap();
 
         // notify remote
-        self.com.send(QBIMessage::Sync { common, changes }).await;
+        for msg in QBIMessage::sync(common, changes) {
+            self.com.send(msg).await;
+        }
     }
 
     async fn on_notification(&mut self, notification: NotifyAndroid) {
@@ -141,7 +208,7 @@ impl Runner {
         let path = &resource.path;
 
         // skip internal files
-        if INTERNAL.is_parent(&path) {
+        if INTERNAL.is_parent(path) {
             return;
         }
 
@@ -150,24 +217,49 @@ impl Runner {
             return;
         }
 
-        let change = match notification.kind {
+        let entries = match notification.kind {
             NotifyKind::Write => {
                 info!("KIND: {:?}", self.fs.wrapper.fspath(&resource));
-                let kind = self.fs.diff(&resource).await;
-                let kind = kind.unwrap();
-                match kind {
-                    Some(QBFileDiff::Text(diff)) => {
-                        QBChange::new(self.recorder.record(), QBChangeKind::UpdateText(diff))
+                let kind = match self.fs.diff(&resource).await {
+                    Ok(kind) => kind,
+                    Err(err) => {
+                        warn!("could not diff {}: {}", resource, err);
+                        return;
                     }
-                    Some(QBFileDiff::Binary(contents)) => {
-                        QBChange::new(self.recorder.record(), QBChangeKind::UpdateBinary(contents))
-                    }
-                    None => return,
+                };
+                match kind {
+                    Some(QBFileDiff::Text(diff)) => vec![(
+                        resource,
+                        QBChange::new(self.recorder.record(), QBChangeKind::UpdateText(diff)),
+                    )],
+                    Some(QBFileDiff::Binary(contents)) => vec![(
+                        resource,
+                        QBChange::new(self.recorder.record(), QBChangeKind::UpdateBinary(contents)),
+                    )],
+                    Some(QBFileDiff::BinaryPatch { base_hash, patch }) => vec![(
+                        resource,
+                        QBChange::new(
+                            self.recorder.record(),
+                            QBChangeKind::BinaryDiff { base_hash, patch },
+                        ),
+                    )],
+                    Some(QBFileDiff::Kind { from, to }) => vec![
+                        (
+                            QBResource::new(resource.path.clone(), from),
+                            QBChange::new(self.recorder.record(), QBChangeKind::Delete),
+                        ),
+                        (
+                            QBResource::new(resource.path, to),
+                            QBChange::new(self.recorder.record(), QBChangeKind::Create),
+                        ),
+                    ],
+                    // the tree has been updated already
+                    Some(QBFileDiff::Touched) | None => return,
                 }
             }
         };
 
-        self.fs.changemap.push((resource, change));
+        self.fs.changemap.append(entries);
         info!("CHANGE ADDED: should_sync = {}", self.should_sync());
     }
 
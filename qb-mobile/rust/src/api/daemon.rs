@@ -54,7 +54,7 @@ impl DaemonWrapper {
             content_type,
             content,
         };
-        daemon.add(QBCId::root(), name, blob).unwrap();
+        daemon.add(QBCId::root(), name, blob, false).unwrap();
     }
 
     /// Remove an extension to this daemon.
@@ -108,7 +108,7 @@ impl DaemonWrapper {
         daemon
             .master
             .send(&QBExtId(id), QBIHostMessage::Bridge(data))
-            .await;
+            .unwrap();
     }
 
     /// Cancel cancelable tasks.
@@ -140,6 +140,12 @@ impl DaemonWrapper {
             Some(v) = daemon.master.qbi_rx.recv() => daemon.master.iprocess(v).await,
             // process hooks
             Some(v) = daemon.master.qbh_rx.recv() => daemon.master.hprocess(v),
+            // process interfaces whose handshake deadline passed
+            Some(v) = daemon.master.handshake_rx.recv() => daemon.master.check_handshake(v),
+            // process state transitions of interfaces
+            Some(v) = daemon.master.state_rx.recv() => daemon.supervise(v).await,
+            // process autostart interfaces which should be restarted
+            Some(v) = daemon.retry_rx.recv() => daemon.retry(v).await,
             // process control messages
             Some(v) = daemon.req_rx.recv() => daemon.process(v).await,
             // process daemon setup queue
@@ -4,15 +4,26 @@
 
 #![warn(missing_docs)]
 
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    io::{self, IoSlice},
+    pin::Pin,
+    task::Poll,
+    time::{Duration, Instant},
+};
 
 use bitcode::{Decode, Encode};
+use hkdf::Hkdf;
 use itertools::Itertools;
 use phf::phf_ordered_map;
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use simdutf8::basic::Utf8Error;
 use thiserror::Error;
-use tracing::trace;
+use tokio_util::sync::CancellationToken;
+use tracing::{trace, warn};
 use url_search_params::{build_url_search_params, parse_url_search_params};
 
 /// This struct contains errors which may yield when working with QBP.
@@ -64,6 +75,188 @@ pub enum Error {
     /// Connection has been closed while negotiating.
     #[error("received EOF while reading")]
     Closed,
+    /// A session key was requested, but one of the peers did not
+    /// send a (valid) nonce in its header packet.
+    #[error("no valid nonce has been exchanged!")]
+    MissingNonce,
+    /// The peer sent an error frame (see [QBP::send_error]). The
+    /// connection is still intact, so this error is recoverable.
+    #[error("peer error {0}: {1}")]
+    PeerError(u16, String),
+    /// The connection has been resumed (see [QBP::with_resumption]), but
+    /// the header packet of the peer shows that negotiating would yield a
+    /// different result. The connection has to be negotiated from scratch.
+    #[error("the connection could not be resumed")]
+    ResumptionRejected,
+    /// A tagged packet has been received (see [QBP::send_tagged]),
+    /// but the tag does not belong to any expected message type.
+    #[error("unknown message tag: {0:?}")]
+    UnknownTag(Option<u8>),
+    /// The peer did not announce support for a kind of frame,
+    /// e.g. error frames (see [ERRORS_HEADER]), so it is not sent.
+    #[error("the peer does not support {0} frames")]
+    Unsupported(&'static str),
+    /// The peer frames its packets differently (see [FRAMING_HEADER]).
+    #[error("peer uses packet framing version {0}, host uses {FRAMING_VERSION}")]
+    FramingMismatch(String),
+    /// A payload could not be decoded into the expected message type,
+    /// e.g. because the peer uses another version of the message.
+    #[error("could not decode {type_name} from {len} bytes of {content_type}: {source}")]
+    DecodeFailed {
+        /// the content type the payload has been decoded with
+        content_type: &'static str,
+        /// the length of the (decompressed) payload in bytes
+        len: usize,
+        /// the name of the type that was decoded
+        type_name: &'static str,
+        /// the error of the decoder
+        source: Box<Error>,
+    },
+    /// A payload could not be decompressed with the negotiated
+    /// content encoding, e.g. because it has been corrupted.
+    #[error("could not decompress payload with {encoding:?}: {source}")]
+    DecompressFailed {
+        /// the content encoding the payload has been decompressed with
+        encoding: QBPContentEncoding,
+        /// the error of the decompressor
+        source: io::Error,
+    },
+    /// Receiving or sending has been aborted by a cancellation
+    /// token (see [QBP::recv_cancelable]).
+    #[error("canceled")]
+    Canceled,
+}
+
+impl Error {
+    /// Returns whether the connection is still intact after this error.
+    ///
+    /// Writes which failed because of such an error keep their unsent
+    /// bytes buffered, so sending or flushing again resumes them.
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            Error::PeerError(..) => true,
+            Error::IOError(err) => matches!(
+                err.kind(),
+                io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ),
+            _ => false,
+        }
+    }
+}
+
+/// Error codes which can be sent in an error frame, see [QBP::send_error].
+pub mod error_code {
+    /// An unspecified error.
+    pub const OTHER: u16 = 0;
+    /// The changes which have been sent could not be applied.
+    pub const APPLY_FAILED: u16 = 1;
+    /// The peer did not authenticate itself correctly.
+    pub const UNAUTHORIZED: u16 = 2;
+    /// The changes since the common change have already been
+    /// removed, the peer has to be synchronized from scratch.
+    pub const TRUNCATED: u16 = 3;
+    /// The peer exceeded the limits of the connection, e.g.
+    /// by sending a sync with too many changes.
+    pub const LIMIT_EXCEEDED: u16 = 4;
+    /// A diff could not be applied, because it is not based on the
+    /// current content of the resource, the full content has to be resent.
+    pub const MISSING_BASE: u16 = 5;
+}
+
+/// The version of the packet framing, that is, how packets are delimited.
+/// Version 0 prefixes every packet with its length as a big-endian u64
+/// ([LENGTH_PREFIX_LEN] bytes), whose highest bits are used as flags.
+pub const FRAMING_VERSION: u8 = 0;
+
+/// The length of the length prefix of a packet in bytes.
+pub const LENGTH_PREFIX_LEN: usize = 8;
+
+/// The header which carries the framing version, see [FRAMING_VERSION].
+/// Header packets are always framed using version 0, so that they can be
+/// read before the version is known. Peers which do not send this header
+/// use version 0.
+pub const FRAMING_HEADER: &str = "framing";
+
+/// The flag in the length prefix of a packet which marks an error frame.
+/// Packets will never be this long, so the bit is free to use.
+const ERROR_FLAG: u64 = 1 << 63;
+
+/// The flag in the length prefix of a packet which marks a fragment
+/// of a larger packet, see [QBP::queue].
+const FRAGMENT_FLAG: u64 = 1 << 62;
+
+/// The flag in the length prefix of a fragment which marks that
+/// more fragments of the same packet follow.
+const MORE_FLAG: u64 = 1 << 61;
+
+/// The flag in the length prefix of an (empty) heartbeat frame, which
+/// is only sent if both peers have announced a heartbeat interval.
+const HEARTBEAT_FLAG: u64 = 1 << 60;
+
+/// The flag in the length prefix of a packet whose first
+/// byte is a type tag, see [QBP::send_tagged].
+const TAGGED_FLAG: u64 = 1 << 59;
+
+/// The flag in the length prefix of a packet whose payload has not been
+/// compressed with the negotiated content encoding, because it is too
+/// small, see [QBP::with_compress_min_size].
+const PLAIN_FLAG: u64 = 1 << 58;
+
+/// All flags which can be set in the length prefix of a packet.
+const FLAGS: u64 =
+    ERROR_FLAG | FRAGMENT_FLAG | MORE_FLAG | HEARTBEAT_FLAG | TAGGED_FLAG | PLAIN_FLAG;
+
+/// The maximum size of a fragment in bytes. Packets with a
+/// low priority which are larger than this get fragmented.
+pub const FRAGMENT_SIZE: usize = 16 * 1024;
+
+/// The header which announces that fragmented packets are understood.
+pub const FRAGMENTS_HEADER: &str = "fragments";
+
+/// The header which announces that packets which have not
+/// been compressed (see [QBP::with_compress_min_size]) are understood.
+pub const PLAIN_HEADER: &str = "plain";
+
+/// The header which announces that error frames (see [QBP::send_error])
+/// are understood. Peers which do not announce it would read the flag in
+/// the length prefix as part of the length.
+pub const ERRORS_HEADER: &str = "errors";
+
+/// The header which announces that tagged packets
+/// (see [QBP::send_tagged]) are understood.
+pub const TAGGED_HEADER: &str = "tagged";
+
+/// The size in bytes below which payloads are not compressed by default,
+/// as compressing tiny control messages makes them larger instead.
+pub const DEFAULT_COMPRESS_MIN_SIZE: usize = 64;
+
+/// The header which carries the token of a resumed connection.
+pub const RESUME_HEADER: &str = "resume";
+
+/// The header which carries the desired heartbeat interval in milliseconds.
+pub const HEARTBEAT_HEADER: &str = "heartbeat";
+
+/// The header which carries the name of the software of the peer.
+pub const PEER_NAME_HEADER: &str = "x-peer-name";
+
+/// The header which carries the version of the software of the peer.
+pub const PEER_VERSION_HEADER: &str = "x-peer-version";
+
+/// The heartbeat interval used by the interfaces which support heartbeats.
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The amount of heartbeat intervals without receiving anything
+/// after which the peer is considered dead, see [QBP::is_timed_out].
+pub const HEARTBEAT_TIMEOUT_FACTOR: u32 = 3;
+
+/// The priority of a queued message, see [QBP::queue].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QBPPriority {
+    /// Small control messages, which are sent in between
+    /// the fragments of messages with a low priority.
+    High,
+    /// Large data messages, which get fragmented if the peer supports it.
+    Low,
 }
 
 /// A result type alias for convenience.
@@ -117,14 +310,29 @@ pub const MAJOR_VERSION: u8 = 0;
 /// The minor version of this QBP.
 pub const MINOR_VERSION: u8 = 0;
 
+/// The length of the nonce sent in the header packet in bytes.
+pub const NONCE_LEN: usize = 16;
+
+/// The length of a session key derived by [QBP::session_key] in bytes.
+pub const SESSION_KEY_LEN: usize = 32;
+
 /// The content types which this QBP supports.
 pub const SUPPORTED_CONTENT_TYPES: phf::OrderedMap<&'static str, QBPContentType> = phf_ordered_map! {
     "application/bitcode" => QBPContentType::Bitcode,
     "application/json" => QBPContentType::Json,
 };
 
+/// The header which hints that the transport already compresses
+/// payloads, so that QBP itself should not compress them again.
+pub const TRANSPORT_COMPRESSION_HEADER: &str = "transport-compression";
+
+/// The quality level brotli uses by default (0-11).
+pub const BROTLI_DEFAULT_QUALITY: u32 = 9;
+
 /// The content encodings which this QBP supports.
 pub const SUPPORTED_CONTENT_ENCODINGS: phf::OrderedMap<&'static str, QBPContentEncoding> = phf_ordered_map! {
+    "zstd" => QBPContentEncoding::Zstd,
+    "br" => QBPContentEncoding::Brotli { quality: BROTLI_DEFAULT_QUALITY },
     "zlib" => QBPContentEncoding::Zlib,
     "gzip" => QBPContentEncoding::Gzip,
     "plain" => QBPContentEncoding::Plain,
@@ -187,17 +395,46 @@ impl QBPHeaderPacket {
         headers.insert("accept".to_owned(), accept);
         let accept_encoding = SUPPORTED_CONTENT_ENCODINGS.keys().join(",");
         headers.insert("accept-encoding".to_owned(), accept_encoding);
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        headers.insert("nonce".to_owned(), hex::encode(nonce));
+        headers.insert(
+            PEER_NAME_HEADER.to_owned(),
+            env!("CARGO_PKG_NAME").to_owned(),
+        );
+        headers.insert(
+            PEER_VERSION_HEADER.to_owned(),
+            env!("CARGO_PKG_VERSION").to_owned(),
+        );
         QBPHeaderPacket {
             major_version: MAJOR_VERSION,
             minor_version: MINOR_VERSION,
             headers,
         }
     }
+
+    /// Get the name and version of the software which sent this header packet.
+    pub fn peer_info(&self) -> QBPPeerInfo {
+        QBPPeerInfo {
+            name: self.headers.get(PEER_NAME_HEADER).cloned(),
+            version: self.headers.get(PEER_VERSION_HEADER).cloned(),
+        }
+    }
+
+    /// Get the nonce of this header packet, if it contains a valid one.
+    pub fn nonce(&self) -> Option<[u8; NONCE_LEN]> {
+        let mut nonce = [0u8; NONCE_LEN];
+        hex::decode_to_slice(self.headers.get("nonce")?, &mut nonce).ok()?;
+        Some(nonce)
+    }
 }
 
 /// Negotiate the content-type.
 pub fn negotiate_content_type(headers: &HashMap<String, String>) -> Option<QBPContentType> {
-    let accept = headers.get("accept").unwrap();
+    let accept = headers
+        .get("accept")
+        .map(String::as_str)
+        .unwrap_or_default();
     let accept = accept
         .split(',')
         .enumerate()
@@ -229,15 +466,57 @@ pub fn negotiate_content_type(headers: &HashMap<String, String>) -> Option<QBPCo
     })
 }
 
+/// Check that the peer frames its packets like we do, see [FRAMING_HEADER].
+fn check_framing(headers: &HashMap<String, String>) -> Result<()> {
+    match headers.get(FRAMING_HEADER) {
+        None => Ok(()),
+        Some(version) if version.parse() == Ok(FRAMING_VERSION) => Ok(()),
+        Some(version) => Err(Error::FramingMismatch(version.clone())),
+    }
+}
+
+/// Build the error for a failed negotiation of the given header,
+/// which lists what both peers have advertised for diagnosis.
+fn negotiation_failed<'a>(
+    name: &str,
+    header: &str,
+    mut supported: impl Iterator<Item = &'a &'static str>,
+    headers: &HashMap<String, String>,
+) -> Error {
+    let host = supported.join(",");
+    let peer = headers.get(header).map(String::as_str).unwrap_or_default();
+    Error::NegotiationFailed(format!(
+        "{name} (host accepts [{host}], peer accepts [{peer}])"
+    ))
+}
+
 /// Negotiate the content-encoding.
-pub fn negotiate_content_encoding(headers: &HashMap<String, String>) -> Option<QBPContentEncoding> {
-    let accept_encoding = headers.get("accept-encoding").unwrap();
+///
+/// If either the host (transport_compression) or the peer (see
+/// [TRANSPORT_COMPRESSION_HEADER]) hints that the transport already
+/// compresses, plain is preferred, as long as the peer supports it.
+pub fn negotiate_content_encoding(
+    headers: &HashMap<String, String>,
+    transport_compression: bool,
+) -> Option<QBPContentEncoding> {
+    let accept_encoding = headers
+        .get("accept-encoding")
+        .map(String::as_str)
+        .unwrap_or_default();
     let accept = accept_encoding
         .split(',')
         .enumerate()
         .map(|(i, e)| (e.trim(), i))
         .collect::<HashMap<&str, usize>>();
 
+    let transport_compression = transport_compression
+        || headers
+            .get(TRANSPORT_COMPRESSION_HEADER)
+            .is_some_and(|e| e == "1");
+    if transport_compression && accept.contains_key("plain") {
+        return Some(QBPContentEncoding::Plain);
+    }
+
     let mut possible_canidates: Vec<(&str, usize)> = Vec::new();
 
     for (i, name) in SUPPORTED_CONTENT_ENCODINGS.keys().enumerate() {
@@ -265,8 +544,15 @@ pub fn negotiate_content_encoding(headers: &HashMap<String, String>) -> Option<Q
 
 /// This struct describes a content encoding that can be negotiated
 /// in a QBP connection.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum QBPContentEncoding {
+    /// Use zstd to (de)compress payloads.
+    Zstd,
+    /// Use brotli to (de)compress payloads.
+    Brotli {
+        /// the quality level used for compression (0-11)
+        quality: u32,
+    },
     /// Use zlib to (de)compress payloads.
     Zlib,
     /// Use gzip to (de)compress payloads.
@@ -279,22 +565,72 @@ pub enum QBPContentEncoding {
 // synchronous Write trait from std::io, which conflicts
 // the asynchronous write traits from tokio.
 mod encodeimpl {
-    use super::QBPContentEncoding;
+    use super::{Error, QBPContentEncoding, Result};
+    use brotli::{CompressorWriter, DecompressorWriter};
     use flate2::{
         write::{GzDecoder, GzEncoder, ZlibDecoder, ZlibEncoder},
         Compression,
     };
-    use std::io::Write;
+    use std::io::{self, Write};
     use tracing::trace;
 
+    /// The buffer size used by the brotli (de)compressor.
+    const BROTLI_BUFFER_SIZE: usize = 4096;
+    /// The window size used by the brotli compressor (log2).
+    const BROTLI_LGWIN: u32 = 22;
+    /// The level used by the zstd compressor.
+    const ZSTD_LEVEL: i32 = 3;
+    /// The highest level supported by the zstd compressor.
+    const ZSTD_MAX_LEVEL: u32 = 22;
+
+    /// The compression used by the zlib and gzip encoders, which
+    /// defaults to the best one.
+    fn compression(level: Option<u32>) -> Compression {
+        level.map_or(Compression::best(), |level| Compression::new(level.min(9)))
+    }
+
     impl QBPContentEncoding {
         /// Encode data with this encoding.
         pub fn encode(&self, data: &[u8]) -> Vec<u8> {
+            self.encode_with_level(data, None)
+        }
+
+        /// Encode data with this encoding, using the given compression level
+        /// instead of the default one. The level is capped at the highest
+        /// level of the encoding (9 for zlib and gzip, 11 for brotli and 22
+        /// for zstd), lower levels are faster but compress less.
+        pub fn encode_with_level(&self, data: &[u8], level: Option<u32>) -> Vec<u8> {
             match self {
+                QBPContentEncoding::Zstd => {
+                    trace!("encode: encoding data with zstd: {}", data.len());
+
+                    let level = level.map_or(ZSTD_LEVEL, |level| level.min(ZSTD_MAX_LEVEL) as i32);
+                    let res = zstd::stream::encode_all(data, level).unwrap();
+
+                    trace!("encode: result: {}", res.len());
+
+                    res
+                }
+                QBPContentEncoding::Brotli { quality } => {
+                    trace!("encode: encoding data with brotli: {}", data.len());
+
+                    let mut encoder = CompressorWriter::new(
+                        Vec::new(),
+                        BROTLI_BUFFER_SIZE,
+                        level.unwrap_or(*quality).min(11),
+                        BROTLI_LGWIN,
+                    );
+                    encoder.write_all(data).unwrap();
+                    let res = encoder.into_inner();
+
+                    trace!("encode: result: {}", res.len());
+
+                    res
+                }
                 QBPContentEncoding::Zlib => {
                     trace!("encode: encoding data with zlib: {}", data.len());
 
-                    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+                    let mut encoder = ZlibEncoder::new(Vec::new(), compression(level));
                     encoder.write_all(data).unwrap();
                     let res = encoder.finish().unwrap();
 
@@ -305,7 +641,7 @@ mod encodeimpl {
                 QBPContentEncoding::Gzip => {
                     trace!("encode: encoding data with gzip: {}", data.len());
 
-                    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+                    let mut encoder = GzEncoder::new(Vec::new(), compression(level));
                     encoder.write_all(data).unwrap();
                     let res = encoder.finish().unwrap();
 
@@ -322,31 +658,64 @@ mod encodeimpl {
         }
 
         /// Decode encoded data.
-        pub fn decode(&self, data: &[u8]) -> Vec<u8> {
+        ///
+        /// Returns [Error::DecompressFailed] if the data is malformed.
+        pub fn decode(&self, data: &[u8]) -> Result<Vec<u8>> {
+            let mut out = Vec::new();
+            self.decode_into(data, &mut out)?;
+            Ok(out)
+        }
+
+        /// Decode encoded data into the given buffer, which gets cleared
+        /// first. This reuses the capacity of the buffer.
+        ///
+        /// Returns [Error::DecompressFailed] if the data is malformed.
+        pub fn decode_into(&self, data: &[u8], out: &mut Vec<u8>) -> Result<()> {
+            out.clear();
+            self.try_decode_into(data, out)
+                .map_err(|source| Error::DecompressFailed {
+                    encoding: self.clone(),
+                    source,
+                })
+        }
+
+        fn try_decode_into(&self, data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
             match self {
+                QBPContentEncoding::Zstd => {
+                    // unlike the writing decoder, this detects truncated frames
+                    zstd::stream::copy_decode(data, out)?;
+                }
+                QBPContentEncoding::Brotli { .. } => {
+                    let mut decoder = DecompressorWriter::new(out, BROTLI_BUFFER_SIZE);
+                    decoder.write_all(data)?;
+                    decoder.into_inner().map_err(|_| {
+                        io::Error::new(io::ErrorKind::UnexpectedEof, "truncated brotli stream")
+                    })?;
+                }
                 QBPContentEncoding::Zlib => {
-                    let mut decoder = ZlibDecoder::new(Vec::new());
-                    decoder.write_all(data).unwrap();
-                    decoder.finish().unwrap()
+                    let mut decoder = ZlibDecoder::new(out);
+                    decoder.write_all(data)?;
+                    decoder.finish()?;
                 }
                 QBPContentEncoding::Gzip => {
-                    let mut decoder = GzDecoder::new(Vec::new());
-                    decoder.write_all(data).unwrap();
-                    decoder.finish().unwrap()
+                    let mut decoder = GzDecoder::new(out);
+                    decoder.write_all(data)?;
+                    decoder.finish()?;
                 }
                 QBPContentEncoding::Plain => {
                     trace!("encode: skip decompression");
 
-                    data.into()
+                    out.extend_from_slice(data);
                 }
             }
+            Ok(())
         }
     }
 }
 
 /// This struct describes a content type that can be negotiated
 /// in a QBP connection.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum QBPContentType {
     /// application/json
     ///
@@ -363,11 +732,25 @@ pub enum QBPContentType {
 }
 
 impl QBPContentType {
+    /// Returns the mime type of this content type.
+    pub fn name(&self) -> &'static str {
+        match self {
+            QBPContentType::Json => "application/json",
+            QBPContentType::Bitcode => "application/bitcode",
+        }
+    }
+
     /// Convert bytes of this content type to a message.
     pub fn from_bytes<T: QBPDeserialize>(&self, data: &[u8]) -> Result<T> {
-        Ok(match self {
-            QBPContentType::Json => T::from_json(data)?,
-            QBPContentType::Bitcode => T::from_bitcode(data)?,
+        let result = match self {
+            QBPContentType::Json => T::from_json(data),
+            QBPContentType::Bitcode => T::from_bitcode(data),
+        };
+        result.map_err(|err| Error::DecodeFailed {
+            content_type: self.name(),
+            len: data.len(),
+            type_name: std::any::type_name::<T>(),
+            source: Box::new(err),
         })
     }
 
@@ -412,11 +795,20 @@ impl<T> QBPDeserialize for T where T: for<'a> Decode<'a> + for<'a> Deserialize<'
 pub trait QBPMessage: QBPSerialize + QBPDeserialize {}
 impl<T> QBPMessage for T where T: QBPSerialize + QBPDeserialize {}
 
+/// A message which is one of several message types, which are told
+/// apart by the tag they are sent with, see [QBP::recv_any].
+pub trait QBPTagged: Sized {
+    /// Decode the message from a payload with the given tag.
+    /// Packets which have been sent without a tag have none.
+    fn from_tagged(tag: Option<u8>, content_type: &QBPContentType, data: &[u8]) -> Result<Self>;
+}
+
 /// This enum represents the state a QBP connection is in.
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub enum QBPState {
     /// Initial state. We need to send the header
     /// for negotiation purposes.
+    #[default]
     Initial,
     /// Negotiation state. We need to negotiate
     /// the content type and the content encoding
@@ -432,18 +824,179 @@ pub enum QBPState {
     },
 }
 
-impl Default for QBPState {
-    fn default() -> Self {
-        Self::Initial
+/// The phase a QBP connection is in, see [QBPStateDetail].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QBPPhase {
+    /// The header packet has not been sent yet.
+    Initial,
+    /// The header packet has been sent, waiting
+    /// for the header packet of the peer.
+    Negotiate,
+    /// Content type and encoding have been negotiated.
+    Messages,
+}
+
+impl fmt::Display for QBPPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QBPPhase::Initial => write!(f, "initial"),
+            QBPPhase::Negotiate => write!(f, "negotiate"),
+            QBPPhase::Messages => write!(f, "messages"),
+        }
+    }
+}
+
+/// A detailed description of the state of a QBP connection,
+/// useful for debugging connections which are stuck.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QBPStateDetail {
+    /// The phase the connection is in.
+    pub phase: QBPPhase,
+    /// The amount of bytes which have been written
+    /// to the writer, but not flushed yet.
+    pub unflushed: usize,
+    /// The amount of bytes of the current packet which have been
+    /// received already. While negotiating, this is the header packet.
+    pub received: usize,
+    /// The length of the current packet, if already received.
+    pub expected: Option<usize>,
+}
+
+impl fmt::Display for QBPStateDetail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "phase: {}, unflushed: {}, received: {}",
+            self.phase, self.unflushed, self.received
+        )?;
+        if let Some(expected) = self.expected {
+            write!(f, "/{}", expected)?;
+        }
+        Ok(())
     }
 }
 
+/// The name and version of the software of a peer, which are used for
+/// diagnostics. Peers which do not announce them leave them empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QBPPeerInfo {
+    /// The name of the software, see [PEER_NAME_HEADER].
+    pub name: Option<String>,
+    /// The version of the software, see [PEER_VERSION_HEADER].
+    pub version: Option<String>,
+}
+
+/// The result of negotiating a connection, which can be used for
+/// resuming it on a later connection, see [QBP::with_resumption].
+#[derive(Debug, Clone)]
+pub struct QBPResumption {
+    token: [u8; NONCE_LEN],
+    /// the sorted nonces of the connection the token was issued on
+    nonces: [u8; NONCE_LEN * 2],
+    content_type: QBPContentType,
+    content_encoding: QBPContentEncoding,
+    fragments: bool,
+    plain: bool,
+    errors: bool,
+    tagged: bool,
+}
+
+impl QBPResumption {
+    /// Returns the token, which is the same for both peers.
+    pub fn token(&self) -> &[u8; NONCE_LEN] {
+        &self.token
+    }
+}
+
+/// Describe the capabilities announced in a header packet, that is,
+/// everything which is the same on every connection.
+fn capabilities(header: &QBPHeaderPacket) -> String {
+    let headers = header
+        .headers
+        .iter()
+        .filter(|(key, _)| *key != "nonce" && *key != RESUME_HEADER)
+        .sorted()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .join("&");
+    format!(
+        "{}.{}?{}",
+        header.major_version, header.minor_version, headers
+    )
+}
+
+/// Derive the resumption token from the nonces and the capabilities of both
+/// peers, so that the token becomes invalid once either of them changes.
+fn resumption_token(nonces: &[u8], host: &str, peer: &str) -> [u8; NONCE_LEN] {
+    let (first, second) = match host <= peer {
+        true => (host, peer),
+        false => (peer, host),
+    };
+    let capabilities = [first.as_bytes(), b"\0", second.as_bytes()].concat();
+
+    let mut token = [0u8; NONCE_LEN];
+    Hkdf::<Sha256>::new(Some(nonces), &capabilities)
+        .expand(b"qbp resumption", &mut token)
+        .expect("NONCE_LEN is a valid length for HKDF-SHA256");
+    token
+}
+
 /// This struct represents a QBP connection.
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct QBP {
     state: QBPState,
     reader: QBPReader,
     writer: QBPWriter,
+    /// the nonce we sent in our header packet
+    host_nonce: Option<[u8; NONCE_LEN]>,
+    /// the nonce the peer sent in its header packet
+    peer_nonce: Option<[u8; NONCE_LEN]>,
+    /// whether the transport already compresses payloads
+    transport_compression: bool,
+    /// the buffer decoded payloads are stored in, see [QBP::recv_into]
+    payload: Vec<u8>,
+    /// the capabilities we sent in our header packet
+    host_capabilities: String,
+    /// the connection to resume, which is kept until
+    /// the header packet of the peer has been checked
+    resume: Option<QBPResumption>,
+    /// the result of negotiating this connection
+    resumption: Option<QBPResumption>,
+    /// the heartbeat interval we announce
+    heartbeat: Option<Duration>,
+    /// the heartbeat interval both peers agreed on
+    heartbeat_interval: Option<Duration>,
+    /// the size below which payloads are not compressed,
+    /// see [QBP::with_compress_min_size]
+    compress_min_size: usize,
+    /// the compression level used instead of the default one
+    compression_level: Option<u32>,
+    /// the name and version we announce, instead of the ones of this crate
+    host_info: Option<QBPPeerInfo>,
+    /// the name and version the peer announced
+    peer_info: Option<QBPPeerInfo>,
+}
+
+impl Default for QBP {
+    fn default() -> Self {
+        Self {
+            state: Default::default(),
+            reader: Default::default(),
+            writer: Default::default(),
+            host_nonce: None,
+            peer_nonce: None,
+            transport_compression: false,
+            payload: Vec::new(),
+            host_capabilities: String::new(),
+            resume: None,
+            resumption: None,
+            heartbeat: None,
+            heartbeat_interval: None,
+            compress_min_size: DEFAULT_COMPRESS_MIN_SIZE,
+            compression_level: None,
+            host_info: None,
+            peer_info: None,
+        }
+    }
 }
 
 /// Utility trait for impl usage.
@@ -459,6 +1012,268 @@ pub trait ReadWrite: tokio::io::AsyncReadExt + tokio::io::AsyncWriteExt + Unpin
 impl<T> ReadWrite for T where T: tokio::io::AsyncReadExt + tokio::io::AsyncWriteExt + Unpin {}
 
 impl QBP {
+    /// Hint that the transport this connection runs on already compresses
+    /// payloads, so that no compression is negotiated (if possible).
+    pub fn with_transport_compression(mut self, transport_compression: bool) -> Self {
+        self.transport_compression = transport_compression;
+        self
+    }
+
+    /// Do not compress payloads smaller than the given size in bytes,
+    /// as they gain little from it, but still pay for the encoder setup.
+    ///
+    /// Such packets are flagged, so that the peer does not decompress
+    /// them. If the peer does not understand this, every payload is
+    /// compressed. Defaults to [DEFAULT_COMPRESS_MIN_SIZE], zero
+    /// compresses every payload.
+    pub fn with_compress_min_size(mut self, compress_min_size: usize) -> Self {
+        self.compress_min_size = compress_min_size;
+        self
+    }
+
+    /// Announce the given software name and version to the peer, instead
+    /// of the name and version of this crate, see [QBP::peer_info].
+    pub fn with_peer_info(mut self, name: impl Into<String>, version: impl Into<String>) -> Self {
+        self.host_info = Some(QBPPeerInfo {
+            name: Some(name.into()),
+            version: Some(version.into()),
+        });
+        self
+    }
+
+    /// Returns the name and version the peer announced, which are
+    /// available once the header packet of the peer has been received.
+    pub fn peer_info(&self) -> Option<&QBPPeerInfo> {
+        self.peer_info.as_ref()
+    }
+
+    /// Compress payloads with the given level instead of the default one
+    /// (the best compression for zlib and gzip), see
+    /// [QBPContentEncoding::encode_with_level].
+    ///
+    /// Interactive connections may prefer a low level, which is faster,
+    /// while connections which transfer large payloads prefer a high one.
+    pub fn with_compression_level(mut self, level: u32) -> Self {
+        self.compression_level = Some(level);
+        self
+    }
+
+    /// Announce that we want to exchange heartbeats in the given interval.
+    ///
+    /// The peers settle on the shorter one of both intervals. If the peer does
+    /// not announce an interval, no heartbeats are exchanged.
+    pub fn with_heartbeat(mut self, interval: Duration) -> Self {
+        self.heartbeat = Some(interval);
+        self
+    }
+
+    /// Returns the heartbeat interval both peers agreed on, which is
+    /// available once the header packet of the peer has been received.
+    ///
+    /// Both peers should call [QBP::send_heartbeat] in this interval and
+    /// check [QBP::is_timed_out] to detect that the connection died.
+    pub fn heartbeat_interval(&self) -> Option<Duration> {
+        self.heartbeat_interval
+    }
+
+    /// Send a heartbeat frame, which the peer does not receive as a
+    /// message, but which shows it that the connection is still alive.
+    ///
+    /// Does nothing if no heartbeat interval has been negotiated.
+    ///
+    /// # Cancelation Safety
+    /// This method is cancelation safe.
+    pub async fn send_heartbeat(&mut self, write: &mut impl Write) -> Result<()> {
+        if self.heartbeat_interval.is_none() {
+            return Ok(());
+        }
+        self.writer.write_flagged(write, &[], HEARTBEAT_FLAG).await
+    }
+
+    /// Returns whether nothing has been received for [HEARTBEAT_TIMEOUT_FACTOR]
+    /// heartbeat intervals, which means that the peer is considered dead.
+    pub fn is_timed_out(&self) -> bool {
+        let (Some(interval), Some(last)) = (self.heartbeat_interval, self.reader.last_received)
+        else {
+            return false;
+        };
+        last.elapsed() > interval * HEARTBEAT_TIMEOUT_FACTOR
+    }
+
+    /// Settle on the heartbeat interval, given the headers of the peer.
+    fn negotiate_heartbeat(&self, headers: &HashMap<String, String>) -> Option<Duration> {
+        let peer = headers.get(HEARTBEAT_HEADER)?.parse().ok()?;
+        Some(self.heartbeat?.min(Duration::from_millis(peer)))
+    }
+
+    /// Resume a previous connection with the same peer, see [QBP::resumption].
+    ///
+    /// Instead of waiting for the header packet of the peer, negotiating uses
+    /// the content-type and content-encoding negotiated previously and returns
+    /// right after sending our header packet. The header packet of the peer
+    /// is checked once the first packet is received. If the capabilities of
+    /// either peer changed in the meantime, receiving fails with
+    /// [Error::ResumptionRejected] and the connection has to be negotiated
+    /// from scratch.
+    ///
+    /// [QBP::session_key] is only available after the first packet has
+    /// been received, as the nonce of the peer is not known before.
+    pub fn with_resumption(mut self, resumption: QBPResumption) -> Self {
+        self.resume = Some(resumption);
+        self
+    }
+
+    /// Returns the resumption of this connection, which
+    /// is available once the connection has been negotiated.
+    pub fn resumption(&self) -> Option<&QBPResumption> {
+        self.resumption.as_ref()
+    }
+
+    /// Get the header packet to send for this connection.
+    fn host_header(&mut self) -> QBPHeaderPacket {
+        let mut header = QBPHeaderPacket::host();
+        if self.transport_compression {
+            header
+                .headers
+                .insert(TRANSPORT_COMPRESSION_HEADER.to_owned(), "1".to_owned());
+        }
+        header
+            .headers
+            .insert(FRAGMENTS_HEADER.to_owned(), "1".to_owned());
+        header
+            .headers
+            .insert(PLAIN_HEADER.to_owned(), "1".to_owned());
+        header
+            .headers
+            .insert(ERRORS_HEADER.to_owned(), "1".to_owned());
+        header
+            .headers
+            .insert(TAGGED_HEADER.to_owned(), "1".to_owned());
+        header
+            .headers
+            .insert(FRAMING_HEADER.to_owned(), FRAMING_VERSION.to_string());
+        if let Some(info) = &self.host_info {
+            for (key, value) in [
+                (PEER_NAME_HEADER, &info.name),
+                (PEER_VERSION_HEADER, &info.version),
+            ] {
+                if let Some(value) = value {
+                    header.headers.insert(key.to_owned(), value.clone());
+                }
+            }
+        }
+        if let Some(heartbeat) = self.heartbeat {
+            header.headers.insert(
+                HEARTBEAT_HEADER.to_owned(),
+                heartbeat.as_millis().to_string(),
+            );
+        }
+        self.host_nonce = header.nonce();
+        self.host_capabilities = capabilities(&header);
+        if let Some(resume) = &self.resume {
+            header
+                .headers
+                .insert(RESUME_HEADER.to_owned(), hex::encode(resume.token));
+        }
+        header
+    }
+
+    /// Send our header packet and move on to the next state, which
+    /// is the resumed state when resuming a previous connection.
+    async fn send_header(&mut self, write: &mut impl Write) -> Result<()> {
+        let header = self.host_header();
+        self.send_packet(write, &header.serialize()).await?;
+        self.state = match &self.resume {
+            Some(resume) => {
+                self.writer.fragments = resume.fragments;
+                self.writer.plain = resume.plain;
+                self.writer.errors = resume.errors;
+                self.writer.tagged = resume.tagged;
+                QBPState::Messages {
+                    content_type: resume.content_type.clone(),
+                    content_encoding: resume.content_encoding.clone(),
+                }
+            }
+            None => QBPState::Negotiate,
+        };
+        Ok(())
+    }
+
+    /// Process the header packet of the peer.
+    fn recv_header(&mut self, header: QBPHeaderPacket) -> Result<()> {
+        trace!("recv header: {:?}", header);
+        check_framing(&header.headers)?;
+        self.peer_nonce = header.nonce();
+        self.peer_info = Some(header.peer_info());
+        self.writer.fragments = header.headers.contains_key(FRAGMENTS_HEADER);
+        self.writer.plain = header.headers.contains_key(PLAIN_HEADER);
+        self.writer.errors = header.headers.contains_key(ERRORS_HEADER);
+        self.writer.tagged = header.headers.contains_key(TAGGED_HEADER);
+        self.heartbeat_interval = self.negotiate_heartbeat(&header.headers);
+        self.state = self.negotiate_content(&header.headers)?;
+
+        let (Some(host_nonce), Some(peer_nonce)) = (self.host_nonce, self.peer_nonce) else {
+            return Ok(());
+        };
+        let (first, second) = match host_nonce <= peer_nonce {
+            true => (host_nonce, peer_nonce),
+            false => (peer_nonce, host_nonce),
+        };
+        let mut nonces = [0u8; NONCE_LEN * 2];
+        nonces[..NONCE_LEN].copy_from_slice(&first);
+        nonces[NONCE_LEN..].copy_from_slice(&second);
+
+        let (content_type, content_encoding) = self.get_content()?;
+        self.resumption = Some(QBPResumption {
+            token: resumption_token(&nonces, &self.host_capabilities, &capabilities(&header)),
+            nonces,
+            content_type: content_type.clone(),
+            content_encoding: content_encoding.clone(),
+            fragments: self.writer.fragments,
+            plain: self.writer.plain,
+            errors: self.writer.errors,
+            tagged: self.writer.tagged,
+        });
+        Ok(())
+    }
+
+    /// Check the header packet of the peer of a resumed connection.
+    fn recv_resumed_header(&mut self, packet: &[u8]) -> Result<()> {
+        let resume = self.resume.take().expect("connection is being resumed");
+        let header = QBPHeaderPacket::deserialize(packet)?;
+        check_framing(&header.headers)?;
+
+        let token = resumption_token(
+            &resume.nonces,
+            &self.host_capabilities,
+            &capabilities(&header),
+        );
+        let resumed = header.headers.get(RESUME_HEADER) == Some(&hex::encode(resume.token));
+        if resumed && token == resume.token {
+            trace!("resumed connection");
+            self.peer_nonce = header.nonce();
+            self.peer_info = Some(header.peer_info());
+            self.heartbeat_interval = self.negotiate_heartbeat(&header.headers);
+            self.resumption = Some(resume);
+            return Ok(());
+        }
+
+        // the peer did not resume, which is fine as long as
+        // negotiating yields the same result as before
+        self.recv_header(header)?;
+        let (content_type, content_encoding) = self.get_content()?;
+        if content_type != &resume.content_type
+            || content_encoding != &resume.content_encoding
+            || self.writer.fragments != resume.fragments
+            || self.writer.plain != resume.plain
+            || self.writer.errors != resume.errors
+            || self.writer.tagged != resume.tagged
+        {
+            return Err(Error::ResumptionRejected);
+        }
+        Ok(())
+    }
+
     /// Returns whether this connection is unitialized,
     /// which means that no negotiation request has been sent yet.
     pub fn is_uninitialized(&self) -> bool {
@@ -478,6 +1293,35 @@ impl QBP {
         matches!(self.state, QBPState::Messages { .. })
     }
 
+    /// Report the progress of receiving packets to the given callback.
+    ///
+    /// The callback is called whenever bytes of a packet arrive, with the amount
+    /// of bytes received so far and the length of the packet. The length of a
+    /// fragmented packet grows with each fragment, as it is not known upfront.
+    pub fn with_recv_progress(
+        mut self,
+        progress: impl FnMut(usize, usize) + Send + Sync + 'static,
+    ) -> Self {
+        self.reader.progress = Some(QBPProgress(Box::new(progress)));
+        self
+    }
+
+    /// Returns a detailed description of the state of this connection.
+    pub fn state_detail(&self) -> QBPStateDetail {
+        let phase = match self.state {
+            QBPState::Initial => QBPPhase::Initial,
+            QBPState::Negotiate => QBPPhase::Negotiate,
+            QBPState::Messages { .. } => QBPPhase::Messages,
+        };
+
+        QBPStateDetail {
+            phase,
+            unflushed: self.writer.unflushed(),
+            received: self.reader.received(),
+            expected: self.reader.packet_len,
+        }
+    }
+
     /// Send a packet through this protocol.
     ///
     /// You probably don't want to use this method, as-is,
@@ -503,7 +1347,46 @@ impl QBP {
     /// # Cancelation Safety
     /// This method is cancelation safe.
     pub async fn recv_packet(&mut self, read: &mut impl Read) -> Result<Vec<u8>> {
-        self.reader.read(read).await
+        let mut buf = Vec::new();
+        self.recv_packet_into(read, &mut buf).await?;
+        Ok(buf)
+    }
+
+    /// Receive a packet into the given buffer, see [QBP::recv_packet].
+    ///
+    /// # Cancelation Safety
+    /// This method is cancelation safe.
+    pub async fn recv_packet_into(
+        &mut self,
+        read: &mut impl Read,
+        buf: &mut Vec<u8>,
+    ) -> Result<()> {
+        // the first packet of a resumed connection is the header of the peer
+        if self.resume.is_some() && self.is_ready() {
+            self.reader.read_into(read, buf).await?;
+            self.recv_resumed_header(buf)?;
+        }
+        self.reader.read_into(read, buf).await
+    }
+
+    /// Send an error frame through this protocol, e.g. to tell the peer
+    /// that the last sync could not be applied. The peer receives it as
+    /// [Error::PeerError] and may continue to use the connection.
+    ///
+    /// Error frames bypass content-type and content-encoding, so they can be
+    /// sent at any time once the header packet of the peer has been received.
+    /// Fails with [Error::Unsupported] if the peer does not understand them.
+    ///
+    /// # Cancelation Safety
+    /// This method is cancelation safe.
+    pub async fn send_error(&mut self, write: &mut impl Write, code: u16, msg: &str) -> Result<()> {
+        if !self.writer.errors {
+            return Err(Error::Unsupported(ERRORS_HEADER));
+        }
+        let mut packet = Vec::with_capacity(msg.len() + 2);
+        packet.extend_from_slice(&code.to_be_bytes());
+        packet.extend_from_slice(msg.as_bytes());
+        self.writer.write_flagged(write, &packet, ERROR_FLAG).await
     }
 
     /// Send a binary payload through this protocol.
@@ -511,9 +1394,35 @@ impl QBP {
     /// # Cancelation Safety
     /// This method is cancelation safe.
     pub async fn send_payload(&mut self, write: &mut impl Write, payload: &[u8]) -> Result<()> {
+        let (packet, flags) = self.encode(payload)?;
+        self.writer.write_flagged(write, &packet, flags).await
+    }
+
+    /// Encode a payload with the negotiated content encoding, unless it is
+    /// too small (see [QBP::with_compress_min_size]). Returns the packet
+    /// and the flags it has to be sent with.
+    fn encode(&self, payload: &[u8]) -> Result<(Vec<u8>, u64)> {
+        let (_, content_encoding) = self.get_content()?;
+        if self.writer.plain
+            && payload.len() < self.compress_min_size
+            && content_encoding != &QBPContentEncoding::Plain
+        {
+            return Ok((payload.to_vec(), PLAIN_FLAG));
+        }
+        Ok((
+            content_encoding.encode_with_level(payload, self.compression_level),
+            0,
+        ))
+    }
+
+    /// Decode a packet received with the negotiated content encoding,
+    /// unless it has not been compressed by the peer.
+    fn decode_into(&self, packet: &[u8], out: &mut Vec<u8>) -> Result<()> {
         let (_, content_encoding) = self.get_content()?;
-        let packet = content_encoding.encode(payload);
-        self.send_packet(write, &packet).await
+        match self.reader.plain {
+            true => QBPContentEncoding::Plain.decode_into(packet, out),
+            false => content_encoding.decode_into(packet, out),
+        }
     }
 
     /// Receive a binary payload through this protocol.
@@ -522,8 +1431,8 @@ impl QBP {
     /// This method is cancelation safe.
     pub async fn recv_payload(&mut self, read: &mut impl Read) -> Result<Vec<u8>> {
         let packet = self.recv_packet(read).await?;
-        let (_, content_encoding) = self.get_content()?;
-        let payload = content_encoding.decode(&packet);
+        let mut payload = Vec::new();
+        self.decode_into(&packet, &mut payload)?;
         Ok(payload)
     }
 
@@ -532,10 +1441,107 @@ impl QBP {
     /// # Cancelation Safety
     /// This method is cancelation safe.
     pub async fn send(&mut self, write: &mut impl Write, msg: impl QBPSerialize) -> Result<()> {
-        let (content_type, content_encoding) = self.get_content()?;
+        let (content_type, _) = self.get_content()?;
         let payload = content_type.to_bytes(msg)?;
-        let packet = content_encoding.encode(&payload);
-        self.send_packet(write, &packet).await
+        let (packet, flags) = self.encode(&payload)?;
+        self.writer.write_flagged(write, &packet, flags).await
+    }
+
+    /// Send a message tagged with its type through this protocol, so that
+    /// a connection can carry several message types, see [QBP::recv_any].
+    /// Fails with [Error::Unsupported] if the peer does not understand them.
+    ///
+    /// # Cancelation Safety
+    /// This method is cancelation safe.
+    pub async fn send_tagged(
+        &mut self,
+        write: &mut impl Write,
+        tag: u8,
+        msg: impl QBPSerialize,
+    ) -> Result<()> {
+        if !self.writer.tagged {
+            return Err(Error::Unsupported(TAGGED_HEADER));
+        }
+        let (content_type, _) = self.get_content()?;
+        let payload = content_type.to_bytes(msg)?;
+        let (encoded, flags) = self.encode(&payload)?;
+        let mut packet = vec![tag];
+        packet.extend(encoded);
+        self.writer
+            .write_flagged(write, &packet, TAGGED_FLAG | flags)
+            .await
+    }
+
+    /// Queue a message to be sent through this protocol, see [QBP::progress].
+    ///
+    /// Messages with a high priority are sent before any queued messages
+    /// with a low priority. Large messages with a low priority are sent in
+    /// fragments (if the peer supports it), so that messages with a high
+    /// priority can be sent in between instead of waiting for them.
+    pub fn queue(&mut self, msg: impl QBPSerialize, priority: QBPPriority) -> Result<()> {
+        let (content_type, _) = self.get_content()?;
+        let payload = content_type.to_bytes(msg)?;
+        let (packet, flags) = self.encode(&payload)?;
+        self.writer.queue(packet, flags, priority);
+        Ok(())
+    }
+
+    /// Returns whether there are queued messages which have not been sent yet.
+    pub fn has_queued(&self) -> bool {
+        self.writer.has_queued()
+    }
+
+    /// Make progress on this connection. If there are queued messages, this
+    /// sends a single packet (or fragment) and returns none, otherwise this
+    /// waits for the next message.
+    ///
+    /// # Cancelation Safety
+    /// This method is cancelation safe.
+    pub async fn progress<T: QBPDeserialize>(
+        &mut self,
+        conn: &mut impl ReadWrite,
+        buf: &mut Vec<u8>,
+    ) -> Result<Option<T>> {
+        if self.writer.next_frame() {
+            self.writer.flush(conn).await?;
+            return Ok(None);
+        }
+
+        self.recv_into(conn, buf).await.map(Some)
+    }
+
+    /// Make progress on this connection, unless the token is canceled
+    /// first, see [QBP::progress] and [QBP::recv_cancelable].
+    ///
+    /// Returns [Error::Canceled] if the token has been canceled.
+    ///
+    /// # Cancelation Safety
+    /// This method is cancelation safe.
+    pub async fn progress_cancelable<T: QBPDeserialize>(
+        &mut self,
+        conn: &mut impl ReadWrite,
+        buf: &mut Vec<u8>,
+        cancel: &CancellationToken,
+    ) -> Result<Option<T>> {
+        tokio::select! {
+            biased;
+            _ = cancel.cancelled() => Err(Error::Canceled),
+            res = self.progress(conn, buf) => res,
+        }
+    }
+
+    /// Send all queued messages.
+    ///
+    /// After a recoverable error (see [Error::is_recoverable]) this
+    /// can be called again to resume sending where it stopped.
+    ///
+    /// # Cancelation Safety
+    /// This method is cancelation safe.
+    pub async fn flush_queue(&mut self, write: &mut impl Write) -> Result<()> {
+        while self.writer.next_frame() {
+            self.writer.flush(write).await?;
+        }
+        Ok(())
     }
 
     /// Read a message from this protocol.
@@ -543,13 +1549,122 @@ impl QBP {
     /// # Cancelation Safety
     /// This method is cancelation safe.
     pub async fn recv<T: QBPDeserialize>(&mut self, read: &mut impl Read) -> Result<T> {
-        let packet = self.recv_packet(read).await?;
-        let (content_type, content_encoding) = self.get_content()?;
-        let payload = content_encoding.decode(&packet);
-        let message = content_type.from_bytes::<T>(&payload)?;
+        self.recv_into(read, &mut Vec::new()).await
+    }
+
+    /// Read a message from this protocol, unless the token is canceled
+    /// first, which aborts a read that is still waiting for the peer,
+    /// e.g. when shutting down an interface.
+    ///
+    /// Returns [Error::Canceled] if the token has been canceled.
+    ///
+    /// # Cancelation Safety
+    /// This method is cancelation safe, the parts of a packet read before
+    /// the token is canceled are kept, so receiving again resumes them.
+    pub async fn recv_cancelable<T: QBPDeserialize>(
+        &mut self,
+        read: &mut impl Read,
+        cancel: &CancellationToken,
+    ) -> Result<T> {
+        tokio::select! {
+            biased;
+            _ = cancel.cancelled() => Err(Error::Canceled),
+            msg = self.recv(read) => msg,
+        }
+    }
+
+    /// Read a message from this protocol, using the given buffer for
+    /// receiving the packet. Reusing the buffer across calls avoids
+    /// allocating for every message.
+    ///
+    /// # Cancelation Safety
+    /// This method is cancelation safe.
+    pub async fn recv_into<T: QBPDeserialize>(
+        &mut self,
+        read: &mut impl Read,
+        buf: &mut Vec<u8>,
+    ) -> Result<T> {
+        self.recv_packet_into(read, buf).await?;
+        let (content_type, content_encoding) = match &self.state {
+            QBPState::Messages {
+                content_type,
+                content_encoding,
+            } => (content_type, content_encoding),
+            _ => return Err(Error::NotReady),
+        };
+
+        // the tag is not needed, as the type is known already
+        let packet = match self.reader.tagged {
+            true => buf.get(1..).unwrap_or_default(),
+            false => &buf[..],
+        };
+
+        // plain payloads can be decoded from the packet directly
+        let message = match content_encoding {
+            QBPContentEncoding::Plain => content_type.from_bytes::<T>(packet)?,
+            _ if self.reader.plain => content_type.from_bytes::<T>(packet)?,
+            _ => {
+                content_encoding.decode_into(packet, &mut self.payload)?;
+                content_type.from_bytes::<T>(&self.payload)?
+            }
+        };
         Ok(message)
     }
 
+    /// Read a message of any of the types of T from this protocol, which is
+    /// decoded using the tag it has been sent with, see [QBP::send_tagged].
+    ///
+    /// # Cancelation Safety
+    /// This method is cancelation safe.
+    pub async fn recv_any<T: QBPTagged>(&mut self, read: &mut impl Read) -> Result<T> {
+        let mut buf = Vec::new();
+        self.recv_packet_into(read, &mut buf).await?;
+        let (tag, packet) = match self.reader.tagged {
+            true => match buf.split_first() {
+                Some((tag, packet)) => (Some(*tag), packet),
+                None => return Err(Error::UnknownTag(None)),
+            },
+            false => (None, &buf[..]),
+        };
+        let mut payload = Vec::new();
+        self.decode_into(packet, &mut payload)?;
+        let (content_type, _) = self.get_content()?;
+        T::from_tagged(tag, content_type, &payload)
+    }
+
+    /// Derive a session key from a secret shared between the two peers.
+    ///
+    /// The key is derived using HKDF-SHA256 over the nonces which have been
+    /// exchanged in the header packets, so it differs for every connection
+    /// and both peers derive the same key. Sending this key instead of the
+    /// secret itself proves knowledge of the secret without exposing it.
+    ///
+    /// Returns an error if the connection has not been negotiated yet
+    /// or the peer did not send a valid nonce.
+    pub fn session_key(&self, secret: &[u8]) -> Result<[u8; SESSION_KEY_LEN]> {
+        if !self.is_ready() {
+            return Err(Error::NotReady);
+        }
+
+        let host_nonce = self.host_nonce.ok_or(Error::MissingNonce)?;
+        let peer_nonce = self.peer_nonce.ok_or(Error::MissingNonce)?;
+
+        // sort the nonces, so that both peers use the same salt
+        let (first, second) = match host_nonce <= peer_nonce {
+            true => (host_nonce, peer_nonce),
+            false => (peer_nonce, host_nonce),
+        };
+        let mut salt = [0u8; NONCE_LEN * 2];
+        salt[..NONCE_LEN].copy_from_slice(&first);
+        salt[NONCE_LEN..].copy_from_slice(&second);
+
+        let mut key = [0u8; SESSION_KEY_LEN];
+        Hkdf::<Sha256>::new(Some(&salt), secret)
+            .expand(b"qbp session key", &mut key)
+            .expect("SESSION_KEY_LEN is a valid length for HKDF-SHA256");
+        Ok(key)
+    }
+
     /// Try to get content-type and content-encoding of this
     /// protocol. Returns an error if not negotiated yet.
     fn get_content(&self) -> Result<(&QBPContentType, &QBPContentEncoding)> {
@@ -577,9 +1692,7 @@ impl QBP {
     pub async fn update<T: QBPDeserialize>(&mut self, conn: &mut impl ReadWrite) -> Result<T> {
         // send header packet
         if let QBPState::Initial = self.state {
-            self.state = QBPState::Negotiate;
-            let header = QBPHeaderPacket::host();
-            self.send_packet(conn, &header.serialize()).await?;
+            self.send_header(conn).await?;
         }
 
         // flush the writer
@@ -591,21 +1704,11 @@ impl QBP {
             match &self.state {
                 QBPState::Negotiate => {
                     let header = QBPHeaderPacket::deserialize(&packet)?;
-                    trace!("recv header: {:?}", header);
-                    let content_type = negotiate_content_type(&header.headers)
-                        .ok_or(Error::NegotiationFailed("content-type".into()))?;
-                    let content_encoding = negotiate_content_encoding(&header.headers)
-                        .ok_or(Error::NegotiationFailed("content-encoding".into()))?;
-                    self.state = QBPState::Messages {
-                        content_type,
-                        content_encoding,
-                    };
+                    self.recv_header(header)?;
                 }
-                QBPState::Messages {
-                    content_type,
-                    content_encoding,
-                } => {
-                    let payload = content_encoding.decode(&packet);
+                QBPState::Messages { content_type, .. } => {
+                    let mut payload = Vec::new();
+                    self.decode_into(&packet, &mut payload)?;
                     let message = content_type.from_bytes::<T>(&payload)?;
                     return Ok(message);
                 }
@@ -629,54 +1732,210 @@ impl QBP {
     pub async fn negotiate(&mut self, conn: &mut impl ReadWrite) -> Result<()> {
         assert!(self.is_uninitialized());
 
-        let header = QBPHeaderPacket::host();
-        self.send_packet(conn, &header.serialize()).await?;
-        self.state = QBPState::Negotiate;
+        self.send_header(conn).await?;
+        // a resumed connection does not wait for the header of the peer
+        if self.is_ready() {
+            return Ok(());
+        }
 
         let packet = self.recv_packet(conn).await?;
         let header = QBPHeaderPacket::deserialize(&packet)?;
-        trace!("recv header: {:?}", header);
-        let content_type = negotiate_content_type(&header.headers)
-            .ok_or(Error::NegotiationFailed("content-type".into()))?;
-        let content_encoding = negotiate_content_encoding(&header.headers)
-            .ok_or(Error::NegotiationFailed("content-encoding".into()))?;
-        self.state = QBPState::Messages {
-            content_type,
-            content_encoding,
-        };
+        self.recv_header(header)?;
 
         Ok(())
     }
+
+    /// Negotiate the content-type and content-encoding
+    /// using the headers of the peer.
+    fn negotiate_content(&self, headers: &HashMap<String, String>) -> Result<QBPState> {
+        let content_type = negotiate_content_type(headers).ok_or_else(|| {
+            negotiation_failed(
+                "content-type",
+                "accept",
+                SUPPORTED_CONTENT_TYPES.keys(),
+                headers,
+            )
+        })?;
+        let content_encoding = negotiate_content_encoding(headers, self.transport_compression)
+            .ok_or_else(|| {
+                negotiation_failed(
+                    "content-encoding",
+                    "accept-encoding",
+                    SUPPORTED_CONTENT_ENCODINGS.keys(),
+                    headers,
+                )
+            })?;
+        Ok(QBPState::Messages {
+            content_type,
+            content_encoding,
+        })
+    }
 }
 
 #[derive(Debug, Default)]
 struct QBPWriter {
     bytes: Vec<u8>,
     written: usize,
+    /// whether the peer understands fragmented packets
+    fragments: bool,
+    /// whether the peer understands packets which have not been compressed
+    plain: bool,
+    /// whether the peer understands error frames
+    errors: bool,
+    /// whether the peer understands tagged packets
+    tagged: bool,
+    /// the queued packets with a high priority and their flags
+    high: VecDeque<(Vec<u8>, u64)>,
+    /// the queued packets with a low priority and their flags
+    low: VecDeque<(Vec<u8>, u64)>,
+    /// the amount of bytes of the first low priority packet already framed
+    low_offset: usize,
 }
 
 impl QBPWriter {
+    /// Returns the amount of bytes which have not been flushed yet.
+    fn unflushed(&self) -> usize {
+        self.bytes.len() - self.written
+    }
+
+    /// Queue a packet, see [QBP::queue].
+    fn queue(&mut self, packet: Vec<u8>, flags: u64, priority: QBPPriority) {
+        match priority {
+            QBPPriority::High => self.high.push_back((packet, flags)),
+            QBPPriority::Low => self.low.push_back((packet, flags)),
+        }
+    }
+
+    /// Returns whether there are queued packets or unflushed bytes.
+    fn has_queued(&self) -> bool {
+        self.unflushed() > 0 || !self.high.is_empty() || !self.low.is_empty()
+    }
+
+    /// Move the next frame of the queued packets into the buffer, unless
+    /// there are unflushed bytes. Returns whether there is anything to flush.
+    fn next_frame(&mut self) -> bool {
+        if self.unflushed() > 0 {
+            return true;
+        }
+
+        if let Some((packet, flags)) = self.high.pop_front() {
+            Self::frame(&mut self.bytes, &packet, flags);
+            return true;
+        }
+
+        let Some((packet, flags)) = self.low.front() else {
+            return false;
+        };
+
+        if !self.fragments || (self.low_offset == 0 && packet.len() <= FRAGMENT_SIZE) {
+            let (packet, flags) = self.low.pop_front().unwrap();
+            Self::frame(&mut self.bytes, &packet, flags);
+            return true;
+        }
+
+        let start = self.low_offset;
+        let end = packet.len().min(start + FRAGMENT_SIZE);
+        let flags = match end < packet.len() {
+            true => flags | FRAGMENT_FLAG | MORE_FLAG,
+            false => flags | FRAGMENT_FLAG,
+        };
+        let len = packet.len();
+        Self::frame(&mut self.bytes, &packet[start..end], flags);
+
+        if end < len {
+            self.low_offset = end;
+        } else {
+            self.low.pop_front();
+            self.low_offset = 0;
+        }
+
+        true
+    }
+
+    /// Append a packet with the given flags to the buffer.
+    fn frame(bytes: &mut Vec<u8>, packet: &[u8], flags: u64) {
+        trace!("write: frame len {}", packet.len());
+        let len_bytes = (packet.len() as u64 | flags).to_be_bytes();
+        bytes.extend_from_slice(&len_bytes);
+        bytes.extend_from_slice(packet);
+    }
+
     /// Write a packet.
     ///
     /// # Cancelation Safety
     /// This method is cancelation safe.
     pub async fn write(&mut self, write: &mut impl Write, packet: &[u8]) -> Result<()> {
-        trace!("write: len {}:", packet.len());
-        let len_bytes = (packet.len() as u64).to_be_bytes();
-        self.bytes.extend_from_slice(&len_bytes);
-        trace!("write: data");
-        self.bytes.extend_from_slice(packet);
+        self.write_flagged(write, packet, 0).await
+    }
+
+    /// Write a packet with the given flags set in its length prefix.
+    ///
+    /// # Cancelation Safety
+    /// This method is cancelation safe.
+    pub async fn write_flagged(
+        &mut self,
+        write: &mut impl Write,
+        packet: &[u8],
+        flags: u64,
+    ) -> Result<()> {
+        // the buffer has to be flushed first to keep the order
+        if self.unflushed() > 0 {
+            Self::frame(&mut self.bytes, packet, flags);
+            return self.flush(write).await;
+        }
+
+        // write the length prefix and the packet without copying them into
+        // the buffer. The write is polled exactly once, so that we can not be
+        // canceled while the packet has neither been written nor buffered.
+        let len_bytes = (packet.len() as u64 | flags).to_be_bytes();
+        let bufs = [IoSlice::new(&len_bytes), IoSlice::new(packet)];
+        let res = std::future::poll_fn(|cx| {
+            Poll::Ready(Pin::new(&mut *write).poll_write_vectored(cx, &bufs))
+        })
+        .await;
+        let (len, err) = match res {
+            Poll::Ready(Ok(len)) => (len, None),
+            Poll::Ready(Err(err)) if err.kind() == io::ErrorKind::Interrupted => (0, None),
+            Poll::Ready(Err(err)) => (0, Some(err)),
+            Poll::Pending => (0, None),
+        };
+        trace!("write: wrote bytes vectored: {}", len);
+
+        // buffer the remainder
+        self.bytes.clear();
+        self.written = 0;
+        match len < len_bytes.len() {
+            true => {
+                self.bytes.extend_from_slice(&len_bytes[len..]);
+                self.bytes.extend_from_slice(packet);
+            }
+            false => self
+                .bytes
+                .extend_from_slice(&packet[len - len_bytes.len()..]),
+        }
+        // the packet stays buffered, so that flushing resumes it
+        if let Some(err) = err {
+            return Err(err.into());
+        }
         self.flush(write).await
     }
 
     /// Flush this writer.
     ///
+    /// Interrupted writes are retried. On any other error the unflushed
+    /// bytes are kept, so that flushing again resumes where this stopped.
+    ///
     /// # Cancelation Safety
     /// This method is cancelation safe.
     pub async fn flush(&mut self, write: &mut impl Write) -> Result<()> {
         trace!("write: bytes to flush: {}", self.bytes.len());
         while self.bytes.len() > self.written {
-            let len = write.write(&self.bytes[self.written..]).await?;
+            let len = match write.write(&self.bytes[self.written..]).await {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero).into()),
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            };
             trace!("write: wrote bytes: {}", len);
             self.written += len;
         }
@@ -688,18 +1947,72 @@ impl QBPWriter {
     }
 }
 
+// bytes which have not been flushed are lost, e.g. if negotiating
+// has been canceled, which is hard to diagnose otherwise
+impl Drop for QBPWriter {
+    fn drop(&mut self) {
+        if self.has_queued() {
+            warn!(
+                "write: dropped with {} unflushed bytes and {} queued packets",
+                self.unflushed(),
+                self.high.len() + self.low.len()
+            );
+        }
+    }
+}
+
+/// A callback which gets told how many bytes of the packet
+/// which is currently being received have arrived, and how
+/// many bytes it is long, see [QBP::with_recv_progress].
+struct QBPProgress(Box<dyn FnMut(usize, usize) + Send + Sync>);
+
+impl fmt::Debug for QBPProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("QBPProgress")
+    }
+}
+
 #[derive(Debug, Default)]
 struct QBPReader {
     packet_len: Option<usize>,
+    packet_flags: u64,
+    /// whether the last packet received has been tagged
+    tagged: bool,
+    /// whether the last packet received has not been compressed
+    plain: bool,
     bytes: Vec<u8>,
+    /// the fragments of the packet which is currently being received
+    fragments: Vec<u8>,
+    progress: Option<QBPProgress>,
+    /// when bytes have been received for the last time
+    last_received: Option<Instant>,
 }
 
 impl QBPReader {
-    /// Read a packet.
+    /// Decode the contents of an error frame.
+    fn peer_error(packet: &[u8]) -> Error {
+        if packet.len() < 2 {
+            return Error::PeerError(error_code::OTHER, String::new());
+        }
+        let code = u16::from_be_bytes([packet[0], packet[1]]);
+        let msg = String::from_utf8_lossy(&packet[2..]).into_owned();
+        Error::PeerError(code, msg)
+    }
+
+    /// Returns the amount of bytes of the current packet received so far.
+    fn received(&self) -> usize {
+        match self.packet_len {
+            Some(len) => self.bytes.len().min(len),
+            None => self.bytes.len(),
+        }
+    }
+
+    /// Read a packet into the given buffer, which gets cleared first.
+    /// The buffer is only touched once the packet is complete.
     ///
     /// # Cancelation Safety
     /// This method is cancelation safe.
-    pub async fn read(&mut self, read: &mut impl Read) -> Result<Vec<u8>> {
+    pub async fn read_into(&mut self, read: &mut impl Read, buf: &mut Vec<u8>) -> Result<()> {
         trace!("read: read packet");
         loop {
             // process loop
@@ -707,24 +2020,60 @@ impl QBPReader {
                 trace!("read: bytes in buffer {}", self.bytes.len());
                 match self.packet_len {
                     Some(len) => {
+                        let heartbeat = self.packet_flags & HEARTBEAT_FLAG != 0;
+                        if let (Some(QBPProgress(progress)), false) =
+                            (&mut self.progress, heartbeat)
+                        {
+                            // fragments count towards the packet they belong to
+                            let done = self.fragments.len();
+                            progress(done + self.bytes.len().min(len), done + len);
+                        }
+
                         // read payload
                         if self.bytes.len() >= len {
                             trace!("read: complete");
-                            let packet = self.bytes.drain(0..len).collect::<Vec<_>>();
                             self.packet_len = None;
-                            return Ok(packet);
+                            if self.packet_flags & ERROR_FLAG != 0 {
+                                let err = Self::peer_error(&self.bytes[0..len]);
+                                self.bytes.drain(0..len);
+                                return Err(err);
+                            }
+                            if self.packet_flags & HEARTBEAT_FLAG != 0 {
+                                self.bytes.drain(0..len);
+                                continue;
+                            }
+                            if self.packet_flags & FRAGMENT_FLAG != 0 {
+                                self.fragments.extend(self.bytes.drain(0..len));
+                                if self.packet_flags & MORE_FLAG != 0 {
+                                    continue;
+                                }
+                                self.tagged = self.packet_flags & TAGGED_FLAG != 0;
+                                self.plain = self.packet_flags & PLAIN_FLAG != 0;
+                                // swap instead of copying, so that both
+                                // buffers keep their capacity
+                                std::mem::swap(buf, &mut self.fragments);
+                                self.fragments.clear();
+                                return Ok(());
+                            }
+                            self.tagged = self.packet_flags & TAGGED_FLAG != 0;
+                            self.plain = self.packet_flags & PLAIN_FLAG != 0;
+                            buf.clear();
+                            buf.extend(self.bytes.drain(0..len));
+                            return Ok(());
                         } else {
                             break;
                         }
                     }
                     None => {
                         // read length
-                        if self.bytes.len() >= 8 {
-                            let mut len_bytes = [0u8; 8];
-                            len_bytes.copy_from_slice(&self.bytes[0..8]);
+                        if self.bytes.len() >= LENGTH_PREFIX_LEN {
+                            let mut len_bytes = [0u8; LENGTH_PREFIX_LEN];
+                            len_bytes.copy_from_slice(&self.bytes[0..LENGTH_PREFIX_LEN]);
                             // remove len bytes from buffer
-                            self.bytes.drain(0..8);
-                            let len = u64::from_be_bytes(len_bytes) as usize;
+                            self.bytes.drain(0..LENGTH_PREFIX_LEN);
+                            let len = u64::from_be_bytes(len_bytes);
+                            self.packet_flags = len & FLAGS;
+                            let len = (len & !FLAGS) as usize;
                             trace!("read: len: {}", len);
                             self.packet_len = Some(len);
                         } else {
@@ -741,7 +2090,225 @@ impl QBPReader {
             if len == 0 {
                 return Err(Error::Closed);
             }
+            self.last_received = Some(Instant::now());
             self.bytes.extend_from_slice(&bytes[0..len]);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncWriteExt, DuplexStream};
+
+    use super::*;
+
+    /// Negotiate a connection over an in-memory pipe.
+    async fn connect() -> (QBP, QBP, DuplexStream, DuplexStream) {
+        let (mut a, mut b) = tokio::io::duplex(64 * 1024);
+        let (mut host, mut peer) = (QBP::default(), QBP::default());
+        let (res_a, res_b) = tokio::join!(host.negotiate(&mut a), peer.negotiate(&mut b));
+        res_a.unwrap();
+        res_b.unwrap();
+        (host, peer, a, b)
+    }
+
+    /// Negotiate a connection with a peer which only sends the given
+    /// header packet, like an older peer which announces fewer features.
+    async fn connect_raw(header: QBPHeaderPacket) -> (Result<QBP>, DuplexStream, DuplexStream) {
+        let (mut a, mut b) = tokio::io::duplex(64 * 1024);
+        let mut host = QBP::default();
+        let mut peer = QBP::default();
+        let header = header.serialize();
+        let (res, sent) = tokio::join!(host.negotiate(&mut a), peer.send_packet(&mut b, &header));
+        sent.unwrap();
+        // the header packet of the host
+        peer.recv_packet(&mut b).await.unwrap();
+        (res.map(|_| host), a, b)
+    }
+
+    #[tokio::test]
+    async fn reject_mismatched_framing() {
+        let framing = |mut header: QBPHeaderPacket| {
+            let version = (FRAMING_VERSION + 1).to_string();
+            header.headers.insert(FRAMING_HEADER.to_owned(), version);
+            header
+        };
+        let (res, _a, _b) = connect_raw(framing(QBPHeaderPacket::host())).await;
+        let err = res.err();
+        assert!(
+            matches!(&err, Some(Error::FramingMismatch(v)) if v == "1"),
+            "{err:?}"
+        );
+
+        // a resumed connection checks the version with the first packet
+        let (host, peer, _a, _b) = connect().await;
+        let mut host = QBP::default().with_resumption(host.resumption().unwrap().clone());
+        let mut peer = QBP::default().with_resumption(peer.resumption().unwrap().clone());
+        let header = framing(peer.host_header()).serialize();
+        let (mut a, mut b) = tokio::io::duplex(64 * 1024);
+        let (res, sent) = tokio::join!(host.negotiate(&mut a), peer.send_packet(&mut b, &header));
+        res.unwrap();
+        sent.unwrap();
+        let res = host.recv::<String>(&mut a).await;
+        assert!(
+            matches!(&res, Err(Error::FramingMismatch(v)) if v == "1"),
+            "{res:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn peer_error_keeps_connection() {
+        let (mut host, mut peer, mut a, mut b) = connect().await;
+        host.send_error(&mut a, error_code::APPLY_FAILED, "no space left")
+            .await
+            .unwrap();
+        host.send(&mut a, "hello".to_owned()).await.unwrap();
+
+        let res = peer.recv::<String>(&mut b).await;
+        assert!(
+            matches!(&res, Err(Error::PeerError(error_code::APPLY_FAILED, msg)) if msg == "no space left"),
+            "{res:?}"
+        );
+        assert!(res.unwrap_err().is_recoverable());
+        assert_eq!(peer.recv::<String>(&mut b).await.unwrap(), "hello");
+    }
+
+    #[tokio::test]
+    async fn error_frame_requires_support() {
+        let (host, mut a, _b) = connect_raw(QBPHeaderPacket::host()).await;
+        let mut host = host.unwrap();
+        let mut written = Vec::new();
+        let res = host.send_error(&mut written, error_code::OTHER, "").await;
+        assert!(
+            matches!(res, Err(Error::Unsupported(ERRORS_HEADER))),
+            "{res:?}"
+        );
+        assert!(written.is_empty());
+        // the connection can still be used
+        host.send(&mut a, "hello".to_owned()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn tagged_packet_requires_support() {
+        let (host, mut a, _b) = connect_raw(QBPHeaderPacket::host()).await;
+        let mut host = host.unwrap();
+        let mut written = Vec::new();
+        let res = host.send_tagged(&mut written, 1, "hello".to_owned()).await;
+        assert!(
+            matches!(res, Err(Error::Unsupported(TAGGED_HEADER))),
+            "{res:?}"
+        );
+        assert!(written.is_empty());
+        host.send(&mut a, "hello".to_owned()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn small_payload_is_not_compressed() {
+        let (mut host, mut peer, mut a, mut b) = connect().await;
+        let payload = [1u8; 10];
+        let (packet, flags) = host.encode(&payload).unwrap();
+        assert_eq!(flags, PLAIN_FLAG);
+        assert_eq!(packet, payload);
+
+        host.send_payload(&mut a, &payload).await.unwrap();
+        assert_eq!(peer.recv_payload(&mut b).await.unwrap(), payload);
+    }
+
+    #[tokio::test]
+    async fn large_payload_is_compressed() {
+        let (mut host, mut peer, mut a, mut b) = connect().await;
+        let payload = [1u8; 10 * 1024];
+        let (packet, flags) = host.encode(&payload).unwrap();
+        assert_eq!(flags, 0);
+        assert!(packet.len() < payload.len());
+
+        host.send_payload(&mut a, &payload).await.unwrap();
+        assert_eq!(peer.recv_payload(&mut b).await.unwrap(), payload);
+    }
+
+    #[tokio::test]
+    async fn cancel_aborts_stuck_recv() {
+        let (mut host, mut peer, mut a, mut b) = connect().await;
+        let mut packet = Vec::new();
+        host.send(&mut packet, "hello".to_owned()).await.unwrap();
+        // the peer stops sending in the middle of the packet
+        let (head, tail) = packet.split_at(LENGTH_PREFIX_LEN / 2);
+        a.write_all(head).await.unwrap();
+
+        let cancel = CancellationToken::new();
+        tokio::spawn({
+            let cancel = cancel.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                cancel.cancel();
+            }
+        });
+        let res = tokio::time::timeout(
+            Duration::from_secs(5),
+            peer.recv_cancelable::<String>(&mut b, &cancel),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(res, Err(Error::Canceled)), "{res:?}");
+
+        // receiving again resumes the packet
+        a.write_all(tail).await.unwrap();
+        assert_eq!(peer.recv::<String>(&mut b).await.unwrap(), "hello");
+    }
+
+    #[test]
+    fn zstd_round_trip() {
+        let data = (0..1 << 20).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let encoded = QBPContentEncoding::Zstd.encode(&data);
+        assert!(encoded.len() < data.len());
+        assert_eq!(QBPContentEncoding::Zstd.decode(&encoded).unwrap(), data);
+    }
+
+    #[test]
+    fn lower_level_compresses_less() {
+        // text made of pseudo-random words, which leaves some
+        // room for the higher levels to find better matches
+        let words = [
+            "sync", "file", "change", "device", "tree", "hash", "diff", "peer",
+        ];
+        let mut state = 1u32;
+        let data = (0..16 * 1024)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                words[(state >> 16) as usize % words.len()]
+            })
+            .join(" ");
+        for encoding in SUPPORTED_CONTENT_ENCODINGS.values() {
+            if encoding == &QBPContentEncoding::Plain {
+                continue;
+            }
+            let fast = encoding.encode_with_level(data.as_bytes(), Some(1));
+            let best = encoding.encode(data.as_bytes());
+            assert!(
+                fast.len() > best.len(),
+                "{encoding:?}: {} <= {}",
+                fast.len(),
+                best.len()
+            );
+            assert_eq!(encoding.decode(&fast).unwrap(), data.as_bytes());
+        }
+    }
+
+    #[test]
+    fn corrupt_payload_fails_to_decode() {
+        for encoding in SUPPORTED_CONTENT_ENCODINGS.values() {
+            if encoding == &QBPContentEncoding::Plain {
+                continue;
+            }
+            let mut encoded = encoding.encode(&[7u8; 1024]);
+            encoded.truncate(encoded.len() / 2);
+            let res = encoding.decode(&encoded);
+            assert!(
+                matches!(res, Err(Error::DecompressFailed { .. })),
+                "{encoding:?}: {res:?}"
+            );
+        }
+    }
+}